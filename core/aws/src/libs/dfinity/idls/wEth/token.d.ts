@@ -50,10 +50,13 @@ export interface TokenInfo {
 }
 
 export default interface _SERVICE {
+  acceptOwnership: () => Promise<undefined>;
+  addMinter: (arg_0: Principal) => Promise<undefined>;
   allowance: (arg_0: Principal, arg_1: Principal) => Promise<bigint>;
   approve: (arg_0: Principal, arg_1: bigint) => Promise<Result>;
   balanceOf: (arg_0: Principal) => Promise<bigint>;
   burn: (arg_0: bigint) => Promise<Result>;
+  cancelOwnershipTransfer: () => Promise<undefined>;
   decimals: () => Promise<number>;
   getAllowanceSize: () => Promise<bigint>;
   getHolders: (
@@ -62,6 +65,7 @@ export default interface _SERVICE {
   ) => Promise<Array<[Principal, bigint]>>;
   getLogo: () => Promise<string>;
   getMetadta: () => Promise<Metadata>;
+  getMinters: () => Promise<Array<Principal>>;
   getTokenInfo: () => Promise<TokenInfo>;
   getTransaction: (arg_0: bigint) => Promise<OpRecord>;
   getTransactions: (arg_0: bigint, arg_1: bigint) => Promise<Array<OpRecord>>;
@@ -76,10 +80,12 @@ export default interface _SERVICE {
   mint: (arg_0: Principal, arg_1: bigint) => Promise<Result>;
   name: () => Promise<string>;
   owner: () => Promise<Principal>;
+  pendingOwner: () => Promise<[] | [Principal]>;
+  proposeOwner: (arg_0: Principal) => Promise<undefined>;
+  removeMinter: (arg_0: Principal) => Promise<undefined>;
   setFee: (arg_0: bigint) => Promise<undefined>;
   setFeeTo: (arg_0: Principal) => Promise<undefined>;
   setLogo: (arg_0: string) => Promise<undefined>;
-  setOwner: (arg_0: Principal) => Promise<undefined>;
   symbol: () => Promise<string>;
   totalSupply: () => Promise<bigint>;
   transfer: (arg_0: Principal, arg_1: bigint) => Promise<Result>;
@@ -47,10 +47,13 @@ export default ({ IDL }: { IDL: any }) => {
     amount: IDL.Nat64,
   });
   return IDL.Service({
+    acceptOwnership: IDL.Func([], [], []),
+    addMinter: IDL.Func([IDL.Principal], [], []),
     allowance: IDL.Func([IDL.Principal, IDL.Principal], [IDL.Nat64], ['query']),
     approve: IDL.Func([IDL.Principal, IDL.Nat64], [Result], []),
     balanceOf: IDL.Func([IDL.Principal], [IDL.Nat64], ['query']),
     burn: IDL.Func([IDL.Nat64], [Result], []),
+    cancelOwnershipTransfer: IDL.Func([], [], []),
     decimals: IDL.Func([], [IDL.Nat8], ['query']),
     getAllowanceSize: IDL.Func([], [IDL.Nat64], ['query']),
     getHolders: IDL.Func(
@@ -60,6 +63,7 @@ export default ({ IDL }: { IDL: any }) => {
     ),
     getLogo: IDL.Func([], [IDL.Text], ['query']),
     getMetadta: IDL.Func([], [Metadata], ['query']),
+    getMinters: IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
     getTokenInfo: IDL.Func([], [TokenInfo], ['query']),
     getTransaction: IDL.Func([IDL.Nat64], [OpRecord], ['query']),
     getTransactions: IDL.Func(
@@ -82,10 +86,12 @@ export default ({ IDL }: { IDL: any }) => {
     mint: IDL.Func([IDL.Principal, IDL.Nat64], [Result], []),
     name: IDL.Func([], [IDL.Text], ['query']),
     owner: IDL.Func([], [IDL.Principal], ['query']),
+    pendingOwner: IDL.Func([], [IDL.Opt(IDL.Principal)], ['query']),
+    proposeOwner: IDL.Func([IDL.Principal], [], []),
+    removeMinter: IDL.Func([IDL.Principal], [], []),
     setFee: IDL.Func([IDL.Nat64], [], []),
     setFeeTo: IDL.Func([IDL.Principal], [], []),
     setLogo: IDL.Func([IDL.Text], [], []),
-    setOwner: IDL.Func([IDL.Principal], [], []),
     symbol: IDL.Func([], [IDL.Text], ['query']),
     totalSupply: IDL.Func([], [IDL.Nat64], ['query']),
     transfer: IDL.Func([IDL.Principal, IDL.Nat64], [Result], []),
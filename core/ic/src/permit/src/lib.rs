@@ -0,0 +1,155 @@
+/**
+* Module     : lib.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+// approvals signed off-chain, anyone can submit them. The owner is the
+// self-authenticating principal of the signing key, the principal the
+// same key has when it calls the token itself. Shared by the tokens that
+// accept permits and the proxies that submit them
+use candid::Nat;
+use ic_kit::Principal;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use std::convert::TryFrom;
+
+// keeps permit signatures from being valid for any other message
+const PERMIT_DOMAIN: &[u8] = b"\x0cdip20-permit";
+// der encoding of a public key is this prefix followed by the raw key
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const SECP256K1_DER_PREFIX: [u8; 23] = [
+    0x30, 0x56, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b,
+    0x81, 0x04, 0x00, 0x0a, 0x03, 0x42, 0x00,
+];
+
+// told apart by length, 32 bytes for ed25519 and 33 or 65 bytes
+// of sec1 encoding for secp256k1
+pub enum PermitKey {
+    Ed25519(ed25519_dalek::PublicKey),
+    Secp256k1(k256::ecdsa::VerifyingKey),
+}
+
+impl PermitKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        match bytes.len() {
+            32 => ed25519_dalek::PublicKey::from_bytes(bytes)
+                .map(PermitKey::Ed25519)
+                .map_err(|_| "Invalid ed25519 public key".to_string()),
+            33 | 65 => k256::ecdsa::VerifyingKey::from_sec1_bytes(bytes)
+                .map(PermitKey::Secp256k1)
+                .map_err(|_| "Invalid secp256k1 public key".to_string()),
+            len => Err(format!("Unsupported public key of {} bytes", len)),
+        }
+    }
+
+    pub fn owner(&self) -> Principal {
+        let der = match self {
+            PermitKey::Ed25519(key) => [&ED25519_DER_PREFIX[..], key.as_bytes()].concat(),
+            PermitKey::Secp256k1(key) => [
+                &SECP256K1_DER_PREFIX[..],
+                key.to_encoded_point(false).as_bytes(),
+            ]
+            .concat(),
+        };
+        Principal::self_authenticating(der)
+    }
+
+    // secp256k1 signatures are ecdsa over the sha-256 of the message,
+    // 64 bytes of r and s
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        match self {
+            PermitKey::Ed25519(key) => {
+                let signature = ed25519_dalek::Signature::try_from(signature)
+                    .map_err(|_| "Malformed ed25519 signature".to_string())?;
+                key.verify_strict(message, &signature)
+                    .map_err(|_| "Invalid ed25519 signature".to_string())
+            }
+            PermitKey::Secp256k1(key) => {
+                use k256::ecdsa::signature::Verifier;
+
+                let signature = k256::ecdsa::Signature::try_from(signature)
+                    .map_err(|_| "Malformed secp256k1 signature".to_string())?;
+                key.verify(message, &signature)
+                    .map_err(|_| "Invalid secp256k1 signature".to_string())
+            }
+        }
+    }
+}
+
+// the message the owner signs, principals and the value are length prefixed,
+// numbers are big endian
+pub fn permit_message(
+    token: Principal,
+    owner: Principal,
+    spender: Principal,
+    value: &Nat,
+    deadline: u64,
+    nonce: u64,
+) -> Vec<u8> {
+    let mut message = PERMIT_DOMAIN.to_vec();
+    for principal in &[token, owner, spender] {
+        message.push(principal.as_slice().len() as u8);
+        message.extend_from_slice(principal.as_slice());
+    }
+    let value = value.0.to_bytes_be();
+    message.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    message.extend_from_slice(&value);
+    message.extend_from_slice(&deadline.to_be_bytes());
+    message.extend_from_slice(&nonce.to_be_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+    use ic_kit::mock_principals;
+
+    #[test]
+    fn test_ed25519_permit() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let keypair = ed25519_dalek::Keypair { secret, public };
+        let key = PermitKey::from_bytes(public.as_bytes()).unwrap();
+        let message = permit_message(
+            mock_principals::alice(),
+            key.owner(),
+            mock_principals::bob(),
+            &Nat::from(50),
+            u64::MAX,
+            0,
+        );
+        let signature = keypair.sign(&message).to_bytes();
+
+        assert!(key.verify(&message, &signature).is_ok());
+        assert!(key.verify(&message[1..], &signature).is_err());
+        assert!(key.verify(&message, &signature[1..]).is_err());
+        // the same key always has the same owner
+        assert_eq!(
+            key.owner(),
+            PermitKey::from_bytes(public.as_bytes()).unwrap().owner()
+        );
+    }
+
+    #[test]
+    fn test_secp256k1_owner() {
+        let signing = k256::ecdsa::SigningKey::from_bytes(&[7; 32]).unwrap();
+        let verifying = signing.verifying_key();
+        let compressed = verifying.to_encoded_point(true);
+        let uncompressed = verifying.to_encoded_point(false);
+
+        // either encoding of the key is the same owner
+        assert_eq!(
+            PermitKey::from_bytes(compressed.as_bytes())
+                .unwrap()
+                .owner(),
+            PermitKey::from_bytes(uncompressed.as_bytes())
+                .unwrap()
+                .owner()
+        );
+        assert!(PermitKey::from_bytes(&[0; 16]).is_err());
+    }
+}
@@ -1,16 +1,35 @@
 use candid::{candid_method, Principal};
-use ic_cdk_macros::update;
+use ic_cdk_macros::{query, update};
 
-use crate::tera::STATE;
+use crate::{
+    common::{
+        types::{AuditEntry, ProposalAction},
+        utils::args_digest,
+    },
+    tera::STATE,
+};
+
+use super::governance::open_proposal;
 
 pub fn is_authorized() -> Result<(), String> {
     STATE.with(|s| s.is_authorized())
 }
 
-#[update(name = "authorize")]
+/// Opens a proposal to authorize other, returns the proposal id
+#[update(name = "authorize", guard = "is_authorized")]
 #[candid_method(update)]
-fn authorize(other: Principal) {
-    STATE.with(|s| s.authorize(other))
+fn authorize(other: Principal) -> Result<u64, String> {
+    open_proposal(
+        "authorize",
+        args_digest((other,)),
+        ProposalAction::Authorize(other),
+    )
+}
+
+#[query(name = "get_audit_log", guard = "is_authorized")]
+#[candid_method(query, rename = "get_audit_log")]
+fn get_audit_log(start: u64, limit: u64) -> Vec<AuditEntry> {
+    STATE.with(|s| s.get_audit_log(start, limit as usize))
 }
 
 #[cfg(test)]
@@ -29,7 +48,7 @@ mod tests {
     fn test_authorize() {
         let mock_ctx = before_each();
 
-        authorize(mock_principals::bob());
+        let _ = authorize(mock_principals::bob());
 
         mock_ctx.update_caller(mock_principals::bob());
         let is_authorized = STATE.with(|s| s.is_authorized());
@@ -38,4 +57,24 @@ mod tests {
 
         assert!(is_authorized.is_ok());
     }
+
+    #[test]
+    fn test_authorize_audit() {
+        before_each();
+        STATE.with(|s| s.authorized.borrow_mut().push(mock_principals::alice()));
+
+        let id = authorize(mock_principals::bob()).unwrap();
+
+        let audit_log = get_audit_log(0, 10);
+
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].method, "authorize");
+        assert_eq!(
+            audit_log[0].args_digest,
+            args_digest((mock_principals::bob(),))
+        );
+        // a single approval without delay executes right away
+        assert_eq!(audit_log[1].method, "execute_proposal");
+        assert_eq!(audit_log[1].args_digest, args_digest((id,)));
+    }
 }
@@ -2,6 +2,7 @@ use candid::{candid_method, Nat, Principal};
 use ic_cdk_macros::update;
 use ic_kit::ic::caller;
 
+use super::governance::is_not_paused;
 use crate::{
     common::{
         types::{ConsumeMessageResponse, IncomingMessageHashParams, Message, Nonce},
@@ -10,7 +11,7 @@ use crate::{
     tera::{ToNat, STATE},
 };
 
-#[update(name = "consume_message")]
+#[update(name = "consume_message", guard = "is_not_paused")]
 #[candid_method(update, rename = "consume_message")]
 fn consume(from: Principal, nonce: Nonce, payload: Vec<Nat>) -> ConsumeMessageResponse {
     let nonce_exists = STATE.with(|s| s.nonce_exists(&nonce));
@@ -0,0 +1,243 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+use ic_kit::ic::{caller, time};
+
+use super::admin::is_authorized;
+use crate::{
+    common::{
+        types::{AuditOutcome, GovernanceConfig, Proposal, ProposalAction},
+        utils::args_digest,
+    },
+    tera::STATE,
+};
+
+pub fn is_not_paused() -> Result<(), String> {
+    STATE.with(|s| s.is_not_paused())
+}
+
+fn validate_action(action: &ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::SetGovernance(config) => config.validate(),
+        _ => Ok(()),
+    }
+}
+
+fn apply_action(action: ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::Authorize(other) => STATE.with(|s| {
+            let mut authorized = s.authorized.borrow_mut();
+            if !authorized.contains(&other) {
+                authorized.push(other);
+            }
+        }),
+        ProposalAction::SetGovernance(config) => {
+            config.validate()?;
+            STATE.with(|s| s.governance.replace(config));
+        }
+        ProposalAction::Unpause => {
+            STATE.with(|s| s.paused.replace(false));
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute a ready proposal and record the outcome
+fn execute(id: u64) -> Result<(), String> {
+    let result = STATE
+        .with(|s| s.take_ready_proposal(id, time()))
+        .and_then(|proposal| apply_action(proposal.action));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "execute_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+/// Execute a proposal if it needs no further approvals or delay,
+/// returns whether it was executed
+fn execute_if_ready(id: u64) -> Result<bool, String> {
+    if STATE.with(|s| s.proposal_ready(id, time())).is_err() {
+        return Ok(false);
+    }
+
+    execute(id).map(|_| true)
+}
+
+/// Open a proposal approved by the caller
+pub fn open_proposal(method: &str, digest: String, action: ProposalAction) -> Result<u64, String> {
+    let result =
+        validate_action(&action).map(|_| STATE.with(|s| s.propose(caller(), action, time())));
+
+    STATE.with(|s| s.record_audit(method, digest, AuditOutcome::from(&result)));
+
+    let id = result?;
+    execute_if_ready(id)?;
+
+    Ok(id)
+}
+
+#[update(name = "propose", guard = "is_authorized")]
+#[candid_method(update, rename = "propose")]
+fn propose(action: ProposalAction) -> Result<u64, String> {
+    open_proposal("propose", args_digest((&action,)), action)
+}
+
+#[update(name = "approve_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "approve_proposal")]
+fn approve_proposal(id: u64) -> Result<bool, String> {
+    let result = STATE.with(|s| s.approve_proposal(id, caller(), time()));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "approve_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result?;
+    execute_if_ready(id)
+}
+
+#[update(name = "execute_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "execute_proposal")]
+fn execute_proposal(id: u64) -> Result<(), String> {
+    execute(id)
+}
+
+#[update(name = "cancel_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "cancel_proposal")]
+fn cancel_proposal(id: u64) -> Result<(), String> {
+    let result = STATE.with(|s| s.cancel_proposal(id)).map(|_| ());
+
+    STATE.with(|s| {
+        s.record_audit(
+            "cancel_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+#[query(name = "get_pending_proposals", guard = "is_authorized")]
+#[candid_method(query, rename = "get_pending_proposals")]
+fn get_pending_proposals() -> Vec<Proposal> {
+    STATE.with(|s| s.get_pending_proposals(time()))
+}
+
+#[query(name = "get_governance_config", guard = "is_authorized")]
+#[candid_method(query, rename = "get_governance_config")]
+fn get_governance_config() -> GovernanceConfig {
+    STATE.with(|s| s.governance.borrow().clone())
+}
+
+/// Pausing takes effect immediately, unpausing is a proposal
+#[update(name = "pause", guard = "is_authorized")]
+#[candid_method(update, rename = "pause")]
+fn pause() {
+    STATE.with(|s| s.paused.replace(true));
+    STATE.with(|s| s.record_audit("pause", args_digest(()), AuditOutcome::Ok));
+}
+
+#[query(name = "paused")]
+#[candid_method(query, rename = "paused")]
+fn paused() -> bool {
+    STATE.with(|s| *s.paused.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    fn before_each() -> &'static mut MockContext {
+        let mock_ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.authorized
+                .borrow_mut()
+                .extend([mock_principals::alice(), mock_principals::bob()])
+        });
+
+        mock_ctx
+    }
+
+    #[test]
+    fn test_single_approval_executes() {
+        before_each();
+
+        let id = propose(ProposalAction::Authorize(mock_principals::john())).unwrap();
+
+        assert!(STATE.with(|s| s.authorized.borrow().contains(&mock_principals::john())));
+        assert!(STATE.with(|s| s.proposals.borrow().get(&id).is_none()));
+    }
+
+    #[test]
+    fn test_approval_executes() {
+        let mock_ctx = before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        let id = propose(ProposalAction::Authorize(mock_principals::john())).unwrap();
+
+        assert!(!STATE.with(|s| s.authorized.borrow().contains(&mock_principals::john())));
+        assert_eq!(get_pending_proposals().len(), 1);
+        assert!(execute_proposal(id).is_err());
+
+        mock_ctx.update_caller(mock_principals::bob());
+        assert_eq!(approve_proposal(id), Ok(true));
+
+        assert!(STATE.with(|s| s.authorized.borrow().contains(&mock_principals::john())));
+        assert!(get_pending_proposals().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_governance_rejected() {
+        before_each();
+
+        let config = GovernanceConfig {
+            threshold: 0,
+            ..GovernanceConfig::default()
+        };
+
+        assert!(propose(ProposalAction::SetGovernance(config)).is_err());
+        assert!(get_pending_proposals().is_empty());
+    }
+
+    #[test]
+    fn test_pause_unpause() {
+        let mock_ctx = before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        pause();
+        assert!(is_not_paused().is_err());
+
+        let id = propose(ProposalAction::Unpause).unwrap();
+        assert!(paused());
+
+        mock_ctx.update_caller(mock_principals::bob());
+        assert_eq!(approve_proposal(id), Ok(true));
+        assert!(is_not_paused().is_ok());
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        let id = propose(ProposalAction::Unpause).unwrap();
+
+        assert!(cancel_proposal(id).is_ok());
+        assert!(approve_proposal(id).is_err());
+    }
+}
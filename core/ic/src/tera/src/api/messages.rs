@@ -3,14 +3,22 @@ use ic_cdk_macros::{query, update};
 
 use super::admin::is_authorized;
 use crate::{
-    common::types::{OutgoingMessagePair, RemoveMessagesResponse},
+    common::{
+        types::{AuditOutcome, OutgoingMessagePair, RemoveMessagesResponse},
+        utils::args_digest,
+    },
     tera::STATE,
 };
 
 #[update(name = "remove_messages", guard = "is_authorized")]
 #[candid_method(update, rename = "remove_messages")]
 fn remove_messages(messages: Vec<OutgoingMessagePair>) -> RemoveMessagesResponse {
-    STATE.with(|s| RemoveMessagesResponse(s.remove_messages(messages)))
+    let digest = args_digest((&messages,));
+    let result = STATE.with(|s| s.remove_messages(messages));
+
+    STATE.with(|s| s.record_audit("remove_messages", digest, AuditOutcome::from(&result)));
+
+    RemoveMessagesResponse(result)
 }
 
 #[query(name = "get_messages", guard = "is_authorized")]
@@ -71,5 +79,10 @@ mod tests {
         let stored_messages = get_messages();
 
         assert_eq!(stored_messages.len(), 0);
+
+        let audit_log = STATE.with(|s| s.get_audit_log(0, 10));
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].method, "remove_messages");
+        assert_eq!(audit_log[0].outcome, AuditOutcome::Ok);
     }
 }
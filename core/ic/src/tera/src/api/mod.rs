@@ -1,5 +1,6 @@
 pub mod admin;
 pub mod consume_message;
+pub mod governance;
 pub mod init;
 pub mod inspect_message;
 pub mod messages;
@@ -2,6 +2,7 @@ use candid::{candid_method, Nat, Principal};
 use ic_cdk_macros::update;
 use ic_kit::ic::caller;
 
+use super::governance::is_not_paused;
 use crate::{
     common::{
         types::{Message, OutgoingMessageHashParams, SendMessageResponse},
@@ -10,7 +11,7 @@ use crate::{
     tera::{ToNat, STATE},
 };
 
-#[update(name = "send_message")]
+#[update(name = "send_message", guard = "is_not_paused")]
 #[candid_method(update, rename = "send_message")]
 fn send(to: Principal, payload: Vec<Nat>) -> SendMessageResponse {
     let caller = caller();
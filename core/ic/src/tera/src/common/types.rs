@@ -1,4 +1,4 @@
-use ic_kit::candid::{CandidType, Deserialize, Nat};
+use ic_kit::candid::{CandidType, Deserialize, Nat, Principal};
 use serde::Serialize;
 
 pub type Nonce = Nat;
@@ -51,3 +51,80 @@ pub struct OutgoingMessagePair {
     pub(crate) msg_key: String,
     pub(crate) msg_hash: String,
 }
+
+#[derive(Serialize, Clone, Copy, CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub enum IngressPolicy {
+    /// Any non-anonymous principal
+    Public,
+    /// Principals in the authorized list only
+    Authorized,
+    /// Rejected for every ingress caller
+    Disabled,
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub struct GovernanceConfig {
+    /// Approvals required before a proposal can execute
+    pub(crate) threshold: u32,
+    /// Nanoseconds a proposal waits before it can execute
+    pub(crate) delay: u64,
+    /// Nanoseconds before a pending proposal expires
+    pub(crate) ttl: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            threshold: 1,
+            delay: 0,
+            ttl: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+impl GovernanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            return Err("Threshold must be at least 1".to_string());
+        }
+
+        if self.ttl <= self.delay {
+            return Err("Proposal ttl must be longer than the delay".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub enum ProposalAction {
+    Authorize(Principal),
+    SetGovernance(GovernanceConfig),
+    Unpause,
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize, Debug)]
+pub struct Proposal {
+    pub(crate) id: u64,
+    pub(crate) action: ProposalAction,
+    pub(crate) proposer: Principal,
+    pub(crate) approvals: Vec<Principal>,
+    pub(crate) created_at: u64,
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub(crate) index: u64,
+    pub(crate) caller: Principal,
+    pub(crate) method: String,
+    /// Hex encoded keccak256 of the candid encoded arguments
+    pub(crate) args_digest: String,
+    pub(crate) time: u64,
+    pub(crate) outcome: AuditOutcome,
+}
@@ -1,7 +1,25 @@
-use candid::Nat;
+use candid::{encode_args, utils::ArgumentEncoder, Nat};
 use sha3::{Digest, Keccak256};
 
-use super::types::{IncomingMessageHashParams, Message, OutgoingMessageHashParams};
+use super::types::{AuditOutcome, IncomingMessageHashParams, Message, OutgoingMessageHashParams};
+
+impl<T> From<&Result<T, String>> for AuditOutcome {
+    fn from(result: &Result<T, String>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Ok,
+            Err(error) => AuditOutcome::Err(error.clone()),
+        }
+    }
+}
+
+/// Digest of candid encoded call arguments for the audit log
+pub fn args_digest<T: ArgumentEncoder>(args: T) -> String {
+    let mut hasher = Keccak256::new();
+
+    hasher.update(encode_args(args).unwrap_or_default());
+
+    hex::encode(hasher.finalize().to_vec())
+}
 
 pub trait Keccak256HashFn<T> {
     fn calculate_hash(&self, params: T) -> String;
@@ -1,5 +1,6 @@
 mod api;
 mod common;
+mod migration;
 mod tera;
 mod upgrade;
 
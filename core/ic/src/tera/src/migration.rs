@@ -0,0 +1,290 @@
+use candid::de::IDLDeserialize;
+use candid::{encode_args, CandidType, Deserialize, Principal};
+use ic_cdk::api::stable;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    common::types::{
+        AuditEntry, GovernanceConfig, IngressPolicy, Nonce, OutgoingMessage, Proposal,
+    },
+    tera::StableTerabetiaState,
+};
+
+/// Written ahead of the versioned envelope, the bare record of
+/// canisters deployed before versioning starts with the candid magic
+const ENVELOPE_MAGIC: &[u8; 4] = b"VSTA";
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Outgoing message as deployed, frozen so later fields do not change it
+#[derive(Serialize, Clone, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub struct OutgoingMessageV0 {
+    #[serde(with = "serde_bytes")]
+    pub msg_key: Vec<u8>,
+    pub msg_hash: String,
+}
+
+/// Stable state layout before versioning was introduced,
+/// stored as a bare record
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableTerabetiaStateV0 {
+    pub messages: HashMap<String, u32>,
+    pub nonce: HashSet<Nonce>,
+    pub messages_out: HashSet<OutgoingMessageV0>,
+    pub message_out_index: u64,
+    pub authorized: Vec<Principal>,
+}
+
+/// Stable state layout of version 1, frozen so a change to the live
+/// state needs a new version and a migration
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableTerabetiaStateV1 {
+    pub messages: HashMap<String, u32>,
+    pub nonce: HashSet<Nonce>,
+    pub messages_out: HashSet<OutgoingMessage>,
+    pub message_out_index: u64,
+    pub authorized: Vec<Principal>,
+    pub ingress_policies: HashMap<String, IngressPolicy>,
+    pub audit_log: Vec<AuditEntry>,
+    pub audit_index: u64,
+    pub governance: GovernanceConfig,
+    pub proposals: HashMap<u64, Proposal>,
+    pub proposal_index: u64,
+    pub paused: bool,
+}
+
+/// Versioned envelope written to stable memory on every upgrade
+#[derive(CandidType, Deserialize)]
+pub enum VersionedTerabetiaState {
+    V0(StableTerabetiaStateV0),
+    V1(StableTerabetiaStateV1),
+}
+
+impl VersionedTerabetiaState {
+    /// Wrap state in the current version
+    pub fn current(state: StableTerabetiaState) -> Self {
+        VersionedTerabetiaState::V1(state.into())
+    }
+
+    /// Apply every migration from the stored version up to the current one
+    pub fn migrate(self) -> Result<StableTerabetiaState, String> {
+        match self {
+            VersionedTerabetiaState::V0(state) => {
+                VersionedTerabetiaState::V1(migrate_v0_to_v1(state)?).migrate()
+            }
+            VersionedTerabetiaState::V1(state) => Ok(state.into()),
+        }
+    }
+}
+
+fn migrate_v0_to_v1(state: StableTerabetiaStateV0) -> Result<StableTerabetiaStateV1, String> {
+    // new outgoing message keys are derived from the index,
+    // so it can never be behind the number of stored messages
+    if (state.messages_out.len() as u64) > state.message_out_index {
+        return Err(format!(
+            "Outgoing message index {} is behind {} stored messages",
+            state.message_out_index,
+            state.messages_out.len()
+        ));
+    }
+
+    Ok(StableTerabetiaStateV1 {
+        messages: state.messages,
+        nonce: state.nonce,
+        messages_out: state
+            .messages_out
+            .into_iter()
+            .map(|message| OutgoingMessage {
+                msg_key: message.msg_key,
+                msg_hash: message.msg_hash,
+            })
+            .collect(),
+        message_out_index: state.message_out_index,
+        authorized: state.authorized,
+        // the rest starts empty, every method on its default ingress policy
+        // and a single approval without delay keep the previous behaviour
+        ..StableTerabetiaStateV1::default()
+    })
+}
+
+impl From<StableTerabetiaState> for StableTerabetiaStateV1 {
+    fn from(state: StableTerabetiaState) -> Self {
+        StableTerabetiaStateV1 {
+            messages: state.messages,
+            nonce: state.nonce,
+            messages_out: state.messages_out,
+            message_out_index: state.message_out_index,
+            authorized: state.authorized,
+            ingress_policies: state.ingress_policies,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+            paused: state.paused,
+        }
+    }
+}
+
+impl From<StableTerabetiaStateV1> for StableTerabetiaState {
+    fn from(state: StableTerabetiaStateV1) -> Self {
+        StableTerabetiaState {
+            messages: state.messages,
+            nonce: state.nonce,
+            messages_out: state.messages_out,
+            message_out_index: state.message_out_index,
+            authorized: state.authorized,
+            ingress_policies: state.ingress_policies,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+            paused: state.paused,
+        }
+    }
+}
+
+/// Encode state in the current version behind the envelope magic
+pub fn encode(state: StableTerabetiaState) -> Result<Vec<u8>, String> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.extend(
+        encode_args((VersionedTerabetiaState::current(state),)).map_err(|e| e.to_string())?,
+    );
+
+    Ok(bytes)
+}
+
+/// Decode stable memory by its version tag, never by trying layouts in turn
+pub fn decode(bytes: &[u8]) -> Result<VersionedTerabetiaState, String> {
+    if bytes.starts_with(ENVELOPE_MAGIC) {
+        let mut de =
+            IDLDeserialize::new(&bytes[ENVELOPE_MAGIC.len()..]).map_err(|e| e.to_string())?;
+        de.get_value::<VersionedTerabetiaState>()
+            .map_err(|e| e.to_string())
+    } else if bytes.starts_with(CANDID_MAGIC) {
+        // canisters deployed before versioning hold a bare record
+        let mut de = IDLDeserialize::new(bytes).map_err(|e| e.to_string())?;
+        de.get_value::<StableTerabetiaStateV0>()
+            .map(VersionedTerabetiaState::V0)
+            .map_err(|e| e.to_string())
+    } else {
+        Err("Stable memory holds no known state layout".to_string())
+    }
+}
+
+/// Save state in the current version over whatever stable memory held
+pub fn store(state: StableTerabetiaState) -> Result<(), String> {
+    let bytes = encode(state)?;
+    let pages = (bytes.len() as u64 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    let size = stable::stable_size() as u64;
+    if pages > size {
+        stable::stable_grow((pages - size) as u32).map_err(|e| format!("{:?}", e))?;
+    }
+    stable::stable_write(0, &bytes);
+
+    Ok(())
+}
+
+/// Restore state from stable memory and migrate it to the current version
+pub fn restore() -> Result<StableTerabetiaState, String> {
+    decode(&stable::stable_bytes())?.migrate()
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Nat;
+    use ic_kit::mock_principals;
+
+    use super::*;
+    use crate::common::types::GovernanceConfig;
+    use crate::tera::STATE;
+
+    fn round_trip(state: StableTerabetiaState) -> Result<StableTerabetiaState, String> {
+        decode(&encode(state)?)?.migrate()
+    }
+
+    fn legacy_state() -> StableTerabetiaStateV0 {
+        let msg_hash =
+            String::from("c9e23418a985892acc0fa031331080bfce112bdf841a3ae04a5181c6da1610b1");
+
+        StableTerabetiaStateV0 {
+            messages: HashMap::from([(msg_hash.clone(), 2)]),
+            nonce: HashSet::from([Nat::from(4)]),
+            messages_out: HashSet::from([OutgoingMessageV0 {
+                msg_key: vec![1; 32],
+                msg_hash,
+            }]),
+            message_out_index: 1,
+            authorized: vec![mock_principals::alice()],
+        }
+    }
+
+    #[test]
+    fn test_upgrade_round_trip() {
+        let msg_hash =
+            String::from("d0379be15bb6f33737b756e512dad1e71226b31fa648da57811f930badf6c163");
+
+        STATE.with(|s| {
+            s.authorized.borrow_mut().push(mock_principals::alice());
+            s.store_incoming_message(msg_hash.clone());
+            s.update_nonce(Nat::from(1));
+            let _ = s.store_outgoing_message(msg_hash.clone());
+        });
+
+        let stable_state = STATE.with(|s| s.take_all());
+        let restored = round_trip(stable_state).unwrap();
+
+        STATE.with(|s| s.clear_all());
+        STATE.with(|s| s.replace_all(restored));
+
+        assert!(STATE.with(|s| s.message_exists(msg_hash)).is_ok());
+        assert!(STATE.with(|s| s.nonce_exists(&Nat::from(1))));
+        assert_eq!(STATE.with(|s| s.get_messages()).len(), 1);
+        assert_eq!(STATE.with(|s| *s.message_out_index.borrow()), 1);
+        assert_eq!(
+            STATE.with(|s| s.authorized.borrow().clone()),
+            vec![mock_principals::alice()]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy_state() {
+        let bytes = encode_args((legacy_state(),)).unwrap();
+        let migrated = decode(&bytes).unwrap().migrate().unwrap();
+
+        assert_eq!(migrated.messages.len(), 1);
+        assert_eq!(migrated.nonce.len(), 1);
+        assert_eq!(migrated.messages_out.len(), 1);
+        assert_eq!(migrated.message_out_index, 1);
+        assert_eq!(migrated.authorized, vec![mock_principals::alice()]);
+        assert!(migrated.ingress_policies.is_empty());
+        assert!(migrated.audit_log.is_empty());
+        assert_eq!(migrated.governance, GovernanceConfig::default());
+        assert!(!migrated.paused);
+    }
+
+    #[test]
+    fn test_decode_by_version_tag() {
+        let mut bytes = encode(StableTerabetiaState::default()).unwrap();
+        assert!(matches!(decode(&bytes), Ok(VersionedTerabetiaState::V1(_))));
+
+        // a broken envelope is an error, not a legacy record
+        bytes.truncate(ENVELOPE_MAGIC.len() + 8);
+        assert!(decode(&bytes).is_err());
+
+        assert!(decode(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let mut legacy = legacy_state();
+        legacy.message_out_index = 0;
+
+        let bytes = encode_args((legacy,)).unwrap();
+
+        // post_upgrade traps on the error and the upgrade is rolled back
+        assert!(decode(&bytes).unwrap().migrate().is_err());
+    }
+}
@@ -1,12 +1,21 @@
-use crate::common::types::{Nonce, OutgoingMessage, OutgoingMessagePair};
+use crate::common::types::{
+    AuditEntry, AuditOutcome, GovernanceConfig, IngressPolicy, Nonce, OutgoingMessage,
+    OutgoingMessagePair, Proposal, ProposalAction,
+};
 use candid::{CandidType, Deserialize, Nat, Principal};
-use ic_kit::ic::caller;
+use ic_kit::ic::{caller, time};
 use sha2::{Digest, Sha256};
 use std::{
     cell::RefCell,
     collections::{HashMap, HashSet},
 };
 
+/// Audit entries kept before the oldest are dropped
+pub const MAX_AUDIT_ENTRIES: usize = 1_000;
+
+/// Audit entries returned by a single page
+pub const MAX_AUDIT_PAGE: usize = 100;
+
 thread_local! {
     pub static STATE: TerabetiaState = TerabetiaState::default();
 }
@@ -27,6 +36,27 @@ pub struct TerabetiaState {
 
     /// List of authorized pids
     pub authorized: RefCell<Vec<Principal>>,
+
+    /// Per-method ingress policy overrides
+    pub ingress_policies: RefCell<HashMap<String, IngressPolicy>>,
+
+    /// Admin action audit log, oldest first
+    pub audit_log: RefCell<Vec<AuditEntry>>,
+
+    /// Index of the next audit entry
+    pub audit_index: RefCell<u64>,
+
+    /// Approval threshold, delay and ttl of proposals
+    pub governance: RefCell<GovernanceConfig>,
+
+    /// Pending proposals by id
+    pub proposals: RefCell<HashMap<u64, Proposal>>,
+
+    /// Id of the next proposal
+    pub proposal_index: RefCell<u64>,
+
+    /// Whether user facing endpoints are paused
+    pub paused: RefCell<bool>,
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -45,6 +75,27 @@ pub struct StableTerabetiaState {
 
     /// List of authorized pids
     pub authorized: Vec<Principal>,
+
+    /// Per-method ingress policy overrides
+    pub ingress_policies: HashMap<String, IngressPolicy>,
+
+    /// Admin action audit log, oldest first
+    pub audit_log: Vec<AuditEntry>,
+
+    /// Index of the next audit entry
+    pub audit_index: u64,
+
+    /// Approval threshold, delay and ttl of proposals
+    pub governance: GovernanceConfig,
+
+    /// Pending proposals by id
+    pub proposals: HashMap<u64, Proposal>,
+
+    /// Id of the next proposal
+    pub proposal_index: u64,
+
+    /// Whether user facing endpoints are paused
+    pub paused: bool,
 }
 
 impl OutgoingMessage {
@@ -225,6 +276,192 @@ impl TerabetiaState {
         }
     }
 
+    ///
+    /// Governance
+    ///
+
+    /// Open a new proposal, approved by its proposer
+    pub fn propose(&self, proposer: Principal, action: ProposalAction, now: u64) -> u64 {
+        let id = self.proposal_index.replace_with(|index| *index + 1);
+
+        self.proposals.borrow_mut().insert(
+            id,
+            Proposal {
+                id,
+                action,
+                proposer,
+                approvals: vec![proposer],
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    /// Add an approval to a pending proposal
+    pub fn approve_proposal(&self, id: u64, approver: Principal, now: u64) -> Result<(), String> {
+        self.expire_proposals(now);
+
+        let mut proposals = self.proposals.borrow_mut();
+        let proposal = proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if proposal.approvals.contains(&approver) {
+            return Err(format!("Proposal {} is already approved by caller", id));
+        }
+
+        proposal.approvals.push(approver);
+
+        Ok(())
+    }
+
+    /// Check that a proposal has enough approvals, its delay passed and it has not expired
+    pub fn proposal_ready(&self, id: u64, now: u64) -> Result<(), String> {
+        let config = self.governance.borrow().clone();
+        let proposals = self.proposals.borrow();
+        let proposal = proposals
+            .get(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if now > proposal.created_at.saturating_add(config.ttl) {
+            return Err(format!("Proposal {} has expired", id));
+        }
+
+        // approvals of principals that lost their role do not count
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|p| self.authorized.borrow().contains(p))
+            .count() as u32;
+        if approvals < config.threshold {
+            return Err(format!(
+                "Proposal {} has {} of {} approvals",
+                id, approvals, config.threshold
+            ));
+        }
+
+        let executable_at = proposal.created_at.saturating_add(config.delay);
+        if now < executable_at {
+            return Err(format!(
+                "Proposal {} is timelocked until {}",
+                id, executable_at
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Remove a proposal once it is ready to execute
+    pub fn take_ready_proposal(&self, id: u64, now: u64) -> Result<Proposal, String> {
+        self.expire_proposals(now);
+        self.proposal_ready(id, now)?;
+
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    /// Remove a pending proposal without executing it
+    pub fn cancel_proposal(&self, id: u64) -> Result<Proposal, String> {
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    /// Drop proposals older than the configured ttl
+    pub fn expire_proposals(&self, now: u64) {
+        let ttl = self.governance.borrow().ttl;
+
+        self.proposals
+            .borrow_mut()
+            .retain(|_, p| now <= p.created_at.saturating_add(ttl));
+    }
+
+    /// Get pending proposals that have not expired, oldest first
+    pub fn get_pending_proposals(&self, now: u64) -> Vec<Proposal> {
+        let ttl = self.governance.borrow().ttl;
+        let mut proposals: Vec<Proposal> = self
+            .proposals
+            .borrow()
+            .values()
+            .filter(|p| now <= p.created_at.saturating_add(ttl))
+            .cloned()
+            .collect();
+
+        proposals.sort_by_key(|p| p.id);
+        proposals
+    }
+
+    /// Check that user facing endpoints are not paused
+    pub fn is_not_paused(&self) -> Result<(), String> {
+        (!*self.paused.borrow())
+            .then(|| ())
+            .ok_or("Canister is paused".to_string())
+    }
+
+    ///
+    /// Ingress
+    ///
+
+    /// Get ingress policy override for a method
+    pub fn get_ingress_policy(&self, method: &str) -> Option<IngressPolicy> {
+        self.ingress_policies.borrow().get(method).copied()
+    }
+
+    /// Override ingress policy for a method
+    pub fn set_ingress_policy(&self, method: String, policy: IngressPolicy) {
+        self.ingress_policies.borrow_mut().insert(method, policy);
+    }
+
+    /// Get all ingress policy overrides
+    pub fn get_ingress_policies(&self) -> Vec<(String, IngressPolicy)> {
+        self.ingress_policies
+            .borrow()
+            .iter()
+            .map(|(method, policy)| (method.clone(), *policy))
+            .collect()
+    }
+
+    ///
+    /// Audit
+    ///
+
+    /// Append an admin action to the audit log
+    pub fn record_audit(&self, method: &str, args_digest: String, outcome: AuditOutcome) {
+        let mut audit_log = self.audit_log.borrow_mut();
+        let mut audit_index = self.audit_index.borrow_mut();
+
+        audit_log.push(AuditEntry {
+            index: *audit_index,
+            caller: caller(),
+            method: method.to_string(),
+            args_digest,
+            time: time(),
+            outcome,
+        });
+        *audit_index += 1;
+
+        // the log is bounded, drop the oldest entries
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            let overflow = audit_log.len() - MAX_AUDIT_ENTRIES;
+            audit_log.drain(..overflow);
+        }
+    }
+
+    /// Get a page of audit entries starting at index
+    pub fn get_audit_log(&self, start: u64, limit: usize) -> Vec<AuditEntry> {
+        self.audit_log
+            .borrow()
+            .iter()
+            .filter(|e| e.index >= start)
+            .take(limit.min(MAX_AUDIT_PAGE))
+            .cloned()
+            .collect()
+    }
+
     ///
     /// Pre/Post Upgrade
     ///
@@ -238,6 +475,13 @@ impl TerabetiaState {
             messages_out: self.messages_out.take(),
             message_out_index: self.message_out_index.take(),
             authorized: self.authorized.take(),
+            ingress_policies: self.ingress_policies.take(),
+            audit_log: self.audit_log.take(),
+            audit_index: self.audit_index.take(),
+            governance: self.governance.take(),
+            proposals: self.proposals.take(),
+            proposal_index: self.proposal_index.take(),
+            paused: self.paused.take(),
         }
     }
 
@@ -249,6 +493,13 @@ impl TerabetiaState {
         self.messages_out.borrow_mut().clear();
         self.message_out_index.replace(0);
         self.authorized.borrow_mut().clear();
+        self.ingress_policies.borrow_mut().clear();
+        self.audit_log.borrow_mut().clear();
+        self.audit_index.replace(0);
+        self.governance.replace(GovernanceConfig::default());
+        self.proposals.borrow_mut().clear();
+        self.proposal_index.replace(0);
+        self.paused.replace(false);
     }
 
     /// Replace state with new state
@@ -260,6 +511,15 @@ impl TerabetiaState {
         self.message_out_index
             .replace(stable_tera_state.message_out_index);
         self.authorized.replace(stable_tera_state.authorized);
+        self.ingress_policies
+            .replace(stable_tera_state.ingress_policies);
+        self.audit_log.replace(stable_tera_state.audit_log);
+        self.audit_index.replace(stable_tera_state.audit_index);
+        self.governance.replace(stable_tera_state.governance);
+        self.proposals.replace(stable_tera_state.proposals);
+        self.proposal_index
+            .replace(stable_tera_state.proposal_index);
+        self.paused.replace(stable_tera_state.paused);
     }
 }
 
@@ -478,18 +738,200 @@ mod tests {
         assert!(is_authorized.is_ok());
     }
 
+    fn populate_state() -> String {
+        let msg_hash =
+            String::from("c9e23418a985892acc0fa031331080bfce112bdf841a3ae04a5181c6da1610b1");
+
+        STATE.with(|s| {
+            s.authorized
+                .borrow_mut()
+                .push(Principal::from_slice(&[1, 0x00]));
+            s.store_incoming_message(msg_hash.clone());
+            s.update_nonce(Nat::from(1));
+            let _ = s.store_outgoing_message(msg_hash.clone());
+        });
+
+        msg_hash
+    }
+
     #[test]
     fn test_take_all() {
-        // ToDo
+        let msg_hash = populate_state();
+
+        let stable_state = STATE.with(|s| s.take_all());
+
+        assert_eq!(stable_state.messages.get(&msg_hash), Some(&1));
+        assert_eq!(stable_state.nonce.len(), 1);
+        assert_eq!(stable_state.messages_out.len(), 1);
+        assert_eq!(stable_state.message_out_index, 1);
+        assert_eq!(stable_state.authorized.len(), 1);
+
+        // state is moved out
+        assert_eq!(STATE.with(|s| s.get_messages()).len(), 0);
+        assert_eq!(STATE.with(|s| s.get_nonces()).len(), 0);
     }
 
     #[test]
     fn test_clear_all() {
-        // ToDo
+        let msg_hash = populate_state();
+
+        STATE.with(|s| s.clear_all());
+
+        assert!(STATE.with(|s| s.message_exists(msg_hash)).is_err());
+        assert_eq!(STATE.with(|s| s.get_nonces()).len(), 0);
+        assert_eq!(STATE.with(|s| s.get_messages()).len(), 0);
+        assert_eq!(STATE.with(|s| *s.message_out_index.borrow()), 0);
+        assert_eq!(STATE.with(|s| s.authorized.borrow().len()), 0);
     }
 
     #[test]
     fn test_replace_all() {
-        // ToDo
+        let msg_hash = populate_state();
+
+        let stable_state = STATE.with(|s| s.take_all());
+        STATE.with(|s| s.clear_all());
+        STATE.with(|s| s.replace_all(stable_state));
+
+        assert!(STATE.with(|s| s.message_exists(msg_hash)).is_ok());
+        assert_eq!(STATE.with(|s| s.get_nonces()).len(), 1);
+        assert_eq!(STATE.with(|s| s.get_messages()).len(), 1);
+        assert_eq!(STATE.with(|s| *s.message_out_index.borrow()), 1);
+        assert_eq!(STATE.with(|s| s.authorized.borrow().len()), 1);
+    }
+
+    #[test]
+    fn test_record_audit() {
+        MockContext::new()
+            .with_caller(Principal::from_slice(&[1, 0x00]))
+            .inject();
+
+        STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        STATE.with(|s| {
+            s.record_audit(
+                "remove_messages",
+                String::from("01"),
+                AuditOutcome::Err(String::from("Message not found")),
+            )
+        });
+
+        let audit_log = STATE.with(|s| s.get_audit_log(0, 10));
+
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].caller, Principal::from_slice(&[1, 0x00]));
+        assert_eq!(audit_log[0].method, "authorize");
+        assert_eq!(audit_log[1].index, 1);
+        assert_eq!(
+            audit_log[1].outcome,
+            AuditOutcome::Err(String::from("Message not found"))
+        );
+
+        let page = STATE.with(|s| s.get_audit_log(1, 10));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].method, "remove_messages");
+    }
+
+    #[test]
+    fn test_audit_log_bounded() {
+        MockContext::new()
+            .with_caller(Principal::from_slice(&[1, 0x00]))
+            .inject();
+
+        for _ in 0..MAX_AUDIT_ENTRIES + 5 {
+            STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        }
+
+        let audit_log = STATE.with(|s| s.audit_log.borrow().clone());
+
+        assert_eq!(audit_log.len(), MAX_AUDIT_ENTRIES);
+        // oldest entries were dropped
+        assert_eq!(audit_log[0].index, 5);
+        assert_eq!(
+            STATE.with(|s| s.get_audit_log(0, usize::MAX)).len(),
+            MAX_AUDIT_PAGE
+        );
+    }
+
+    fn governance_state(threshold: u32, delay: u64) -> (Principal, Principal) {
+        let alice = Principal::from_slice(&[1, 0x00]);
+        let bob = Principal::from_slice(&[2, 0x00]);
+
+        MockContext::new().with_caller(alice).inject();
+
+        STATE.with(|s| {
+            s.authorized.borrow_mut().extend([alice, bob]);
+            s.governance.replace(GovernanceConfig {
+                threshold,
+                delay,
+                ..GovernanceConfig::default()
+            });
+        });
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_proposal_threshold() {
+        let (alice, bob) = governance_state(2, 0);
+        let new_pid = Principal::from_slice(&[3, 0x00]);
+
+        let id = STATE.with(|s| s.propose(alice, ProposalAction::Authorize(new_pid), 0));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+        // approving twice does not count twice
+        assert!(STATE.with(|s| s.approve_proposal(id, alice, 0)).is_err());
+
+        STATE.with(|s| s.approve_proposal(id, bob, 0)).unwrap();
+        let proposal = STATE.with(|s| s.take_ready_proposal(id, 0)).unwrap();
+
+        assert_eq!(proposal.action, ProposalAction::Authorize(new_pid));
+        assert!(STATE.with(|s| s.get_pending_proposals(0)).is_empty());
+    }
+
+    #[test]
+    fn test_proposal_delay() {
+        let (alice, _) = governance_state(1, 100);
+
+        let id = STATE.with(|s| s.propose(alice, ProposalAction::Unpause, 10));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 109)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 110)).is_ok());
+    }
+
+    #[test]
+    fn test_proposal_expiry() {
+        let (alice, bob) = governance_state(2, 0);
+        let ttl = GovernanceConfig::default().ttl;
+
+        let id = STATE.with(|s| s.propose(alice, ProposalAction::Unpause, 0));
+
+        assert_eq!(STATE.with(|s| s.get_pending_proposals(ttl)).len(), 1);
+        assert!(STATE.with(|s| s.get_pending_proposals(ttl + 1)).is_empty());
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, bob, ttl + 1))
+            .is_err());
+        assert!(STATE.with(|s| s.proposals.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        let (alice, _) = governance_state(2, 0);
+
+        let id = STATE.with(|s| s.propose(alice, ProposalAction::Unpause, 0));
+
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_ok());
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+    }
+
+    #[test]
+    fn test_revoked_approval() {
+        let (alice, bob) = governance_state(2, 0);
+
+        let id = STATE.with(|s| s.propose(alice, ProposalAction::Unpause, 0));
+        STATE.with(|s| s.approve_proposal(id, bob, 0)).unwrap();
+
+        STATE.with(|s| s.authorized.borrow_mut().retain(|p| *p != bob));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
     }
 }
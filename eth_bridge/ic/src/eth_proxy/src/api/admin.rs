@@ -1,12 +1,30 @@
+use crate::api::governance::open_proposal;
+use crate::common::types::{AuditEntry, ProposalAction};
+use crate::common::utils::args_digest;
 use crate::proxy::STATE;
-use ic_kit::{candid::candid_method, macros::update, Principal};
+use ic_kit::{
+    candid::candid_method,
+    macros::{query, update},
+    Principal,
+};
 
 pub fn is_authorized() -> Result<(), String> {
     STATE.with(|s| s.is_authorized())
 }
 
-#[update(name = "authorize")]
+/// Opens a proposal to authorize other, returns the proposal id
+#[update(name = "authorize", guard = "is_authorized")]
 #[candid_method(update)]
-fn authorize(other: Principal) {
-    STATE.with(|s| s.authorize(other))
+fn authorize(other: Principal) -> Result<u64, String> {
+    open_proposal(
+        "authorize",
+        args_digest((other,)),
+        ProposalAction::Authorize(other),
+    )
+}
+
+#[query(name = "get_audit_log", guard = "is_authorized")]
+#[candid_method(query, rename = "get_audit_log")]
+fn get_audit_log(start: u64, limit: u64) -> Vec<AuditEntry> {
+    STATE.with(|s| s.get_audit_log(start, limit as usize))
 }
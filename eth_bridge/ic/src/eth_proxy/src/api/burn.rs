@@ -1,21 +1,30 @@
-use std::str::FromStr;
-
 use ic_kit::candid::candid_method;
-use ic_kit::{ic, macros::update};
+use ic_kit::{
+    ic,
+    macros::{query, update},
+};
 
+use crate::api::admin::is_authorized;
+use crate::api::governance::is_not_paused;
 use crate::common::tera::Tera;
 use crate::common::weth::Weth;
-use crate::proxy::{ToNat, STATE, TERA_ADDRESS, WETH_ADDRESS_IC};
-use ic_cdk::export::candid::{Nat, Principal};
+use crate::proxy::{ToNat, STATE};
+use ic_cdk::export::candid::Nat;
+use permit::PermitKey;
 
-use crate::common::types::{ClaimableMessage, EthereumAddr, TxError, TxReceipt};
+use crate::common::types::{
+    BridgeReceipt, BridgeResult, BurnOperation, BurnPermit, BurnSource, BurnStep, ClaimableMessage,
+    CompensationOutcome, CompensationPolicy, EthereumAddr, Flow, HeldAction, TxError, TxReceipt,
+    VolumeEntry,
+};
 
-#[update(name = "burn")]
+#[update(name = "burn", guard = "is_not_paused")]
 #[candid_method(update, rename = "burn")]
-async fn burn(eth_addr: EthereumAddr, amount: Nat) -> TxReceipt {
+async fn burn(eth_addr: EthereumAddr, amount: Nat) -> BridgeResult {
     let caller = ic::caller();
     let self_id = ic::id();
-    let weth_ic_addr_pid = Principal::from_str(WETH_ADDRESS_IC).unwrap();
+    let config = STATE.with(|s| s.get_config());
+    let weth_ic_addr_pid = config.weth_address_ic;
 
     if (weth_ic_addr_pid.name().await).is_err() {
         return Err(TxError::Other(format!(
@@ -24,64 +33,543 @@ async fn burn(eth_addr: EthereumAddr, amount: Nat) -> TxReceipt {
         )));
     }
 
-    let transfer_from = weth_ic_addr_pid
+    // the fee is taken from the amount, what is left is burned and bridged
+    let fee = STATE.with(|s| s.fee_for(weth_ic_addr_pid, Flow::Burn, &amount));
+    if fee >= amount {
+        return Err(TxError::AmountTooSmall);
+    }
+
+    weth_ic_addr_pid
         .transfer_from(caller, self_id, amount.clone())
-        .await;
-
-    match transfer_from {
-        Ok(_) => {
-            STATE.with(|s| s.add_balance(caller, weth_ic_addr_pid, amount.clone()));
-
-            let burn = weth_ic_addr_pid.burn(amount.clone()).await;
-
-            match burn {
-                Ok(burn_txn_id) => {
-                    let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
-                    let payload = [eth_addr.clone().to_nat(), amount.clone()].to_vec();
-
-                    let send_message = tera_id.send_message(weth_ic_addr_pid, payload).await;
-                    match send_message {
-                        Ok(outgoing_message) => {
-                            // there could be an underflow here
-                            // like negative balance
-                            STATE.with(|s| {
-                                let current_balance = s
-                                    .get_balance(caller, weth_ic_addr_pid)
-                                    .unwrap_or(Nat::from(0));
-
-                                s.update_balance(
-                                    caller,
-                                    weth_ic_addr_pid,
-                                    current_balance - amount.clone(),
-                                );
-
-                                s.add_claimable_message(ClaimableMessage {
-                                    owner: eth_addr.clone(),
-                                    msg_hash: outgoing_message.msg_hash.clone(),
-                                    msg_key: outgoing_message.msg_key.clone(),
-                                    token: weth_ic_addr_pid.clone(),
-                                    amount: amount.clone(),
-                                });
-                            });
-                            // All correct
-                            return Ok(burn_txn_id);
-                        }
-                        // send_message to Tera error
-                        Err(_) => {
-                            return Err(TxError::Other(format!(
-                                "Sending message to L1 failed with caller {:?}!",
-                                caller.to_string()
-                            )));
-                        }
+        .await?;
+
+    STATE.with(|s| s.collect_fee(weth_ic_addr_pid, fee.clone()));
+    let amount = amount - fee.clone();
+
+    // from here on the operation journals every step, so a burn that
+    // stops partway can be resumed instead of leaving loose escrow
+    let op_id = STATE.with(|s| {
+        s.create_burn_operation(
+            caller,
+            eth_addr,
+            weth_ic_addr_pid,
+            amount.clone(),
+            BurnSource::Wallet,
+            ic::time(),
+        )
+    });
+
+    start_burn(op_id, fee.clone())
+        .await
+        .map(|txn_id| BridgeReceipt {
+            txn_id,
+            amount,
+            fee,
+        })
+}
+
+/// Burn without an earlier approve, the permit approves the amount to the
+/// proxy and must be signed by the key of the caller
+#[update(name = "burn_with_permit", guard = "is_not_paused")]
+#[candid_method(update, rename = "burn_with_permit")]
+async fn burn_with_permit(eth_addr: EthereumAddr, amount: Nat, permit: BurnPermit) -> BridgeResult {
+    // the permit of another key would burn weth the caller does not own
+    let owner = PermitKey::from_bytes(&permit.owner_pubkey)
+        .map_err(TxError::Other)?
+        .owner();
+    if owner != ic::caller() {
+        return Err(TxError::Unauthorized);
+    }
+
+    let weth_ic_addr_pid = STATE.with(|s| s.get_config().weth_address_ic);
+
+    weth_ic_addr_pid
+        .permit(
+            permit.owner_pubkey,
+            ic::id(),
+            amount.clone(),
+            permit.deadline,
+            permit.nonce,
+            permit.signature,
+        )
+        .await?;
+
+    burn(eth_addr, amount).await
+}
+
+/// Continue a failed burn from its last completed step, a burn stuck in flight
+/// can only be taken over by an admin once the timeout passed
+#[update(name = "resume_burn", guard = "is_not_paused")]
+#[candid_method(update, rename = "resume_burn")]
+async fn resume_burn(op_id: u64) -> TxReceipt {
+    let admin = is_authorized().is_ok();
+    let operation = STATE
+        .with(|s| s.burn_resumable(op_id, ic::time(), admin))
+        .map_err(TxError::Other)?;
+
+    if operation.owner != ic::caller() && !admin {
+        return Err(TxError::Unauthorized);
+    }
+
+    // an admin taking over a stuck call has checked whether it went through,
+    // the next step is marked in flight before the first await
+    STATE
+        .with(|s| s.update_burn_step(op_id, operation.step.last_completed(), ic::time()))
+        .map_err(TxError::Other)?;
+
+    // the volume of a burn that failed before burning was released, so it is
+    // checked against the limits again. Its bridge fee was collected by the first
+    // attempt and stays collected, a rejected hold returns the amount alone
+    match operation.step {
+        BurnStep::Failed(completed, _) if *completed == BurnStep::Escrowed => {
+            start_burn(op_id, Nat::from(0_u32)).await
+        }
+        _ => advance_burn(op_id).await,
+    }
+}
+
+#[query(name = "get_burn_operations")]
+#[candid_method(query, rename = "get_burn_operations")]
+fn get_burn_operations() -> Vec<BurnOperation> {
+    STATE.with(|s| s.get_burn_operations(ic::caller()))
+}
+
+/// Reserve the volume of a burn operation that has not burned yet and run it,
+/// a burn over the limits is held for approval instead
+pub(crate) async fn start_burn(op_id: u64, fee: Nat) -> TxReceipt {
+    let operation = STATE
+        .with(|s| s.get_burn_operation(op_id))
+        .ok_or_else(|| TxError::Other(format!("Burn operation {} not found", op_id)))?;
+
+    let reserved = STATE.with(|s| {
+        s.reserve_volume(
+            operation.token,
+            Flow::Burn,
+            operation.amount.clone(),
+            ic::time(),
+        )
+    });
+
+    match reserved {
+        Ok(volume) => advance_reserved_burn(op_id, volume).await,
+        Err(reason) => {
+            let id = STATE.with(|s| {
+                let _ = s.update_burn_step(op_id, BurnStep::Held, ic::time());
+                s.hold_operation(
+                    operation.owner,
+                    operation.token,
+                    operation.amount,
+                    fee,
+                    HeldAction::Burn(op_id),
+                    reason.clone(),
+                    ic::time(),
+                )
+            });
+
+            Err(TxError::Other(format!(
+                "Burn operation {} is held for approval as operation {}: {}",
+                op_id, id, reason
+            )))
+        }
+    }
+}
+
+/// Run a burn whose volume is counted, the volume is released again
+/// when the burn fails before burning or is refunded
+pub(crate) async fn advance_reserved_burn(op_id: u64, volume: VolumeEntry) -> TxReceipt {
+    let result = advance_burn(op_id).await;
+
+    if result.is_err() {
+        let step = STATE
+            .with(|s| s.get_burn_operation(op_id))
+            .map(|op| op.step);
+        let unbridged = match step {
+            Some(BurnStep::Failed(completed, _)) => *completed == BurnStep::Escrowed,
+            Some(BurnStep::Refunded) => true,
+            _ => false,
+        };
+        if unbridged {
+            STATE.with(|s| s.release_volume(&volume));
+        }
+    }
+
+    result
+}
+
+/// Run the remaining steps of a burn, returns the burn transaction id
+pub(crate) async fn advance_burn(op_id: u64) -> TxReceipt {
+    loop {
+        let operation = STATE
+            .with(|s| s.get_burn_operation(op_id))
+            .ok_or_else(|| TxError::Other(format!("Burn operation {} not found", op_id)))?;
+
+        // every call marks its step in flight first, so the operation
+        // cannot be resumed while the call is outstanding
+        let completed = match operation.step {
+            BurnStep::Escrowed => {
+                STATE
+                    .with(|s| s.update_burn_step(op_id, BurnStep::Burning, ic::time()))
+                    .map_err(TxError::Other)?;
+
+                match operation.token.burn(operation.amount.clone()).await {
+                    Ok(burn_txn_id) => STATE.with(|s| {
+                        s.set_burn_txn_id(op_id, burn_txn_id);
+                        s.update_burn_step(op_id, BurnStep::Burned, ic::time())
+                    }),
+                    Err(error) => return Err(fail_burn(op_id, BurnStep::Escrowed, error)),
+                }
+            }
+            BurnStep::Burned => {
+                STATE
+                    .with(|s| s.update_burn_step(op_id, BurnStep::Sending, ic::time()))
+                    .map_err(TxError::Other)?;
+
+                let tera_id = STATE.with(|s| s.get_config().tera_address);
+                let payload = [operation.eth_addr.to_nat(), operation.amount.clone()].to_vec();
+
+                match tera_id.send_message(operation.token, payload).await {
+                    Ok(outgoing_message) => STATE.with(|s| {
+                        s.add_claimable_message(ClaimableMessage::pending(
+                            operation.eth_addr,
+                            operation.owner,
+                            &outgoing_message,
+                            operation.token,
+                            operation.amount.clone(),
+                            ic::time(),
+                        ));
+                        s.set_burn_message(op_id, outgoing_message);
+                        s.update_burn_step(op_id, BurnStep::MessageSent, ic::time())
+                    }),
+                    Err(_) => {
+                        let error = TxError::Other(format!(
+                            "Sending message to L1 failed with caller {:?}!",
+                            operation.owner.to_string()
+                        ));
+                        return Err(compensate(operation, error).await);
                     }
                 }
-                // burn error
-                Err(error) => {
-                    return Err(error);
+            }
+            BurnStep::MessageSent => {
+                return operation.burn_txn_id.ok_or_else(|| {
+                    TxError::Other(format!("Burn operation {} has no burn txn", op_id))
+                })
+            }
+            BurnStep::Refunded => {
+                return Err(TxError::Other(format!(
+                    "Burn operation {} was refunded",
+                    op_id
+                )))
+            }
+            BurnStep::Held => {
+                return Err(TxError::Other(format!(
+                    "Burn operation {} is held for approval",
+                    op_id
+                )))
+            }
+            BurnStep::Rejected => {
+                return Err(TxError::Other(format!(
+                    "Burn operation {} was rejected",
+                    op_id
+                )))
+            }
+            BurnStep::Burning | BurnStep::Sending | BurnStep::Refunding => {
+                return Err(TxError::Other(format!(
+                    "Burn operation {} is in progress",
+                    op_id
+                )))
+            }
+            BurnStep::Failed(_, error) => return Err(TxError::Other(error)),
+        };
+
+        completed.map_err(TxError::Other)?;
+    }
+}
+
+/// Handle a burn whose message could not be sent, following the compensation policy
+async fn compensate(operation: BurnOperation, error: TxError) -> TxError {
+    let op_id = operation.id;
+    let reason = format!("{:?}", error);
+
+    let failed = BurnStep::Failed(Box::new(BurnStep::Burned), reason.clone());
+
+    // a withdraw is retried from the escrow, so its amount always goes back there
+    let policy = match operation.source {
+        BurnSource::Wallet => STATE.with(|s| *s.compensation_policy.borrow()),
+        BurnSource::Escrow => CompensationPolicy::Refund,
+    };
+    let refund_to = match operation.source {
+        BurnSource::Wallet => operation.owner,
+        BurnSource::Escrow => ic::id(),
+    };
+
+    let outcome = match policy {
+        CompensationPolicy::Retry => {
+            let _ = STATE.with(|s| s.update_burn_step(op_id, failed, ic::time()));
+            CompensationOutcome::Retryable
+        }
+        CompensationPolicy::Refund => {
+            // in flight so the operation cannot be resumed while minting back
+            let _ = STATE.with(|s| s.update_burn_step(op_id, BurnStep::Refunding, ic::time()));
+
+            match operation
+                .token
+                .mint(refund_to, operation.amount.clone())
+                .await
+            {
+                Ok(mint_txn_id) => {
+                    STATE.with(|s| {
+                        if operation.source == BurnSource::Escrow {
+                            let _ = s.credit_escrow(
+                                operation.owner,
+                                operation.token,
+                                operation.amount.clone(),
+                            );
+                        }
+                        let _ = s.update_burn_step(op_id, BurnStep::Refunded, ic::time());
+                    });
+                    CompensationOutcome::Refunded(mint_txn_id)
+                }
+                Err(mint_error) => {
+                    let _ = STATE.with(|s| s.update_burn_step(op_id, failed, ic::time()));
+                    CompensationOutcome::RefundFailed(format!("{:?}", mint_error))
                 }
-            };
+            }
         }
-        // transfer_from error
-        Err(error) => Err(error),
+    };
+
+    STATE.with(|s| {
+        s.record_compensation(
+            operation.owner,
+            operation.eth_addr,
+            operation.token,
+            operation.amount,
+            reason,
+            outcome,
+        )
+    });
+
+    error
+}
+
+fn fail_burn(op_id: u64, completed: BurnStep, error: TxError) -> TxError {
+    let step = BurnStep::Failed(Box::new(completed), format!("{:?}", error));
+    let _ = STATE.with(|s| s.update_burn_step(op_id, step, ic::time()));
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext, Principal};
+
+    use super::*;
+    use crate::common::types::{OutgoingMessage, VolumeLimit};
+
+    // the mock stays in reply callback mode after a call, so every entry
+    // point awaited after another needs a fresh context
+    fn mock_send_message(owner: Principal) {
+        MockContext::new()
+            .with_caller(owner)
+            .with_handler(
+                Method::new()
+                    .name("send_message")
+                    .response::<Result<OutgoingMessage, String>>(Ok(OutgoingMessage {
+                        msg_key: [1; 32],
+                        msg_hash: String::from("a"),
+                    })),
+            )
+            .inject();
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_resume() {
+        let owner = mock_principals::bob();
+        mock_send_message(owner);
+        STATE.with(|s| s.controllers.borrow_mut().push(owner));
+
+        let failed = BurnStep::Failed(Box::new(BurnStep::Burned), String::from("error"));
+        let op_id = STATE.with(|s| {
+            let op_id = s.create_burn_operation(
+                owner,
+                Principal::from_slice(&[1; 20]),
+                mock_principals::alice(),
+                Nat::from(100_u32),
+                BurnSource::Wallet,
+                0,
+            );
+            s.set_burn_txn_id(op_id, Nat::from(7_u32));
+            op_id
+        });
+
+        // another resume is waiting on its send, not even an admin can take over yet
+        STATE
+            .with(|s| s.update_burn_step(op_id, BurnStep::Sending, ic::time()))
+            .unwrap();
+        assert!(resume_burn(op_id).await.is_err());
+        assert_eq!(
+            STATE.with(|s| s.get_burn_operation(op_id)).unwrap().step,
+            BurnStep::Sending
+        );
+
+        // the first resume failed, the next one continues it exactly once
+        STATE
+            .with(|s| s.update_burn_step(op_id, failed, ic::time()))
+            .unwrap();
+        assert_eq!(resume_burn(op_id).await, Ok(Nat::from(7_u32)));
+        mock_send_message(owner);
+        assert!(resume_burn(op_id).await.is_err());
+        assert_eq!(
+            STATE
+                .with(|s| s.get_claimable_messages(Principal::from_slice(&[1; 20])))
+                .len(),
+            1
+        );
+    }
+
+    #[async_std::test]
+    async fn test_burn_with_permit_of_caller() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let owner = PermitKey::from_bytes(public.as_bytes()).unwrap().owner();
+        let permit = BurnPermit {
+            owner_pubkey: public.as_bytes().to_vec(),
+            deadline: u64::MAX,
+            nonce: 0,
+            signature: vec![0; 64],
+        };
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::bob())
+            .with_handler(
+                Method::new()
+                    .name("permit")
+                    .response::<TxReceipt>(Err(TxError::Other(String::from("expired")))),
+            )
+            .inject();
+        let eth_addr = Principal::from_slice(&[1; 20]);
+
+        // rejected before the token is called
+        assert_eq!(
+            burn_with_permit(eth_addr, Nat::from(100_u32), permit.clone()).await,
+            Err(TxError::Unauthorized)
+        );
+        let invalid = BurnPermit {
+            owner_pubkey: vec![0; 16],
+            ..permit.clone()
+        };
+        assert!(matches!(
+            burn_with_permit(eth_addr, Nat::from(100_u32), invalid).await,
+            Err(TxError::Other(_))
+        ));
+
+        ctx.update_caller(owner);
+        assert_eq!(
+            burn_with_permit(eth_addr, Nat::from(100_u32), permit).await,
+            Err(TxError::Other(String::from("expired")))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_failed_burn_releases_volume() {
+        let owner = mock_principals::bob();
+        let token = mock_principals::alice();
+        MockContext::new()
+            .with_caller(owner)
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Err(TxError::InsufficientBalance)),
+            )
+            .inject();
+
+        let op_id = STATE.with(|s| {
+            s.create_burn_operation(
+                owner,
+                Principal::from_slice(&[1; 20]),
+                token,
+                Nat::from(100_u32),
+                BurnSource::Wallet,
+                0,
+            )
+        });
+
+        assert!(start_burn(op_id, Nat::from(0_u32)).await.is_err());
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(Some(token), Flow::Burn)),
+            Nat::from(0_u32)
+        );
+
+        // a resume burns the amount after all, so it counts again
+        MockContext::new()
+            .with_caller(owner)
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Ok(Nat::from(7_u32))),
+            )
+            .with_handler(
+                Method::new()
+                    .name("send_message")
+                    .response::<Result<OutgoingMessage, String>>(Ok(OutgoingMessage {
+                        msg_key: [1; 32],
+                        msg_hash: String::from("a"),
+                    })),
+            )
+            .inject();
+
+        assert_eq!(resume_burn(op_id).await, Ok(Nat::from(7_u32)));
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(Some(token), Flow::Burn)),
+            Nat::from(100_u32)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_resume_over_limit_is_held() {
+        let owner = mock_principals::bob();
+        let token = mock_principals::alice();
+        MockContext::new()
+            .with_caller(owner)
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Err(TxError::InsufficientBalance)),
+            )
+            .inject();
+
+        let op_id = STATE.with(|s| {
+            s.create_burn_operation(
+                owner,
+                Principal::from_slice(&[1; 20]),
+                token,
+                Nat::from(100_u32),
+                BurnSource::Wallet,
+                0,
+            )
+        });
+        assert!(start_burn(op_id, Nat::from(0_u32)).await.is_err());
+
+        // the limit is lowered before the burn is resumed
+        STATE.with(|s| {
+            s.token_limits.borrow_mut().insert(
+                token,
+                VolumeLimit {
+                    per_transaction: None,
+                    daily_mint: None,
+                    daily_burn: Some(Nat::from(50_u32)),
+                },
+            )
+        });
+        MockContext::new().with_caller(owner).inject();
+
+        assert!(resume_burn(op_id).await.is_err());
+        assert_eq!(
+            STATE.with(|s| s.get_burn_operation(op_id)).unwrap().step,
+            BurnStep::Held
+        );
+        assert_eq!(STATE.with(|s| s.held_operations.borrow().len()), 1);
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(Some(token), Flow::Burn)),
+            Nat::from(0_u32)
+        );
     }
 }
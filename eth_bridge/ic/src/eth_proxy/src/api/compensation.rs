@@ -0,0 +1,51 @@
+use crate::api::admin::is_authorized;
+use crate::api::governance::open_proposal;
+use crate::common::types::{CompensationEntry, CompensationPolicy, ProposalAction};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+use ic_kit::{
+    candid::candid_method,
+    macros::{query, update},
+};
+
+#[query(name = "get_compensation_policy")]
+#[candid_method(query, rename = "get_compensation_policy")]
+fn get_compensation_policy() -> CompensationPolicy {
+    STATE.with(|s| *s.compensation_policy.borrow())
+}
+
+/// Opens a proposal to change how unsent burns are compensated, returns the proposal id
+#[update(name = "set_compensation_policy", guard = "is_authorized")]
+#[candid_method(update, rename = "set_compensation_policy")]
+fn set_compensation_policy(policy: CompensationPolicy) -> Result<u64, String> {
+    open_proposal(
+        "set_compensation_policy",
+        args_digest((policy,)),
+        ProposalAction::SetCompensationPolicy(policy),
+    )
+}
+
+#[query(name = "get_compensations", guard = "is_authorized")]
+#[candid_method(query, rename = "get_compensations")]
+fn get_compensations(start: u64, limit: u64) -> Vec<CompensationEntry> {
+    STATE.with(|s| s.get_compensations(start, limit as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    #[test]
+    fn test_set_compensation_policy() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        assert_eq!(get_compensation_policy(), CompensationPolicy::Retry);
+        assert!(set_compensation_policy(CompensationPolicy::Refund).is_ok());
+        assert_eq!(get_compensation_policy(), CompensationPolicy::Refund);
+    }
+}
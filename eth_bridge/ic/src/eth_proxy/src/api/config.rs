@@ -0,0 +1,103 @@
+use crate::api::admin::is_authorized;
+use crate::api::governance::open_proposal;
+use crate::common::types::{EthereumAddr, ProposalAction, ProxyConfig};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+use ic_kit::{
+    candid::candid_method,
+    macros::{query, update},
+    Principal,
+};
+
+#[query(name = "get_config")]
+#[candid_method(query, rename = "get_config")]
+fn get_config() -> ProxyConfig {
+    STATE.with(|s| s.get_config())
+}
+
+/// Opens a proposal to change the tera address, returns the proposal id
+#[update(name = "set_tera_address", guard = "is_authorized")]
+#[candid_method(update, rename = "set_tera_address")]
+fn set_tera_address(tera_address: Principal) -> Result<u64, String> {
+    open_proposal(
+        "set_tera_address",
+        args_digest((tera_address,)),
+        ProposalAction::SetTeraAddress(tera_address),
+    )
+}
+
+/// Opens a proposal to change the weth canister, returns the proposal id
+#[update(name = "set_weth_address_ic", guard = "is_authorized")]
+#[candid_method(update, rename = "set_weth_address_ic")]
+fn set_weth_address_ic(weth_address_ic: Principal) -> Result<u64, String> {
+    open_proposal(
+        "set_weth_address_ic",
+        args_digest((weth_address_ic,)),
+        ProposalAction::SetWethAddressIc(weth_address_ic),
+    )
+}
+
+/// Opens a proposal to change the weth contract, returns the proposal id
+#[update(name = "set_weth_address_eth", guard = "is_authorized")]
+#[candid_method(update, rename = "set_weth_address_eth")]
+fn set_weth_address_eth(weth_address_eth: EthereumAddr) -> Result<u64, String> {
+    open_proposal(
+        "set_weth_address_eth",
+        args_digest((weth_address_eth,)),
+        ProposalAction::SetWethAddressEth(weth_address_eth),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    fn before_each() -> &'static mut MockContext {
+        let mock_ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.controllers.borrow_mut().push(mock_principals::alice());
+            s.config.replace(ProxyConfig {
+                tera_address: mock_principals::xtc(),
+                weth_address_ic: mock_principals::bob(),
+                weth_address_eth: Principal::from_slice(&[1; 20]),
+            });
+        });
+
+        mock_ctx
+    }
+
+    #[test]
+    fn test_set_tera_address() {
+        before_each();
+
+        assert!(set_tera_address(mock_principals::john()).is_ok());
+        assert_eq!(get_config().tera_address, mock_principals::john());
+    }
+
+    #[test]
+    fn test_set_weth_address_eth_invalid() {
+        before_each();
+
+        assert!(set_weth_address_eth(mock_principals::john()).is_err());
+        assert_eq!(
+            get_config().weth_address_eth,
+            Principal::from_slice(&[1; 20])
+        );
+    }
+
+    #[test]
+    fn test_set_address_delayed() {
+        before_each();
+        STATE.with(|s| s.governance.borrow_mut().delay = 3_600_000_000_000);
+
+        let id = set_weth_address_ic(mock_principals::john()).unwrap();
+
+        assert_eq!(get_config().weth_address_ic, mock_principals::bob());
+        assert!(STATE.with(|s| s.proposals.borrow().contains_key(&id)));
+    }
+}
@@ -0,0 +1,45 @@
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{query, update},
+};
+
+use crate::api::admin::is_authorized;
+use crate::common::types::{EscrowEntry, EscrowReport};
+use crate::common::weth::Weth;
+use crate::proxy::STATE;
+
+#[query(name = "get_escrows", guard = "is_authorized")]
+#[candid_method(query, rename = "get_escrows")]
+fn get_escrows(start: u64, limit: u64) -> Vec<EscrowEntry> {
+    STATE.with(|s| s.get_escrows(start, limit as usize))
+}
+
+/// Compare the escrowed weth, collected fees and weth of unfinished burns
+/// against the balance the proxy holds
+#[update(name = "check_escrow_invariant", guard = "is_authorized")]
+#[candid_method(update, rename = "check_escrow_invariant")]
+async fn check_escrow_invariant() -> Result<EscrowReport, String> {
+    let weth_ic_addr_pid = STATE.with(|s| s.get_config().weth_address_ic);
+
+    let held = weth_ic_addr_pid
+        .balance_of(ic::id())
+        .await
+        .map_err(|error| format!("{:?}", error))?;
+    let (escrowed, fees, unburned) = STATE.with(|s| {
+        (
+            s.escrow_total(weth_ic_addr_pid),
+            s.collected_fee(weth_ic_addr_pid),
+            s.unburned_total(weth_ic_addr_pid),
+        )
+    });
+
+    Ok(EscrowReport {
+        token: weth_ic_addr_pid,
+        balanced: held >= escrowed.clone() + fees.clone() + unburned.clone(),
+        escrowed,
+        fees,
+        unburned,
+        held,
+    })
+}
@@ -0,0 +1,286 @@
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{query, update},
+};
+
+use crate::api::admin::is_authorized;
+use crate::common::types::{AuditOutcome, GovernanceConfig, Proposal, ProposalAction, ProxyConfig};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+
+pub fn is_not_paused() -> Result<(), String> {
+    STATE.with(|s| s.is_not_paused())
+}
+
+fn validate_action(action: &ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::SetGovernance(config) => config.validate(),
+        ProposalAction::SetFee(_, fee) => fee.validate(),
+        ProposalAction::SetTeraAddress(_)
+        | ProposalAction::SetWethAddressIc(_)
+        | ProposalAction::SetWethAddressEth(_) => updated_config(action).validate(),
+        _ => Ok(()),
+    }
+}
+
+fn apply_action(action: ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::Authorize(other) => STATE.with(|s| {
+            let mut controllers = s.controllers.borrow_mut();
+            if !controllers.contains(&other) {
+                controllers.push(other);
+            }
+        }),
+        ProposalAction::SetGovernance(config) => {
+            config.validate()?;
+            STATE.with(|s| s.governance.replace(config));
+        }
+        ProposalAction::SetTeraAddress(_)
+        | ProposalAction::SetWethAddressIc(_)
+        | ProposalAction::SetWethAddressEth(_) => {
+            let config = updated_config(&action);
+            config.validate()?;
+            STATE.with(|s| s.config.replace(config));
+        }
+        ProposalAction::SetCompensationPolicy(policy) => {
+            STATE.with(|s| s.compensation_policy.replace(policy));
+        }
+        ProposalAction::SetVolumeLimit(token, limit) => {
+            STATE.with(|s| s.set_volume_limit(token, limit));
+        }
+        ProposalAction::SetFee(token, fee) => {
+            fee.validate()?;
+            STATE.with(|s| s.set_fee(token, fee));
+        }
+        ProposalAction::SetTreasury(treasury) => {
+            STATE.with(|s| s.treasury.replace(Some(treasury)));
+        }
+        ProposalAction::SetReporter(reporter) => {
+            STATE.with(|s| s.reporter.replace(Some(reporter)));
+        }
+        ProposalAction::Unpause => {
+            STATE.with(|s| s.paused.replace(false));
+        }
+    }
+
+    Ok(())
+}
+
+/// Proxy config with the address change of action applied
+fn updated_config(action: &ProposalAction) -> ProxyConfig {
+    let mut config = STATE.with(|s| s.get_config());
+
+    match action {
+        ProposalAction::SetTeraAddress(tera_address) => config.tera_address = *tera_address,
+        ProposalAction::SetWethAddressIc(weth_address_ic) => {
+            config.weth_address_ic = *weth_address_ic
+        }
+        ProposalAction::SetWethAddressEth(weth_address_eth) => {
+            config.weth_address_eth = *weth_address_eth
+        }
+        _ => (),
+    }
+
+    config
+}
+
+/// Execute a ready proposal and record the outcome
+fn execute(id: u64) -> Result<(), String> {
+    let result = STATE
+        .with(|s| s.take_ready_proposal(id, ic::time()))
+        .and_then(|proposal| apply_action(proposal.action));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "execute_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+/// Execute a proposal if it needs no further approvals or delay,
+/// returns whether it was executed
+fn execute_if_ready(id: u64) -> Result<bool, String> {
+    if STATE.with(|s| s.proposal_ready(id, ic::time())).is_err() {
+        return Ok(false);
+    }
+
+    execute(id).map(|_| true)
+}
+
+/// Open a proposal approved by the caller
+pub fn open_proposal(method: &str, digest: String, action: ProposalAction) -> Result<u64, String> {
+    let result = validate_action(&action)
+        .map(|_| STATE.with(|s| s.propose(ic::caller(), action, ic::time())));
+
+    STATE.with(|s| s.record_audit(method, digest, AuditOutcome::from(&result)));
+
+    let id = result?;
+    execute_if_ready(id)?;
+
+    Ok(id)
+}
+
+#[update(name = "propose", guard = "is_authorized")]
+#[candid_method(update, rename = "propose")]
+fn propose(action: ProposalAction) -> Result<u64, String> {
+    open_proposal("propose", args_digest((&action,)), action)
+}
+
+#[update(name = "approve_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "approve_proposal")]
+fn approve_proposal(id: u64) -> Result<bool, String> {
+    let result = STATE.with(|s| s.approve_proposal(id, ic::caller(), ic::time()));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "approve_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result?;
+    execute_if_ready(id)
+}
+
+#[update(name = "execute_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "execute_proposal")]
+fn execute_proposal(id: u64) -> Result<(), String> {
+    execute(id)
+}
+
+#[update(name = "cancel_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "cancel_proposal")]
+fn cancel_proposal(id: u64) -> Result<(), String> {
+    let result = STATE.with(|s| s.cancel_proposal(id)).map(|_| ());
+
+    STATE.with(|s| {
+        s.record_audit(
+            "cancel_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+#[query(name = "get_pending_proposals", guard = "is_authorized")]
+#[candid_method(query, rename = "get_pending_proposals")]
+fn get_pending_proposals() -> Vec<Proposal> {
+    STATE.with(|s| s.get_pending_proposals(ic::time()))
+}
+
+#[query(name = "get_governance_config", guard = "is_authorized")]
+#[candid_method(query, rename = "get_governance_config")]
+fn get_governance_config() -> GovernanceConfig {
+    STATE.with(|s| s.governance.borrow().clone())
+}
+
+/// Pausing takes effect immediately, unpausing is a proposal
+#[update(name = "pause", guard = "is_authorized")]
+#[candid_method(update, rename = "pause")]
+fn pause() {
+    STATE.with(|s| s.paused.replace(true));
+    STATE.with(|s| s.record_audit("pause", args_digest(()), AuditOutcome::Ok));
+}
+
+#[query(name = "paused")]
+#[candid_method(query, rename = "paused")]
+fn paused() -> bool {
+    STATE.with(|s| *s.paused.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    fn before_each() -> &'static mut MockContext {
+        let mock_ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.controllers
+                .borrow_mut()
+                .extend([mock_principals::alice(), mock_principals::bob()])
+        });
+
+        mock_ctx
+    }
+
+    #[test]
+    fn test_single_approval_executes() {
+        before_each();
+
+        let id = propose(ProposalAction::Authorize(mock_principals::john())).unwrap();
+
+        assert!(STATE.with(|s| s.controllers.borrow().contains(&mock_principals::john())));
+        assert!(STATE.with(|s| s.proposals.borrow().get(&id).is_none()));
+    }
+
+    #[test]
+    fn test_approval_executes() {
+        let mock_ctx = before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        let id = propose(ProposalAction::Authorize(mock_principals::john())).unwrap();
+
+        assert!(!STATE.with(|s| s.controllers.borrow().contains(&mock_principals::john())));
+        assert_eq!(get_pending_proposals().len(), 1);
+        assert!(execute_proposal(id).is_err());
+
+        mock_ctx.update_caller(mock_principals::bob());
+        assert_eq!(approve_proposal(id), Ok(true));
+
+        assert!(STATE.with(|s| s.controllers.borrow().contains(&mock_principals::john())));
+        assert!(get_pending_proposals().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_governance_rejected() {
+        before_each();
+
+        let config = GovernanceConfig {
+            threshold: 0,
+            ..GovernanceConfig::default()
+        };
+
+        assert!(propose(ProposalAction::SetGovernance(config)).is_err());
+        assert!(get_pending_proposals().is_empty());
+    }
+
+    #[test]
+    fn test_pause_unpause() {
+        let mock_ctx = before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        pause();
+        assert!(is_not_paused().is_err());
+
+        let id = propose(ProposalAction::Unpause).unwrap();
+        assert!(paused());
+
+        mock_ctx.update_caller(mock_principals::bob());
+        assert_eq!(approve_proposal(id), Ok(true));
+        assert!(is_not_paused().is_ok());
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        before_each();
+        STATE.with(|s| s.governance.borrow_mut().threshold = 2);
+
+        let id = propose(ProposalAction::Unpause).unwrap();
+
+        assert!(cancel_proposal(id).is_ok());
+        assert!(approve_proposal(id).is_err());
+    }
+}
@@ -1,25 +1,22 @@
+use crate::api::governance::is_not_paused;
 use crate::api::mint::mint;
 use ic_kit::candid::candid_method;
 use ic_kit::macros::update;
 
 use ic_cdk::export::candid::Nat;
 
-use crate::common::types::{EthereumAddr, Nonce, TxError, TxReceipt};
-use crate::proxy::WETH_ADDRESS_ETH;
+use crate::common::types::{BridgeResult, EthereumAddr, Nonce, TxError};
+use crate::proxy::STATE;
 
-#[update(name = "handle_message")]
+#[update(name = "handle_message", guard = "is_not_paused")]
 #[candid_method(update, rename = "handle_message")]
-async fn handler(eth_addr: EthereumAddr, nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
-    let eth_addr_hex = hex::encode(eth_addr);
+async fn handler(eth_addr: EthereumAddr, nonce: Nonce, payload: Vec<Nat>) -> BridgeResult {
+    let weth_eth_addr_pid = STATE.with(|s| s.get_config().weth_address_eth);
 
-    if !(eth_addr_hex
-        == WETH_ADDRESS_ETH
-            .trim_start_matches("0x")
-            .to_ascii_lowercase())
-    {
+    if eth_addr != weth_eth_addr_pid {
         return Err(TxError::Other(format!(
             "Eth Contract Address is inccorrect: {}",
-            eth_addr_hex
+            hex::encode(eth_addr)
         )));
     }
 
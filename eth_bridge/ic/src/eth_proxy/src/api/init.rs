@@ -1,8 +1,12 @@
 use ic_kit::{ic, macros::*};
 
+use crate::common::types::ProxyConfig;
 use crate::proxy::STATE;
 
 #[init]
-pub fn init() {
+pub fn init(config: ProxyConfig) {
+    config.validate().unwrap();
+
     STATE.with(|s| s.controllers.borrow_mut().push(ic::caller()));
+    STATE.with(|s| s.config.replace(config));
 }
@@ -1,24 +1,28 @@
-use std::str::FromStr;
-
 use ic_kit::candid::candid_method;
-use ic_kit::{ic, macros::update};
+use ic_kit::{
+    ic,
+    macros::{query, update},
+};
 
+use crate::api::admin::is_authorized;
+use crate::api::governance::is_not_paused;
 use crate::common::tera::Tera;
 use crate::common::utils::Keccak256HashFn;
 use crate::common::weth::Weth;
-use crate::proxy::{FromNat, ToNat, STATE, TERA_ADDRESS, WETH_ADDRESS_ETH, WETH_ADDRESS_IC};
+use crate::proxy::{FromNat, ToNat, STATE};
 use ic_cdk::export::candid::{Nat, Principal};
 
 use crate::common::types::{
-    IncomingMessageHashParams, Message, MessageStatus, Nonce, TxError, TxReceipt,
+    BridgeReceipt, BridgeResult, Flow, HeldAction, IncomingMessageHashParams, Message, MessageHash,
+    MessageStatus, Nonce, StuckMessage, StuckMint, TokendId, TxError, VolumeEntry,
 };
 
-#[update(name = "mint")]
+#[update(name = "mint", guard = "is_not_paused")]
 #[candid_method(update, rename = "mint")]
-pub async fn mint(nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
-    let eth_addr_hex = WETH_ADDRESS_ETH.trim_start_matches("0x");
-    let weth_ic_addr_pid = Principal::from_str(WETH_ADDRESS_IC).unwrap();
-    let weth_eth_addr_pid = Principal::from_slice(&hex::decode(eth_addr_hex).unwrap());
+pub async fn mint(nonce: Nonce, payload: Vec<Nat>) -> BridgeResult {
+    let config = STATE.with(|s| s.get_config());
+    let weth_ic_addr_pid = config.weth_address_ic;
+    let weth_eth_addr_pid = config.weth_address_eth;
 
     if (weth_ic_addr_pid.name().await).is_err() {
         return Err(TxError::Other(format!(
@@ -48,9 +52,9 @@ pub async fn mint(nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
             }
         }
     } else {
-        let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
+        let tera_id = config.tera_address;
         if tera_id
-            .consume_message(weth_eth_addr_pid, nonce, payload.clone())
+            .consume_message(weth_eth_addr_pid, nonce.clone(), payload.clone())
             .await
             .is_err()
         {
@@ -67,13 +71,110 @@ pub async fn mint(nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
     let amount = Nat::from(payload[1].0.clone());
     let to = Principal::from_nat(payload[0].clone());
 
-    match weth_ic_addr_pid.mint(to, amount).await {
+    mint_message(StuckMint {
+        msg_hash,
+        nonce,
+        payload,
+        token: weth_ic_addr_pid,
+        beneficiary: to,
+        amount,
+        stuck_since: ic::time(),
+    })
+    .await
+}
+
+/// Mint a message again whose mint failed after it was consumed
+#[update(name = "retry_mint", guard = "is_not_paused")]
+#[candid_method(update, rename = "retry_mint")]
+pub async fn retry_mint(msg_hash: MessageHash) -> BridgeResult {
+    let stuck_mint = STATE
+        .with(|s| s.get_stuck_mint(&msg_hash))
+        .ok_or_else(|| TxError::Other(format!("Message {} is not stuck!", &msg_hash)))?;
+
+    if stuck_mint.beneficiary != ic::caller() && is_authorized().is_err() {
+        return Err(TxError::Unauthorized);
+    }
+
+    match STATE.with(|s| s.get_message(&msg_hash)) {
+        Some(MessageStatus::ConsumedNotMinted) => (),
+        _ => {
+            return Err(TxError::Other(format!(
+                "Meesage {}: is already being consumed/minted!",
+                &msg_hash
+            )));
+        }
+    }
+
+    STATE.with(|s| s.update_incoming_message_status(msg_hash, MessageStatus::Consuming));
+
+    mint_message(stuck_mint).await
+}
+
+#[query(name = "get_stuck_messages", guard = "is_authorized")]
+#[candid_method(query, rename = "get_stuck_messages")]
+fn get_stuck_messages() -> Vec<StuckMessage> {
+    STATE.with(|s| s.get_stuck_messages(ic::time()))
+}
+
+/// Mint a consumed message within the volume limits, holding it for approval otherwise
+async fn mint_message(stuck_mint: StuckMint) -> BridgeResult {
+    let reserved = STATE.with(|s| {
+        s.reserve_volume(
+            stuck_mint.token,
+            Flow::Mint,
+            stuck_mint.amount.clone(),
+            ic::time(),
+        )
+    });
+
+    match reserved {
+        Ok(volume) => mint_reserved(stuck_mint, volume).await,
+        Err(reason) => {
+            let msg_hash = stuck_mint.msg_hash.clone();
+            let id = STATE.with(|s| {
+                s.update_incoming_message_status(msg_hash.clone(), MessageStatus::Held);
+                s.hold_operation(
+                    stuck_mint.beneficiary,
+                    stuck_mint.token,
+                    stuck_mint.amount.clone(),
+                    Nat::from(0_u32),
+                    HeldAction::Mint(stuck_mint),
+                    reason.clone(),
+                    ic::time(),
+                )
+            });
+
+            Err(TxError::Other(format!(
+                "Mint of message {} is held for approval as operation {}: {}",
+                msg_hash, id, reason
+            )))
+        }
+    }
+}
+
+/// Mint a consumed message whose volume is recorded, keeping its arguments
+/// for a retry if the mint fails
+pub(crate) async fn mint_reserved(stuck_mint: StuckMint, volume: VolumeEntry) -> BridgeResult {
+    let msg_hash = stuck_mint.msg_hash.clone();
+    let fee = STATE.with(|s| s.fee_for(stuck_mint.token, Flow::Mint, &stuck_mint.amount));
+    let amount = stuck_mint.amount.clone() - fee.clone();
+
+    match stuck_mint
+        .token
+        .mint(stuck_mint.beneficiary, amount.clone())
+        .await
+    {
         Ok(txn_id) => {
             if STATE
                 .with(|s| s.remove_incoming_message(msg_hash.clone()))
                 .is_some()
             {
-                return Ok(txn_id);
+                let fee = collect_mint_fee(stuck_mint.token, fee).await;
+                return Ok(BridgeReceipt {
+                    txn_id,
+                    amount,
+                    fee,
+                });
             }
             Err(TxError::Other(format!(
                 "Message {:?} does not exist!",
@@ -82,9 +183,22 @@ pub async fn mint(nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
         }
         Err(error) => {
             STATE.with(|s| {
-                s.update_incoming_message_status(msg_hash.clone(), MessageStatus::ConsumedNotMinted)
+                s.release_volume(&volume);
+                s.update_incoming_message_status(msg_hash, MessageStatus::ConsumedNotMinted);
+                s.store_stuck_mint(stuck_mint);
             });
             Err(error)
         }
     }
 }
+
+/// Mint the fee of a mint to the proxy, a fee that cannot be minted is waived
+async fn collect_mint_fee(token: TokendId, fee: Nat) -> Nat {
+    if fee == Nat::from(0_u32) || token.mint(ic::id(), fee.clone()).await.is_err() {
+        return Nat::from(0_u32);
+    }
+
+    STATE.with(|s| s.collect_fee(token, fee.clone()));
+
+    fee
+}
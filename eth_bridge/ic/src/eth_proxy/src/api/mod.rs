@@ -1,9 +1,16 @@
 mod admin;
 mod burn;
 mod claimable_assets;
+mod compensation;
+mod config;
+mod escrow;
+mod fees;
 mod get_balance;
+mod governance;
 mod handle_message;
 mod init;
 mod mint;
+mod reconciliation;
 mod upgrade;
+mod volume;
 mod withdraw;
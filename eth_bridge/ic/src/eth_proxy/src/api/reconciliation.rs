@@ -0,0 +1,104 @@
+use ic_kit::{
+    candid::{candid_method, Nat},
+    ic,
+    macros::{query, update},
+    Principal,
+};
+
+use crate::api::admin::is_authorized;
+use crate::api::governance::open_proposal;
+use crate::common::types::{AuditOutcome, ProposalAction, ReserveReport};
+use crate::common::utils::args_digest;
+use crate::common::weth::Weth;
+use crate::proxy::STATE;
+
+pub fn is_reporter() -> Result<(), String> {
+    STATE.with(|s| s.is_reporter())
+}
+
+#[query(name = "get_reporter")]
+#[candid_method(query, rename = "get_reporter")]
+fn get_reporter() -> Option<Principal> {
+    STATE.with(|s| *s.reporter.borrow())
+}
+
+/// Opens a proposal to change the reporter, returns the proposal id
+#[update(name = "set_reporter", guard = "is_authorized")]
+#[candid_method(update, rename = "set_reporter")]
+fn set_reporter(reporter: Principal) -> Result<u64, String> {
+    open_proposal(
+        "set_reporter",
+        args_digest((reporter,)),
+        ProposalAction::SetReporter(reporter),
+    )
+}
+
+/// Compare the eth locked in EthProxy.sol, as reported by the reporter in gwei,
+/// against the weth supply and what is in flight between the two
+#[update(name = "reconcile", guard = "is_reporter")]
+#[candid_method(update, rename = "reconcile")]
+async fn reconcile(l1_balance: Nat) -> Result<ReserveReport, String> {
+    let reporter = ic::caller();
+    let weth_ic_addr_pid = STATE.with(|s| s.get_config().weth_address_ic);
+
+    let report = match weth_ic_addr_pid.total_supply().await {
+        Ok(ic_supply) => Ok(STATE.with(|s| {
+            s.record_reserve_report(
+                weth_ic_addr_pid,
+                reporter,
+                l1_balance.clone(),
+                ic_supply,
+                ic::time(),
+            )
+        })),
+        Err(error) => Err(format!("{:?}", error)),
+    };
+
+    STATE.with(|s| {
+        s.record_audit_of(
+            reporter,
+            "reconcile",
+            args_digest((l1_balance,)),
+            AuditOutcome::from(&report),
+        )
+    });
+
+    report
+}
+
+#[query(name = "get_reserve_reports", guard = "is_authorized")]
+#[candid_method(query, rename = "get_reserve_reports")]
+fn get_reserve_reports(start: u64, limit: u64) -> Vec<ReserveReport> {
+    STATE.with(|s| s.get_reserve_reports(start, limit as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn test_reconcile() {
+        MockContext::new()
+            .with_caller(mock_principals::john())
+            .with_handler(
+                Method::new()
+                    .name("totalSupply")
+                    .response(Nat::from(100_u32)),
+            )
+            .inject();
+
+        // an admin is not the reporter
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::john()));
+        assert!(is_reporter().is_err());
+
+        STATE.with(|s| s.reporter.replace(Some(mock_principals::john())));
+        assert!(is_reporter().is_ok());
+
+        let report = reconcile(Nat::from(100_u32)).await.unwrap();
+        assert_eq!(report.reporter, mock_principals::john());
+        assert!(report.balanced);
+        assert_eq!(get_reserve_reports(0, 10).len(), 1);
+    }
+}
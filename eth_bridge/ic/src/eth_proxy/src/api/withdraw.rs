@@ -1,29 +1,26 @@
-use std::str::FromStr;
-
 use ic_kit::{
     candid::{candid_method, Nat},
     ic,
     macros::update,
-    Principal,
 };
 
 use crate::{
+    api::{burn::start_burn, governance::is_not_paused},
     common::{
-        tera::Tera,
-        types::{EthereumAddr, TxError, TxReceipt},
+        types::{BurnSource, EthereumAddr, Flow, OutgoingMessage, TxError},
         weth::Weth,
     },
-    proxy::{ToNat, STATE, TERA_ADDRESS, WETH_ADDRESS_ETH, WETH_ADDRESS_IC},
+    proxy::STATE,
 };
 
-/// withdraw left over balance if burn/mint fails
-/// this will attempt to bridge the leftover balance
-/// todo withdraw specific balance
-#[update(name = "withdraw")]
+/// withdraw escrowed balance left over if burn/mint fails
+/// this will bridge amount of the leftover balance to eth_addr,
+/// it is burned and sent like a burn and returned to the escrow if the send fails
+#[update(name = "withdraw", guard = "is_not_paused")]
 #[candid_method(update, rename = "withdraw")]
-pub async fn withdraw(eth_addr: EthereumAddr, _amount: Nat) -> TxReceipt {
+pub async fn withdraw(eth_addr: EthereumAddr, amount: Nat) -> Result<OutgoingMessage, TxError> {
     let caller = ic::caller();
-    let weth_ic_addr_pid = Principal::from_str(WETH_ADDRESS_IC).unwrap();
+    let weth_ic_addr_pid = STATE.with(|s| s.get_config().weth_address_ic);
 
     if (weth_ic_addr_pid.name().await).is_err() {
         return Err(TxError::Other(format!(
@@ -32,28 +29,154 @@ pub async fn withdraw(eth_addr: EthereumAddr, _amount: Nat) -> TxReceipt {
         )));
     }
 
-    let eth_addr_hex = WETH_ADDRESS_ETH.trim_start_matches("0x");
-    let weth_eth_addr_pid = Principal::from_slice(&hex::decode(eth_addr_hex).unwrap());
+    // the fee is taken from the amount, what is left is burned and bridged
+    let fee = STATE.with(|s| s.fee_for(weth_ic_addr_pid, Flow::Burn, &amount));
+    if fee >= amount {
+        return Err(TxError::AmountTooSmall);
+    }
+
+    // debit before the call so a concurrent withdraw cannot bridge the same escrow
+    STATE.with(|s| s.debit_escrow(caller, weth_ic_addr_pid, amount.clone()))?;
+    STATE.with(|s| s.collect_fee(weth_ic_addr_pid, fee.clone()));
+
+    let op_id = STATE.with(|s| {
+        s.create_burn_operation(
+            caller,
+            eth_addr,
+            weth_ic_addr_pid,
+            amount - fee.clone(),
+            BurnSource::Escrow,
+            ic::time(),
+        )
+    });
+
+    start_burn(op_id, fee).await?;
+
+    STATE
+        .with(|s| s.get_burn_operation(op_id))
+        .and_then(|operation| operation.message)
+        .ok_or_else(|| TxError::Other(format!("Burn operation {} has no message", op_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext, Principal};
+
+    use super::*;
+    use crate::common::types::{BurnStep, FeeConfig, TxReceipt};
+
+    fn before_each(send_message: Result<OutgoingMessage, String>) -> &'static mut MockContext {
+        let ctx = mock_calls(send_message);
+
+        STATE
+            .with(|s| s.credit_escrow(mock_principals::bob(), weth(), Nat::from(100_u32)))
+            .unwrap();
+
+        ctx
+    }
+
+    // the mock stays in reply callback mode after a call, so every withdraw
+    // after the first needs a fresh context
+    fn mock_calls(send_message: Result<OutgoingMessage, String>) -> &'static mut MockContext {
+        MockContext::new()
+            .with_caller(mock_principals::bob())
+            .with_handler(
+                Method::new()
+                    .name("name")
+                    .response("Wrapped Ether".to_string()),
+            )
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Ok(Nat::from(1_u32))),
+            )
+            .with_handler(
+                Method::new()
+                    .name("mint")
+                    .response::<TxReceipt>(Ok(Nat::from(2_u32))),
+            )
+            .with_handler(Method::new().name("send_message").response(send_message))
+            .inject()
+    }
 
-    let get_balance = STATE.with(|s| s.get_balance(caller, weth_ic_addr_pid));
-    if let Some(balance) = get_balance {
-        let payload = [eth_addr.clone().to_nat(), balance.clone()].to_vec();
-        let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
-        if tera_id
-            .send_message(weth_eth_addr_pid, payload)
-            .await
-            .is_err()
-        {
-            return Err(TxError::Other(format!("Sending message to L1 failed!")));
+    fn weth() -> Principal {
+        STATE.with(|s| s.get_config().weth_address_ic)
+    }
+
+    fn eth_addr() -> EthereumAddr {
+        Principal::from_slice(&[1; 20])
+    }
+
+    fn outgoing_message() -> OutgoingMessage {
+        OutgoingMessage {
+            msg_key: [1; 32],
+            msg_hash: String::from("a"),
         }
+    }
+
+    #[async_std::test]
+    async fn test_withdraw() {
+        before_each(Ok(outgoing_message()));
+
+        assert_eq!(
+            withdraw(eth_addr(), Nat::from(100_u32)).await,
+            Ok(outgoing_message())
+        );
 
-        let zero = Nat::from(0_u32);
-        STATE.with(|s| s.update_balance(caller, weth_ic_addr_pid, zero));
+        assert_eq!(
+            STATE.with(|s| s.get_balance(mock_principals::bob(), weth())),
+            None
+        );
+        let claimables = STATE.with(|s| s.get_claimable_messages(eth_addr()));
+        assert_eq!(claimables.len(), 1);
+        assert_eq!(claimables[0].amount, Nat::from(100_u32));
+        let operations = STATE.with(|s| s.get_burn_operations(mock_principals::bob()));
+        assert_eq!(operations[0].step, BurnStep::MessageSent);
+        assert_eq!(operations[0].source, BurnSource::Escrow);
     }
 
-    Err(TxError::Other(format!(
-        "No balance for caller {:?} in canister {:?}!",
-        caller.to_string(),
-        weth_ic_addr_pid.to_string(),
-    )))
+    #[async_std::test]
+    async fn test_withdraw_partial() {
+        before_each(Ok(outgoing_message()));
+        STATE.with(|s| {
+            s.set_fee(
+                weth(),
+                FeeConfig {
+                    flat: Nat::from(5_u32),
+                    bps: 0,
+                    on_mint: false,
+                },
+            )
+        });
+
+        assert!(withdraw(eth_addr(), Nat::from(40_u32)).await.is_ok());
+        mock_calls(Ok(outgoing_message()));
+        assert!(withdraw(eth_addr(), Nat::from(61_u32)).await.is_err());
+
+        assert_eq!(
+            STATE.with(|s| s.get_balance(mock_principals::bob(), weth())),
+            Some(Nat::from(60_u32))
+        );
+        assert_eq!(STATE.with(|s| s.collected_fee(weth())), Nat::from(5_u32));
+        let claimables = STATE.with(|s| s.get_claimable_messages(eth_addr()));
+        assert_eq!(claimables[0].amount, Nat::from(35_u32));
+    }
+
+    #[async_std::test]
+    async fn test_withdraw_send_failed() {
+        before_each(Err(String::from("tera is down")));
+
+        assert!(withdraw(eth_addr(), Nat::from(100_u32)).await.is_err());
+
+        // minted back to the proxy and credited to the escrow again
+        assert_eq!(
+            STATE.with(|s| s.get_balance(mock_principals::bob(), weth())),
+            Some(Nat::from(100_u32))
+        );
+        assert!(STATE
+            .with(|s| s.get_claimable_messages(eth_addr()))
+            .is_empty());
+        let operations = STATE.with(|s| s.get_burn_operations(mock_principals::bob()));
+        assert_eq!(operations[0].step, BurnStep::Refunded);
+    }
 }
@@ -1,3 +1,4 @@
+pub mod migration;
 pub mod tera;
 pub mod types;
 pub mod utils;
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use ic_kit::candid::{CandidType, Deserialize, Nat, Principal};
 use serde::Serialize;
@@ -14,8 +14,13 @@ pub type EthereumAddr = Principal;
 
 pub type MsgHashKey = [u8; 32];
 
+/// Claimable keys of one principal ordered by creation time
+pub type ClaimableIndex = BTreeSet<(Principal, u64, MsgHashKey)>;
+
 pub type TxReceipt = Result<Nat, TxError>;
 
+pub type BridgeResult = Result<BridgeReceipt, TxError>;
+
 #[derive(Serialize, CandidType, Deserialize)]
 pub struct Message;
 
@@ -26,6 +31,44 @@ pub struct ClaimableMessage {
     pub msg_hash: MessageHash,
     pub token: TokendId,
     pub amount: Nat,
+    /// IC principal whose burn or withdraw sent the message, unknown for older messages
+    pub sender: Option<Principal>,
+    pub status: ClaimStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl ClaimableMessage {
+    pub fn pending(
+        owner: EthereumAddr,
+        sender: Principal,
+        outgoing_message: &OutgoingMessage,
+        token: TokendId,
+        amount: Nat,
+        now: u64,
+    ) -> Self {
+        ClaimableMessage {
+            owner,
+            msg_key: outgoing_message.msg_key,
+            msg_hash: outgoing_message.msg_hash.clone(),
+            token,
+            amount,
+            sender: Some(sender),
+            status: ClaimStatus::Pending,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum ClaimStatus {
+    /// waiting to be claimed on L1
+    Pending,
+    /// claimed on L1 in the transaction with this hash
+    Claimed(String),
+    /// no longer expected to be claimed
+    Expired,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -41,6 +84,29 @@ pub enum FactoryError {
 pub enum MessageStatus {
     Consuming,
     ConsumedNotMinted,
+    /// consumed, the mint waits in the approval queue
+    Held,
+}
+
+/// Arguments of a consumed message whose mint failed, kept so it can be retried
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct StuckMint {
+    pub msg_hash: MessageHash,
+    pub nonce: Nonce,
+    pub payload: Vec<Nat>,
+    /// token the message mints
+    pub token: TokendId,
+    pub beneficiary: Principal,
+    pub amount: Nat,
+    /// time of the first failed mint
+    pub stuck_since: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct StuckMessage {
+    pub mint: StuckMint,
+    /// nanoseconds since the first failed mint
+    pub age: u64,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -64,6 +130,417 @@ pub struct OutgoingMessage {
     pub msg_hash: String,
 }
 
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct GovernanceConfig {
+    /// approvals required before a proposal can execute
+    pub threshold: u32,
+    /// nanoseconds a proposal waits before it can execute
+    pub delay: u64,
+    /// nanoseconds before a pending proposal expires
+    pub ttl: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            threshold: 1,
+            delay: 0,
+            ttl: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+impl GovernanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            return Err("Threshold must be at least 1".to_string());
+        }
+
+        if self.ttl <= self.delay {
+            return Err("Proposal ttl must be longer than the delay".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct ProxyConfig {
+    /// tera canister messages are sent and consumed through
+    pub tera_address: Principal,
+    /// weth token canister on the IC
+    pub weth_address_ic: Principal,
+    /// weth contract on ethereum
+    pub weth_address_eth: EthereumAddr,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            tera_address: Principal::anonymous(),
+            weth_address_ic: Principal::anonymous(),
+            weth_address_eth: Principal::anonymous(),
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tera_address == Principal::anonymous()
+            || self.weth_address_ic == Principal::anonymous()
+        {
+            return Err("Canister address cannot be anonymous".to_string());
+        }
+
+        if self.weth_address_eth.as_slice().len() != 20 {
+            return Err("Ethereum address must be 20 bytes".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum ProposalAction {
+    Authorize(Principal),
+    SetGovernance(GovernanceConfig),
+    SetTeraAddress(Principal),
+    SetWethAddressIc(Principal),
+    SetWethAddressEth(EthereumAddr),
+    SetCompensationPolicy(CompensationPolicy),
+    SetVolumeLimit(Option<TokendId>, VolumeLimit),
+    SetFee(TokendId, FeeConfig),
+    SetTreasury(Principal),
+    SetReporter(Principal),
+    Unpause,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: ProposalAction,
+    pub proposer: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub index: u64,
+    pub caller: Principal,
+    pub method: String,
+    /// hex encoded keccak256 of the candid encoded arguments
+    pub args_digest: String,
+    pub time: u64,
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum EscrowError {
+    ZeroAmount,
+    InsufficientEscrow { available: Nat, requested: Nat },
+}
+
+impl From<EscrowError> for TxError {
+    fn from(error: EscrowError) -> Self {
+        match error {
+            EscrowError::ZeroAmount => TxError::AmountTooSmall,
+            EscrowError::InsufficientEscrow {
+                available,
+                requested,
+            } => TxError::Other(format!(
+                "Escrow of {} is less than the requested {}!",
+                available, requested
+            )),
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct EscrowEntry {
+    pub owner: Principal,
+    pub token: TokendId,
+    pub amount: Nat,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct EscrowReport {
+    pub token: TokendId,
+    /// sum of all user escrows of token
+    pub escrowed: Nat,
+    /// collected fees not yet withdrawn
+    pub fees: Nat,
+    /// taken for burns that are held or failed before burning
+    pub unburned: Nat,
+    /// balance the proxy holds on the token canister
+    pub held: Nat,
+    /// whether the proxy holds at least what it owes
+    pub balanced: bool,
+}
+
+/// approval of the burn amount to the proxy, signed off-chain by the caller
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct BurnPermit {
+    /// ed25519 or secp256k1 public key of the caller
+    pub owner_pubkey: Vec<u8>,
+    pub deadline: u64,
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum BurnStep {
+    /// weth was transferred to the proxy
+    Escrowed,
+    /// the burn call is in flight
+    Burning,
+    /// weth was burned
+    Burned,
+    /// the message call to tera is in flight
+    Sending,
+    /// the message to L1 was sent through tera
+    MessageSent,
+    /// the message could not be sent and the refund mint is in flight
+    Refunding,
+    /// the message could not be sent and the amount was minted back
+    Refunded,
+    /// weth was transferred to the proxy, the burn waits in the approval queue
+    Held,
+    /// the held burn was rejected and the weth returned
+    Rejected,
+    /// last completed step and the error of the step after it
+    Failed(Box<BurnStep>, String),
+}
+
+/// Where the weth of a burn came from
+#[derive(Clone, Copy, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum BurnSource {
+    /// transferred from the wallet of the owner
+    Wallet,
+    /// debited from the escrow of the owner by a withdraw
+    Escrow,
+}
+
+impl BurnStep {
+    /// Whether a call of the step is in flight
+    pub fn in_flight(&self) -> bool {
+        matches!(
+            self,
+            BurnStep::Burning | BurnStep::Sending | BurnStep::Refunding
+        )
+    }
+
+    /// Last completed step, the step a failed or stuck burn continues from
+    pub fn last_completed(&self) -> BurnStep {
+        match self {
+            BurnStep::Failed(step, _) => *step.clone(),
+            BurnStep::Burning => BurnStep::Escrowed,
+            BurnStep::Sending | BurnStep::Refunding => BurnStep::Burned,
+            step => step.clone(),
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct BurnOperation {
+    pub id: u64,
+    pub owner: Principal,
+    pub eth_addr: EthereumAddr,
+    pub token: TokendId,
+    pub amount: Nat,
+    pub source: BurnSource,
+    pub step: BurnStep,
+    pub burn_txn_id: Option<Nat>,
+    pub message: Option<OutgoingMessage>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum CompensationPolicy {
+    /// mint the burned amount back to the user
+    Refund,
+    /// keep the amount so the message can be sent again
+    Retry,
+}
+
+impl Default for CompensationPolicy {
+    fn default() -> Self {
+        CompensationPolicy::Retry
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum CompensationOutcome {
+    /// the amount was minted back, with the mint transaction id
+    Refunded(Nat),
+    /// minting back failed, the amount stays retryable
+    RefundFailed(String),
+    /// the amount was kept for a retry
+    Retryable,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct CompensationEntry {
+    pub index: u64,
+    pub owner: Principal,
+    pub eth_addr: EthereumAddr,
+    pub token: TokendId,
+    pub amount: Nat,
+    /// error of the send that was compensated
+    pub reason: String,
+    pub outcome: CompensationOutcome,
+    pub time: u64,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum Flow {
+    Mint,
+    Burn,
+}
+
+/// Caps on bridged amounts, a missing cap is unlimited
+#[derive(Clone, CandidType, Deserialize, Default, Eq, PartialEq, Debug)]
+pub struct VolumeLimit {
+    /// largest amount of a single mint or burn
+    pub per_transaction: Option<Nat>,
+    /// largest amount minted within any 24h
+    pub daily_mint: Option<Nat>,
+    /// largest amount burned within any 24h
+    pub daily_burn: Option<Nat>,
+}
+
+impl VolumeLimit {
+    /// Check an amount against the caps, volume includes the amount
+    pub fn check(&self, flow: Flow, amount: &Nat, volume: &Nat) -> Result<(), String> {
+        if let Some(max) = &self.per_transaction {
+            if amount > max {
+                return Err(format!(
+                    "amount {} exceeds the per transaction limit {}",
+                    amount, max
+                ));
+            }
+        }
+
+        let (daily, name) = match flow {
+            Flow::Mint => (&self.daily_mint, "mint"),
+            Flow::Burn => (&self.daily_burn, "burn"),
+        };
+        if let Some(max) = daily {
+            if volume > max {
+                return Err(format!(
+                    "24h {} volume {} exceeds the limit {}",
+                    name, volume, max
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct VolumeLimits {
+    /// caps on the sum over all tokens
+    pub global: VolumeLimit,
+    pub tokens: Vec<(TokendId, VolumeLimit)>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct VolumeEntry {
+    pub token: TokendId,
+    pub flow: Flow,
+    pub amount: Nat,
+    pub time: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum HeldAction {
+    /// mint of a consumed message
+    Mint(StuckMint),
+    /// burn operation waiting at the escrowed step
+    Burn(u64),
+}
+
+/// Operation over a volume limit, waiting for an admin to approve or reject it
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct HeldOperation {
+    pub id: u64,
+    /// beneficiary of a mint or sender of a burn
+    pub owner: Principal,
+    pub token: TokendId,
+    pub amount: Nat,
+    /// bridge fee collected with the operation, returned if it is rejected
+    pub fee: Nat,
+    pub action: HeldAction,
+    /// limit the operation hit
+    pub reason: String,
+    pub created_at: u64,
+}
+
+/// Fee charged on a bridged amount, a flat part plus basis points of the amount
+#[derive(Clone, CandidType, Deserialize, Default, Eq, PartialEq, Debug)]
+pub struct FeeConfig {
+    pub flat: Nat,
+    /// basis points of the amount, at most 10_000
+    pub bps: u32,
+    /// charge the fee on mint as well as on burn
+    pub on_mint: bool,
+}
+
+impl FeeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bps > 10_000 {
+            return Err(format!("Fee of {} bps exceeds 10000", self.bps));
+        }
+
+        Ok(())
+    }
+
+    pub fn fee(&self, amount: &Nat) -> Nat {
+        self.flat.clone() + amount.clone() * Nat::from(self.bps) / Nat::from(10_000_u32)
+    }
+}
+
+/// Result of a mint or burn, amount is what reached the other side after the fee
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct BridgeReceipt {
+    pub txn_id: Nat,
+    pub amount: Nat,
+    pub fee: Nat,
+}
+
+/// Comparison of what is locked on L1 against what the bridge owes for it
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct ReserveReport {
+    pub index: u64,
+    pub token: TokendId,
+    /// principal that supplied the L1 balance
+    pub reporter: Principal,
+    /// balance locked on L1 as supplied by the reporter
+    pub l1_balance: Nat,
+    /// total supply of token on the IC
+    pub ic_supply: Nat,
+    /// burned on the IC and not yet withdrawn on L1
+    pub pending_claimables: Nat,
+    /// burned on the IC without a message to L1
+    pub unsent_burns: Nat,
+    /// deposited on L1 and not yet minted on the IC
+    pub unminted: Nat,
+    /// L1 holds more than the bridge owes
+    pub surplus: Nat,
+    /// L1 holds less than the bridge owes
+    pub deficit: Nat,
+    pub balanced: bool,
+    pub time: u64,
+}
+
 #[derive(CandidType, Deserialize, Default)]
 pub struct ProxyState {
     /// store incoming messages against status locks
@@ -72,8 +549,58 @@ pub struct ProxyState {
     pub balances: RefCell<HashMap<Principal, HashMap<TokendId, Nat>>>,
     /// authorized principals
     pub controllers: RefCell<Vec<Principal>>,
-    // store outgoing massages waiting to be claimed
-    pub messages_unclaimed: RefCell<HashMap<EthereumAddr, Vec<ClaimableMessage>>>,
+    /// outgoing messages by msg_key, with their claim status
+    pub claimables: RefCell<HashMap<MsgHashKey, ClaimableMessage>>,
+    /// claimables by ETH address, rebuilt from claimables on upgrade
+    pub claimables_by_owner: RefCell<ClaimableIndex>,
+    /// claimables by IC sender, rebuilt from claimables on upgrade
+    pub claimables_by_sender: RefCell<ClaimableIndex>,
+    /// admin action audit log, oldest first
+    pub audit_log: RefCell<Vec<AuditEntry>>,
+    /// index of the next audit entry
+    pub audit_index: RefCell<u64>,
+    /// approval threshold, delay and ttl of proposals
+    pub governance: RefCell<GovernanceConfig>,
+    /// pending proposals by id
+    pub proposals: RefCell<HashMap<u64, Proposal>>,
+    /// id of the next proposal
+    pub proposal_index: RefCell<u64>,
+    /// whether mint, burn and withdraw are paused
+    pub paused: RefCell<bool>,
+    /// tera and weth addresses
+    pub config: RefCell<ProxyConfig>,
+    /// journal of every burn by operation id
+    pub burn_operations: RefCell<HashMap<u64, BurnOperation>>,
+    /// id of the next burn operation
+    pub burn_operation_index: RefCell<u64>,
+    /// how burns whose message could not be sent are compensated
+    pub compensation_policy: RefCell<CompensationPolicy>,
+    /// every compensation, oldest first
+    pub compensations: RefCell<Vec<CompensationEntry>>,
+    /// mint arguments of ConsumedNotMinted messages
+    pub stuck_mints: RefCell<HashMap<MessageHash, StuckMint>>,
+    /// caps on the sum over all tokens
+    pub global_limit: RefCell<VolumeLimit>,
+    /// caps per token
+    pub token_limits: RefCell<HashMap<TokendId, VolumeLimit>>,
+    /// mints and burns within the last 24h
+    pub volume: RefCell<Vec<VolumeEntry>>,
+    /// operations over a limit, waiting for approval
+    pub held_operations: RefCell<HashMap<u64, HeldOperation>>,
+    /// id of the next held operation
+    pub held_operation_index: RefCell<u64>,
+    /// fee charged per token
+    pub fees: RefCell<HashMap<TokendId, FeeConfig>>,
+    /// principal the collected fees are withdrawn to
+    pub treasury: RefCell<Option<Principal>>,
+    /// fees held by the proxy and not yet withdrawn
+    pub collected_fees: RefCell<HashMap<TokendId, Nat>>,
+    /// tokens whose collected fees are being withdrawn, not kept across upgrades
+    pub fee_withdrawals: RefCell<BTreeSet<TokendId>>,
+    /// history of the reserve reconciliations, the oldest are dropped
+    pub reserve_reports: RefCell<Vec<ReserveReport>>,
+    /// principal that reports the L1 balance for reconciliation
+    pub reporter: RefCell<Option<Principal>>,
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -84,8 +611,51 @@ pub struct StableProxyState {
     pub balances: HashMap<Principal, HashMap<Principal, Nat>>,
     /// authorized principals
     pub controllers: Vec<Principal>,
-    // store outgoing massages waiting to be claimed
-    pub messages_unclaimed: HashMap<EthereumAddr, Vec<ClaimableMessage>>,
+    /// outgoing messages by msg_key, with their claim status
+    pub claimables: HashMap<MsgHashKey, ClaimableMessage>,
+    /// admin action audit log, oldest first
+    pub audit_log: Vec<AuditEntry>,
+    /// index of the next audit entry
+    pub audit_index: u64,
+    /// approval threshold, delay and ttl of proposals
+    pub governance: GovernanceConfig,
+    /// pending proposals by id
+    pub proposals: HashMap<u64, Proposal>,
+    /// id of the next proposal
+    pub proposal_index: u64,
+    /// whether mint, burn and withdraw are paused
+    pub paused: bool,
+    /// tera and weth addresses
+    pub config: ProxyConfig,
+    /// journal of every burn by operation id
+    pub burn_operations: HashMap<u64, BurnOperation>,
+    /// id of the next burn operation
+    pub burn_operation_index: u64,
+    /// how burns whose message could not be sent are compensated
+    pub compensation_policy: CompensationPolicy,
+    /// every compensation, oldest first
+    pub compensations: Vec<CompensationEntry>,
+    /// mint arguments of ConsumedNotMinted messages
+    pub stuck_mints: HashMap<MessageHash, StuckMint>,
+    /// caps on the sum over all tokens
+    pub global_limit: VolumeLimit,
+    /// caps per token
+    pub token_limits: HashMap<TokendId, VolumeLimit>,
+    /// mints and burns within the last 24h
+    pub volume: Vec<VolumeEntry>,
+    /// operations over a limit, waiting for approval
+    pub held_operations: HashMap<u64, HeldOperation>,
+    /// id of the next held operation
+    pub held_operation_index: u64,
+    /// fee charged per token
+    pub fees: HashMap<TokendId, FeeConfig>,
+    /// principal the collected fees are withdrawn to
+    pub treasury: Option<Principal>,
+    /// fees held by the proxy and not yet withdrawn
+    pub collected_fees: HashMap<TokendId, Nat>,
+    pub reserve_reports: Vec<ReserveReport>,
+    /// principal that reports the L1 balance for reconciliation
+    pub reporter: Option<Principal>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Copy)]
@@ -105,4 +675,5 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other(String),
+    MintCapExceeded,
 }
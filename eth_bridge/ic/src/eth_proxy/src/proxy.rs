@@ -1,16 +1,32 @@
-use std::{collections::HashMap, ops::AddAssign};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::AddAssign;
 
 use ic_cdk::export::candid::{Nat, Principal};
 use ic_kit::ic;
 
 use crate::common::types::{
-    ClaimableMessage, EthereumAddr, MessageHash, MessageStatus, ProxyState, StableProxyState,
-    TokendId,
+    AuditEntry, AuditOutcome, BurnOperation, BurnSource, BurnStep, ClaimStatus, ClaimableIndex,
+    ClaimableMessage, CompensationEntry, CompensationOutcome, CompensationPolicy, EscrowEntry,
+    EscrowError, EthereumAddr, FeeConfig, Flow, GovernanceConfig, HeldAction, HeldOperation,
+    MessageHash, MessageStatus, MsgHashKey, OutgoingMessage, Proposal, ProposalAction, ProxyConfig,
+    ProxyState, ReserveReport, StableProxyState, StuckMessage, StuckMint, TokendId, VolumeEntry,
+    VolumeLimit, VolumeLimits,
 };
 
-pub const TERA_ADDRESS: &str = "timop-6qaaa-aaaab-qaeea-cai";
-pub const WETH_ADDRESS_IC: &str = "tgodh-faaaa-aaaab-qaefa-cai";
-pub const WETH_ADDRESS_ETH: &str = "0x2e130e57021bb4dfb95eb4dd0dd8cfceb936148a";
+pub const MAX_AUDIT_ENTRIES: usize = 1_000;
+pub const MAX_AUDIT_PAGE: usize = 100;
+pub const MAX_ESCROW_PAGE: usize = 100;
+pub const MAX_COMPENSATION_PAGE: usize = 100;
+pub const MAX_CLAIMABLE_PAGE: usize = 100;
+pub const MAX_RESERVE_REPORTS: usize = 1_000;
+pub const MAX_RESERVE_REPORT_PAGE: usize = 100;
+
+/// nanoseconds the rolling volume limits look back
+pub const VOLUME_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// nanoseconds after which a burn that stopped without failing can be resumed
+pub const BURN_RESUME_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
 
 thread_local! {
     pub static STATE: ProxyState = ProxyState::default();
@@ -33,9 +49,38 @@ impl ProxyState {
     }
 
     pub fn remove_incoming_message(&self, msg_hash: MessageHash) -> Option<MessageStatus> {
+        self.stuck_mints.borrow_mut().remove(&msg_hash);
         self.incoming_messages.borrow_mut().remove(&msg_hash)
     }
 
+    /// Keep the arguments of a failed mint, a retry keeps the time of the first failure
+    pub fn store_stuck_mint(&self, stuck_mint: StuckMint) {
+        self.stuck_mints
+            .borrow_mut()
+            .entry(stuck_mint.msg_hash.clone())
+            .or_insert(stuck_mint);
+    }
+
+    pub fn get_stuck_mint(&self, msg_hash: &MessageHash) -> Option<StuckMint> {
+        self.stuck_mints.borrow().get(msg_hash).cloned()
+    }
+
+    /// All stuck mints, oldest first
+    pub fn get_stuck_messages(&self, now: u64) -> Vec<StuckMessage> {
+        let mut stuck_messages: Vec<StuckMessage> = self
+            .stuck_mints
+            .borrow()
+            .values()
+            .map(|mint| StuckMessage {
+                age: now.saturating_sub(mint.stuck_since),
+                mint: mint.clone(),
+            })
+            .collect();
+        stuck_messages.sort_by(|a, b| b.age.cmp(&a.age));
+
+        stuck_messages
+    }
+
     pub fn get_balance(&self, caller: Principal, token_id: TokendId) -> Option<Nat> {
         self.balances
             .borrow()
@@ -60,62 +105,596 @@ impl ProxyState {
         Err(format!("User {} has no token balances!", &caller))
     }
 
-    pub fn add_balance(&self, caller: Principal, token_id: TokendId, amount: Nat) {
-        self.balances
-            .borrow_mut()
-            .entry(caller)
+    pub fn credit_escrow(
+        &self,
+        owner: Principal,
+        token_id: TokendId,
+        amount: Nat,
+    ) -> Result<Nat, EscrowError> {
+        if amount == Nat::from(0_u32) {
+            return Err(EscrowError::ZeroAmount);
+        }
+
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances
+            .entry(owner)
             .or_default()
             .entry(token_id)
-            .or_default()
-            .add_assign(amount.clone())
+            .or_default();
+        balance.add_assign(amount);
+
+        Ok(balance.clone())
+    }
+
+    pub fn debit_escrow(
+        &self,
+        owner: Principal,
+        token_id: TokendId,
+        amount: Nat,
+    ) -> Result<Nat, EscrowError> {
+        if amount == Nat::from(0_u32) {
+            return Err(EscrowError::ZeroAmount);
+        }
+
+        let mut balances = self.balances.borrow_mut();
+        let available = balances
+            .get(&owner)
+            .and_then(|b| b.get(&token_id))
+            .cloned()
+            .unwrap_or_default();
+
+        if available < amount {
+            return Err(EscrowError::InsufficientEscrow {
+                available,
+                requested: amount,
+            });
+        }
+
+        let remaining = available - amount;
+        let owner_balances = balances.entry(owner).or_default();
+        if remaining == Nat::from(0_u32) {
+            owner_balances.remove(&token_id);
+        } else {
+            owner_balances.insert(token_id, remaining.clone());
+        }
+
+        if owner_balances.is_empty() {
+            balances.remove(&owner);
+        }
+
+        Ok(remaining)
+    }
+
+    /// Escrow entries ordered by owner and token
+    pub fn get_escrows(&self, start: u64, limit: usize) -> Vec<EscrowEntry> {
+        let mut escrows: Vec<EscrowEntry> = self
+            .balances
+            .borrow()
+            .iter()
+            .flat_map(|(owner, tokens)| {
+                tokens.iter().map(move |(token, amount)| EscrowEntry {
+                    owner: *owner,
+                    token: *token,
+                    amount: amount.clone(),
+                })
+            })
+            .collect();
+
+        escrows.sort_by(|a, b| (a.owner, a.token).cmp(&(b.owner, b.token)));
+        escrows
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_ESCROW_PAGE))
+            .collect()
     }
 
-    pub fn update_balance(&self, caller: Principal, token_id: TokendId, amount: Nat) {
+    pub fn escrow_total(&self, token_id: TokendId) -> Nat {
         self.balances
-            .borrow_mut()
-            .insert(caller, HashMap::from([(token_id, amount)]));
+            .borrow()
+            .values()
+            .filter_map(|tokens| tokens.get(&token_id))
+            .fold(Nat::from(0_u32), |total, amount| total + amount.clone())
     }
 
     pub fn add_claimable_message(&self, message: ClaimableMessage) {
-        let mut map = self.messages_unclaimed.borrow_mut();
-        let messages = map.entry(message.owner.clone()).or_insert_with(Vec::new);
+        let previous = self
+            .claimables
+            .borrow_mut()
+            .insert(message.msg_key, message.clone());
+
+        if let Some(previous) = previous {
+            self.unindex_claimable(&previous);
+        }
+        self.index_claimable(&message);
+    }
+
+    fn index_claimable(&self, message: &ClaimableMessage) {
+        self.claimables_by_owner.borrow_mut().insert((
+            message.owner,
+            message.created_at,
+            message.msg_key,
+        ));
+        if let Some(sender) = message.sender {
+            self.claimables_by_sender.borrow_mut().insert((
+                sender,
+                message.created_at,
+                message.msg_key,
+            ));
+        }
+    }
+
+    fn unindex_claimable(&self, message: &ClaimableMessage) {
+        self.claimables_by_owner.borrow_mut().remove(&(
+            message.owner,
+            message.created_at,
+            message.msg_key,
+        ));
+        if let Some(sender) = message.sender {
+            self.claimables_by_sender.borrow_mut().remove(&(
+                sender,
+                message.created_at,
+                message.msg_key,
+            ));
+        }
+    }
 
-        messages.push(message.clone());
-        return;
+    fn reindex_claimables(&self) {
+        self.claimables_by_owner.borrow_mut().clear();
+        self.claimables_by_sender.borrow_mut().clear();
+        for message in self.claimables.borrow().values() {
+            self.index_claimable(message);
+        }
     }
 
+    /// Messages to an ETH address still waiting to be claimed
     pub fn get_claimable_messages(&self, eth_address: EthereumAddr) -> Vec<ClaimableMessage> {
-        let unclaimed_messages = self
-            .messages_unclaimed
-            .borrow()
-            .get(&eth_address)
-            .unwrap_or(&vec![])
-            .clone();
-        return unclaimed_messages;
+        self.claimables_where(&self.claimables_by_owner, eth_address, |message| {
+            message.status == ClaimStatus::Pending
+        })
     }
 
-    pub fn remove_claimable_message(
+    /// Messages to an ETH address in any status, oldest first
+    pub fn get_claimables_by_eth_address(
         &self,
         eth_address: EthereumAddr,
-        amount: Nat,
+        start: u64,
+        limit: usize,
+    ) -> Vec<ClaimableMessage> {
+        self.claimables_where(&self.claimables_by_owner, eth_address, |_| true)
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_CLAIMABLE_PAGE))
+            .collect()
+    }
+
+    /// Messages sent by an IC principal in any status, oldest first
+    pub fn get_claimables_by_sender(
+        &self,
+        sender: Principal,
+        start: u64,
+        limit: usize,
+    ) -> Vec<ClaimableMessage> {
+        self.claimables_where(&self.claimables_by_sender, sender, |_| true)
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_CLAIMABLE_PAGE))
+            .collect()
+    }
+
+    /// Messages of principal in index passing filter, oldest first
+    fn claimables_where<F: Fn(&ClaimableMessage) -> bool>(
+        &self,
+        index: &RefCell<ClaimableIndex>,
+        principal: Principal,
+        filter: F,
+    ) -> Vec<ClaimableMessage> {
+        let claimables = self.claimables.borrow();
+
+        index
+            .borrow()
+            .range((principal, 0, [0; 32])..=(principal, u64::MAX, [u8::MAX; 32]))
+            .filter_map(|(_, _, msg_key)| claimables.get(msg_key))
+            .filter(|message| filter(message))
+            .cloned()
+            .collect()
+    }
+
+    /// Record the L1 transaction that claimed a message, expired messages can still be claimed
+    pub fn mark_claimed(
+        &self,
+        msg_key: MsgHashKey,
+        tx_hash: String,
+        now: u64,
     ) -> Result<(), String> {
-        let eth_addr_hex = WETH_ADDRESS_ETH.trim_start_matches("0x");
-        let eth_addr_pid = Principal::from_slice(&hex::decode(eth_addr_hex).unwrap());
+        let mut claimables = self.claimables.borrow_mut();
+        let message = claimables
+            .get_mut(&msg_key)
+            .ok_or_else(|| String::from("Message not found"))?;
+
+        if let ClaimStatus::Claimed(_) = message.status {
+            return Err(String::from("Message is already claimed"));
+        }
+
+        message.status = ClaimStatus::Claimed(tx_hash);
+        message.updated_at = now;
+
+        Ok(())
+    }
+
+    pub fn expire_claimable(&self, msg_key: MsgHashKey, now: u64) -> Result<(), String> {
+        let mut claimables = self.claimables.borrow_mut();
+        let message = claimables
+            .get_mut(&msg_key)
+            .ok_or_else(|| String::from("Message not found"))?;
+
+        if message.status != ClaimStatus::Pending {
+            return Err(String::from("Message is not pending"));
+        }
+
+        message.status = ClaimStatus::Expired;
+        message.updated_at = now;
+
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> ProxyConfig {
+        self.config.borrow().clone()
+    }
+
+    pub fn create_burn_operation(
+        &self,
+        owner: Principal,
+        eth_addr: EthereumAddr,
+        token: TokendId,
+        amount: Nat,
+        source: BurnSource,
+        now: u64,
+    ) -> u64 {
+        let id = self.burn_operation_index.replace_with(|index| *index + 1);
+
+        self.burn_operations.borrow_mut().insert(
+            id,
+            BurnOperation {
+                id,
+                owner,
+                eth_addr,
+                token,
+                amount,
+                source,
+                step: BurnStep::Escrowed,
+                burn_txn_id: None,
+                message: None,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn get_burn_operation(&self, id: u64) -> Option<BurnOperation> {
+        self.burn_operations.borrow().get(&id).cloned()
+    }
+
+    pub fn get_burn_operations(&self, owner: Principal) -> Vec<BurnOperation> {
+        let mut operations: Vec<BurnOperation> = self
+            .burn_operations
+            .borrow()
+            .values()
+            .filter(|op| op.owner == owner)
+            .cloned()
+            .collect();
+
+        operations.sort_by_key(|op| op.id);
+        operations
+    }
+
+    pub fn update_burn_step(&self, id: u64, step: BurnStep, now: u64) -> Result<(), String> {
+        let mut operations = self.burn_operations.borrow_mut();
+        let operation = operations
+            .get_mut(&id)
+            .ok_or_else(|| format!("Burn operation {} not found", id))?;
 
-        let mut map = self.messages_unclaimed.borrow_mut();
-        let messages = map
-            .get_mut(&eth_address)
-            .ok_or_else(|| "Eth address not found")?;
+        operation.step = step;
+        operation.updated_at = now;
 
-        // Eth address could have multiple messages with the same amount, so we only remove one
-        let item_index = messages
+        Ok(())
+    }
+
+    pub fn set_burn_txn_id(&self, id: u64, burn_txn_id: Nat) {
+        if let Some(operation) = self.burn_operations.borrow_mut().get_mut(&id) {
+            operation.burn_txn_id = Some(burn_txn_id);
+        }
+    }
+
+    pub fn set_burn_message(&self, id: u64, message: OutgoingMessage) {
+        if let Some(operation) = self.burn_operations.borrow_mut().get_mut(&id) {
+            operation.message = Some(message);
+        }
+    }
+
+    /// Burns can be resumed from a settled step, a call stuck in flight for longer
+    /// than the timeout, because its callback trapped, only by an admin
+    pub fn burn_resumable(&self, id: u64, now: u64, admin: bool) -> Result<BurnOperation, String> {
+        let operation = self
+            .get_burn_operation(id)
+            .ok_or_else(|| format!("Burn operation {} not found", id))?;
+
+        match operation.step {
+            BurnStep::MessageSent => Err(format!("Burn operation {} is complete", id)),
+            BurnStep::Refunded => Err(format!("Burn operation {} was refunded", id)),
+            BurnStep::Held => Err(format!("Burn operation {} is held for approval", id)),
+            BurnStep::Rejected => Err(format!("Burn operation {} was rejected", id)),
+            ref step if step.in_flight() => {
+                if admin && now >= operation.updated_at.saturating_add(BURN_RESUME_TIMEOUT) {
+                    Ok(operation)
+                } else {
+                    Err(format!("Burn operation {} is in progress", id))
+                }
+            }
+            _ => Ok(operation),
+        }
+    }
+
+    pub fn record_compensation(
+        &self,
+        owner: Principal,
+        eth_addr: EthereumAddr,
+        token: TokendId,
+        amount: Nat,
+        reason: String,
+        outcome: CompensationOutcome,
+    ) -> u64 {
+        let mut compensations = self.compensations.borrow_mut();
+        let index = compensations.len() as u64;
+
+        compensations.push(CompensationEntry {
+            index,
+            owner,
+            eth_addr,
+            token,
+            amount,
+            reason,
+            outcome,
+            time: ic::time(),
+        });
+
+        index
+    }
+
+    pub fn get_compensations(&self, start: u64, limit: usize) -> Vec<CompensationEntry> {
+        self.compensations
+            .borrow()
             .iter()
-            .position(|m| m.amount == amount && m.token == eth_addr_pid)
-            .ok_or_else(|| "Message not found")?;
+            .skip(start as usize)
+            .take(limit.min(MAX_COMPENSATION_PAGE))
+            .cloned()
+            .collect()
+    }
 
-        messages.remove(item_index);
+    /// Record a mint or burn if it stays within the token and global limits
+    pub fn reserve_volume(
+        &self,
+        token: TokendId,
+        flow: Flow,
+        amount: Nat,
+        now: u64,
+    ) -> Result<VolumeEntry, String> {
+        self.volume
+            .borrow_mut()
+            .retain(|entry| now < entry.time.saturating_add(VOLUME_WINDOW));
 
-        return Ok(());
+        let token_limit = self
+            .token_limits
+            .borrow()
+            .get(&token)
+            .cloned()
+            .unwrap_or_default();
+        token_limit
+            .check(
+                flow,
+                &amount,
+                &(self.rolling_volume(Some(token), flow) + amount.clone()),
+            )
+            .map_err(|error| format!("Token {}: {}", token, error))?;
+
+        self.global_limit
+            .borrow()
+            .check(
+                flow,
+                &amount,
+                &(self.rolling_volume(None, flow) + amount.clone()),
+            )
+            .map_err(|error| format!("Global: {}", error))?;
+
+        Ok(self.record_volume(token, flow, amount, now))
+    }
+
+    /// Record a mint or burn without checking the limits
+    pub fn record_volume(&self, token: TokendId, flow: Flow, amount: Nat, now: u64) -> VolumeEntry {
+        let entry = VolumeEntry {
+            token,
+            flow,
+            amount,
+            time: now,
+        };
+        self.volume.borrow_mut().push(entry.clone());
+
+        entry
+    }
+
+    /// Give back the volume of an operation that failed
+    pub fn release_volume(&self, entry: &VolumeEntry) {
+        let mut volume = self.volume.borrow_mut();
+        if let Some(index) = volume.iter().position(|e| e == entry) {
+            volume.remove(index);
+        }
+    }
+
+    /// Volume of a token, or of all tokens, recorded within the window
+    pub fn rolling_volume(&self, token: Option<TokendId>, flow: Flow) -> Nat {
+        self.volume
+            .borrow()
+            .iter()
+            .filter(|entry| entry.flow == flow && token.map_or(true, |t| t == entry.token))
+            .fold(Nat::from(0_u32), |total, entry| {
+                total + entry.amount.clone()
+            })
+    }
+
+    pub fn set_volume_limit(&self, token: Option<TokendId>, limit: VolumeLimit) {
+        match token {
+            Some(token) => {
+                self.token_limits.borrow_mut().insert(token, limit);
+            }
+            None => {
+                self.global_limit.replace(limit);
+            }
+        }
+    }
+
+    pub fn get_volume_limits(&self) -> VolumeLimits {
+        VolumeLimits {
+            global: self.global_limit.borrow().clone(),
+            tokens: self
+                .token_limits
+                .borrow()
+                .iter()
+                .map(|(token, limit)| (*token, limit.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn hold_operation(
+        &self,
+        owner: Principal,
+        token: TokendId,
+        amount: Nat,
+        fee: Nat,
+        action: HeldAction,
+        reason: String,
+        now: u64,
+    ) -> u64 {
+        let mut held_operation_index = self.held_operation_index.borrow_mut();
+        let id = *held_operation_index;
+        *held_operation_index += 1;
+
+        self.held_operations.borrow_mut().insert(
+            id,
+            HeldOperation {
+                id,
+                owner,
+                token,
+                amount,
+                fee,
+                action,
+                reason,
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    /// Held operations, oldest first
+    pub fn get_held_operations(&self) -> Vec<HeldOperation> {
+        let mut held_operations: Vec<HeldOperation> =
+            self.held_operations.borrow().values().cloned().collect();
+        held_operations.sort_by_key(|operation| operation.id);
+
+        held_operations
+    }
+
+    /// Remove a held operation so it is approved or rejected only once
+    pub fn take_held_operation(&self, id: u64) -> Result<HeldOperation, String> {
+        self.held_operations
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Held operation {} not found", id))
+    }
+
+    /// Put back a held operation whose approval or rejection failed
+    pub fn restore_held_operation(&self, operation: HeldOperation) {
+        self.held_operations
+            .borrow_mut()
+            .insert(operation.id, operation);
+    }
+
+    pub fn set_fee(&self, token: TokendId, fee: FeeConfig) {
+        self.fees.borrow_mut().insert(token, fee);
+    }
+
+    pub fn get_fees(&self) -> Vec<(TokendId, FeeConfig)> {
+        self.fees
+            .borrow()
+            .iter()
+            .map(|(token, fee)| (*token, fee.clone()))
+            .collect()
+    }
+
+    /// Fee on amount of token, never more than the amount itself
+    pub fn fee_for(&self, token: TokendId, flow: Flow, amount: &Nat) -> Nat {
+        match self.fees.borrow().get(&token) {
+            Some(fee) if flow == Flow::Burn || fee.on_mint => fee.fee(amount).min(amount.clone()),
+            _ => Nat::from(0_u32),
+        }
+    }
+
+    pub fn collect_fee(&self, token: TokendId, fee: Nat) {
+        self.collected_fees
+            .borrow_mut()
+            .entry(token)
+            .or_default()
+            .add_assign(fee);
+    }
+
+    pub fn collected_fee(&self, token: TokendId) -> Nat {
+        self.collected_fees
+            .borrow()
+            .get(&token)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn get_collected_fees(&self) -> Vec<(TokendId, Nat)> {
+        self.collected_fees
+            .borrow()
+            .iter()
+            .map(|(token, fee)| (*token, fee.clone()))
+            .collect()
+    }
+
+    /// Take back a fee collected with an operation that is not bridged,
+    /// returns what is left of it when the fees were withdrawn in between
+    pub fn return_collected_fee(&self, token: TokendId, fee: &Nat) -> Nat {
+        let mut collected_fees = self.collected_fees.borrow_mut();
+        let collected = collected_fees.entry(token).or_default();
+        let returned = fee.clone().min(collected.clone());
+        *collected -= returned.clone();
+
+        returned
+    }
+
+    /// Mark the collected fees of a token as being withdrawn,
+    /// so they are not withdrawn twice while the transfer is in flight
+    pub fn start_fee_withdrawal(&self, token: TokendId) -> Result<(), String> {
+        if !self.fee_withdrawals.borrow_mut().insert(token) {
+            return Err(format!("Fees of {} are already being withdrawn", token));
+        }
+
+        Ok(())
+    }
+
+    /// Deduct fees that reached the treasury, fees collected while
+    /// they were in flight stay collected
+    pub fn finish_fee_withdrawal(&self, token: TokendId, withdrawn: Option<&Nat>) {
+        self.fee_withdrawals.borrow_mut().remove(&token);
+
+        if let Some(withdrawn) = withdrawn {
+            let mut collected_fees = self.collected_fees.borrow_mut();
+            let collected = collected_fees.entry(token).or_default();
+            *collected -= withdrawn.clone().min(collected.clone());
+        }
     }
 
     pub fn authorize(&self, other: Principal) {
@@ -126,6 +705,12 @@ impl ProxyState {
         }
     }
 
+    pub fn is_reporter(&self) -> Result<(), String> {
+        (*self.reporter.borrow() == Some(ic::caller()))
+            .then(|| ())
+            .ok_or_else(|| String::from("Caller is not the reporter"))
+    }
+
     pub fn is_authorized(&self) -> Result<(), String> {
         self.controllers
             .borrow()
@@ -134,12 +719,322 @@ impl ProxyState {
             .ok_or("Caller is not authorized".to_string())
     }
 
+    pub fn propose(&self, proposer: Principal, action: ProposalAction, now: u64) -> u64 {
+        let id = self.proposal_index.replace_with(|index| *index + 1);
+
+        self.proposals.borrow_mut().insert(
+            id,
+            Proposal {
+                id,
+                action,
+                proposer,
+                approvals: vec![proposer],
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn approve_proposal(&self, id: u64, approver: Principal, now: u64) -> Result<(), String> {
+        self.expire_proposals(now);
+
+        let mut proposals = self.proposals.borrow_mut();
+        let proposal = proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if proposal.approvals.contains(&approver) {
+            return Err(format!("Proposal {} is already approved by caller", id));
+        }
+
+        proposal.approvals.push(approver);
+
+        Ok(())
+    }
+
+    pub fn proposal_ready(&self, id: u64, now: u64) -> Result<(), String> {
+        let config = self.governance.borrow().clone();
+        let proposals = self.proposals.borrow();
+        let proposal = proposals
+            .get(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if now > proposal.created_at.saturating_add(config.ttl) {
+            return Err(format!("Proposal {} has expired", id));
+        }
+
+        // approvals of principals that lost their role do not count
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|p| self.controllers.borrow().contains(p))
+            .count() as u32;
+        if approvals < config.threshold {
+            return Err(format!(
+                "Proposal {} has {} of {} approvals",
+                id, approvals, config.threshold
+            ));
+        }
+
+        let executable_at = proposal.created_at.saturating_add(config.delay);
+        if now < executable_at {
+            return Err(format!(
+                "Proposal {} is timelocked until {}",
+                id, executable_at
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn take_ready_proposal(&self, id: u64, now: u64) -> Result<Proposal, String> {
+        self.expire_proposals(now);
+        self.proposal_ready(id, now)?;
+
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn cancel_proposal(&self, id: u64) -> Result<Proposal, String> {
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn expire_proposals(&self, now: u64) {
+        let ttl = self.governance.borrow().ttl;
+
+        self.proposals
+            .borrow_mut()
+            .retain(|_, p| now <= p.created_at.saturating_add(ttl));
+    }
+
+    pub fn get_pending_proposals(&self, now: u64) -> Vec<Proposal> {
+        let ttl = self.governance.borrow().ttl;
+        let mut proposals: Vec<Proposal> = self
+            .proposals
+            .borrow()
+            .values()
+            .filter(|p| now <= p.created_at.saturating_add(ttl))
+            .cloned()
+            .collect();
+
+        proposals.sort_by_key(|p| p.id);
+        proposals
+    }
+
+    pub fn is_not_paused(&self) -> Result<(), String> {
+        (!*self.paused.borrow())
+            .then(|| ())
+            .ok_or("Bridge is paused".to_string())
+    }
+
+    pub fn record_audit(&self, method: &str, args_digest: String, outcome: AuditOutcome) {
+        self.record_audit_of(ic::caller(), method, args_digest, outcome);
+    }
+
+    /// Record a call whose caller was read before an await, the caller
+    /// cannot be read in the callback of an inter-canister call
+    pub fn record_audit_of(
+        &self,
+        caller: Principal,
+        method: &str,
+        args_digest: String,
+        outcome: AuditOutcome,
+    ) {
+        let mut audit_log = self.audit_log.borrow_mut();
+        let mut audit_index = self.audit_index.borrow_mut();
+
+        audit_log.push(AuditEntry {
+            index: *audit_index,
+            caller,
+            method: method.to_string(),
+            args_digest,
+            time: ic::time(),
+            outcome,
+        });
+        *audit_index += 1;
+
+        // the log is bounded, drop the oldest entries
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            let overflow = audit_log.len() - MAX_AUDIT_ENTRIES;
+            audit_log.drain(..overflow);
+        }
+    }
+
+    pub fn get_audit_log(&self, start: u64, limit: usize) -> Vec<AuditEntry> {
+        self.audit_log
+            .borrow()
+            .iter()
+            .filter(|e| e.index >= start)
+            .take(limit.min(MAX_AUDIT_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    /// Amount of token burned on the IC and not yet claimed on L1,
+    /// expired messages can still be claimed so they are owed as well
+    pub fn pending_claimable_total(&self, token: TokendId) -> Nat {
+        self.claimables
+            .borrow()
+            .values()
+            .filter(|message| {
+                message.token == token && !matches!(message.status, ClaimStatus::Claimed(_))
+            })
+            .fold(Nat::from(0_u32), |total, message| {
+                total + message.amount.clone()
+            })
+    }
+
+    /// Amount of token burned on the IC whose message to L1 was never sent
+    pub fn unsent_burn_total(&self, token: TokendId) -> Nat {
+        self.burn_operations
+            .borrow()
+            .values()
+            .filter(|operation| operation.token == token)
+            .filter(|operation| match &operation.step {
+                BurnStep::Burned | BurnStep::Sending | BurnStep::Refunding => true,
+                BurnStep::Failed(step, _) => **step == BurnStep::Burned,
+                _ => false,
+            })
+            .fold(Nat::from(0_u32), |total, operation| {
+                total + operation.amount.clone()
+            })
+    }
+
+    /// Amount of token the proxy took for burns it has not burned yet,
+    /// the burn is running, held for approval or failed before burning
+    pub fn unburned_total(&self, token: TokendId) -> Nat {
+        self.burn_operations
+            .borrow()
+            .values()
+            .filter(|operation| operation.token == token)
+            .filter(|operation| match &operation.step {
+                BurnStep::Escrowed | BurnStep::Burning | BurnStep::Held => true,
+                BurnStep::Failed(step, _) => **step == BurnStep::Escrowed,
+                _ => false,
+            })
+            .fold(Nat::from(0_u32), |total, operation| {
+                total + operation.amount.clone()
+            })
+    }
+
+    /// Amount of token consumed from L1 whose mint is stuck or held,
+    /// a held retry of a stuck mint is counted once
+    pub fn unminted_total(&self, token: TokendId) -> Nat {
+        let mut unminted: HashMap<MessageHash, Nat> = self
+            .stuck_mints
+            .borrow()
+            .values()
+            .filter(|mint| mint.token == token)
+            .map(|mint| (mint.msg_hash.clone(), mint.amount.clone()))
+            .collect();
+
+        for operation in self.held_operations.borrow().values() {
+            if let HeldAction::Mint(mint) = &operation.action {
+                if mint.token == token {
+                    unminted.insert(mint.msg_hash.clone(), mint.amount.clone());
+                }
+            }
+        }
+
+        unminted
+            .into_values()
+            .fold(Nat::from(0_u32), |total, amount| total + amount)
+    }
+
+    /// Compare the L1 balance of token against its IC supply and what is in flight,
+    /// the report is kept in the history
+    pub fn record_reserve_report(
+        &self,
+        token: TokendId,
+        reporter: Principal,
+        l1_balance: Nat,
+        ic_supply: Nat,
+        now: u64,
+    ) -> ReserveReport {
+        let pending_claimables = self.pending_claimable_total(token);
+        let unsent_burns = self.unsent_burn_total(token);
+        let unminted = self.unminted_total(token);
+
+        let owed = ic_supply.clone()
+            + pending_claimables.clone()
+            + unsent_burns.clone()
+            + unminted.clone();
+        let (surplus, deficit) = if l1_balance >= owed {
+            (l1_balance.clone() - owed, Nat::from(0_u32))
+        } else {
+            (Nat::from(0_u32), owed - l1_balance.clone())
+        };
+
+        let mut reserve_reports = self.reserve_reports.borrow_mut();
+        let report = ReserveReport {
+            index: reserve_reports.last().map_or(0, |report| report.index + 1),
+            token,
+            reporter,
+            l1_balance,
+            ic_supply,
+            pending_claimables,
+            unsent_burns,
+            unminted,
+            balanced: surplus == Nat::from(0_u32) && deficit == Nat::from(0_u32),
+            surplus,
+            deficit,
+            time: now,
+        };
+        reserve_reports.push(report.clone());
+
+        // the history is bounded, drop the oldest reports
+        if reserve_reports.len() > MAX_RESERVE_REPORTS {
+            let overflow = reserve_reports.len() - MAX_RESERVE_REPORTS;
+            reserve_reports.drain(..overflow);
+        }
+
+        report
+    }
+
+    pub fn get_reserve_reports(&self, start: u64, limit: usize) -> Vec<ReserveReport> {
+        self.reserve_reports
+            .borrow()
+            .iter()
+            .filter(|report| report.index >= start)
+            .take(limit.min(MAX_RESERVE_REPORT_PAGE))
+            .cloned()
+            .collect()
+    }
+
     pub fn take_all(&self) -> StableProxyState {
         StableProxyState {
             balances: self.balances.take(),
             controllers: self.controllers.take(),
             incoming_messages: self.incoming_messages.take(),
-            messages_unclaimed: self.messages_unclaimed.take(),
+            claimables: self.claimables.take(),
+            audit_log: self.audit_log.take(),
+            audit_index: self.audit_index.take(),
+            governance: self.governance.take(),
+            proposals: self.proposals.take(),
+            proposal_index: self.proposal_index.take(),
+            paused: self.paused.take(),
+            config: self.config.take(),
+            burn_operations: self.burn_operations.take(),
+            burn_operation_index: self.burn_operation_index.take(),
+            compensation_policy: self.compensation_policy.take(),
+            compensations: self.compensations.take(),
+            stuck_mints: self.stuck_mints.take(),
+            global_limit: self.global_limit.take(),
+            token_limits: self.token_limits.take(),
+            volume: self.volume.take(),
+            held_operations: self.held_operations.take(),
+            held_operation_index: self.held_operation_index.take(),
+            fees: self.fees.take(),
+            treasury: self.treasury.take(),
+            collected_fees: self.collected_fees.take(),
+            reserve_reports: self.reserve_reports.take(),
+            reporter: self.reporter.take(),
         }
     }
 
@@ -147,7 +1042,33 @@ impl ProxyState {
         self.balances.borrow_mut().clear();
         self.controllers.borrow_mut().clear();
         self.incoming_messages.borrow_mut().clear();
-        self.messages_unclaimed.borrow_mut().clear();
+        self.claimables.borrow_mut().clear();
+        self.claimables_by_owner.borrow_mut().clear();
+        self.claimables_by_sender.borrow_mut().clear();
+        self.audit_log.borrow_mut().clear();
+        self.audit_index.replace(0);
+        self.governance.replace(GovernanceConfig::default());
+        self.proposals.borrow_mut().clear();
+        self.proposal_index.replace(0);
+        self.paused.replace(false);
+        self.config.replace(ProxyConfig::default());
+        self.burn_operations.borrow_mut().clear();
+        self.burn_operation_index.replace(0);
+        self.compensation_policy
+            .replace(CompensationPolicy::default());
+        self.compensations.borrow_mut().clear();
+        self.stuck_mints.borrow_mut().clear();
+        self.global_limit.replace(VolumeLimit::default());
+        self.token_limits.borrow_mut().clear();
+        self.volume.borrow_mut().clear();
+        self.held_operations.borrow_mut().clear();
+        self.held_operation_index.replace(0);
+        self.fees.borrow_mut().clear();
+        self.treasury.replace(None);
+        self.collected_fees.borrow_mut().clear();
+        self.fee_withdrawals.borrow_mut().clear();
+        self.reserve_reports.borrow_mut().clear();
+        self.reporter.replace(None);
     }
 
     pub fn replace_all(&self, stable_message_state: StableProxyState) {
@@ -155,8 +1076,39 @@ impl ProxyState {
         self.controllers.replace(stable_message_state.controllers);
         self.incoming_messages
             .replace(stable_message_state.incoming_messages);
-        self.messages_unclaimed
-            .replace(stable_message_state.messages_unclaimed);
+        self.claimables.replace(stable_message_state.claimables);
+        self.reindex_claimables();
+        self.audit_log.replace(stable_message_state.audit_log);
+        self.audit_index.replace(stable_message_state.audit_index);
+        self.governance.replace(stable_message_state.governance);
+        self.proposals.replace(stable_message_state.proposals);
+        self.proposal_index
+            .replace(stable_message_state.proposal_index);
+        self.paused.replace(stable_message_state.paused);
+        self.config.replace(stable_message_state.config);
+        self.burn_operations
+            .replace(stable_message_state.burn_operations);
+        self.burn_operation_index
+            .replace(stable_message_state.burn_operation_index);
+        self.compensation_policy
+            .replace(stable_message_state.compensation_policy);
+        self.compensations
+            .replace(stable_message_state.compensations);
+        self.stuck_mints.replace(stable_message_state.stuck_mints);
+        self.global_limit.replace(stable_message_state.global_limit);
+        self.token_limits.replace(stable_message_state.token_limits);
+        self.volume.replace(stable_message_state.volume);
+        self.held_operations
+            .replace(stable_message_state.held_operations);
+        self.held_operation_index
+            .replace(stable_message_state.held_operation_index);
+        self.fees.replace(stable_message_state.fees);
+        self.treasury.replace(stable_message_state.treasury);
+        self.collected_fees
+            .replace(stable_message_state.collected_fees);
+        self.reserve_reports
+            .replace(stable_message_state.reserve_reports);
+        self.reporter.replace(stable_message_state.reporter);
     }
 }
 
@@ -202,7 +1154,7 @@ mod tests {
     };
 
     use super::*;
-    use ic_kit::mock_principals;
+    use ic_kit::{mock_principals, MockContext};
 
     #[test]
     fn test_message_status_new_message() {
@@ -272,7 +1224,7 @@ mod tests {
         let pid = mock_principals::bob();
         let token_id = mock_principals::alice();
 
-        STATE.with(|s| s.add_balance(pid, token_id, amount.clone()));
+        STATE.with(|s| s.credit_escrow(pid, token_id, amount.clone()).unwrap());
 
         let balance_of = STATE.with(|s| s.get_balance(pid, token_id));
         let balance = balance_of.unwrap();
@@ -287,8 +1239,8 @@ mod tests {
         let token_id_1 = mock_principals::alice();
         let token_id_2 = mock_principals::john();
 
-        STATE.with(|s| s.add_balance(caller, token_id_1, amount.clone()));
-        STATE.with(|s| s.add_balance(caller, token_id_2, amount.clone()));
+        STATE.with(|s| s.credit_escrow(caller, token_id_1, amount.clone()).unwrap());
+        STATE.with(|s| s.credit_escrow(caller, token_id_2, amount.clone()).unwrap());
 
         let balances = STATE.with(|s| s.get_all_balances(caller));
 
@@ -300,24 +1252,60 @@ mod tests {
     }
 
     #[test]
-    fn test_update_balance() {
+    fn test_debit_escrow() {
         let amount = Nat::from(100_u32);
         let caller = mock_principals::bob();
-        let token_id = mock_principals::alice();
+        let token_id_1 = mock_principals::alice();
+        let token_id_2 = mock_principals::john();
 
-        STATE.with(|s| s.add_balance(caller, token_id, amount.clone()));
+        STATE.with(|s| s.credit_escrow(caller, token_id_1, amount.clone()).unwrap());
+        STATE.with(|s| s.credit_escrow(caller, token_id_2, amount.clone()).unwrap());
 
-        let balance_of = STATE.with(|s| s.get_balance(caller, token_id));
-        let balance = balance_of.unwrap();
+        let remaining = STATE.with(|s| s.debit_escrow(caller, token_id_1, Nat::from(40_u32)));
+        assert_eq!(remaining, Ok(Nat::from(60_u32)));
 
-        assert_eq!(balance, amount.clone());
+        // other tokens of the same owner are untouched
+        let balance = STATE.with(|s| s.get_balance(caller, token_id_2));
+        assert_eq!(balance, Some(amount.clone()));
+
+        let overdraft = STATE.with(|s| s.debit_escrow(caller, token_id_1, amount.clone()));
+        assert_eq!(
+            overdraft,
+            Err(EscrowError::InsufficientEscrow {
+                available: Nat::from(60_u32),
+                requested: amount.clone(),
+            })
+        );
+
+        let zero = STATE.with(|s| s.debit_escrow(caller, token_id_1, Nat::from(0_u32)));
+        assert_eq!(zero, Err(EscrowError::ZeroAmount));
+    }
+
+    #[test]
+    fn test_get_escrows() {
+        let amount = Nat::from(100_u32);
+        let token_id = mock_principals::alice();
 
-        let new_balance = Nat::from(134_u32);
-        STATE.with(|s| s.update_balance(caller, token_id, new_balance.clone()));
+        STATE.with(|s| {
+            s.credit_escrow(mock_principals::bob(), token_id, amount.clone())
+                .unwrap()
+        });
+        STATE.with(|s| {
+            s.credit_escrow(mock_principals::john(), token_id, amount.clone())
+                .unwrap()
+        });
+        STATE.with(|s| {
+            s.debit_escrow(mock_principals::john(), token_id, amount.clone())
+                .unwrap()
+        });
 
-        let balance_after_update = STATE.with(|s| s.get_balance(caller, token_id));
+        let escrows = STATE.with(|s| s.get_escrows(0, 10));
 
-        assert_eq!(balance_after_update.unwrap(), new_balance);
+        // emptied escrows are dropped
+        assert_eq!(escrows.len(), 1);
+        assert_eq!(escrows[0].owner, mock_principals::bob());
+        assert_eq!(STATE.with(|s| s.escrow_total(token_id)), amount);
+        assert!(STATE.with(|s| s.get_escrows(1, 10)).is_empty());
     }
 
     #[test]
@@ -416,121 +1404,663 @@ mod tests {
         );
     }
 
+    fn claimable(owner: EthereumAddr, msg_key: MsgHashKey, created_at: u64) -> ClaimableMessage {
+        ClaimableMessage::pending(
+            owner,
+            mock_principals::bob(),
+            &OutgoingMessage {
+                msg_key,
+                msg_hash: String::from("123123123"),
+            },
+            mock_principals::alice(),
+            Nat::from(1_u64),
+            created_at,
+        )
+    }
+
     #[test]
     fn test_claimable_messages() {
-        const WETH_ADDRESS_ETH: &str = "2e130e57021bb4dfb95eb4dd0dd8cfceb936148a";
-
         let eth_addr_1 = Principal::from_slice(
             &hex::decode("15B661f6D3FD9A7ED8Ed4c88bCcfD1546644443f").unwrap(),
         );
         let msg_key_1: [u8; 32] = [0; 32];
-
-        let msg_hash_1 = String::from("123123123");
         let msg_key_2: [u8; 32] = [1; 32];
-        let token_id_1 = Principal::from_slice(&hex::decode(WETH_ADDRESS_ETH).unwrap());
-        let amount_1 = Nat::from(1_u64);
-
-        // first msg
-        let message_1 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_1.clone(),
-            token: token_id_1.clone(),
-            amount: amount_1.clone(),
-        };
-
-        // second msg -> identical to first msg but with different msg_key
-        let message_2 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_2.clone(),
-            token: token_id_1.clone(),
-            amount: amount_1.clone(),
-        };
 
-        // add first msg
-        STATE.with(|s| s.add_claimable_message(message_1));
-        // add second msg
-        STATE.with(|s| s.add_claimable_message(message_2));
+        // two identical withdrawals only differ in msg_key
+        STATE.with(|s| {
+            s.add_claimable_message(claimable(eth_addr_1, msg_key_1, 1));
+            s.add_claimable_message(claimable(eth_addr_1, msg_key_2, 2));
+        });
 
-        // check if both messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
+        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1));
         assert_eq!(claimable_messages.len(), 2);
 
-        // remove one msg (both have same amount and token)
-        let result_remove_1 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), amount_1.clone());
-        });
-        assert_eq!(result_remove_1, ());
+        // the second message is claimed, the first one stays pending
+        assert!(STATE
+            .with(|s| s.mark_claimed(msg_key_2, String::from("0xabc"), 3))
+            .is_ok());
+        assert!(STATE
+            .with(|s| s.mark_claimed(msg_key_2, String::from("0xabc"), 3))
+            .is_err());
 
-        // check if only one message is in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
+        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1));
         assert_eq!(claimable_messages.len(), 1);
+        assert_eq!(claimable_messages[0].msg_key, msg_key_1);
+
+        assert!(STATE.with(|s| s.expire_claimable(msg_key_1, 4)).is_ok());
+        assert!(STATE.with(|s| s.expire_claimable(msg_key_2, 4)).is_err());
+        assert!(STATE
+            .with(|s| s.get_claimable_messages(eth_addr_1))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_claimables_paged() {
+        let eth_addr_1 = Principal::from_slice(&[1; 20]);
+        let eth_addr_2 = Principal::from_slice(&[2; 20]);
 
-        // remove second msg (both have same amount and token)
-        let result_remove_2 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), amount_1.clone());
+        STATE.with(|s| {
+            for i in 0..MAX_CLAIMABLE_PAGE as u8 + 1 {
+                s.add_claimable_message(claimable(eth_addr_1, [i; 32], i as u64));
+            }
+            s.add_claimable_message(claimable(eth_addr_2, [255; 32], 0));
         });
-        assert_eq!(result_remove_2, ());
 
-        // check if no messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 0);
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_1, 0, usize::MAX));
+        assert_eq!(page.len(), MAX_CLAIMABLE_PAGE);
+        assert_eq!(page[1].msg_key, [1; 32]);
+
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_1, 100, 10));
+        assert_eq!(page.len(), 1);
+
+        let page = STATE.with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10));
+        assert_eq!(page.len(), 10);
+        assert_eq!(page[0].msg_key, [0; 32]);
+        assert!(STATE
+            .with(|s| s.get_claimables_by_sender(mock_principals::alice(), 0, 10))
+            .is_empty());
     }
 
     #[test]
-    fn test_claimable_messages_with_different_amounts() {
-        const WETH_ADDRESS_ETH: &str = "2e130e57021bb4dfb95eb4dd0dd8cfceb936148a";
+    fn test_claimables_index() {
+        let eth_addr_1 = Principal::from_slice(&[1; 20]);
+        let eth_addr_2 = Principal::from_slice(&[2; 20]);
 
-        let eth_addr_1 = Principal::from_slice(
-            &hex::decode("15B661f6D3FD9A7ED8Ed4c88bCcfD1546644443f").unwrap(),
+        // a message stored again under its msg_key moves to its new owner
+        STATE.with(|s| {
+            s.add_claimable_message(claimable(eth_addr_1, [0; 32], 0));
+            s.add_claimable_message(claimable(eth_addr_2, [0; 32], 1));
+        });
+        assert!(STATE
+            .with(|s| s.get_claimables_by_eth_address(eth_addr_1, 0, 10))
+            .is_empty());
+        assert_eq!(
+            STATE
+                .with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10))
+                .len(),
+            1
         );
-        let msg_key_1: [u8; 32] = [0; 32];
-        let msg_hash_1 = String::from("123123123");
-        let weth_principal = Principal::from_slice(&hex::decode(WETH_ADDRESS_ETH).unwrap());
-        let amount_1 = Nat::from(1_u64);
 
-        let msg_key_2: [u8; 32] = [1; 32];
-        let amount_2 = Nat::from(2_u64);
-
-        // first msg
-        let message_1 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_1.clone(),
-            token: weth_principal.clone(),
-            amount: amount_1.clone(),
+        // the indexes are rebuilt on upgrade
+        STATE.with(|s| {
+            let stable_state = s.take_all();
+            s.clear_all();
+            s.replace_all(stable_state);
+        });
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_2, 0, 10));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].created_at, 1);
+        assert_eq!(
+            STATE
+                .with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_claim_expired() {
+        let eth_addr = Principal::from_slice(&[1; 20]);
+        STATE.with(|s| s.add_claimable_message(claimable(eth_addr, [0; 32], 0)));
+
+        assert_eq!(
+            STATE.with(|s| s.mark_claimed([1; 32], String::from("0xabc"), 1)),
+            Err(String::from("Message not found"))
+        );
+
+        // an expired message can still be claimed, a claimed one cannot expire
+        assert!(STATE.with(|s| s.expire_claimable([0; 32], 1)).is_ok());
+        assert!(STATE
+            .with(|s| s.mark_claimed([0; 32], String::from("0xabc"), 2))
+            .is_ok());
+        assert!(STATE.with(|s| s.expire_claimable([0; 32], 3)).is_err());
+
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr, 0, 10));
+        assert_eq!(page[0].status, ClaimStatus::Claimed(String::from("0xabc")));
+        assert_eq!(page[0].updated_at, 2);
+    }
+
+    #[test]
+    fn test_record_audit() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        STATE.with(|s| {
+            s.record_audit(
+                "remove_claimable",
+                String::from("01"),
+                AuditOutcome::Err(String::from("Message not found")),
+            )
+        });
+
+        let audit_log = STATE.with(|s| s.get_audit_log(0, 10));
+
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].caller, mock_principals::alice());
+        assert_eq!(audit_log[0].method, "authorize");
+        assert_eq!(audit_log[1].index, 1);
+        assert_eq!(
+            audit_log[1].outcome,
+            AuditOutcome::Err(String::from("Message not found"))
+        );
+
+        let page = STATE.with(|s| s.get_audit_log(1, 10));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].method, "remove_claimable");
+    }
+
+    #[test]
+    fn test_audit_log_bounded() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        for _ in 0..MAX_AUDIT_ENTRIES + 5 {
+            STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        }
+
+        let audit_log = STATE.with(|s| s.audit_log.borrow().clone());
+
+        assert_eq!(audit_log.len(), MAX_AUDIT_ENTRIES);
+        // oldest entries were dropped
+        assert_eq!(audit_log[0].index, 5);
+        assert_eq!(
+            STATE.with(|s| s.get_audit_log(0, usize::MAX)).len(),
+            MAX_AUDIT_PAGE
+        );
+    }
+
+    fn governance_state(threshold: u32, delay: u64) {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.controllers
+                .borrow_mut()
+                .extend([mock_principals::alice(), mock_principals::bob()]);
+            s.governance.replace(GovernanceConfig {
+                threshold,
+                delay,
+                ..GovernanceConfig::default()
+            });
+        });
+    }
+
+    #[test]
+    fn test_proposal_threshold() {
+        governance_state(2, 0);
+        let action = ProposalAction::Authorize(mock_principals::john());
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action.clone(), 0));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+        // approving twice does not count twice
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::alice(), 0))
+            .is_err());
+
+        STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), 0))
+            .unwrap();
+        let proposal = STATE.with(|s| s.take_ready_proposal(id, 0)).unwrap();
+
+        assert_eq!(proposal.action, action);
+        assert!(STATE.with(|s| s.get_pending_proposals(0)).is_empty());
+    }
+
+    #[test]
+    fn test_proposal_delay() {
+        governance_state(1, 100);
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 10));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 109)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 110)).is_ok());
+    }
+
+    #[test]
+    fn test_proposal_expiry() {
+        governance_state(2, 0);
+        let ttl = GovernanceConfig::default().ttl;
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 0));
+
+        assert_eq!(STATE.with(|s| s.get_pending_proposals(ttl)).len(), 1);
+        assert!(STATE.with(|s| s.get_pending_proposals(ttl + 1)).is_empty());
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), ttl + 1))
+            .is_err());
+        assert!(STATE.with(|s| s.proposals.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        governance_state(2, 0);
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 0));
+
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_ok());
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+    }
+
+    #[test]
+    fn test_burn_operation_steps() {
+        let owner = mock_principals::bob();
+        let token_id = mock_principals::alice();
+        let amount = Nat::from(100_u32);
+
+        let op_id = STATE.with(|s| {
+            s.create_burn_operation(owner, owner, token_id, amount, BurnSource::Wallet, 0)
+        });
+        STATE
+            .with(|s| s.update_burn_step(op_id, BurnStep::Burned, 10))
+            .unwrap();
+        STATE.with(|s| s.set_burn_txn_id(op_id, Nat::from(7_u32)));
+
+        let operations = STATE.with(|s| s.get_burn_operations(owner));
+
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].step, BurnStep::Burned);
+        assert_eq!(operations[0].burn_txn_id, Some(Nat::from(7_u32)));
+        assert_eq!(operations[0].updated_at, 10);
+        assert!(STATE
+            .with(|s| s.get_burn_operations(mock_principals::john()))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unburned_total() {
+        let owner = mock_principals::bob();
+        let token_id = mock_principals::alice();
+
+        let held = STATE.with(|s| {
+            s.create_burn_operation(
+                owner,
+                owner,
+                token_id,
+                100_u32.into(),
+                BurnSource::Wallet,
+                0,
+            )
+        });
+        let failed = STATE.with(|s| {
+            s.create_burn_operation(owner, owner, token_id, 20_u32.into(), BurnSource::Wallet, 0)
+        });
+        let burned = STATE.with(|s| {
+            s.create_burn_operation(owner, owner, token_id, 3_u32.into(), BurnSource::Wallet, 0)
+        });
+        STATE.with(|s| {
+            s.update_burn_step(held, BurnStep::Held, 0).unwrap();
+            let step = BurnStep::Failed(Box::new(BurnStep::Escrowed), String::from("error"));
+            s.update_burn_step(failed, step, 0).unwrap();
+            let step = BurnStep::Failed(Box::new(BurnStep::Burned), String::from("error"));
+            s.update_burn_step(burned, step, 0).unwrap();
+        });
+
+        assert_eq!(
+            STATE.with(|s| s.unburned_total(token_id)),
+            Nat::from(120_u32)
+        );
+        assert_eq!(STATE.with(|s| s.unburned_total(owner)), Nat::from(0_u32));
+    }
+
+    #[test]
+    fn test_burn_resumable() {
+        let owner = mock_principals::bob();
+        let token_id = mock_principals::alice();
+        let amount = Nat::from(100_u32);
+
+        let op_id = STATE.with(|s| {
+            s.create_burn_operation(owner, owner, token_id, amount, BurnSource::Wallet, 0)
+        });
+
+        // the burn call is in flight
+        STATE
+            .with(|s| s.update_burn_step(op_id, BurnStep::Burning, 0))
+            .unwrap();
+        assert!(STATE.with(|s| s.burn_resumable(op_id, 1, true)).is_err());
+        // stuck past the timeout, only an admin can take it over
+        assert!(STATE
+            .with(|s| s.burn_resumable(op_id, BURN_RESUME_TIMEOUT, false))
+            .is_err());
+        assert!(STATE
+            .with(|s| s.burn_resumable(op_id, BURN_RESUME_TIMEOUT, true))
+            .is_ok());
+
+        let failed = BurnStep::Failed(Box::new(BurnStep::Escrowed), String::from("error"));
+        STATE
+            .with(|s| s.update_burn_step(op_id, failed, 1))
+            .unwrap();
+        assert!(STATE.with(|s| s.burn_resumable(op_id, 1, false)).is_ok());
+
+        STATE
+            .with(|s| s.update_burn_step(op_id, BurnStep::MessageSent, 2))
+            .unwrap();
+        assert!(STATE
+            .with(|s| s.burn_resumable(op_id, u64::MAX, true))
+            .is_err());
+    }
+
+    #[test]
+    fn test_burn_step_last_completed() {
+        let failed = BurnStep::Failed(Box::new(BurnStep::Burned), String::from("error"));
+
+        assert_eq!(failed.last_completed(), BurnStep::Burned);
+        assert_eq!(BurnStep::Burning.last_completed(), BurnStep::Escrowed);
+        assert_eq!(BurnStep::Sending.last_completed(), BurnStep::Burned);
+        assert_eq!(BurnStep::Refunding.last_completed(), BurnStep::Burned);
+        assert!(BurnStep::Sending.in_flight());
+        assert!(!failed.in_flight());
+    }
+
+    #[test]
+    fn test_record_compensation() {
+        MockContext::new().inject();
+
+        let owner = mock_principals::bob();
+        let token_id = mock_principals::alice();
+        let amount = Nat::from(100_u32);
+
+        for _ in 0..MAX_COMPENSATION_PAGE + 1 {
+            STATE.with(|s| {
+                s.record_compensation(
+                    owner,
+                    owner,
+                    token_id,
+                    amount.clone(),
+                    String::from("error"),
+                    CompensationOutcome::Retryable,
+                )
+            });
+        }
+
+        let compensations = STATE.with(|s| s.get_compensations(0, usize::MAX));
+
+        assert_eq!(compensations.len(), MAX_COMPENSATION_PAGE);
+        assert_eq!(compensations[1].index, 1);
+        assert_eq!(compensations[1].outcome, CompensationOutcome::Retryable);
+
+        let last = STATE.with(|s| s.get_compensations(MAX_COMPENSATION_PAGE as u64, 10));
+        assert_eq!(last.len(), 1);
+    }
+
+    #[test]
+    fn test_stuck_mints() {
+        MockContext::new().inject();
+
+        let stuck_mint = |msg_hash: &str, stuck_since: u64| StuckMint {
+            msg_hash: String::from(msg_hash),
+            nonce: Nat::from(4_u32),
+            payload: vec![],
+            token: mock_principals::alice(),
+            beneficiary: mock_principals::bob(),
+            amount: Nat::from(100_u32),
+            stuck_since,
         };
 
-        // second msg -> same token, different amount
-        let message_2 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_2.clone(),
-            token: weth_principal.clone(),
-            amount: amount_2.clone(),
+        STATE.with(|s| {
+            s.store_incoming_message(String::from("a"));
+            s.store_stuck_mint(stuck_mint("a", 20));
+            s.store_stuck_mint(stuck_mint("b", 10));
+            // a failed retry keeps the first failure time
+            s.store_stuck_mint(stuck_mint("a", 30));
+        });
+
+        let stuck_messages = STATE.with(|s| s.get_stuck_messages(50));
+        assert_eq!(stuck_messages.len(), 2);
+        assert_eq!(stuck_messages[0].mint.msg_hash, "b");
+        assert_eq!(stuck_messages[0].age, 40);
+        assert_eq!(stuck_messages[1].age, 30);
+
+        STATE.with(|s| s.remove_incoming_message(String::from("a")));
+        assert!(STATE
+            .with(|s| s.get_stuck_mint(&String::from("a")))
+            .is_none());
+    }
+
+    #[test]
+    fn test_reserve_volume() {
+        MockContext::new().inject();
+
+        let token_id = mock_principals::alice();
+        let other_token_id = mock_principals::bob();
+
+        STATE.with(|s| {
+            s.set_volume_limit(
+                Some(token_id),
+                VolumeLimit {
+                    per_transaction: Some(Nat::from(50_u32)),
+                    daily_mint: Some(Nat::from(100_u32)),
+                    daily_burn: None,
+                },
+            );
+            s.set_volume_limit(
+                None,
+                VolumeLimit {
+                    per_transaction: None,
+                    daily_mint: Some(Nat::from(120_u32)),
+                    daily_burn: None,
+                },
+            );
+        });
+
+        let reserve = |token: TokendId, amount: u32, now: u64| {
+            STATE.with(|s| s.reserve_volume(token, Flow::Mint, Nat::from(amount), now))
         };
 
-        // add first msg
-        STATE.with(|s| s.add_claimable_message(message_1));
-        // add second msg
-        STATE.with(|s| s.add_claimable_message(message_2));
+        assert!(reserve(token_id, 60, 0).is_err());
+        assert!(reserve(token_id, 50, 0).is_ok());
+        assert!(reserve(token_id, 50, 10).is_ok());
+        // token cap reached
+        assert!(reserve(token_id, 10, 20).is_err());
+        // global cap reached
+        assert!(reserve(other_token_id, 30, 20).is_err());
+        assert!(reserve(other_token_id, 20, 20).is_ok());
+        // burns are not capped
+        assert!(STATE
+            .with(|s| s.reserve_volume(token_id, Flow::Burn, Nat::from(50_u32), 20))
+            .is_ok());
+
+        // the first mint left the window
+        assert!(reserve(token_id, 50, VOLUME_WINDOW).is_ok());
+
+        let entry = reserve(other_token_id, 0, VOLUME_WINDOW).unwrap();
+        STATE.with(|s| s.release_volume(&entry));
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(None, Flow::Mint)),
+            Nat::from(120_u32)
+        );
+    }
 
-        // check if both messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 2);
+    #[test]
+    fn test_held_operations() {
+        MockContext::new().inject();
+
+        let id = STATE.with(|s| {
+            s.hold_operation(
+                mock_principals::bob(),
+                mock_principals::alice(),
+                Nat::from(100_u32),
+                Nat::from(0_u32),
+                HeldAction::Burn(0),
+                String::from("limit"),
+                0,
+            )
+        });
+
+        assert_eq!(STATE.with(|s| s.get_held_operations()).len(), 1);
+        assert!(STATE.with(|s| s.take_held_operation(id)).is_ok());
+        assert!(STATE.with(|s| s.take_held_operation(id)).is_err());
+    }
+
+    #[test]
+    fn test_fees() {
+        MockContext::new().inject();
 
-        // remove one msg -> the one with amount_2 (both are the same token, but different amount)
-        let result_remove_1 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), amount_2.clone());
+        let token_id = mock_principals::alice();
+        STATE.with(|s| {
+            s.set_fee(
+                token_id,
+                FeeConfig {
+                    flat: Nat::from(10_u32),
+                    bps: 50,
+                    on_mint: false,
+                },
+            )
         });
-        assert_eq!(result_remove_1, ());
 
-        // check if only one message is in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 1);
+        let fee_for = |token: TokendId, flow: Flow, amount: u32| {
+            STATE.with(|s| s.fee_for(token, flow, &Nat::from(amount)))
+        };
+
+        assert_eq!(fee_for(token_id, Flow::Burn, 10_000), Nat::from(60_u32));
+        assert_eq!(fee_for(token_id, Flow::Burn, 5), Nat::from(5_u32));
+        assert_eq!(fee_for(token_id, Flow::Mint, 10_000), Nat::from(0_u32));
+        assert_eq!(
+            fee_for(mock_principals::bob(), Flow::Burn, 10_000),
+            Nat::from(0_u32)
+        );
+
+        STATE.with(|s| {
+            s.collect_fee(token_id, Nat::from(60_u32));
+            s.collect_fee(token_id, Nat::from(5_u32));
+        });
+        assert_eq!(STATE.with(|s| s.collected_fee(token_id)), Nat::from(65_u32));
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_ok());
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_err());
+
+        // a fee collected while the withdrawal is in flight stays collected
+        STATE.with(|s| {
+            s.collect_fee(token_id, Nat::from(10_u32));
+            s.finish_fee_withdrawal(token_id, Some(&Nat::from(65_u32)));
+        });
+        assert_eq!(STATE.with(|s| s.collected_fee(token_id)), Nat::from(10_u32));
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_ok());
+    }
+
+    #[test]
+    fn test_reserve_report() {
+        MockContext::new().inject();
+
+        let token_id = mock_principals::alice();
+        let eth_addr = Principal::from_slice(&[1; 20]);
+
+        STATE.with(|s| {
+            // 1 burned and pending on L1, 1 expired and still claimable,
+            // 100 burned without a message
+            s.add_claimable_message(claimable(eth_addr, [0; 32], 0));
+            s.add_claimable_message(claimable(eth_addr, [1; 32], 0));
+            s.expire_claimable([1; 32], 0).unwrap();
+            let op_id = s.create_burn_operation(
+                mock_principals::bob(),
+                eth_addr,
+                token_id,
+                Nat::from(100_u32),
+                BurnSource::Wallet,
+                0,
+            );
+            s.update_burn_step(op_id, BurnStep::Burned, 0).unwrap();
+
+            // 10 consumed from L1, stuck and then held on retry
+            let stuck_mint = StuckMint {
+                msg_hash: String::from("a"),
+                nonce: Nat::from(4_u32),
+                payload: vec![],
+                token: token_id,
+                beneficiary: mock_principals::bob(),
+                amount: Nat::from(10_u32),
+                stuck_since: 0,
+            };
+            s.store_stuck_mint(stuck_mint.clone());
+            s.hold_operation(
+                mock_principals::bob(),
+                token_id,
+                Nat::from(10_u32),
+                Nat::from(0_u32),
+                HeldAction::Mint(stuck_mint),
+                String::from("limit"),
+                0,
+            );
+        });
+
+        let report = STATE.with(|s| {
+            s.record_reserve_report(
+                token_id,
+                mock_principals::john(),
+                Nat::from(1_112_u32),
+                Nat::from(1_000_u32),
+                5,
+            )
+        });
+        assert_eq!(report.pending_claimables, Nat::from(2_u32));
+        assert_eq!(report.unsent_burns, Nat::from(100_u32));
+        assert_eq!(report.unminted, Nat::from(10_u32));
+        assert!(report.balanced);
+
+        let report = STATE.with(|s| {
+            s.record_reserve_report(
+                token_id,
+                mock_principals::john(),
+                Nat::from(1_000_u32),
+                Nat::from(1_000_u32),
+                6,
+            )
+        });
+        assert_eq!(report.deficit, Nat::from(112_u32));
+        assert_eq!(report.surplus, Nat::from(0_u32));
+        assert!(!report.balanced);
+
+        let reports = STATE.with(|s| s.get_reserve_reports(1, 10));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].index, 1);
+    }
+
+    #[test]
+    fn test_reserve_reports_bounded() {
+        MockContext::new().inject();
+
+        for time in 0..MAX_RESERVE_REPORTS as u64 + 5 {
+            STATE.with(|s| {
+                s.record_reserve_report(
+                    mock_principals::alice(),
+                    mock_principals::john(),
+                    Nat::from(0_u32),
+                    Nat::from(0_u32),
+                    time,
+                )
+            });
+        }
 
-        // the message that is left is the one with amount_1
-        assert_eq!(claimable_messages[0].amount, amount_1);
+        let reserve_reports = STATE.with(|s| s.reserve_reports.borrow().clone());
+        assert_eq!(reserve_reports.len(), MAX_RESERVE_REPORTS);
+        // oldest reports were dropped, indexes keep counting
+        assert_eq!(reserve_reports[0].index, 5);
+        let page = STATE.with(|s| s.get_reserve_reports(MAX_RESERVE_REPORTS as u64, 10));
+        assert_eq!(page.len(), 5);
+        assert_eq!(page[0].index, MAX_RESERVE_REPORTS as u64);
     }
 }
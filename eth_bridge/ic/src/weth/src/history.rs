@@ -0,0 +1,87 @@
+/**
+* Module     : history.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+// where the outbox sends transaction events, cap unless init picks the memory
+// sink, which lets the token run without a cap canister, e.g. in cargo test
+use candid::{CandidType, Deserialize};
+use cap_sdk::{insert, IndefiniteEvent};
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type SinkResult = Pin<Box<dyn Future<Output = Result<u64, String>>>>;
+
+pub trait HistorySink {
+    // resolves to the id the sink gave the event
+    fn insert(&self, event: IndefiniteEvent) -> SinkResult;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum HistorySinkKind {
+    Cap,
+    Memory,
+}
+
+impl HistorySinkKind {
+    pub fn sink(self) -> Box<dyn HistorySink> {
+        match self {
+            HistorySinkKind::Cap => Box::new(CapSink),
+            HistorySinkKind::Memory => Box::new(MemorySink),
+        }
+    }
+}
+
+pub struct CapSink;
+
+impl HistorySink for CapSink {
+    fn insert(&self, event: IndefiniteEvent) -> SinkResult {
+        Box::pin(async move {
+            insert(event)
+                .await
+                .map_err(|error| format!("Inserting into cap failed with error: {:?}", error))
+        })
+    }
+}
+
+// events are not kept across upgrades
+pub struct MemorySink;
+
+thread_local! {
+    static MEMORY_EVENTS: RefCell<Vec<IndefiniteEvent>> = RefCell::new(Vec::default());
+    // inserts left to fail, set by tests to exercise the outbox retries
+    static MEMORY_FAILURES: RefCell<u32> = RefCell::new(0);
+}
+
+impl HistorySink for MemorySink {
+    fn insert(&self, event: IndefiniteEvent) -> SinkResult {
+        let failing = MEMORY_FAILURES.with(|f| {
+            let mut failures = f.borrow_mut();
+            let failing = *failures > 0;
+            *failures = failures.saturating_sub(1);
+            failing
+        });
+        if failing {
+            return Box::pin(async { Err("Inserting into memory failed".to_string()) });
+        }
+        let id = MEMORY_EVENTS.with(|m| {
+            let mut events = m.borrow_mut();
+            events.push(event);
+            events.len() as u64 - 1
+        });
+        Box::pin(async move { Ok(id) })
+    }
+}
+
+#[cfg(test)]
+pub fn memory_events() -> Vec<IndefiniteEvent> {
+    MEMORY_EVENTS.with(|m| m.borrow().clone())
+}
+
+#[cfg(test)]
+pub fn fail_memory_inserts(count: u32) {
+    MEMORY_FAILURES.with(|f| f.replace(count));
+}
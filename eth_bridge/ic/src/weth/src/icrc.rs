@@ -0,0 +1,1053 @@
+/**
+* Module     : icrc.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+// ICRC-1 and ICRC-2 on the same ledger as the DIP20 interface, the default
+// subaccount of a principal is its DIP20 balance and allowance, other
+// subaccounts are only reachable through the icrc methods
+use crate::{
+    _balance_ins, _balance_rem, _flush_cap_outbox, _get_fee, _history_inc, _log_record, balance_of,
+    decimals, logo, name, symbol, total_supply, ALLOWS, ICRC, STATS,
+};
+use candid::{candid_method, CandidType, Deserialize, Int, Nat};
+use cap_std::dip20::{Operation, TransactionStatus};
+use ic_cdk_macros::*;
+use ic_kit::{ic, Principal};
+use std::collections::{BTreeSet, HashMap};
+
+pub type Subaccount = Vec<u8>;
+
+// transactions with a created_at_time are deduplicated within this window
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+// how far ahead of the ledger time a created_at_time may be
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+const MAX_MEMO_LENGTH: usize = 32;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    fn new(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+
+    // the default subaccount is always None so it maps to the DIP20 state
+    fn normalized(self) -> Result<Self, Rejection> {
+        match self.subaccount {
+            Some(subaccount) if subaccount.len() != 32 => Err(Rejection::Generic(
+                "Subaccount must be 32 bytes".to_string(),
+            )),
+            Some(subaccount) if subaccount.iter().all(|b| *b == 0) => Ok(Account::new(self.owner)),
+            subaccount => Ok(Account {
+                owner: self.owner,
+                subaccount,
+            }),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Transaction {
+    Transfer {
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Vec<u8>>,
+    },
+    Approve {
+        spender: Account,
+        amount: Nat,
+        expected_allowance: Option<Nat>,
+        expires_at: Option<u64>,
+        fee: Option<Nat>,
+        memo: Option<Vec<u8>>,
+    },
+    TransferFrom {
+        from: Account,
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Vec<u8>>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct IcrcState {
+    // balances of non default subaccounts, default ones live in BALANCES
+    pub balances: HashMap<Account, Nat>,
+    // allowances involving a non default subaccount, default ones live in ALLOWS
+    pub allowances: HashMap<(Account, Account), Nat>,
+    // expiry of allowances given through icrc2_approve
+    pub expiries: HashMap<(Account, Account), u64>,
+    // transactions by caller and created_at_time with their block index
+    pub transactions: HashMap<(Account, u64), Vec<(Transaction, Nat)>>,
+    // keys of the transactions ordered by created_at_time, oldest first
+    pub transaction_times: BTreeSet<(u64, Account)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// checks shared by the icrc updates, converted into the error of each method
+enum Rejection {
+    BadFee(Nat),
+    InsufficientFunds(Nat),
+    TooOld,
+    CreatedInFuture(u64),
+    Duplicate(Nat),
+    Generic(String),
+}
+
+impl From<Rejection> for TransferError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee(expected_fee) => TransferError::BadFee { expected_fee },
+            Rejection::InsufficientFunds(balance) => TransferError::InsufficientFunds { balance },
+            Rejection::TooOld => TransferError::TooOld,
+            Rejection::CreatedInFuture(ledger_time) => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+            Rejection::Duplicate(duplicate_of) => TransferError::Duplicate { duplicate_of },
+            Rejection::Generic(message) => TransferError::GenericError {
+                error_code: Nat::from(0),
+                message,
+            },
+        }
+    }
+}
+
+impl From<Rejection> for ApproveError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee(expected_fee) => ApproveError::BadFee { expected_fee },
+            Rejection::InsufficientFunds(balance) => ApproveError::InsufficientFunds { balance },
+            Rejection::TooOld => ApproveError::TooOld,
+            Rejection::CreatedInFuture(ledger_time) => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+            Rejection::Duplicate(duplicate_of) => ApproveError::Duplicate { duplicate_of },
+            Rejection::Generic(message) => ApproveError::GenericError {
+                error_code: Nat::from(0),
+                message,
+            },
+        }
+    }
+}
+
+impl From<Rejection> for TransferFromError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee(expected_fee) => TransferFromError::BadFee { expected_fee },
+            Rejection::InsufficientFunds(balance) => {
+                TransferFromError::InsufficientFunds { balance }
+            }
+            Rejection::TooOld => TransferFromError::TooOld,
+            Rejection::CreatedInFuture(ledger_time) => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            Rejection::Duplicate(duplicate_of) => TransferFromError::Duplicate { duplicate_of },
+            Rejection::Generic(message) => TransferFromError::GenericError {
+                error_code: Nat::from(0),
+                message,
+            },
+        }
+    }
+}
+
+/* ICRC-1 */
+
+#[query(name = "icrc1_name")]
+#[candid_method(query, rename = "icrc1_name")]
+fn icrc1_name() -> String {
+    name()
+}
+
+#[query(name = "icrc1_symbol")]
+#[candid_method(query, rename = "icrc1_symbol")]
+fn icrc1_symbol() -> String {
+    symbol()
+}
+
+#[query(name = "icrc1_decimals")]
+#[candid_method(query, rename = "icrc1_decimals")]
+fn icrc1_decimals() -> u8 {
+    decimals()
+}
+
+#[query(name = "icrc1_fee")]
+#[candid_method(query, rename = "icrc1_fee")]
+fn icrc1_fee() -> Nat {
+    _get_fee()
+}
+
+#[query(name = "icrc1_total_supply")]
+#[candid_method(query, rename = "icrc1_total_supply")]
+fn icrc1_total_supply() -> Nat {
+    total_supply()
+}
+
+// tokens are minted by the minters through the DIP20 mint
+#[query(name = "icrc1_minting_account")]
+#[candid_method(query, rename = "icrc1_minting_account")]
+fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[query(name = "icrc1_metadata")]
+#[candid_method(query, rename = "icrc1_metadata")]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(name())),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(symbol())),
+        (
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(decimals())),
+        ),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(_get_fee())),
+        ("icrc1:logo".to_string(), MetadataValue::Text(logo())),
+    ]
+}
+
+#[query(name = "icrc1_supported_standards")]
+#[candid_method(query, rename = "icrc1_supported_standards")]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[query(name = "icrc1_balance_of")]
+#[candid_method(query, rename = "icrc1_balance_of")]
+fn icrc1_balance_of(account: Account) -> Nat {
+    match account.normalized() {
+        Ok(account) => _account_balance(&account),
+        Err(_) => Nat::from(0),
+    }
+}
+
+#[update(name = "icrc1_transfer")]
+#[candid_method(update, rename = "icrc1_transfer")]
+async fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let now = ic::time();
+    let from = Account {
+        owner: ic::caller(),
+        subaccount: arg.from_subaccount,
+    }
+    .normalized()?;
+    let to = arg.to.normalized()?;
+    let fee = _check_fee(&arg.fee)?;
+    _check_memo(&arg.memo)?;
+
+    let transaction = Transaction::Transfer {
+        to: to.clone(),
+        amount: arg.amount.clone(),
+        fee: arg.fee,
+        memo: arg.memo,
+    };
+    _check_created_at_time(&from, arg.created_at_time, &transaction, now)?;
+
+    let balance = _account_balance(&from);
+    if balance < arg.amount.clone() + fee.clone() {
+        return Err(Rejection::InsufficientFunds(balance).into());
+    }
+    _charge_account_fee(&from, fee.clone());
+    _account_transfer(&from, &to, arg.amount.clone());
+
+    _history_inc();
+    let record = _log_record(
+        from.owner,
+        Operation::Transfer,
+        from.owner,
+        to.owner,
+        arg.amount,
+        fee,
+        now,
+        TransactionStatus::Succeeded,
+    );
+    let block_index = record.index.clone();
+    _remember_transaction(
+        &from,
+        arg.created_at_time,
+        transaction,
+        block_index.clone(),
+        now,
+    );
+    _flush_cap_outbox(1, false).await;
+
+    Ok(block_index)
+}
+
+/* ICRC-2 */
+
+#[update(name = "icrc2_approve")]
+#[candid_method(update, rename = "icrc2_approve")]
+async fn icrc2_approve(arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    let now = ic::time();
+    let from = Account {
+        owner: ic::caller(),
+        subaccount: arg.from_subaccount,
+    }
+    .normalized()?;
+    let spender = arg.spender.normalized()?;
+    if from.owner == spender.owner {
+        return Err(Rejection::Generic("Cannot approve the own principal".to_string()).into());
+    }
+    let fee = _check_fee(&arg.fee)?;
+    _check_memo(&arg.memo)?;
+
+    if let Some(expires_at) = arg.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+
+    let transaction = Transaction::Approve {
+        spender: spender.clone(),
+        amount: arg.amount.clone(),
+        expected_allowance: arg.expected_allowance.clone(),
+        expires_at: arg.expires_at,
+        fee: arg.fee,
+        memo: arg.memo,
+    };
+    _check_created_at_time(&from, arg.created_at_time, &transaction, now)?;
+
+    if let Some(expected_allowance) = arg.expected_allowance {
+        let current_allowance = _account_allowance(&from, &spender, now);
+        if current_allowance != expected_allowance {
+            return Err(ApproveError::AllowanceChanged { current_allowance });
+        }
+    }
+
+    let balance = _account_balance(&from);
+    if balance < fee {
+        return Err(Rejection::InsufficientFunds(balance).into());
+    }
+    _charge_account_fee(&from, fee.clone());
+    _set_account_allowance(&from, &spender, arg.amount.clone(), arg.expires_at);
+
+    _history_inc();
+    let record = _log_record(
+        from.owner,
+        Operation::Approve,
+        from.owner,
+        spender.owner,
+        arg.amount,
+        fee,
+        now,
+        TransactionStatus::Succeeded,
+    );
+    let block_index = record.index.clone();
+    _remember_transaction(
+        &from,
+        arg.created_at_time,
+        transaction,
+        block_index.clone(),
+        now,
+    );
+    _flush_cap_outbox(1, false).await;
+
+    Ok(block_index)
+}
+
+#[query(name = "icrc2_allowance")]
+#[candid_method(query, rename = "icrc2_allowance")]
+fn icrc2_allowance(arg: AllowanceArgs) -> Allowance {
+    let now = ic::time();
+    match (arg.account.normalized(), arg.spender.normalized()) {
+        (Ok(account), Ok(spender)) => Allowance {
+            allowance: _account_allowance(&account, &spender, now),
+            expires_at: ICRC.with(|i| {
+                i.borrow()
+                    .expiries
+                    .get(&(account, spender))
+                    .copied()
+                    .filter(|expires_at| *expires_at > now)
+            }),
+        },
+        _ => Allowance {
+            allowance: Nat::from(0),
+            expires_at: None,
+        },
+    }
+}
+
+#[update(name = "icrc2_transfer_from")]
+#[candid_method(update, rename = "icrc2_transfer_from")]
+async fn icrc2_transfer_from(arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let now = ic::time();
+    let spender = Account {
+        owner: ic::caller(),
+        subaccount: arg.spender_subaccount,
+    }
+    .normalized()?;
+    let from = arg.from.normalized()?;
+    let to = arg.to.normalized()?;
+    let fee = _check_fee(&arg.fee)?;
+    _check_memo(&arg.memo)?;
+
+    let transaction = Transaction::TransferFrom {
+        from: from.clone(),
+        to: to.clone(),
+        amount: arg.amount.clone(),
+        fee: arg.fee,
+        memo: arg.memo,
+    };
+    _check_created_at_time(&spender, arg.created_at_time, &transaction, now)?;
+
+    let total = arg.amount.clone() + fee.clone();
+    let allowance = _account_allowance(&from, &spender, now);
+    if allowance < total {
+        return Err(TransferFromError::InsufficientAllowance { allowance });
+    }
+    let balance = _account_balance(&from);
+    if balance < total {
+        return Err(Rejection::InsufficientFunds(balance).into());
+    }
+    _charge_account_fee(&from, fee.clone());
+    _account_transfer(&from, &to, arg.amount.clone());
+    let expires_at = ICRC.with(|i| {
+        i.borrow()
+            .expiries
+            .get(&(from.clone(), spender.clone()))
+            .copied()
+    });
+    _set_account_allowance(&from, &spender, allowance - total, expires_at);
+
+    _history_inc();
+    let record = _log_record(
+        spender.owner,
+        Operation::TransferFrom,
+        from.owner,
+        to.owner,
+        arg.amount,
+        fee,
+        now,
+        TransactionStatus::Succeeded,
+    );
+    let block_index = record.index.clone();
+    _remember_transaction(
+        &spender,
+        arg.created_at_time,
+        transaction,
+        block_index.clone(),
+        now,
+    );
+    _flush_cap_outbox(1, false).await;
+
+    Ok(block_index)
+}
+
+/* INTERNAL FNS */
+
+fn _check_fee(fee: &Option<Nat>) -> Result<Nat, Rejection> {
+    let expected_fee = _get_fee();
+    match fee {
+        Some(fee) if *fee != expected_fee => Err(Rejection::BadFee(expected_fee)),
+        _ => Ok(expected_fee),
+    }
+}
+
+fn _check_memo(memo: &Option<Vec<u8>>) -> Result<(), Rejection> {
+    match memo {
+        Some(memo) if memo.len() > MAX_MEMO_LENGTH => Err(Rejection::Generic(format!(
+            "Memo is longer than {} bytes",
+            MAX_MEMO_LENGTH
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// only transactions with a created_at_time are deduplicated
+fn _check_created_at_time(
+    caller: &Account,
+    created_at_time: Option<u64>,
+    transaction: &Transaction,
+    now: u64,
+) -> Result<(), Rejection> {
+    let created_at_time = match created_at_time {
+        Some(created_at_time) => created_at_time,
+        None => return Ok(()),
+    };
+
+    if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+        return Err(Rejection::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+        return Err(Rejection::CreatedInFuture(now));
+    }
+
+    ICRC.with(|i| {
+        let icrc = i.borrow();
+        let duplicate = icrc
+            .transactions
+            .get(&(caller.clone(), created_at_time))
+            .and_then(|transactions| {
+                transactions
+                    .iter()
+                    .find(|(recorded, _)| recorded == transaction)
+            });
+
+        match duplicate {
+            Some((_, block_index)) => Err(Rejection::Duplicate(block_index.clone())),
+            None => Ok(()),
+        }
+    })
+}
+
+// keep a transaction with a created_at_time for deduplication, the
+// transactions that left the deduplication window are dropped oldest
+// first so only the dropped ones are visited
+fn _remember_transaction(
+    caller: &Account,
+    created_at_time: Option<u64>,
+    transaction: Transaction,
+    block_index: Nat,
+    now: u64,
+) {
+    ICRC.with(|i| {
+        let mut icrc = i.borrow_mut();
+        while let Some((oldest, account)) = icrc.transaction_times.iter().next().cloned() {
+            if oldest.saturating_add(TX_WINDOW + PERMITTED_DRIFT) >= now {
+                break;
+            }
+            icrc.transaction_times.remove(&(oldest, account.clone()));
+            icrc.transactions.remove(&(account, oldest));
+        }
+        if let Some(created_at_time) = created_at_time {
+            icrc.transaction_times
+                .insert((created_at_time, caller.clone()));
+            icrc.transactions
+                .entry((caller.clone(), created_at_time))
+                .or_default()
+                .push((transaction, block_index));
+        }
+    });
+}
+
+fn _account_balance(account: &Account) -> Nat {
+    match account.subaccount {
+        None => balance_of(account.owner),
+        Some(_) => ICRC.with(|i| {
+            i.borrow()
+                .balances
+                .get(account)
+                .cloned()
+                .unwrap_or_else(|| Nat::from(0))
+        }),
+    }
+}
+
+fn _set_account_balance(account: &Account, value: Nat) {
+    match account.subaccount {
+        None if value != 0 => _balance_ins(account.owner, value),
+        None => _balance_rem(account.owner),
+        Some(_) => ICRC.with(|i| {
+            let mut icrc = i.borrow_mut();
+            if value != 0 {
+                icrc.balances.insert(account.clone(), value);
+            } else {
+                icrc.balances.remove(account);
+            }
+        }),
+    }
+}
+
+fn _account_transfer(from: &Account, to: &Account, value: Nat) {
+    let from_balance = _account_balance(from);
+    _set_account_balance(from, from_balance - value.clone());
+    let to_balance = _account_balance(to);
+    _set_account_balance(to, to_balance + value);
+}
+
+fn _charge_account_fee(account: &Account, fee: Nat) {
+    let fee_to = STATS.with(|s| s.borrow().fee_to);
+    if fee > Nat::from(0) {
+        _account_transfer(account, &Account::new(fee_to), fee);
+    }
+}
+
+// whether an allowance given through icrc2_approve has expired,
+// allowances given through the DIP20 approve never expire
+pub fn _is_expired(owner: Principal, spender: Principal, now: u64) -> bool {
+    ICRC.with(|i| {
+        i.borrow()
+            .expiries
+            .get(&(Account::new(owner), Account::new(spender)))
+            .map_or(false, |expires_at| *expires_at <= now)
+    })
+}
+
+// the DIP20 approve replaces an allowance without an expiry
+pub fn _clear_expiry(owner: Principal, spender: Principal) {
+    ICRC.with(|i| {
+        i.borrow_mut()
+            .expiries
+            .remove(&(Account::new(owner), Account::new(spender)));
+    })
+}
+
+fn _account_allowance(owner: &Account, spender: &Account, now: u64) -> Nat {
+    let expired = ICRC.with(|i| {
+        i.borrow()
+            .expiries
+            .get(&(owner.clone(), spender.clone()))
+            .map_or(false, |expires_at| *expires_at <= now)
+    });
+    if expired {
+        return Nat::from(0);
+    }
+
+    match (&owner.subaccount, &spender.subaccount) {
+        (None, None) => ALLOWS.with(|a| {
+            a.borrow()
+                .get(&owner.owner)
+                .and_then(|inner| inner.get(&spender.owner))
+                .cloned()
+                .unwrap_or_else(|| Nat::from(0))
+        }),
+        _ => ICRC.with(|i| {
+            i.borrow()
+                .allowances
+                .get(&(owner.clone(), spender.clone()))
+                .cloned()
+                .unwrap_or_else(|| Nat::from(0))
+        }),
+    }
+}
+
+fn _set_account_allowance(owner: &Account, spender: &Account, value: Nat, expires_at: Option<u64>) {
+    match (&owner.subaccount, &spender.subaccount) {
+        (None, None) => ALLOWS.with(|a| {
+            let mut allowances = a.borrow_mut();
+            let inner = allowances.entry(owner.owner).or_insert_with(HashMap::new);
+            if value != 0 {
+                inner.insert(spender.owner, value.clone());
+            } else {
+                inner.remove(&spender.owner);
+            }
+            if inner.is_empty() {
+                allowances.remove(&owner.owner);
+            }
+        }),
+        _ => ICRC.with(|i| {
+            let mut icrc = i.borrow_mut();
+            if value != 0 {
+                icrc.allowances
+                    .insert((owner.clone(), spender.clone()), value.clone());
+            } else {
+                icrc.allowances.remove(&(owner.clone(), spender.clone()));
+            }
+        }),
+    }
+
+    ICRC.with(|i| {
+        let mut icrc = i.borrow_mut();
+        match expires_at {
+            Some(expires_at) if value != 0 => {
+                icrc.expiries
+                    .insert((owner.clone(), spender.clone()), expires_at);
+            }
+            _ => {
+                icrc.expiries.remove(&(owner.clone(), spender.clone()));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::{allowance, init, HistorySinkKind};
+
+    fn before_each() -> &'static mut MockContext {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        init(
+            "".to_string(),
+            "Wrapped Ether".to_string(),
+            "WETH".to_string(),
+            18,
+            Nat::from(1_000),
+            mock_principals::alice(),
+            Nat::from(1),
+            mock_principals::john(),
+            Principal::anonymous(),
+            Some(HistorySinkKind::Memory),
+        );
+        ctx
+    }
+
+    fn subaccount(owner: Principal, byte: u8) -> Account {
+        Account {
+            owner,
+            subaccount: Some(vec![byte; 32]),
+        }
+    }
+
+    fn transfer_arg(to: Account, amount: u64) -> TransferArg {
+        TransferArg {
+            from_subaccount: None,
+            to,
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn approve_args(spender: Account, amount: u64, expires_at: Option<u64>) -> ApproveArgs {
+        ApproveArgs {
+            from_subaccount: None,
+            spender,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_transfer_subaccounts() {
+        let ctx = before_each();
+        let bob = mock_principals::bob();
+
+        assert!(icrc1_transfer(transfer_arg(subaccount(bob, 1), 100))
+            .await
+            .is_ok());
+        // a subaccount is kept apart from the DIP20 balance
+        assert_eq!(icrc1_balance_of(subaccount(bob, 1)), Nat::from(100));
+        assert_eq!(balance_of(bob), Nat::from(0));
+        assert_eq!(balance_of(mock_principals::alice()), Nat::from(899));
+
+        ctx.update_caller(bob);
+        let arg = TransferArg {
+            from_subaccount: Some(vec![1; 32]),
+            ..transfer_arg(Account::new(bob), 50)
+        };
+        assert!(icrc1_transfer(arg).await.is_ok());
+        assert_eq!(icrc1_balance_of(subaccount(bob, 1)), Nat::from(49));
+        // the zero subaccount is the DIP20 balance
+        assert_eq!(balance_of(bob), Nat::from(50));
+        assert_eq!(icrc1_balance_of(subaccount(bob, 0)), Nat::from(50));
+        assert_eq!(balance_of(mock_principals::john()), Nat::from(2));
+
+        let arg = TransferArg {
+            from_subaccount: Some(vec![1; 32]),
+            ..transfer_arg(Account::new(bob), 49)
+        };
+        assert_eq!(
+            icrc1_transfer(arg).await,
+            Err(TransferError::InsufficientFunds {
+                balance: Nat::from(49)
+            })
+        );
+        let arg = TransferArg {
+            from_subaccount: Some(vec![1; 31]),
+            ..transfer_arg(Account::new(bob), 1)
+        };
+        assert!(matches!(
+            icrc1_transfer(arg).await,
+            Err(TransferError::GenericError { .. })
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_transfer_created_at_time() {
+        before_each();
+        let bob = Account::new(mock_principals::bob());
+
+        let arg = TransferArg {
+            created_at_time: Some(ic::time()),
+            ..transfer_arg(bob.clone(), 10)
+        };
+        let block_index = icrc1_transfer(arg.clone()).await.unwrap();
+        assert_eq!(
+            icrc1_transfer(arg.clone()).await,
+            Err(TransferError::Duplicate {
+                duplicate_of: block_index
+            })
+        );
+
+        // another memo is another transaction
+        let other = TransferArg {
+            memo: Some(vec![1]),
+            ..arg.clone()
+        };
+        assert!(icrc1_transfer(other).await.is_ok());
+
+        // without a created_at_time nothing is deduplicated
+        assert!(icrc1_transfer(transfer_arg(bob.clone(), 10)).await.is_ok());
+        assert!(icrc1_transfer(transfer_arg(bob.clone(), 10)).await.is_ok());
+        assert_eq!(balance_of(mock_principals::bob()), Nat::from(40));
+
+        let future = TransferArg {
+            created_at_time: Some(ic::time() + PERMITTED_DRIFT + 60 * 1_000_000_000),
+            ..transfer_arg(bob, 10)
+        };
+        assert!(matches!(
+            icrc1_transfer(future).await,
+            Err(TransferError::CreatedInFuture { .. })
+        ));
+    }
+
+    #[test]
+    fn test_deduplication_window() {
+        before_each();
+        let caller = Account::new(mock_principals::alice());
+        let transaction = Transaction::Transfer {
+            to: Account::new(mock_principals::bob()),
+            amount: Nat::from(1),
+            fee: None,
+            memo: None,
+        };
+        let created_at_time = 1_000;
+        let last = created_at_time + TX_WINDOW + PERMITTED_DRIFT;
+
+        _remember_transaction(
+            &caller,
+            Some(created_at_time),
+            transaction.clone(),
+            Nat::from(7),
+            created_at_time,
+        );
+
+        // deduplicated up to the end of the window, too old right after it
+        assert!(matches!(
+            _check_created_at_time(&caller, Some(created_at_time), &transaction, last),
+            Err(Rejection::Duplicate(_))
+        ));
+        assert!(matches!(
+            _check_created_at_time(&caller, Some(created_at_time), &transaction, last + 1),
+            Err(Rejection::TooOld)
+        ));
+
+        // kept at the end of the window, dropped right after it
+        _remember_transaction(&caller, None, transaction.clone(), Nat::from(8), last);
+        assert_eq!(ICRC.with(|i| i.borrow().transactions.len()), 1);
+        _remember_transaction(
+            &caller,
+            Some(last),
+            transaction.clone(),
+            Nat::from(9),
+            last + 1,
+        );
+        ICRC.with(|i| {
+            let icrc = i.borrow();
+            assert_eq!(icrc.transactions.len(), 1);
+            assert!(icrc.transactions.contains_key(&(caller.clone(), last)));
+            assert_eq!(icrc.transaction_times.len(), 1);
+        });
+    }
+
+    #[async_std::test]
+    async fn test_approve_and_transfer_from() {
+        let ctx = before_each();
+        let alice = mock_principals::alice();
+        let bob = mock_principals::bob();
+        let expires_at = ic::time() + TX_WINDOW;
+
+        assert!(
+            icrc2_approve(approve_args(subaccount(bob, 1), 100, Some(expires_at)))
+                .await
+                .is_ok()
+        );
+
+        // the allowance is only replaced when it is still the expected one
+        let changed = ApproveArgs {
+            expected_allowance: Some(Nat::from(0)),
+            ..approve_args(subaccount(bob, 1), 50, Some(expires_at))
+        };
+        assert_eq!(
+            icrc2_approve(changed).await,
+            Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(100)
+            })
+        );
+        let expected = ApproveArgs {
+            expected_allowance: Some(Nat::from(100)),
+            ..approve_args(subaccount(bob, 1), 50, Some(expires_at))
+        };
+        assert!(icrc2_approve(expected).await.is_ok());
+        assert_eq!(
+            icrc2_allowance(AllowanceArgs {
+                account: Account::new(alice),
+                spender: subaccount(bob, 1),
+            }),
+            Allowance {
+                allowance: Nat::from(50),
+                expires_at: Some(expires_at),
+            }
+        );
+        assert!(matches!(
+            icrc2_approve(approve_args(subaccount(bob, 1), 50, Some(ic::time()))).await,
+            Err(ApproveError::Expired { .. })
+        ));
+
+        ctx.update_caller(bob);
+        let transfer_from = TransferFromArgs {
+            spender_subaccount: Some(vec![1; 32]),
+            from: Account::new(alice),
+            to: Account::new(bob),
+            amount: Nat::from(49),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        assert!(icrc2_transfer_from(transfer_from.clone()).await.is_ok());
+        assert_eq!(balance_of(bob), Nat::from(49));
+
+        // the fee was taken from the allowance as well
+        let more = TransferFromArgs {
+            amount: Nat::from(1),
+            ..transfer_from
+        };
+        assert_eq!(
+            icrc2_transfer_from(more).await,
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(0)
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_allowance_expiry() {
+        before_each();
+        let alice = mock_principals::alice();
+        let bob = mock_principals::bob();
+        let expires_at = ic::time() + TX_WINDOW;
+
+        assert!(
+            icrc2_approve(approve_args(Account::new(bob), 100, Some(expires_at)))
+                .await
+                .is_ok()
+        );
+
+        // the DIP20 allowance of the default accounts expires with it
+        assert_eq!(allowance(alice, bob), Nat::from(100));
+        assert!(!_is_expired(alice, bob, expires_at - 1));
+        assert!(_is_expired(alice, bob, expires_at));
+        assert_eq!(
+            _account_allowance(&Account::new(alice), &Account::new(bob), expires_at),
+            Nat::from(0)
+        );
+
+        // the DIP20 approve gives an allowance without an expiry
+        _clear_expiry(alice, bob);
+        assert!(!_is_expired(alice, bob, expires_at));
+    }
+}
@@ -51,8 +51,27 @@ export const handleWithdraw = async (message: BlockNativePayload) => {
   console.log("amountNat", amountAsNat);
 
 
-  // send message to the proxy
-  await dip20_proxy.removeClaimable(fromAddresPid, tokenAddressPid, amountAsNat);
+  // find the pending claimable consumed by this withdraw
+  const pageSize = BigInt(100);
+  for (let start = BigInt(0); ; start += pageSize) {
+    const claimables = await dip20_proxy.getClaimablesByEthAddress(fromAddresPid, start, pageSize);
+    const claimable = claimables.find((claimable) =>
+      'Pending' in claimable.status
+        && claimable.amount === amountAsNat
+        && claimable.token.toText() === tokenAddressPid.toText());
+
+    if (claimable) {
+      const response = await dip20_proxy.markClaimed(claimable.msg_key, hash);
+      if ('Err' in response) {
+        throw new Error(response.Err);
+      }
+      return;
+    }
+
+    if (claimables.length < Number(pageSize)) {
+      throw new Error('no pending claimable matches the withdraw');
+    }
+  }
 };
 
 export const main = sqsHandler<BlockNativePayload>(handleWithdraw, envs.QUEUE_URL, undefined, 1);
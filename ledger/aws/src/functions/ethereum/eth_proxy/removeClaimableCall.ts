@@ -49,8 +49,26 @@ export const handleWithdraw = async (message: BlockNativePayload) => {
   console.log("amountNat", amountAsNat);
 
 
-  // send message to the proxy
-  await eth_proxy.removeClaimable(fromAddresPid, amountAsNat);
+  // find the pending claimable consumed by this withdraw
+  const pageSize = BigInt(100);
+  for (let start = BigInt(0); ; start += pageSize) {
+    const claimables = await eth_proxy.getClaimablesByEthAddress(fromAddresPid, start, pageSize);
+    const claimable = claimables.find((claimable) =>
+      'Pending' in claimable.status
+        && claimable.amount === amountAsNat);
+
+    if (claimable) {
+      const response = await eth_proxy.markClaimed(claimable.msg_key, hash);
+      if ('Err' in response) {
+        throw new Error(response.Err);
+      }
+      return;
+    }
+
+    if (claimables.length < Number(pageSize)) {
+      throw new Error('no pending claimable matches the withdraw');
+    }
+  }
 };
 
 export const main = sqsHandler<BlockNativePayload>(handleWithdraw, envs.QUEUE_URL, undefined, 1);
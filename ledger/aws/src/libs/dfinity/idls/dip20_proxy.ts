@@ -9,7 +9,8 @@ import {
 import { Principal } from '@dfinity/principal';
 import DIP20_PROXY_FACTORY from './dip20_proxy/dip20_proxy.did'
 import DIP20ProxyService, {
-  RemoveClaimableResponse,
+  ClaimableMessage,
+  MarkClaimedResponse,
 } from './dip20_proxy/dip20_proxy';
 
 export interface ActorParams {
@@ -53,11 +54,18 @@ export class DIP20Proxy {
     }, identity);
   }
 
-  removeClaimable(
+  getClaimablesByEthAddress(
     eth_address: Principal,
-    token_id: Principal,
-    amount: bigint,
-  ): Promise<RemoveClaimableResponse> {
-    return this.actor.remove_claimable(eth_address, token_id, amount);
+    start: bigint,
+    limit: bigint,
+  ): Promise<Array<ClaimableMessage>> {
+    return this.actor.get_claimables_by_eth_address(eth_address, start, limit);
+  }
+
+  markClaimed(
+    msg_key: Array<number>,
+    tx_hash: string,
+  ): Promise<MarkClaimedResponse> {
+    return this.actor.mark_claimed(msg_key, tx_hash);
   }
 }
@@ -1,10 +1,27 @@
 import type { Principal } from "@dfinity/principal";
 
-export type RemoveClaimableResponse = { 'Ok': boolean } | { 'Err': string };
+export type ClaimStatus = { 'Claimed': string } | { 'Expired': null } | { 'Pending': null };
+
+export interface ClaimableMessage {
+  'status': ClaimStatus,
+  'token': Principal,
+  'updated_at': bigint,
+  'msg_hash': string,
+  'sender': [] | [Principal],
+  'owner': Principal,
+  'created_at': bigint,
+  'msg_key': Array<number>,
+  'amount': bigint,
+}
+
+export type MarkClaimedResponse = { 'Ok': null } | { 'Err': string };
 
 export default interface _SERVICE {
-  'remove_claimable': (
+  'get_claimables_by_eth_address': (
     arg_0: Principal, //ethAddress
-    arg_1: Principal, //tokenAddress
-    arg_2: bigint) => Promise<RemoveClaimableResponse>,
+    arg_1: bigint, //start
+    arg_2: bigint) => Promise<Array<ClaimableMessage>>,
+  'mark_claimed': (
+    arg_0: Array<number>, //msgKey
+    arg_1: string) => Promise<MarkClaimedResponse>, //txHash
 }
\ No newline at end of file
@@ -9,7 +9,8 @@ import {
 import { Principal } from '@dfinity/principal';
 import ETH_PROXY_FACTORY from './eth_proxy/eth.did'
 import EthProxyService, {
-  RemoveClaimableResponse,
+  ClaimableMessage,
+  MarkClaimedResponse,
 } from './eth_proxy/eth';
 
 export interface ActorParams {
@@ -53,10 +54,18 @@ export class EthProxy {
     }, identity);
   }
 
-  removeClaimable(
+  getClaimablesByEthAddress(
     eth_address: Principal,
-    amount: bigint,
-  ): Promise<RemoveClaimableResponse> {
-    return this.actor.remove_claimable(eth_address, amount);
+    start: bigint,
+    limit: bigint,
+  ): Promise<Array<ClaimableMessage>> {
+    return this.actor.get_claimables_by_eth_address(eth_address, start, limit);
+  }
+
+  markClaimed(
+    msg_key: Array<number>,
+    tx_hash: string,
+  ): Promise<MarkClaimedResponse> {
+    return this.actor.mark_claimed(msg_key, tx_hash);
   }
 }
@@ -1,12 +1,33 @@
 export default ({ IDL }: { IDL: any }) => {
-  const RemoveClaimableResponse = IDL.Variant({
-    Ok: IDL.bool,
+  const ClaimStatus = IDL.Variant({
+    Claimed: IDL.Text,
+    Expired: IDL.Null,
+    Pending: IDL.Null
+  });
+  const ClaimableMessage = IDL.Record({
+    status: ClaimStatus,
+    token: IDL.Principal,
+    updated_at: IDL.Nat64,
+    msg_hash: IDL.Text,
+    sender: IDL.Opt(IDL.Principal),
+    owner: IDL.Principal,
+    created_at: IDL.Nat64,
+    msg_key: IDL.Vec(IDL.Nat8),
+    amount: IDL.Nat
+  });
+  const MarkClaimedResponse = IDL.Variant({
+    Ok: IDL.Null,
     Err: IDL.Text
   });
   return IDL.Service({
-    remove_claimable: IDL.Func(
-      [IDL.Principal, IDL.Nat],
-      [RemoveClaimableResponse],
+    get_claimables_by_eth_address: IDL.Func(
+      [IDL.Principal, IDL.Nat64, IDL.Nat64],
+      [IDL.Vec(ClaimableMessage)],
+      ['query'],
+    ),
+    mark_claimed: IDL.Func(
+      [IDL.Vec(IDL.Nat8), IDL.Text],
+      [MarkClaimedResponse],
       [],
     )
   })
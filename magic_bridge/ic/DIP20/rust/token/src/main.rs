@@ -5,24 +5,148 @@
 * Maintainer : Psychedelic <support@fleek.co>
 * Stability  : Experimental
 */
+mod history;
+mod memory;
+mod migration;
+
 use candid::{candid_method, CandidType, Deserialize, Int, Nat};
-use cap_sdk::{handshake, insert, Event, IndefiniteEvent, TypedEvent};
+use cap_sdk::{handshake, Event, IndefiniteEvent, TypedEvent};
 use cap_std::dip20::cap::DIP20Details;
-use cap_std::dip20::{Operation, TransactionStatus, TxRecord};
+use cap_std::dip20::{Operation, TransactionStatus, TxRecord as CapTxRecord};
+use history::{HistorySink, HistorySinkKind};
 use ic_cdk_macros::*;
 use ic_kit::{ic, Principal};
+use permit::PermitKey;
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::convert::Into;
 use std::iter::FromIterator;
+use std::ops::Bound::{Excluded, Unbounded};
 use std::string::String;
 
+// events that failed to reach cap before the outbox existed,
+// they are flushed ahead of the outbox
 #[derive(CandidType, Default, Deserialize, Clone)]
 pub struct TxLog {
   pub ie_records: VecDeque<IndefiniteEvent>,
 }
 
+// a transaction in the local log, the cap record cannot be stored or
+// returned through candid
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TxRecord {
+  pub caller: Option<Principal>,
+  pub index: Nat,
+  pub from: Principal,
+  pub to: Principal,
+  pub amount: Nat,
+  pub fee: Nat,
+  pub timestamp: Int,
+  pub status: TransactionStatus,
+  pub operation: Operation,
+}
+
+impl From<TxRecord> for CapTxRecord {
+  fn from(record: TxRecord) -> Self {
+    CapTxRecord {
+      caller: record.caller,
+      index: record.index,
+      from: record.from,
+      to: record.to,
+      amount: record.amount,
+      fee: record.fee,
+      timestamp: record.timestamp,
+      status: record.status,
+      operation: record.operation,
+    }
+  }
+}
+
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct CapOutbox {
+  // local transaction ids waiting to be inserted into cap, oldest first
+  pending: VecDeque<u64>,
+  // cap transaction id of every local transaction inserted into cap
+  cap_ids: HashMap<u64, u64>,
+  // consecutive failed inserts, resets on the first success
+  failures: u32,
+  // time before which the heartbeat does not retry
+  next_flush: u64,
+  last_error: Option<String>,
+}
+
+// a balance is only copied the first time it changes after a snapshot
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct Snapshots {
+  taken: Vec<SnapshotInfo>,
+  // the balance a principal had at each snapshot taken before one of
+  // its changes, oldest snapshot first
+  balances: BTreeMap<Principal, Vec<(u64, Nat)>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct SnapshotInfo {
+  id: u64,
+  // transactions in the local log when the snapshot was taken
+  history_index: u64,
+  total_supply: Nat,
+  timestamp: u64,
+}
+
+// only reserve attestations set the cap, mints that would take
+// the total supply above it are rejected
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct MintCap {
+  cap: Option<Nat>,
+  // the only principal allowed to attest, set by the owner,
+  // never the owner or a minter
+  reserve_attester: Option<Principal>,
+  attester: Option<Principal>,
+  attested_at: u64,
+  // mints rejected for exceeding the cap
+  rejections: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct MintCapStatus {
+  cap: Option<Nat>,
+  total_supply: Nat,
+  // how much can still be minted, none when uncapped
+  headroom: Option<Nat>,
+  attester: Option<Principal>,
+  attested_at: u64,
+  rejections: u64,
+  reserve_attester: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CapOutboxStatus {
+  depth: u64,
+  inserted: u64,
+  failures: u32,
+  next_flush: u64,
+  last_error: Option<String>,
+}
+
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct FreezeList {
+  frozen: Vec<Principal>,
+  events: Vec<FreezeEvent>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FreezeEvent {
+  index: u64,
+  caller: Principal,
+  account: Principal,
+  frozen: bool,
+  timestamp: u64,
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, CandidType, Clone, Debug)]
 struct Metadata {
@@ -47,6 +171,12 @@ struct StatsData {
   fee_to: Principal,
   history_size: usize,
   deploy_time: u64,
+  pending_owner: Option<Principal>,
+  // cap when none
+  history_sink: Option<HistorySinkKind>,
+  // the canister allowed to freeze accounts besides the owner,
+  // the magic bridge for bridged tokens
+  factory: Option<Principal>,
 }
 
 #[allow(non_snake_case)]
@@ -74,12 +204,53 @@ impl Default for StatsData {
       fee_to: Principal::anonymous(),
       history_size: 0,
       deploy_time: 0,
+      pending_owner: None,
+      history_sink: None,
+      factory: None,
     }
   }
 }
 
 type Balances = HashMap<Principal, Nat>;
 type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
+type Minters = Vec<Principal>;
+// permits used by each owner, the next permit must carry this nonce
+type PermitNonces = HashMap<Principal, u64>;
+
+// everything kept across upgrades, stored in the versioned envelope
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+  stats: StatsData,
+  balances: Balances,
+  allows: Allowances,
+  tx_log: TxLog,
+  minters: Minters,
+  cap_outbox: CapOutbox,
+  freeze_list: FreezeList,
+  mint_cap: MintCap,
+  snapshots: Snapshots,
+  permit_nonces: PermitNonces,
+}
+
+// the most transactions returned by one history query
+const MAX_TRANSACTIONS_PAGE: usize = 100;
+// the most holders returned by one holders query
+const MAX_HOLDERS_PAGE: usize = 100;
+// the most recipients of one batch transfer
+const MAX_BATCH_TRANSFERS: usize = 500;
+// the most events inserted into cap by one heartbeat or flush
+const CAP_FLUSH_BATCH: usize = 20;
+// the delay after the first failed insert, doubled on each failure up to the max
+const CAP_BACKOFF_BASE: u64 = 2_000_000_000;
+const CAP_BACKOFF_MAX: u64 = 600_000_000_000;
+const CAP_FLUSH_LEASE_TIME: u64 = 300_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct UserTransactions {
+  transactions: Vec<TxRecord>,
+  // pass as cursor to get the older transactions
+  next_cursor: Option<u64>,
+}
 
 #[derive(CandidType, Debug, PartialEq)]
 pub enum TxError {
@@ -92,14 +263,32 @@ pub enum TxError {
   ErrorOperationStyle,
   ErrorTo,
   Other(String),
+  AccountFrozen,
+  MintCapExceeded,
 }
 pub type TxReceipt = Result<Nat, TxError>;
+pub type BatchReceipt = Result<Vec<Nat>, TxError>;
 
 thread_local! {
     static BALANCES: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
+    // balances from the largest, ties ordered by principal, rebuilt on upgrade
+    static HOLDERS: RefCell<BTreeSet<(Reverse<Nat>, Principal)>> = RefCell::new(BTreeSet::default());
+    // holders ordered by principal, rebuilt on upgrade
+    static HOLDER_PRINCIPALS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::default());
     static ALLOWS: RefCell<HashMap<Principal, HashMap<Principal, Nat>>> = RefCell::new(HashMap::default());
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static TXLOG: RefCell<TxLog> = RefCell::new(TxLog::default());
+    static CAP_OUTBOX: RefCell<CapOutbox> = RefCell::new(CapOutbox::default());
+    static MINT_CAP: RefCell<MintCap> = RefCell::new(MintCap::default());
+    static SNAPSHOTS: RefCell<Snapshots> = RefCell::new(Snapshots::default());
+    static PERMIT_NONCES: RefCell<PermitNonces> = RefCell::new(HashMap::default());
+    // when the flush waiting on cap gives up its lease, so flushes never
+    // overlap. The lease expires in case the flush callback traps
+    static CAP_FLUSH_LEASE: RefCell<u64> = RefCell::new(0);
+    static MINTERS: RefCell<Minters> = RefCell::new(Vec::default());
+    static FREEZE_LIST: RefCell<FreezeList> = RefCell::new(FreezeList::default());
+    // transaction indexes by caller, sender and receiver, rebuilt on upgrade
+    static USER_TRANSACTIONS: RefCell<HashMap<Principal, Vec<u64>>> = RefCell::new(HashMap::default());
 }
 
 #[init]
@@ -114,6 +303,8 @@ fn init(
   fee: Nat,
   fee_to: Principal,
   cap: Principal,
+  history_sink: Option<HistorySinkKind>,
+  factory: Option<Principal>,
 ) {
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
@@ -127,12 +318,17 @@ fn init(
     stats.fee_to = fee_to;
     stats.history_size = 1;
     stats.deploy_time = ic::time();
+    stats.history_sink = history_sink;
+    stats.factory = factory;
   });
-  handshake(1_000_000_000_000, Some(cap));
-  BALANCES.with(|b| {
-    b.borrow_mut().insert(owner, total_supply.clone());
+  MINTERS.with(|m| {
+    m.borrow_mut().push(owner);
   });
-  let _ = add_record(
+  if history_sink.unwrap_or(HistorySinkKind::Cap) == HistorySinkKind::Cap {
+    handshake(1_000_000_000_000, Some(cap));
+  }
+  _balance_ins(owner, total_supply.clone());
+  _log_record(
     owner,
     Operation::Mint,
     owner,
@@ -150,6 +346,7 @@ fn init(
 #[candid_method(update)]
 async fn transfer(to: Principal, value: Nat) -> TxReceipt {
   let from = ic::caller();
+  _check_not_frozen(&[from, to])?;
   let fee = _get_fee();
   if balance_of(from) < value.clone() + fee.clone() {
     return Err(TxError::InsufficientBalance);
@@ -170,10 +367,61 @@ async fn transfer(to: Principal, value: Nat) -> TxReceipt {
   .await
 }
 
+// every transfer is charged the fee, all of them are applied or none is.
+// Returns the transaction id of each transfer in order
+#[update(name = "batchTransfer")]
+#[candid_method(update, rename = "batchTransfer")]
+async fn batch_transfer(transfers: Vec<(Principal, Nat)>) -> BatchReceipt {
+  let from = ic::caller();
+  if transfers.len() > MAX_BATCH_TRANSFERS {
+    return Err(TxError::Other(format!(
+      "Batch of {} transfers exceeds limit of {}",
+      transfers.len(),
+      MAX_BATCH_TRANSFERS
+    )));
+  }
+  let mut accounts: Vec<Principal> = transfers.iter().map(|(to, _)| *to).collect();
+  accounts.push(from);
+  _check_not_frozen(&accounts)?;
+  let fee = _get_fee();
+  let total = transfers.iter().fold(Nat::from(0), |total, (_, value)| {
+    total + value.clone() + fee.clone()
+  });
+  if balance_of(from) < total {
+    return Err(TxError::InsufficientBalance);
+  }
+
+  let now = ic::time();
+  let ids: Vec<Nat> = transfers
+    .into_iter()
+    .map(|(to, value)| {
+      _charge_fee(from, fee.clone());
+      _transfer(from, to, value.clone());
+      _history_inc();
+      _log_record(
+        from,
+        Operation::Transfer,
+        from,
+        to,
+        value,
+        fee.clone(),
+        now,
+        TransactionStatus::Succeeded,
+      )
+      .index
+    })
+    .collect();
+  // as many events as the batch queued, so the outbox does not fall behind
+  _flush_cap_outbox(ids.len(), false).await;
+
+  Ok(ids)
+}
+
 #[update(name = "transferFrom")]
 #[candid_method(update, rename = "transferFrom")]
 async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
   let owner = ic::caller();
+  _check_not_frozen(&[owner, from, to])?;
   let from_allowance = allowance(from, owner);
   let fee = _get_fee();
   if from_allowance < value.clone() + fee.clone() {
@@ -226,6 +474,59 @@ async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt
 #[candid_method(update)]
 async fn approve(spender: Principal, value: Nat) -> TxReceipt {
   let owner = ic::caller();
+  _check_not_frozen(&[owner, spender])?;
+  _approve(owner, owner, spender, value).await
+}
+
+// approves with the signature of the owner instead of its call, the permit
+// can be submitted by anyone and the fee is charged to the owner
+#[update(name = "permit")]
+#[candid_method(update, rename = "permit")]
+async fn permit(
+  owner_pubkey: Vec<u8>,
+  spender: Principal,
+  value: Nat,
+  deadline: u64,
+  nonce: u64,
+  signature: Vec<u8>,
+) -> TxReceipt {
+  if ic::time() > deadline {
+    return Err(TxError::Other("Permit has expired".to_string()));
+  }
+  let key = PermitKey::from_bytes(&owner_pubkey).map_err(TxError::Other)?;
+  let owner = key.owner();
+  let message = permit::permit_message(ic::id(), owner, spender, &value, deadline, nonce);
+  if key.verify(&message, &signature).is_err() {
+    return Err(TxError::Unauthorized);
+  }
+  let expected = get_permit_nonce(owner);
+  if nonce != expected {
+    return Err(TxError::Other(format!(
+      "Permit nonce {} does not match the next nonce {}",
+      nonce, expected
+    )));
+  }
+  _check_not_frozen(&[owner, spender])?;
+  PERMIT_NONCES.with(|p| {
+    *p.borrow_mut().entry(owner).or_insert(0) += 1;
+  });
+  let receipt = _approve(ic::caller(), owner, spender, value).await;
+  // _approve only fails before its first await, so the nonce
+  // can be given back without another permit having used it
+  if receipt.is_err() {
+    PERMIT_NONCES.with(|p| {
+      *p.borrow_mut().entry(owner).or_insert(1) -= 1;
+    });
+  }
+  receipt
+}
+
+async fn _approve(
+  caller: Principal,
+  owner: Principal,
+  spender: Principal,
+  value: Nat,
+) -> TxReceipt {
   let fee = _get_fee();
   if balance_of(owner) < fee.clone() {
     return Err(TxError::InsufficientBalance);
@@ -261,7 +562,7 @@ async fn approve(spender: Principal, value: Nat) -> TxReceipt {
 
   _history_inc();
   add_record(
-    owner,
+    caller,
     Operation::Approve,
     owner,
     spender,
@@ -277,14 +578,12 @@ async fn approve(spender: Principal, value: Nat) -> TxReceipt {
 #[candid_method(update)]
 async fn burn(amount: Nat) -> TxReceipt {
   let caller = ic::caller();
+  _check_not_frozen(&[caller])?;
   let caller_balance = balance_of(caller);
   if caller_balance.clone() < amount.clone() {
     return Err(TxError::InsufficientBalance);
   }
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.insert(caller, caller_balance - amount.clone());
-  });
+  _balance_ins(caller, caller_balance - amount.clone());
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     stats.total_supply -= amount.clone();
@@ -386,6 +685,21 @@ fn owner() -> Principal {
   })
 }
 
+#[query(name = "pendingOwner")]
+#[candid_method(query, rename = "pendingOwner")]
+fn pending_owner() -> Option<Principal> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.pending_owner
+  })
+}
+
+#[query(name = "getMinters")]
+#[candid_method(query, rename = "getMinters")]
+fn get_minters() -> Vec<Principal> {
+  MINTERS.with(|m| m.borrow().clone())
+}
+
 #[query(name = "getMetadata")]
 #[candid_method(query, rename = "getMetadata")]
 fn get_metadata() -> Metadata {
@@ -431,25 +745,207 @@ fn get_token_info() -> TokenInfo {
   })
 }
 
+// the nonce the next permit of the owner must carry
+#[query(name = "getPermitNonce")]
+#[candid_method(query, rename = "getPermitNonce")]
+fn get_permit_nonce(owner: Principal) -> u64 {
+  PERMIT_NONCES.with(|p| p.borrow().get(&owner).copied().unwrap_or(0))
+}
+
+// none for an unknown snapshot
+#[query(name = "balanceOfAt")]
+#[candid_method(query, rename = "balanceOfAt")]
+fn balance_of_at(who: Principal, snapshot_id: u64) -> Option<Nat> {
+  SNAPSHOTS.with(|s| {
+    let snapshots = s.borrow();
+    if snapshot_id == 0 || snapshot_id > snapshots.taken.len() as u64 {
+      return None;
+    }
+    Some(_balance_at(&snapshots, who, snapshot_id))
+  })
+}
+
+#[query(name = "getSnapshots")]
+#[candid_method(query, rename = "getSnapshots")]
+fn get_snapshots() -> Vec<SnapshotInfo> {
+  SNAPSHOTS.with(|s| s.borrow().taken.clone())
+}
+
+// non zero balances at the snapshot ordered by principal, the next page
+// starts after the last holder of the previous one
+#[query(name = "getSnapshotBalances")]
+#[candid_method(query, rename = "getSnapshotBalances")]
+fn get_snapshot_balances(
+  snapshot_id: u64,
+  cursor: Option<Principal>,
+  limit: usize,
+) -> Vec<(Principal, Nat)> {
+  let start = cursor.map_or(Unbounded, Excluded);
+  SNAPSHOTS.with(|s| {
+    let snapshots = s.borrow();
+    if snapshot_id == 0 || snapshot_id > snapshots.taken.len() as u64 {
+      return Vec::new();
+    }
+    // a holder then either still holds or had its balance kept when it changed,
+    // both indexes are ordered by principal and merged without duplicates
+    HOLDER_PRINCIPALS.with(|h| {
+      let holders = h.borrow();
+      let mut current = holders.range((start, Unbounded)).peekable();
+      let mut kept = snapshots
+        .balances
+        .range((start, Unbounded))
+        .map(|(who, _)| who)
+        .peekable();
+      std::iter::from_fn(|| match (current.peek(), kept.peek()) {
+        (Some(a), Some(b)) if a < b => current.next(),
+        (Some(a), Some(b)) if a > b => kept.next(),
+        (Some(_), Some(_)) => {
+          kept.next();
+          current.next()
+        }
+        (Some(_), None) => current.next(),
+        (None, _) => kept.next(),
+      })
+      .map(|who| (*who, _balance_at(&snapshots, *who, snapshot_id)))
+      .filter(|(_, balance)| *balance != 0)
+      .take(limit.min(MAX_HOLDERS_PAGE))
+      .collect()
+    })
+  })
+}
+
+// holders from the largest balance, skipping the first start holders
 #[query(name = "getHolders")]
 #[candid_method(query, rename = "getHolders")]
 fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
-  BALANCES.with(|b| {
-    let balances = b.borrow();
-    let mut balance = Vec::new();
-    for (k, v) in balances.iter() {
-      balance.push((k.clone(), v.clone()));
-    }
-    balance.sort_by(|a, b| b.1.cmp(&a.1));
-    let limit: usize = if start + limit > balance.len() {
-      balance.len() - start
-    } else {
-      limit
-    };
-    balance[start..start + limit].to_vec()
+  HOLDERS.with(|h| {
+    h.borrow()
+      .iter()
+      .skip(start)
+      .take(limit)
+      .map(|(Reverse(balance), holder)| (*holder, balance.clone()))
+      .collect()
+  })
+}
+
+// holders ordered by principal, the next page starts
+// after the last holder of the previous one
+#[query(name = "getHoldersByPrincipal")]
+#[candid_method(query, rename = "getHoldersByPrincipal")]
+fn get_holders_by_principal(cursor: Option<Principal>, limit: usize) -> Vec<(Principal, Nat)> {
+  let start = cursor.map_or(Unbounded, Excluded);
+  HOLDER_PRINCIPALS.with(|h| {
+    BALANCES.with(|b| {
+      let balances = b.borrow();
+      h.borrow()
+        .range((start, Unbounded))
+        .filter_map(|holder| {
+          balances
+            .get(holder)
+            .map(|balance| (*holder, balance.clone()))
+        })
+        .take(limit.min(MAX_HOLDERS_PAGE))
+        .collect()
+    })
+  })
+}
+
+// holders from the largest balance, the next page starts after
+// the last holder and balance of the previous one
+#[query(name = "getTopHolders")]
+#[candid_method(query, rename = "getTopHolders")]
+fn get_top_holders(cursor: Option<(Principal, Nat)>, limit: usize) -> Vec<(Principal, Nat)> {
+  let start = cursor.map_or(Unbounded, |(holder, balance)| {
+    Excluded((Reverse(balance), holder))
+  });
+  HOLDERS.with(|h| {
+    h.borrow()
+      .range((start, Unbounded))
+      .take(limit.min(MAX_HOLDERS_PAGE))
+      .map(|(Reverse(balance), holder)| (*holder, balance.clone()))
+      .collect()
   })
 }
 
+#[query(name = "isFrozen")]
+#[candid_method(query, rename = "isFrozen")]
+fn is_frozen(account: Principal) -> bool {
+  FREEZE_LIST.with(|f| f.borrow().frozen.contains(&account))
+}
+
+#[query(name = "getFrozen")]
+#[candid_method(query, rename = "getFrozen")]
+fn get_frozen() -> Vec<Principal> {
+  FREEZE_LIST.with(|f| f.borrow().frozen.clone())
+}
+
+#[query(name = "getFreezeLog")]
+#[candid_method(query, rename = "getFreezeLog")]
+fn get_freeze_log(start: u64, limit: u64) -> Vec<FreezeEvent> {
+  FREEZE_LIST.with(|f| {
+    f.borrow()
+      .events
+      .iter()
+      .skip(start as usize)
+      .take((limit as usize).min(MAX_TRANSACTIONS_PAGE))
+      .cloned()
+      .collect()
+  })
+}
+
+#[query(name = "get_transaction")]
+#[candid_method(query, rename = "get_transaction")]
+fn get_transaction(id: u64) -> Option<TxRecord> {
+  memory::transaction(id)
+}
+
+#[query(name = "get_transactions")]
+#[candid_method(query, rename = "get_transactions")]
+fn get_transactions(start: u64, limit: u64) -> Vec<TxRecord> {
+  memory::transactions(start, (limit as usize).min(MAX_TRANSACTIONS_PAGE))
+}
+
+// none until the transaction has been inserted into cap
+#[query(name = "get_cap_transaction_id")]
+#[candid_method(query, rename = "get_cap_transaction_id")]
+fn get_cap_transaction_id(id: u64) -> Option<u64> {
+  CAP_OUTBOX.with(|o| o.borrow().cap_ids.get(&id).copied())
+}
+
+// newest first, starting below cursor
+#[query(name = "get_user_transactions")]
+#[candid_method(query, rename = "get_user_transactions")]
+fn get_user_transactions(who: Principal, cursor: Option<u64>) -> UserTransactions {
+  let ids: Vec<u64> = USER_TRANSACTIONS.with(|u| {
+    u.borrow()
+      .get(&who)
+      .map(|ids| {
+        ids
+          .iter()
+          .rev()
+          .filter(|id| cursor.map_or(true, |cursor| **id < cursor))
+          .take(MAX_TRANSACTIONS_PAGE + 1)
+          .copied()
+          .collect()
+      })
+      .unwrap_or_default()
+  });
+  let next_cursor = if ids.len() > MAX_TRANSACTIONS_PAGE {
+    ids.get(MAX_TRANSACTIONS_PAGE - 1).copied()
+  } else {
+    None
+  };
+
+  UserTransactions {
+    transactions: ids
+      .iter()
+      .take(MAX_TRANSACTIONS_PAGE)
+      .filter_map(|id| get_transaction(*id))
+      .collect(),
+    next_cursor,
+  }
+}
+
 #[query(name = "getAllowanceSize")]
 #[candid_method(query, rename = "getAllowanceSize")]
 fn get_allowance_size() -> usize {
@@ -477,16 +973,14 @@ fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
 
 /* CONTROLLER FNS */
 
-#[update(guard = "_is_auth")]
+#[update(guard = "_is_minter")]
 #[candid_method(update, rename = "mint")]
 async fn mint(to: Principal, amount: Nat) -> TxReceipt {
   let caller = ic::caller();
+  _check_mint_cap(&amount)?;
   let to_balance = balance_of(to);
 
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.insert(to, to_balance + amount.clone());
-  });
+  _balance_ins(to, to_balance + amount.clone());
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     stats.total_supply += amount.clone();
@@ -505,7 +999,7 @@ async fn mint(to: Principal, amount: Nat) -> TxReceipt {
   .await
 }
 
-#[update(name = "setName", guard = "_is_auth")]
+#[update(name = "setName", guard = "_is_owner")]
 #[candid_method(update, rename = "setName")]
 fn set_name(name: String) {
   STATS.with(|s| {
@@ -514,7 +1008,7 @@ fn set_name(name: String) {
   });
 }
 
-#[update(name = "setLogo", guard = "_is_auth")]
+#[update(name = "setLogo", guard = "_is_owner")]
 #[candid_method(update, rename = "setLogo")]
 fn set_logo(logo: String) {
   STATS.with(|s| {
@@ -523,7 +1017,7 @@ fn set_logo(logo: String) {
   });
 }
 
-#[update(name = "setFee", guard = "_is_auth")]
+#[update(name = "setFee", guard = "_is_owner")]
 #[candid_method(update, rename = "setFee")]
 fn set_fee(fee: Nat) {
   STATS.with(|s| {
@@ -532,7 +1026,7 @@ fn set_fee(fee: Nat) {
   });
 }
 
-#[update(name = "setFeeTo", guard = "_is_auth")]
+#[update(name = "setFeeTo", guard = "_is_owner")]
 #[candid_method(update, rename = "setFeeTo")]
 fn set_fee_to(fee_to: Principal) {
   STATS.with(|s| {
@@ -541,20 +1035,164 @@ fn set_fee_to(fee_to: Principal) {
   });
 }
 
-#[update(name = "setOwner", guard = "_is_auth")]
-#[candid_method(update, rename = "setOwner")]
-fn set_owner(owner: Principal) {
+// ownership only changes once the new owner accepts it,
+// so a mistyped principal can be replaced before it locks the token
+#[update(name = "proposeOwner", guard = "_is_owner")]
+#[candid_method(update, rename = "proposeOwner")]
+fn propose_owner(owner: Principal) {
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
-    stats.owner = owner;
+    stats.pending_owner = Some(owner);
+  });
+}
+
+#[update(name = "acceptOwnership", guard = "_is_pending_owner")]
+#[candid_method(update, rename = "acceptOwnership")]
+fn accept_ownership() {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.owner = ic::caller();
+    stats.pending_owner = None;
+  });
+}
+
+#[update(name = "cancelOwnershipTransfer", guard = "_is_owner")]
+#[candid_method(update, rename = "cancelOwnershipTransfer")]
+fn cancel_ownership_transfer() {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.pending_owner = None;
+  });
+}
+
+#[update(name = "addMinter", guard = "_is_owner")]
+#[candid_method(update, rename = "addMinter")]
+fn add_minter(minter: Principal) {
+  MINTERS.with(|m| {
+    let mut minters = m.borrow_mut();
+    if !minters.contains(&minter) {
+      minters.push(minter);
+    }
+  });
+}
+
+#[update(name = "removeMinter", guard = "_is_owner")]
+#[candid_method(update, rename = "removeMinter")]
+fn remove_minter(minter: Principal) {
+  MINTERS.with(|m| {
+    let mut minters = m.borrow_mut();
+    minters.retain(|p| *p != minter);
+  });
+}
+
+// none leaves freezing to the owner alone
+#[update(name = "setFactory", guard = "_is_owner")]
+#[candid_method(update, rename = "setFactory")]
+fn set_factory(factory: Option<Principal>) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.factory = factory;
+  });
+}
+
+// frozen accounts cannot send, receive, approve or burn, minting to them still works
+#[update(name = "freeze", guard = "_is_owner_or_factory")]
+#[candid_method(update, rename = "freeze")]
+fn freeze(account: Principal) {
+  _set_frozen(account, true);
+}
+
+#[update(name = "unfreeze", guard = "_is_owner_or_factory")]
+#[candid_method(update, rename = "unfreeze")]
+fn unfreeze(account: Principal) {
+  _set_frozen(account, false);
+}
+
+// returns the snapshot id, ids start at one
+#[update(name = "takeSnapshot", guard = "_is_owner")]
+#[candid_method(update, rename = "takeSnapshot")]
+fn take_snapshot() -> u64 {
+  let history_index = memory::transaction_count();
+  let total_supply = total_supply();
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    let id = snapshots.taken.len() as u64 + 1;
+    snapshots.taken.push(SnapshotInfo {
+      id,
+      history_index,
+      total_supply,
+      timestamp: ic::time(),
+    });
+    id
+  })
+}
+
+#[update(name = "setReserveAttester", guard = "_is_owner")]
+#[candid_method(update, rename = "setReserveAttester")]
+fn set_reserve_attester(attester: Principal) -> Result<(), TxError> {
+  if attester == owner() || get_minters().contains(&attester) {
+    return Err(TxError::Other(
+      "The reserve attester must not be the owner or a minter".to_string(),
+    ));
+  }
+  MINT_CAP.with(|m| m.borrow_mut().reserve_attester = Some(attester));
+  Ok(())
+}
+
+// the l1 collateral attested by the reserve attester becomes the mint cap
+#[update(name = "attestReserve", guard = "_is_reserve_attester")]
+#[candid_method(update, rename = "attestReserve")]
+fn attest_reserve(l1_balance: Nat) -> MintCapStatus {
+  MINT_CAP.with(|m| {
+    let mut mint_cap = m.borrow_mut();
+    mint_cap.cap = Some(l1_balance);
+    mint_cap.attester = Some(ic::caller());
+    mint_cap.attested_at = ic::time();
   });
+  get_mint_cap()
+}
+
+#[query(name = "getMintCap")]
+#[candid_method(query, rename = "getMintCap")]
+fn get_mint_cap() -> MintCapStatus {
+  let total_supply = total_supply();
+  MINT_CAP.with(|m| {
+    let mint_cap = m.borrow();
+    MintCapStatus {
+      cap: mint_cap.cap.clone(),
+      headroom: mint_cap.cap.clone().map(|cap| {
+        if cap > total_supply {
+          cap - total_supply.clone()
+        } else {
+          Nat::from(0)
+        }
+      }),
+      total_supply,
+      attester: mint_cap.attester,
+      attested_at: mint_cap.attested_at,
+      rejections: mint_cap.rejections,
+      reserve_attester: mint_cap.reserve_attester,
+    }
+  })
+}
+
+#[query(name = "getCapOutbox", guard = "_is_owner")]
+#[candid_method(query, rename = "getCapOutbox")]
+fn get_cap_outbox() -> CapOutboxStatus {
+  _cap_outbox_status()
+}
+
+// retries straight away, even while backing off from a failure
+#[update(name = "flushCapOutbox", guard = "_is_owner")]
+#[candid_method(update, rename = "flushCapOutbox")]
+async fn flush_cap_outbox() -> CapOutboxStatus {
+  _flush_cap_outbox(CAP_FLUSH_BATCH, true).await;
+  _cap_outbox_status()
 }
 
 /* INTERNAL FNS */
 
-// TODO: use controllers for ownership
-// this will require the canister to be a controller of itself (like dip721)
-fn _is_auth() -> Result<(), String> {
+fn _is_owner() -> Result<(), String> {
   STATS.with(|s| {
     let stats = s.borrow();
     if ic::caller() == stats.owner {
@@ -565,17 +1203,121 @@ fn _is_auth() -> Result<(), String> {
   })
 }
 
+fn _is_owner_or_factory() -> Result<(), String> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    if ic::caller() == stats.owner || Some(ic::caller()) == stats.factory {
+      Ok(())
+    } else {
+      Err("Error: Unauthorized principal ID".to_string())
+    }
+  })
+}
+
+fn _is_pending_owner() -> Result<(), String> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    if Some(ic::caller()) == stats.pending_owner {
+      Ok(())
+    } else {
+      Err("Error: Caller is not the pending owner".to_string())
+    }
+  })
+}
+
+// minters are managed by the owner, the owner itself
+// is not a minter unless it is on the list
+fn _is_minter() -> Result<(), String> {
+  MINTERS.with(|m| {
+    let minters = m.borrow();
+    if minters.contains(&ic::caller()) {
+      Ok(())
+    } else {
+      Err("Error: Unauthorized principal ID".to_string())
+    }
+  })
+}
+
+fn _check_not_frozen(accounts: &[Principal]) -> Result<(), TxError> {
+  FREEZE_LIST.with(|f| {
+    let freeze_list = f.borrow();
+    if accounts.iter().any(|a| freeze_list.frozen.contains(a)) {
+      Err(TxError::AccountFrozen)
+    } else {
+      Ok(())
+    }
+  })
+}
+
+// logged only when the account changes state
+fn _set_frozen(account: Principal, frozen: bool) {
+  FREEZE_LIST.with(|f| {
+    let mut freeze_list = f.borrow_mut();
+    if freeze_list.frozen.contains(&account) == frozen {
+      return;
+    }
+    if frozen {
+      freeze_list.frozen.push(account);
+    } else {
+      freeze_list.frozen.retain(|a| *a != account);
+    }
+    let index = freeze_list.events.len() as u64;
+    freeze_list.events.push(FreezeEvent {
+      index,
+      caller: ic::caller(),
+      account,
+      frozen,
+      timestamp: ic::time(),
+    });
+  })
+}
+
+// checked on every attestation, the owner or a minter
+// that became the attester since it was set is refused
+fn _is_reserve_attester() -> Result<(), String> {
+  let caller = ic::caller();
+  let attester = MINT_CAP.with(|m| m.borrow().reserve_attester);
+  if attester == Some(caller) && caller != owner() && !get_minters().contains(&caller) {
+    Ok(())
+  } else {
+    Err("Error: Caller is not the reserve attester".to_string())
+  }
+}
+
 fn _balance_ins(from: Principal, value: Nat) {
+  _snapshot_balance(from);
   BALANCES.with(|b| {
     let mut balances = b.borrow_mut();
+    HOLDERS.with(|h| {
+      let mut holders = h.borrow_mut();
+      if let Some(balance) = balances.get(&from) {
+        holders.remove(&(Reverse(balance.clone()), from));
+      }
+      // a zero mint or a full burn leaves no holder behind
+      if value != 0 {
+        holders.insert((Reverse(value.clone()), from));
+      }
+    });
+    HOLDER_PRINCIPALS.with(|h| {
+      let mut holders = h.borrow_mut();
+      if value != 0 {
+        holders.insert(from);
+      } else {
+        holders.remove(&from);
+      }
+    });
     balances.insert(from, value);
   });
 }
 
 fn _balance_rem(from: Principal) {
+  _snapshot_balance(from);
   BALANCES.with(|b| {
     let mut balances = b.borrow_mut();
-    balances.remove(&from);
+    if let Some(balance) = balances.remove(&from) {
+      HOLDERS.with(|h| h.borrow_mut().remove(&(Reverse(balance), from)));
+      HOLDER_PRINCIPALS.with(|h| h.borrow_mut().remove(&from));
+    }
   });
 }
 
@@ -596,6 +1338,46 @@ fn _transfer(from: Principal, to: Principal, value: Nat) {
   }
 }
 
+fn _check_mint_cap(amount: &Nat) -> Result<(), TxError> {
+  let total_supply = total_supply();
+  MINT_CAP.with(|m| {
+    let mut mint_cap = m.borrow_mut();
+    match mint_cap.cap.clone() {
+      Some(cap) if total_supply + amount.clone() > cap => {
+        mint_cap.rejections += 1;
+        Err(TxError::MintCapExceeded)
+      }
+      _ => Ok(()),
+    }
+  })
+}
+
+// keeps the balance as of the latest snapshot before it changes
+fn _snapshot_balance(who: Principal) {
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    let latest = snapshots.taken.len() as u64;
+    if latest == 0 {
+      return;
+    }
+    let kept = snapshots.balances.entry(who).or_insert_with(Vec::new);
+    if kept.last().map_or(true, |(id, _)| *id < latest) {
+      kept.push((latest, balance_of(who)));
+    }
+  })
+}
+
+// the first balance kept at or after the snapshot is the one it had then,
+// with none kept it has not changed since
+fn _balance_at(snapshots: &Snapshots, who: Principal, snapshot_id: u64) -> Nat {
+  snapshots
+    .balances
+    .get(&who)
+    .and_then(|kept| kept.iter().find(|(id, _)| *id >= snapshot_id))
+    .map(|(_, balance)| balance.clone())
+    .unwrap_or_else(|| balance_of(who))
+}
+
 fn _charge_fee(user: Principal, fee: Nat) {
   STATS.with(|s| {
     let stats = s.borrow();
@@ -619,6 +1401,13 @@ fn _get_owner() -> Principal {
   })
 }
 
+fn _history_sink() -> Box<dyn HistorySink> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.history_sink.unwrap_or(HistorySinkKind::Cap).sink()
+  })
+}
+
 fn _history_inc() {
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
@@ -637,25 +1426,54 @@ fn main() {
 
 #[pre_upgrade]
 fn pre_upgrade() {
-  let stats = STATS.with(|s| s.borrow().clone());
-  let balances = BALANCES.with(|b| b.borrow().clone());
-  let allows = ALLOWS.with(|a| a.borrow().clone());
-  let tx_log = TXLOG.with(|t| t.borrow().clone());
-  ic::stable_store((stats, balances, allows, tx_log)).unwrap();
+  let state = StableState {
+    stats: STATS.with(|s| s.borrow().clone()),
+    balances: BALANCES.with(|b| b.borrow().clone()),
+    allows: ALLOWS.with(|a| a.borrow().clone()),
+    tx_log: TXLOG.with(|t| t.borrow().clone()),
+    minters: MINTERS.with(|m| m.borrow().clone()),
+    cap_outbox: CAP_OUTBOX.with(|o| o.borrow().clone()),
+    freeze_list: FREEZE_LIST.with(|f| f.borrow().clone()),
+    mint_cap: MINT_CAP.with(|m| m.borrow().clone()),
+    snapshots: SNAPSHOTS.with(|s| s.borrow().clone()),
+    permit_nonces: PERMIT_NONCES.with(|p| p.borrow().clone()),
+  };
+  migration::store(state).unwrap();
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-  let (metadata_stored, balances_stored, allowances_stored, tx_log_stored): (
-    StatsData,
-    Balances,
-    Allowances,
-    TxLog,
-  ) = ic::stable_restore().unwrap();
+  let StableState {
+    stats: metadata_stored,
+    balances: balances_stored,
+    allows: allowances_stored,
+    tx_log: tx_log_stored,
+    minters: minters_stored,
+    cap_outbox: cap_outbox_stored,
+    freeze_list: freeze_list_stored,
+    mint_cap: mint_cap_stored,
+    snapshots: snapshots_stored,
+    permit_nonces: permit_nonces_stored,
+  } = migration::restore().unwrap();
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     *stats = metadata_stored;
   });
+  HOLDERS.with(|h| {
+    let mut holders = h.borrow_mut();
+    holders.clear();
+    for (holder, balance) in balances_stored.iter().filter(|(_, b)| **b != 0) {
+      holders.insert((Reverse(balance.clone()), *holder));
+    }
+  });
+  HOLDER_PRINCIPALS.with(|h| {
+    let mut holders = h.borrow_mut();
+    *holders = balances_stored
+      .iter()
+      .filter(|(_, b)| **b != 0)
+      .map(|(holder, _)| *holder)
+      .collect();
+  });
   BALANCES.with(|b| {
     let mut balances = b.borrow_mut();
     *balances = balances_stored;
@@ -668,8 +1486,43 @@ fn post_upgrade() {
     let mut tx_log = t.borrow_mut();
     *tx_log = tx_log_stored;
   });
+  MINTERS.with(|m| {
+    let mut minters = m.borrow_mut();
+    *minters = minters_stored;
+  });
+  USER_TRANSACTIONS.with(|u| {
+    let mut user_transactions = u.borrow_mut();
+    user_transactions.clear();
+    for id in 0..memory::transaction_count() {
+      if let Some(record) = memory::transaction(id) {
+        _index_record(&mut user_transactions, id, &record);
+      }
+    }
+  });
+  CAP_OUTBOX.with(|o| {
+    let mut cap_outbox = o.borrow_mut();
+    *cap_outbox = cap_outbox_stored;
+  });
+  FREEZE_LIST.with(|f| {
+    let mut freeze_list = f.borrow_mut();
+    *freeze_list = freeze_list_stored;
+  });
+  MINT_CAP.with(|m| {
+    let mut mint_cap = m.borrow_mut();
+    *mint_cap = mint_cap_stored;
+  });
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    *snapshots = snapshots_stored;
+  });
+  PERMIT_NONCES.with(|p| {
+    let mut permit_nonces = p.borrow_mut();
+    *permit_nonces = permit_nonces_stored;
+  });
 }
 
+// the transaction id is its index in the local log, the record is queued in
+// the cap outbox and the oldest queued event is inserted straight away
 async fn add_record(
   caller: Principal,
   op: Operation,
@@ -680,44 +1533,783 @@ async fn add_record(
   timestamp: u64,
   status: TransactionStatus,
 ) -> TxReceipt {
-  insert_into_cap(Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<
-    TypedEvent<DIP20Details>,
-  >::into(
-    TxRecord {
-      caller: Some(caller),
-      index: Nat::from(0),
-      from,
-      to,
-      amount: Nat::from(amount),
-      fee: Nat::from(fee),
-      timestamp: Int::from(timestamp),
-      status,
-      operation: op,
-    },
-  ))))
-  .await
+  let record = _log_record(caller, op, from, to, amount, fee, timestamp, status);
+  _flush_cap_outbox(1, false).await;
+  Ok(record.index)
+}
+
+fn _log_record(
+  caller: Principal,
+  op: Operation,
+  from: Principal,
+  to: Principal,
+  amount: Nat,
+  fee: Nat,
+  timestamp: u64,
+  status: TransactionStatus,
+) -> TxRecord {
+  let id = memory::transaction_count();
+  let record = TxRecord {
+    caller: Some(caller),
+    index: Nat::from(id),
+    from,
+    to,
+    amount,
+    fee,
+    timestamp: Int::from(timestamp),
+    status,
+    operation: op,
+  };
+  memory::append_transaction(&record);
+  USER_TRANSACTIONS.with(|u| _index_record(&mut u.borrow_mut(), id, &record));
+  CAP_OUTBOX.with(|o| o.borrow_mut().pending.push_back(id));
+  record
+}
+
+fn _index_record(user_transactions: &mut HashMap<Principal, Vec<u64>>, id: u64, record: &TxRecord) {
+  let mut users = vec![record.from, record.to];
+  users.extend(record.caller);
+  for user in users {
+    let ids = user_transactions.entry(user).or_insert_with(Vec::new);
+    if ids.last() != Some(&id) {
+      ids.push(id);
+    }
+  }
+}
+
+#[heartbeat]
+async fn heartbeat() {
+  _flush_cap_outbox(CAP_FLUSH_BATCH, false).await;
 }
 
-pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
-  let mut tx_log = TXLOG.with(|t| t.take());
-  if let Some(failed_ie) = tx_log.ie_records.pop_front() {
-    let _ = insert_into_cap_priv(failed_ie).await;
+// inserts up to limit events into cap, oldest first, stopping at the first
+// failure so cap keeps the local order. Unless forced, nothing is inserted
+// while backing off from a failure
+async fn _flush_cap_outbox(limit: usize, force: bool) {
+  let lease = match _take_cap_flush_lease(ic::time(), force) {
+    Some(lease) => lease,
+    None => return,
+  };
+
+  for _ in 0..limit {
+    let (id, event) = match _next_cap_event() {
+      Some(next) => next,
+      None => break,
+    };
+    match _history_sink().insert(event).await {
+      Ok(cap_id) => {
+        match id {
+          Some(id) => CAP_OUTBOX.with(|o| {
+            let mut outbox = o.borrow_mut();
+            outbox.pending.pop_front();
+            outbox.cap_ids.insert(id, cap_id);
+          }),
+          None => TXLOG.with(|t| {
+            t.borrow_mut().ie_records.pop_front();
+          }),
+        }
+        CAP_OUTBOX.with(|o| {
+          let mut outbox = o.borrow_mut();
+          outbox.failures = 0;
+          outbox.next_flush = 0;
+        });
+      }
+      Err(error) => {
+        _cap_insert_failed(ic::time(), error);
+        break;
+      }
+    }
   }
-  insert_into_cap_priv(ie).await
+
+  _release_cap_flush_lease(lease);
 }
 
-async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
-  let insert_res = insert(ie.clone())
+// returns when the lease expires, none while another flush holds it or,
+// unless forced, while backing off from a failure
+fn _take_cap_flush_lease(now: u64, force: bool) -> Option<u64> {
+  let backing_off = CAP_OUTBOX.with(|o| now < o.borrow().next_flush);
+  if backing_off && !force {
+    return None;
+  }
+  CAP_FLUSH_LEASE.with(|l| {
+    let mut lease = l.borrow_mut();
+    if now < *lease {
+      return None;
+    }
+    *lease = now + CAP_FLUSH_LEASE_TIME;
+    Some(*lease)
+  })
+}
+
+// a flush that outlived its lease leaves the newer lease alone
+fn _release_cap_flush_lease(lease: u64) {
+  CAP_FLUSH_LEASE.with(|l| {
+    let mut current = l.borrow_mut();
+    if *current == lease {
+      *current = 0;
+    }
+  });
+}
+
+fn _cap_insert_failed(now: u64, error: String) {
+  CAP_OUTBOX.with(|o| {
+    let mut outbox = o.borrow_mut();
+    outbox.failures = outbox.failures.saturating_add(1);
+    let backoff = CAP_BACKOFF_BASE
+      .saturating_mul(1 << (outbox.failures - 1).min(20))
+      .min(CAP_BACKOFF_MAX);
+    outbox.next_flush = now + backoff;
+    outbox.last_error = Some(error);
+  });
+}
+
+// the oldest event waiting for cap, with its local transaction id
+// unless it was queued before the outbox existed
+fn _next_cap_event() -> Option<(Option<u64>, IndefiniteEvent)> {
+  if let Some(event) = TXLOG.with(|t| t.borrow().ie_records.front().cloned()) {
+    return Some((None, event));
+  }
+  let id = CAP_OUTBOX.with(|o| o.borrow().pending.front().copied())?;
+  let record = memory::transaction(id)?;
+  let event = Into::<IndefiniteEvent>::into(Into::<Event>::into(
+    Into::<TypedEvent<DIP20Details>>::into(CapTxRecord::from(record)),
+  ));
+  Some((Some(id), event))
+}
+
+fn _cap_outbox_status() -> CapOutboxStatus {
+  let legacy = TXLOG.with(|t| t.borrow().ie_records.len() as u64);
+  CAP_OUTBOX.with(|o| {
+    let outbox = o.borrow();
+    CapOutboxStatus {
+      depth: legacy + outbox.pending.len() as u64,
+      inserted: outbox.cap_ids.len() as u64,
+      failures: outbox.failures,
+      next_flush: outbox.next_flush,
+      last_error: outbox.last_error.clone(),
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use ic_kit::{mock_principals, MockContext};
+
+  use super::*;
+
+  fn before_each() -> &'static mut MockContext {
+    let ctx = MockContext::new()
+      .with_caller(mock_principals::alice())
+      .inject();
+    init(
+      "".to_string(),
+      "Token".to_string(),
+      "TKN".to_string(),
+      8,
+      Nat::from(1_000),
+      mock_principals::alice(),
+      Nat::from(1),
+      mock_principals::john(),
+      Principal::anonymous(),
+      Some(HistorySinkKind::Memory),
+      Some(mock_principals::xtc()),
+    );
+    ctx
+  }
+
+  #[async_std::test]
+  async fn test_transfer() {
+    before_each();
+
+    assert_eq!(
+      transfer(mock_principals::bob(), Nat::from(100)).await,
+      Ok(Nat::from(1))
+    );
+    assert_eq!(
+      transfer(mock_principals::bob(), Nat::from(1_000)).await,
+      Err(TxError::InsufficientBalance)
+    );
+
+    assert_eq!(balance_of(mock_principals::alice()), Nat::from(899));
+    assert_eq!(balance_of(mock_principals::bob()), Nat::from(100));
+    assert_eq!(balance_of(mock_principals::john()), Nat::from(1));
+  }
+
+  #[async_std::test]
+  async fn test_approve_and_transfer_from() {
+    let ctx = before_each();
+
+    assert_eq!(
+      approve(mock_principals::bob(), Nat::from(50)).await,
+      Ok(Nat::from(1))
+    );
+    assert_eq!(
+      allowance(mock_principals::alice(), mock_principals::bob()),
+      Nat::from(51)
+    );
+
+    ctx.update_caller(mock_principals::bob());
+    assert_eq!(
+      transfer_from(
+        mock_principals::alice(),
+        mock_principals::bob(),
+        Nat::from(50)
+      )
+      .await,
+      Ok(Nat::from(2))
+    );
+    assert_eq!(
+      transfer_from(
+        mock_principals::alice(),
+        mock_principals::bob(),
+        Nat::from(1)
+      )
+      .await,
+      Err(TxError::InsufficientAllowance)
+    );
+
+    assert_eq!(
+      allowance(mock_principals::alice(), mock_principals::bob()),
+      Nat::from(0)
+    );
+    assert_eq!(balance_of(mock_principals::alice()), Nat::from(948));
+    assert_eq!(balance_of(mock_principals::bob()), Nat::from(50));
+    assert_eq!(balance_of(mock_principals::john()), Nat::from(2));
+  }
+
+  #[async_std::test]
+  async fn test_mint_and_burn() {
+    let ctx = before_each();
+
+    assert_eq!(
+      mint(mock_principals::bob(), Nat::from(500)).await,
+      Ok(Nat::from(1))
+    );
+
+    ctx.update_caller(mock_principals::bob());
+    assert_eq!(burn(Nat::from(200)).await, Ok(Nat::from(2)));
+    assert_eq!(
+      burn(Nat::from(1_000)).await,
+      Err(TxError::InsufficientBalance)
+    );
+
+    assert_eq!(balance_of(mock_principals::bob()), Nat::from(300));
+    assert_eq!(total_supply(), Nat::from(1_300));
+  }
+
+  #[test]
+  fn test_ownership_transfer() {
+    let ctx = before_each();
+
+    propose_owner(mock_principals::bob());
+    assert_eq!(pending_owner(), Some(mock_principals::bob()));
+    assert_eq!(owner(), mock_principals::alice());
+
+    // only the proposed owner can accept
+    ctx.update_caller(mock_principals::john());
+    assert!(_is_pending_owner().is_err());
+
+    ctx.update_caller(mock_principals::bob());
+    assert!(_is_pending_owner().is_ok());
+    accept_ownership();
+    assert_eq!(owner(), mock_principals::bob());
+    assert_eq!(pending_owner(), None);
+    assert!(_is_owner().is_ok());
+
+    ctx.update_caller(mock_principals::alice());
+    assert!(_is_owner().is_err());
+  }
+
+  #[test]
+  fn test_cancel_ownership_transfer() {
+    let ctx = before_each();
+
+    propose_owner(mock_principals::bob());
+    cancel_ownership_transfer();
+    assert_eq!(pending_owner(), None);
+
+    ctx.update_caller(mock_principals::bob());
+    assert!(_is_pending_owner().is_err());
+    assert_eq!(owner(), mock_principals::alice());
+  }
+
+  #[async_std::test]
+  async fn test_minters() {
+    let ctx = before_each();
+
+    assert_eq!(get_minters(), vec![mock_principals::alice()]);
+
+    add_minter(mock_principals::bob());
+    add_minter(mock_principals::bob());
+    assert_eq!(
+      get_minters(),
+      vec![mock_principals::alice(), mock_principals::bob()]
+    );
+
+    ctx.update_caller(mock_principals::bob());
+    assert!(_is_minter().is_ok());
+    assert!(mint(mock_principals::bob(), Nat::from(10)).await.is_ok());
+
+    ctx.update_caller(mock_principals::alice());
+    remove_minter(mock_principals::alice());
+    assert_eq!(get_minters(), vec![mock_principals::bob()]);
+    assert!(_is_minter().is_err());
+    // removing itself from the minters keeps the owner in charge
+    assert!(_is_owner().is_ok());
+  }
+
+  #[async_std::test]
+  async fn test_freeze() {
+    let ctx = before_each();
+
+    freeze(mock_principals::bob());
+    freeze(mock_principals::bob());
+
+    assert!(is_frozen(mock_principals::bob()));
+    assert_eq!(
+      transfer(mock_principals::bob(), Nat::from(100)).await,
+      Err(TxError::AccountFrozen)
+    );
+    assert_eq!(
+      approve(mock_principals::bob(), Nat::from(100)).await,
+      Err(TxError::AccountFrozen)
+    );
+
+    ctx.update_caller(mock_principals::bob());
+    assert_eq!(burn(Nat::from(0)).await, Err(TxError::AccountFrozen));
+
+    ctx.update_caller(mock_principals::alice());
+    unfreeze(mock_principals::bob());
+
+    assert!(get_frozen().is_empty());
+    assert_eq!(get_freeze_log(0, 10).len(), 2);
+    assert!(transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .is_ok());
+  }
+
+  #[test]
+  fn test_factory() {
+    let ctx = before_each();
+
+    // only the factory given at init can freeze besides the owner
+    ctx.update_caller(mock_principals::bob());
+    assert!(_is_owner_or_factory().is_err());
+    ctx.update_caller(mock_principals::xtc());
+    assert!(_is_owner_or_factory().is_ok());
+
+    ctx.update_caller(mock_principals::alice());
+    set_factory(None);
+    ctx.update_caller(mock_principals::xtc());
+    assert!(_is_owner_or_factory().is_err());
+  }
+
+  #[async_std::test]
+  async fn test_mint_cap() {
+    let ctx = before_each();
+
+    assert!(get_mint_cap().headroom.is_none());
+
+    assert!(set_reserve_attester(mock_principals::alice()).is_err());
+    add_minter(mock_principals::bob());
+    assert!(set_reserve_attester(mock_principals::bob()).is_err());
+    assert!(_is_reserve_attester().is_err());
+    set_reserve_attester(mock_principals::xtc()).unwrap();
+    assert_eq!(
+      get_mint_cap().reserve_attester,
+      Some(mock_principals::xtc())
+    );
+    assert!(_is_reserve_attester().is_err());
+
+    ctx.update_caller(mock_principals::xtc());
+    assert!(_is_reserve_attester().is_ok());
+    let status = attest_reserve(Nat::from(1_500));
+    assert_eq!(status.headroom, Some(Nat::from(500)));
+    assert_eq!(status.attester, Some(mock_principals::xtc()));
+
+    ctx.update_caller(mock_principals::alice());
+    assert_eq!(
+      mint(mock_principals::bob(), Nat::from(501)).await,
+      Err(TxError::MintCapExceeded)
+    );
+    assert!(mint(mock_principals::bob(), Nat::from(500)).await.is_ok());
+
+    ctx.update_caller(mock_principals::xtc());
+    let status = attest_reserve(Nat::from(1_000));
+    assert_eq!(status.headroom, Some(Nat::from(0)));
+    assert_eq!(status.rejections, 1);
+
+    // an attester made a minter can no longer attest
+    ctx.update_caller(mock_principals::alice());
+    add_minter(mock_principals::xtc());
+    ctx.update_caller(mock_principals::xtc());
+    assert!(_is_reserve_attester().is_err());
+  }
+
+  #[async_std::test]
+  async fn test_snapshots() {
+    before_each();
+
+    assert_eq!(balance_of_at(mock_principals::alice(), 1), None);
+
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    assert_eq!(take_snapshot(), 1);
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    assert_eq!(take_snapshot(), 2);
+    mint(mock_principals::xtc(), Nat::from(10)).await.unwrap();
+
+    assert_eq!(
+      balance_of_at(mock_principals::bob(), 1),
+      Some(Nat::from(100))
+    );
+    assert_eq!(
+      balance_of_at(mock_principals::bob(), 2),
+      Some(Nat::from(200))
+    );
+    assert_eq!(balance_of_at(mock_principals::xtc(), 2), Some(Nat::from(0)));
+    assert_eq!(balance_of(mock_principals::xtc()), Nat::from(10));
+
+    let mut expected = vec![
+      (mock_principals::alice(), Nat::from(899)),
+      (mock_principals::bob(), Nat::from(100)),
+      (mock_principals::john(), Nat::from(1)),
+    ];
+    expected.sort();
+    assert_eq!(get_snapshot_balances(1, None, 10), expected);
+    assert_eq!(
+      get_snapshot_balances(1, Some(expected[0].0), 1),
+      expected[1..2].to_vec()
+    );
+    assert!(get_snapshot_balances(1, Some(expected[2].0), 10).is_empty());
+    assert!(get_snapshot_balances(3, None, 10).is_empty());
+    assert_eq!(get_snapshots()[1].history_index, 3);
+  }
+
+  #[async_std::test]
+  async fn test_holders() {
+    before_each();
+
+    transfer(mock_principals::xtc(), Nat::from(100))
+      .await
+      .unwrap();
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+
+    let mut tied = vec![mock_principals::bob(), mock_principals::xtc()];
+    tied.sort();
+    let top = vec![
+      (mock_principals::alice(), Nat::from(798)),
+      (tied[0], Nat::from(100)),
+      (tied[1], Nat::from(100)),
+      (mock_principals::john(), Nat::from(2)),
+    ];
+    assert_eq!(get_top_holders(None, 10), top);
+    assert_eq!(get_top_holders(None, 1), top[..1].to_vec());
+    assert_eq!(get_top_holders(Some(top[0].clone()), 2), top[1..3].to_vec());
+    assert!(get_top_holders(Some(top[3].clone()), 10).is_empty());
+
+    let mut by_principal = top.clone();
+    by_principal.sort();
+    assert_eq!(get_holders_by_principal(None, 10), by_principal);
+    assert_eq!(
+      get_holders_by_principal(Some(by_principal[0].0), 2),
+      by_principal[1..3].to_vec()
+    );
+    assert!(get_holders_by_principal(Some(by_principal[3].0), 10).is_empty());
+
+    // the original paging by offset keeps the largest balances first
+    assert_eq!(get_holders(0, 10), top);
+    assert_eq!(get_holders(1, 2), top[1..3].to_vec());
+    assert!(get_holders(4, 10).is_empty());
+
+    // an emptied balance leaves both indexes
+    _transfer(
+      mock_principals::john(),
+      mock_principals::alice(),
+      Nat::from(2),
+    );
+    assert_eq!(get_holders_by_principal(None, 10).len(), 3);
+    assert_eq!(get_top_holders(None, 10).len(), 3);
+
+    // so does a balance set to zero
+    _balance_ins(mock_principals::bob(), Nat::from(0));
+    assert_eq!(get_holders_by_principal(None, 10).len(), 2);
+    assert_eq!(get_top_holders(None, 10).len(), 2);
+  }
+
+  #[test]
+  fn test_holders_page_limit() {
+    before_each();
+
+    for i in 0..MAX_HOLDERS_PAGE as u8 + 5 {
+      _balance_ins(Principal::from_slice(&[i]), Nat::from(1));
+    }
+
+    assert_eq!(
+      get_holders_by_principal(None, usize::MAX).len(),
+      MAX_HOLDERS_PAGE
+    );
+    assert_eq!(get_top_holders(None, usize::MAX).len(), MAX_HOLDERS_PAGE);
+  }
+
+  #[async_std::test]
+  async fn test_batch_transfer() {
+    before_each();
+    flush_cap_outbox().await;
+
+    assert_eq!(
+      batch_transfer(vec![
+        (mock_principals::bob(), Nat::from(500)),
+        (mock_principals::xtc(), Nat::from(499)),
+      ])
+      .await,
+      Err(TxError::InsufficientBalance)
+    );
+    assert_eq!(
+      batch_transfer(vec![
+        (mock_principals::bob(), Nat::from(500)),
+        (mock_principals::xtc(), Nat::from(498)),
+      ])
+      .await,
+      Ok(vec![Nat::from(1), Nat::from(2)])
+    );
+
+    assert_eq!(balance_of(mock_principals::alice()), Nat::from(0));
+    assert_eq!(balance_of(mock_principals::bob()), Nat::from(500));
+    assert_eq!(balance_of(mock_principals::xtc()), Nat::from(498));
+    assert_eq!(balance_of(mock_principals::john()), Nat::from(2));
+    assert_eq!(history_size(), 3);
+    // the whole batch reached the history sink
+    assert_eq!(history::memory_events().len(), 3);
+    assert_eq!(_cap_outbox_status().depth, 0);
+  }
+
+  #[async_std::test]
+  async fn test_permit() {
+    use ed25519_dalek::Signer;
+
+    let ctx = before_each();
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let keypair = ed25519_dalek::Keypair { secret, public };
+    let owner = PermitKey::from_bytes(public.as_bytes()).unwrap().owner();
+    let spender = mock_principals::bob();
+    transfer(owner, Nat::from(100)).await.unwrap();
+
+    let sign = |value: u64, nonce: u64| {
+      let message =
+        permit::permit_message(ic::id(), owner, spender, &Nat::from(value), u64::MAX, nonce);
+      keypair.sign(&message).to_bytes().to_vec()
+    };
+
+    // submitted by someone other than the owner
+    ctx.update_caller(mock_principals::john());
+    permit(
+      public.as_bytes().to_vec(),
+      spender,
+      Nat::from(50),
+      u64::MAX,
+      0,
+      sign(50, 0),
+    )
     .await
-    .map(|tx_id| Nat::from(tx_id))
-    .map_err(|error| TxError::Other(format!("Inserting into cap failed with error: {:?}", error)));
+    .unwrap();
+    assert_eq!(allowance(owner, spender), Nat::from(51));
+    assert_eq!(balance_of(owner), Nat::from(99));
+    assert_eq!(get_permit_nonce(owner), 1);
 
-  if insert_res.is_err() {
-    TXLOG.with(|t| {
-      let mut tx_log = t.borrow_mut();
-      tx_log.ie_records.push_back(ie.clone());
-    });
+    // a used nonce and a signature over another value are rejected
+    assert!(permit(
+      public.as_bytes().to_vec(),
+      spender,
+      Nat::from(50),
+      u64::MAX,
+      0,
+      sign(50, 0),
+    )
+    .await
+    .is_err());
+    assert_eq!(
+      permit(
+        public.as_bytes().to_vec(),
+        spender,
+        Nat::from(80),
+        u64::MAX,
+        1,
+        sign(50, 1),
+      )
+      .await,
+      Err(TxError::Unauthorized)
+    );
+    assert_eq!(get_permit_nonce(owner), 1);
+
+    ctx.update_caller(spender);
+    transfer_from(owner, spender, Nat::from(50)).await.unwrap();
+    assert_eq!(balance_of(spender), Nat::from(50));
+    assert_eq!(allowance(owner, spender), Nat::from(0));
+
+    // an owner that cannot pay the fee keeps its nonce
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[8; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let keypair = ed25519_dalek::Keypair { secret, public };
+    let owner = PermitKey::from_bytes(public.as_bytes()).unwrap().owner();
+    let message = permit::permit_message(ic::id(), owner, spender, &Nat::from(50), u64::MAX, 0);
+    assert_eq!(
+      permit(
+        public.as_bytes().to_vec(),
+        spender,
+        Nat::from(50),
+        u64::MAX,
+        0,
+        keypair.sign(&message).to_bytes().to_vec(),
+      )
+      .await,
+      Err(TxError::InsufficientBalance)
+    );
+    assert_eq!(get_permit_nonce(owner), 0);
   }
 
-  insert_res
+  #[async_std::test]
+  async fn test_history_sink() {
+    before_each();
+
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    let status = flush_cap_outbox().await;
+
+    assert_eq!(status.depth, 0);
+    assert_eq!(status.inserted, 2);
+    assert_eq!(history::memory_events().len(), 2);
+    assert_eq!(get_transactions(0, 10).len(), 2);
+    assert_eq!(get_cap_transaction_id(1), Some(1));
+    assert_eq!(get_cap_transaction_id(2), None);
+  }
+
+  #[async_std::test]
+  async fn test_upgrade_keeps_transactions() {
+    before_each();
+
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    pre_upgrade();
+    USER_TRANSACTIONS.with(|u| u.borrow_mut().clear());
+    post_upgrade();
+
+    assert_eq!(get_transactions(0, 10).len(), 2);
+    assert_eq!(get_transaction(1).unwrap().to, mock_principals::bob());
+    let user_transactions = get_user_transactions(mock_principals::bob(), None);
+    assert_eq!(user_transactions.transactions.len(), 1);
+    assert_eq!(balance_of(mock_principals::bob()), Nat::from(100));
+
+    // the log keeps growing after the upgrade
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    assert_eq!(get_transaction(2).unwrap().index, Nat::from(2));
+  }
+
+  #[test]
+  fn test_cap_flush_lease() {
+    let lease = _take_cap_flush_lease(10, false).unwrap();
+    assert_eq!(_take_cap_flush_lease(11, false), None);
+    assert_eq!(_take_cap_flush_lease(11, true), None);
+    _release_cap_flush_lease(lease);
+    let lease = _take_cap_flush_lease(11, false).unwrap();
+
+    // never released, as when the flush callback traps
+    let expired = lease;
+    assert_eq!(_take_cap_flush_lease(expired - 1, false), None);
+    let lease = _take_cap_flush_lease(expired, false).unwrap();
+    _release_cap_flush_lease(expired);
+    assert_eq!(_take_cap_flush_lease(expired + 1, false), None);
+    _release_cap_flush_lease(lease);
+    assert!(_take_cap_flush_lease(expired + 1, false).is_some());
+  }
+
+  #[test]
+  fn test_cap_backoff() {
+    _cap_insert_failed(100, "down".to_string());
+    let next_flush = 100 + CAP_BACKOFF_BASE;
+    assert_eq!(CAP_OUTBOX.with(|o| o.borrow().next_flush), next_flush);
+    assert_eq!(_take_cap_flush_lease(next_flush - 1, false), None);
+    let lease = _take_cap_flush_lease(next_flush - 1, true).unwrap();
+    _release_cap_flush_lease(lease);
+
+    // doubles with each failure up to the maximum
+    _cap_insert_failed(100, "down".to_string());
+    assert_eq!(
+      CAP_OUTBOX.with(|o| o.borrow().next_flush),
+      100 + 2 * CAP_BACKOFF_BASE
+    );
+    for _ in 0..30 {
+      _cap_insert_failed(100, "down".to_string());
+    }
+    let outbox = CAP_OUTBOX.with(|o| o.borrow().clone());
+    assert_eq!(outbox.failures, 32);
+    assert_eq!(outbox.next_flush, 100 + CAP_BACKOFF_MAX);
+    assert_eq!(outbox.last_error, Some("down".to_string()));
+  }
+
+  #[async_std::test]
+  async fn test_cap_failure_keeps_events() {
+    before_each();
+    flush_cap_outbox().await;
+
+    history::fail_memory_inserts(1);
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    let status = _cap_outbox_status();
+    assert_eq!(status.depth, 1);
+    assert_eq!(status.failures, 1);
+    assert!(status.next_flush > ic::time());
+    assert!(status.last_error.is_some());
+
+    // backing off, the next event queues behind the failed one
+    transfer(mock_principals::bob(), Nat::from(100))
+      .await
+      .unwrap();
+    assert_eq!(_cap_outbox_status().depth, 2);
+
+    let status = flush_cap_outbox().await;
+    assert_eq!(status.depth, 0);
+    assert_eq!(status.failures, 0);
+    assert_eq!(status.next_flush, 0);
+    assert_eq!(get_cap_transaction_id(1), Some(1));
+    assert_eq!(get_cap_transaction_id(2), Some(2));
+  }
+
+  #[async_std::test]
+  async fn test_cap_flush_batch() {
+    before_each();
+    flush_cap_outbox().await;
+    let inserted = _cap_outbox_status().inserted;
+
+    for _ in 0..CAP_FLUSH_BATCH + 5 {
+      _log_record(
+        mock_principals::alice(),
+        Operation::Transfer,
+        mock_principals::alice(),
+        mock_principals::bob(),
+        Nat::from(0),
+        Nat::from(0),
+        0,
+        TransactionStatus::Succeeded,
+      );
+    }
+    let status = flush_cap_outbox().await;
+    assert_eq!(status.inserted, inserted + CAP_FLUSH_BATCH as u64);
+    assert_eq!(status.depth, 5);
+
+    let status = flush_cap_outbox().await;
+    assert_eq!(status.inserted, inserted + CAP_FLUSH_BATCH as u64 + 5);
+    assert_eq!(status.depth, 0);
+  }
 }
@@ -0,0 +1,169 @@
+/**
+* Module     : memory.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+// stable memory is split by the memory manager, the upgrade state is written
+// to its own region on every upgrade while the transaction log is appended to
+// in place and never copied to the heap
+use crate::TxRecord;
+use candid::{decode_args, encode_args};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableLog, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+type VirtualStableMemory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADE_STATE: MemoryId = MemoryId::new(0);
+const TRANSACTIONS_INDEX: MemoryId = MemoryId::new(1);
+const TRANSACTIONS_DATA: MemoryId = MemoryId::new(2);
+const WASM_PAGE_SIZE: u64 = 65536;
+// the memory manager header starts with this magic
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+struct StoredTxRecord(TxRecord);
+
+impl Storable for StoredTxRecord {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    Cow::Owned(encode_args((&self.0,)).unwrap())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    let (record,): (TxRecord,) = decode_args(&bytes).unwrap();
+    StoredTxRecord(record)
+  }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static TRANSACTIONS: RefCell<StableLog<StoredTxRecord, VirtualStableMemory, VirtualStableMemory>> =
+        MEMORY_MANAGER.with(|m| {
+            let manager = m.borrow();
+            RefCell::new(
+                StableLog::init(manager.get(TRANSACTIONS_INDEX), manager.get(TRANSACTIONS_DATA))
+                    .expect("Transaction log memory is corrupted"),
+            )
+        });
+}
+
+// tokens last upgraded before the memory manager hold their state at offset
+// zero, this must be checked before the memory manager claims the memory
+pub(crate) fn holds_legacy_state() -> bool {
+  let raw = DefaultMemoryImpl::default();
+  if raw.size() == 0 {
+    return false;
+  }
+  let mut magic = [0; 3];
+  raw.read(0, &mut magic);
+  &magic != MANAGER_MAGIC
+}
+
+pub(crate) fn legacy_state() -> Vec<u8> {
+  let raw = DefaultMemoryImpl::default();
+  let mut bytes = vec![0; (raw.size() * WASM_PAGE_SIZE) as usize];
+  raw.read(0, &mut bytes);
+  bytes
+}
+
+// the length is written ahead of the bytes
+pub(crate) fn write_upgrade_state(bytes: &[u8]) -> Result<(), String> {
+  let memory = MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADE_STATE));
+  let length = bytes.len() as u64 + 8;
+  let pages = (length + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+  if pages > memory.size() && memory.grow(pages - memory.size()) < 0 {
+    return Err("Stable memory is full".to_string());
+  }
+  memory.write(0, &(bytes.len() as u64).to_le_bytes());
+  memory.write(8, bytes);
+  Ok(())
+}
+
+pub(crate) fn read_upgrade_state() -> Vec<u8> {
+  let memory = MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADE_STATE));
+  if memory.size() == 0 {
+    return Vec::new();
+  }
+  let mut length = [0; 8];
+  memory.read(0, &mut length);
+  let mut bytes = vec![0; u64::from_le_bytes(length) as usize];
+  memory.read(8, &mut bytes);
+  bytes
+}
+
+// returns the id of the transaction, its index in the log
+pub(crate) fn append_transaction(record: &TxRecord) -> u64 {
+  TRANSACTIONS.with(|t| {
+    t.borrow()
+      .append(&StoredTxRecord(record.clone()))
+      .expect("Stable memory is full")
+  })
+}
+
+pub(crate) fn transaction(id: u64) -> Option<TxRecord> {
+  TRANSACTIONS.with(|t| t.borrow().get(id).map(|stored| stored.0))
+}
+
+pub(crate) fn transaction_count() -> u64 {
+  TRANSACTIONS.with(|t| t.borrow().len())
+}
+
+pub(crate) fn transactions(start: u64, limit: usize) -> Vec<TxRecord> {
+  let end = start.saturating_add(limit as u64).min(transaction_count());
+  (start..end).filter_map(transaction).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use candid::{Int, Nat};
+  use cap_std::dip20::{Operation, TransactionStatus};
+  use ic_kit::mock_principals;
+
+  fn record(index: u64) -> TxRecord {
+    TxRecord {
+      caller: Some(mock_principals::alice()),
+      index: Nat::from(index),
+      from: mock_principals::alice(),
+      to: mock_principals::bob(),
+      amount: Nat::from(10),
+      fee: Nat::from(1),
+      timestamp: Int::from(0),
+      status: TransactionStatus::Succeeded,
+      operation: Operation::Transfer,
+    }
+  }
+
+  #[test]
+  fn test_transaction_log() {
+    for index in 0..3 {
+      assert_eq!(append_transaction(&record(index)), index);
+    }
+
+    assert_eq!(transaction_count(), 3);
+    assert_eq!(transaction(1).unwrap().index, Nat::from(1));
+    assert!(transaction(3).is_none());
+    let page = transactions(1, 10);
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[1].index, Nat::from(2));
+    assert!(transactions(5, 10).is_empty());
+  }
+
+  #[test]
+  fn test_upgrade_state() {
+    assert!(read_upgrade_state().is_empty());
+
+    // a shorter state replaces a longer one completely
+    write_upgrade_state(&vec![1; WASM_PAGE_SIZE as usize]).unwrap();
+    write_upgrade_state(&[2; 4]).unwrap();
+    assert_eq!(read_upgrade_state(), vec![2; 4]);
+
+    // the log does not overlap the upgrade state
+    append_transaction(&record(0));
+    assert_eq!(read_upgrade_state(), vec![2; 4]);
+    assert_eq!(transaction(0).unwrap().index, Nat::from(0));
+  }
+}
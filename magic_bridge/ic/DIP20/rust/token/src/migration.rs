@@ -0,0 +1,260 @@
+/**
+* Module     : migration.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+// the state kept across upgrades is written as a versioned envelope, tokens
+// deployed before versioning hold a bare tuple of the stats, balances,
+// allowances and tx log
+use crate::memory;
+use crate::{
+  Allowances, Balances, CapOutbox, FreezeList, MintCap, Minters, PermitNonces, Snapshots,
+  StableState, StatsData, TxLog,
+};
+use candid::de::IDLDeserialize;
+use candid::{encode_args, CandidType, Deserialize, Nat};
+use cap_sdk::IndefiniteEvent;
+use ic_kit::{ic, Principal};
+use std::collections::VecDeque;
+
+// written ahead of the envelope, the bare tuple starts with the candid magic
+const ENVELOPE_MAGIC: &[u8; 4] = b"VSTA";
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+// the layouts as deployed, frozen so later fields do not change them
+#[derive(CandidType, Deserialize)]
+pub(crate) struct StatsDataV0 {
+  logo: String,
+  name: String,
+  symbol: String,
+  decimals: u8,
+  total_supply: Nat,
+  owner: Principal,
+  fee: Nat,
+  fee_to: Principal,
+  history_size: usize,
+  deploy_time: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TxLogV0 {
+  ie_records: VecDeque<IndefiniteEvent>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct StableStateV0 {
+  stats: StatsDataV0,
+  balances: Balances,
+  allows: Allowances,
+  tx_log: TxLogV0,
+}
+
+// the layout of version 1, frozen so a change to the live state needs a
+// new version and a migration
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct StableStateV1 {
+  stats: StatsData,
+  balances: Balances,
+  allows: Allowances,
+  tx_log: TxLog,
+  minters: Minters,
+  cap_outbox: CapOutbox,
+  freeze_list: FreezeList,
+  mint_cap: MintCap,
+  snapshots: Snapshots,
+  permit_nonces: PermitNonces,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) enum VersionedState {
+  V0(StableStateV0),
+  V1(StableStateV1),
+}
+
+impl VersionedState {
+  pub(crate) fn current(state: StableState) -> Self {
+    VersionedState::V1(state.into())
+  }
+
+  // applies every migration from the stored version up to the current one
+  pub(crate) fn migrate(self) -> Result<StableState, String> {
+    match self {
+      VersionedState::V0(state) => VersionedState::V1(migrate_v0_to_v1(state)?).migrate(),
+      VersionedState::V1(state) => Ok(state.into()),
+    }
+  }
+}
+
+fn migrate_v0_to_v1(state: StableStateV0) -> Result<StableStateV1, String> {
+  let stats = state.stats;
+  // only the owner could mint before the minter list
+  let minters = vec![stats.owner];
+
+  Ok(StableStateV1 {
+    stats: StatsData {
+      logo: stats.logo,
+      name: stats.name,
+      symbol: stats.symbol,
+      decimals: stats.decimals,
+      total_supply: stats.total_supply,
+      owner: stats.owner,
+      fee: stats.fee,
+      fee_to: stats.fee_to,
+      history_size: stats.history_size,
+      deploy_time: stats.deploy_time,
+      pending_owner: None,
+      history_sink: None,
+      // tokens installed before the factory was stored are upgraded by their factory
+      factory: Some(ic::caller()),
+    },
+    balances: state.balances,
+    allows: state.allows,
+    // events that never reached cap are flushed ahead of the outbox
+    tx_log: TxLog {
+      ie_records: state.tx_log.ie_records,
+    },
+    minters,
+    ..StableStateV1::default()
+  })
+}
+
+impl From<StableState> for StableStateV1 {
+  fn from(state: StableState) -> Self {
+    StableStateV1 {
+      stats: state.stats,
+      balances: state.balances,
+      allows: state.allows,
+      tx_log: state.tx_log,
+      minters: state.minters,
+      cap_outbox: state.cap_outbox,
+      freeze_list: state.freeze_list,
+      mint_cap: state.mint_cap,
+      snapshots: state.snapshots,
+      permit_nonces: state.permit_nonces,
+    }
+  }
+}
+
+impl From<StableStateV1> for StableState {
+  fn from(state: StableStateV1) -> Self {
+    StableState {
+      stats: state.stats,
+      balances: state.balances,
+      allows: state.allows,
+      tx_log: state.tx_log,
+      minters: state.minters,
+      cap_outbox: state.cap_outbox,
+      freeze_list: state.freeze_list,
+      mint_cap: state.mint_cap,
+      snapshots: state.snapshots,
+      permit_nonces: state.permit_nonces,
+    }
+  }
+}
+
+pub(crate) fn encode(state: StableState) -> Result<Vec<u8>, String> {
+  let mut bytes = ENVELOPE_MAGIC.to_vec();
+  bytes.extend(encode_args((VersionedState::current(state),)).map_err(|e| e.to_string())?);
+  Ok(bytes)
+}
+
+// by the version tag, never by trying one layout after the other
+pub(crate) fn decode(bytes: &[u8]) -> Result<VersionedState, String> {
+  if bytes.starts_with(ENVELOPE_MAGIC) {
+    let mut de = IDLDeserialize::new(&bytes[ENVELOPE_MAGIC.len()..]).map_err(|e| e.to_string())?;
+    de.get_value::<VersionedState>().map_err(|e| e.to_string())
+  } else if bytes.starts_with(CANDID_MAGIC) {
+    let mut de = IDLDeserialize::new(bytes).map_err(|e| e.to_string())?;
+    Ok(VersionedState::V0(StableStateV0 {
+      stats: de.get_value().map_err(|e| e.to_string())?,
+      balances: de.get_value().map_err(|e| e.to_string())?,
+      allows: de.get_value().map_err(|e| e.to_string())?,
+      tx_log: de.get_value().map_err(|e| e.to_string())?,
+    }))
+  } else {
+    Err("Stable memory holds no known state layout".to_string())
+  }
+}
+
+pub(crate) fn store(state: StableState) -> Result<(), String> {
+  memory::write_upgrade_state(&encode(state)?)
+}
+
+// the transaction log of a legacy token was never kept, only its state at
+// offset zero of the raw stable memory is read
+pub(crate) fn restore() -> Result<StableState, String> {
+  let bytes = if memory::holds_legacy_state() {
+    memory::legacy_state()
+  } else {
+    memory::read_upgrade_state()
+  };
+  decode(&bytes)?.migrate()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ic_kit::{mock_principals, MockContext};
+  use std::collections::HashMap;
+
+  fn legacy_stats() -> StatsDataV0 {
+    StatsDataV0 {
+      logo: "".to_string(),
+      name: "Magic Token".to_string(),
+      symbol: "MGT".to_string(),
+      decimals: 18,
+      total_supply: Nat::from(1_000),
+      owner: mock_principals::alice(),
+      fee: Nat::from(1),
+      fee_to: mock_principals::john(),
+      history_size: 1,
+      deploy_time: 0,
+    }
+  }
+
+  #[test]
+  fn test_migrate_legacy_state() {
+    MockContext::new()
+      .with_caller(mock_principals::bob())
+      .inject();
+    let balances: Balances = HashMap::from([(mock_principals::alice(), Nat::from(1_000))]);
+    let bytes = encode_args((
+      legacy_stats(),
+      balances,
+      Allowances::default(),
+      TxLogV0 {
+        ie_records: VecDeque::new(),
+      },
+    ))
+    .unwrap();
+    let migrated = decode(&bytes).unwrap().migrate().unwrap();
+
+    assert_eq!(migrated.stats.owner, mock_principals::alice());
+    assert_eq!(migrated.stats.pending_owner, None);
+    assert_eq!(migrated.stats.factory, Some(mock_principals::bob()));
+    assert_eq!(
+      migrated.balances.get(&mock_principals::alice()),
+      Some(&Nat::from(1_000))
+    );
+    assert_eq!(migrated.minters, vec![mock_principals::alice()]);
+    assert!(migrated.freeze_list.frozen.is_empty());
+    assert!(migrated.snapshots.taken.is_empty());
+  }
+
+  #[test]
+  fn test_decode_by_version_tag() {
+    let mut state = StableState::default();
+    state.minters.push(mock_principals::bob());
+    let mut bytes = encode(state).unwrap();
+    let restored = decode(&bytes).unwrap().migrate().unwrap();
+    assert_eq!(restored.minters, vec![mock_principals::bob()]);
+
+    // a broken envelope is an error, not a legacy tuple
+    bytes.truncate(ENVELOPE_MAGIC.len() + 8);
+    assert!(decode(&bytes).is_err());
+
+    assert!(decode(&[0; 16]).is_err());
+  }
+}
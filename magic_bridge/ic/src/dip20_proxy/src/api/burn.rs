@@ -4,7 +4,8 @@ use ic_kit::{ic, macros::update};
 use crate::common::dip20::Dip20;
 use crate::common::tera::Tera;
 use crate::common::types::{
-    ClaimableMessage, EthereumAddr, OutgoingMessage, TokendId, TxError, TxReceipt,
+    BridgeReceipt, BridgeResult, ClaimableMessage, CompensationOutcome, CompensationPolicy,
+    EthereumAddr, Flow, HeldAction, OutgoingMessage, TokendId, TxError, TxReceipt, VolumeEntry,
 };
 use crate::proxy::{ToNat, ERC20_ADDRESS_ETH, STATE, TERA_ADDRESS};
 use ic_cdk::export::candid::{Nat, Principal};
@@ -13,7 +14,7 @@ use ic_cdk::export::candid::{Nat, Principal};
 // or should we use our magic_bridge to check if a key exists
 #[update(name = "burn")]
 #[candid_method(update, rename = "burn")]
-async fn burn(token_id: TokendId, eth_addr: EthereumAddr, amount: Nat) -> TxReceipt {
+async fn burn(token_id: TokendId, eth_addr: EthereumAddr, amount: Nat) -> BridgeResult {
     let caller = ic::caller();
     let self_id = ic::id();
 
@@ -24,8 +25,11 @@ async fn burn(token_id: TokendId, eth_addr: EthereumAddr, amount: Nat) -> TxRece
         )));
     }
 
-    let erc20_addr_hex = ERC20_ADDRESS_ETH.trim_start_matches("0x");
-    let erc20_addr_pid = Principal::from_slice(&hex::decode(erc20_addr_hex).unwrap());
+    // the fee is taken from the amount, what is left is burned and bridged
+    let fee = STATE.with(|s| s.fee_for(token_id, Flow::Burn, &amount));
+    if fee >= amount {
+        return Err(TxError::AmountTooSmall);
+    }
 
     let transfer_from = token_id
         .transfer_from(caller, self_id, amount.clone())
@@ -33,64 +37,345 @@ async fn burn(token_id: TokendId, eth_addr: EthereumAddr, amount: Nat) -> TxRece
 
     match transfer_from {
         Ok(_) => {
-            STATE.with(|s| s.add_balance(caller, token_id, amount.clone()));
-
-            let burn = token_id.burn(amount.clone()).await;
-
-            match burn {
-                Ok(burn_txn_id) => {
-                    let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
-                    let payload = [
-                        token_id.clone().to_nat(),
-                        eth_addr.clone().to_nat(),
-                        amount.clone(),
-                    ]
-                    .to_vec();
-
-                    let send_message: Result<OutgoingMessage, TxError> =
-                        tera_id.send_message(erc20_addr_pid, payload.clone()).await;
-
-                    match send_message {
-                        Ok(outgoing_message) => {
-                            STATE.with(|s| {
-                                // there could be an underflow here
-                                // like negative balance
-                                let current_balance =
-                                    s.get_balance(caller, token_id).unwrap_or(Nat::from(0));
-
-                                s.update_balance(
-                                    caller,
-                                    token_id,
-                                    current_balance - amount.clone(),
-                                );
-
-                                s.add_claimable_message(ClaimableMessage {
-                                    owner: eth_addr.clone(),
-                                    msg_hash: outgoing_message.msg_hash.clone(),
-                                    msg_key: outgoing_message.msg_key.clone(),
-                                    token: token_id.clone(),
-                                    amount: amount.clone(),
-                                });
-                            });
-                            // All correct
-                            return Ok(burn_txn_id);
-                        }
-                        // send_message error
-                        Err(_) => {
-                            return Err(TxError::Other(format!(
-                                "Sending message to L1 failed with caller {:?}!",
-                                caller.to_string()
-                            )));
-                        }
-                    }
-                }
-                // burn error
-                Err(error) => {
-                    return Err(error);
+            STATE.with(|s| s.collect_fee(token_id, fee.clone()));
+            let amount = amount - fee.clone();
+
+            let reserved =
+                STATE.with(|s| s.reserve_volume(token_id, Flow::Burn, amount.clone(), ic::time()));
+
+            let volume = match reserved {
+                Ok(volume) => volume,
+                Err(reason) => {
+                    let id = STATE.with(|s| {
+                        s.hold_operation(
+                            caller,
+                            token_id,
+                            amount,
+                            fee,
+                            HeldAction::Burn(eth_addr),
+                            reason.clone(),
+                            ic::time(),
+                        )
+                    });
+
+                    return Err(TxError::Other(format!(
+                        "Burn is held for approval as operation {}: {}",
+                        id, reason
+                    )));
                 }
             };
+
+            burn_received(
+                caller,
+                token_id,
+                eth_addr,
+                amount.clone(),
+                fee.clone(),
+                volume,
+            )
+            .await
+            .map(|txn_id| BridgeReceipt {
+                txn_id,
+                amount,
+                fee,
+            })
         }
         // transfer error
         Err(error) => Err(error),
     }
 }
+
+/// Burn tokens the proxy received from caller and send them to eth_addr,
+/// the volume counted for the burn is released if nothing is burned
+pub(crate) async fn burn_received(
+    caller: Principal,
+    token_id: TokendId,
+    eth_addr: EthereumAddr,
+    amount: Nat,
+    fee: Nat,
+    volume: VolumeEntry,
+) -> TxReceipt {
+    let burn_txn_id = match token_id.burn(amount.clone()).await {
+        Ok(burn_txn_id) => burn_txn_id,
+        Err(error) => {
+            STATE.with(|s| s.release_volume(&volume));
+
+            // nothing was burned, the tokens go back to the caller with their
+            // bridge fee, less the token fee of the transfer back
+            let fee = STATE.with(|s| s.return_collected_fee(token_id, &fee));
+            let amount = amount + fee.clone();
+            let returned = match token_id.fee().await {
+                Ok(token_fee) if token_fee < amount => token_id
+                    .transfer(caller, amount - token_fee)
+                    .await
+                    .map(|_| ()),
+                Ok(_) => Err(TxError::AmountTooSmall),
+                Err(error) => Err(error),
+            };
+
+            if let Err(transfer_error) = returned {
+                STATE.with(|s| s.collect_fee(token_id, fee));
+                return Err(TxError::Other(format!(
+                    "Burn failed with {:?} and returning the tokens failed with {:?}!",
+                    error, transfer_error
+                )));
+            }
+            return Err(error);
+        }
+    };
+
+    // the escrow holds what is burned and not yet sent to L1
+    STATE
+        .with(|s| s.credit_escrow(caller, token_id, amount.clone()))
+        .map_err(TxError::from)?;
+
+    match send_escrow(caller, token_id, eth_addr, amount.clone()).await {
+        Ok(_) => Ok(burn_txn_id),
+        Err(error) => Err(compensate(caller, eth_addr, token_id, amount, error).await),
+    }
+}
+
+/// Send amount of the escrow of caller to eth_addr and register the claimable,
+/// the escrow is debited before the call and credited back if the send fails
+pub(crate) async fn send_escrow(
+    caller: Principal,
+    token_id: TokendId,
+    eth_addr: EthereumAddr,
+    amount: Nat,
+) -> Result<OutgoingMessage, TxError> {
+    let erc20_addr_hex = ERC20_ADDRESS_ETH.trim_start_matches("0x");
+    let erc20_addr_pid = Principal::from_slice(&hex::decode(erc20_addr_hex).unwrap());
+
+    // debit before the call so a concurrent withdraw cannot send the same escrow
+    STATE
+        .with(|s| s.debit_escrow(caller, token_id, amount.clone()))
+        .map_err(TxError::from)?;
+
+    let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
+    let payload = [token_id.to_nat(), eth_addr.to_nat(), amount.clone()].to_vec();
+
+    match tera_id.send_message(erc20_addr_pid, payload).await {
+        Ok(outgoing_message) => {
+            STATE.with(|s| {
+                s.add_claimable_message(ClaimableMessage::pending(
+                    eth_addr,
+                    caller,
+                    &outgoing_message,
+                    token_id,
+                    amount,
+                    ic::time(),
+                ))
+            });
+
+            Ok(outgoing_message)
+        }
+        Err(_) => {
+            let _ = STATE.with(|s| s.credit_escrow(caller, token_id, amount));
+
+            Err(TxError::Other(format!(
+                "Sending message to L1 failed with caller {:?}!",
+                caller.to_string()
+            )))
+        }
+    }
+}
+
+/// Handle a burn whose message could not be sent, following the compensation policy
+async fn compensate(
+    caller: Principal,
+    eth_addr: EthereumAddr,
+    token_id: TokendId,
+    amount: Nat,
+    error: TxError,
+) -> TxError {
+    let reason = format!("{:?}", error);
+
+    let outcome = match STATE.with(|s| *s.compensation_policy.borrow()) {
+        // the escrow stays credited so withdraw can send the message again
+        CompensationPolicy::Retry => CompensationOutcome::Retryable,
+        CompensationPolicy::Refund => {
+            // debit before minting so the escrow cannot be withdrawn as well
+            match STATE.with(|s| s.debit_escrow(caller, token_id, amount.clone())) {
+                Ok(_) => match token_id.mint(caller, amount.clone()).await {
+                    Ok(mint_txn_id) => CompensationOutcome::Refunded(mint_txn_id),
+                    Err(mint_error) => {
+                        let _ = STATE.with(|s| s.credit_escrow(caller, token_id, amount.clone()));
+                        CompensationOutcome::RefundFailed(format!("{:?}", mint_error))
+                    }
+                },
+                Err(escrow_error) => {
+                    CompensationOutcome::RefundFailed(format!("{:?}", escrow_error))
+                }
+            }
+        }
+    };
+
+    STATE.with(|s| s.record_compensation(caller, eth_addr, token_id, amount, reason, outcome));
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext};
+
+    use super::*;
+    use crate::api::withdraw::withdraw;
+    use crate::common::dip20::TokenMetadata;
+    use crate::common::types::FeeConfig;
+
+    fn token() -> TokendId {
+        mock_principals::alice()
+    }
+
+    fn eth_addr() -> EthereumAddr {
+        Principal::from_slice(&[1; 20])
+    }
+
+    fn outgoing_message() -> OutgoingMessage {
+        OutgoingMessage {
+            msg_key: [1; 32],
+            msg_hash: String::from("a"),
+        }
+    }
+
+    fn mock_calls(send_message: Method) {
+        MockContext::new()
+            .with_caller(mock_principals::bob())
+            .with_handler(Method::new().name("name").response("Token".to_string()))
+            .with_handler(
+                Method::new()
+                    .name("transferFrom")
+                    .response::<TxReceipt>(Ok(Nat::from(1_u32))),
+            )
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Ok(Nat::from(2_u32))),
+            )
+            .with_handler(send_message.name("send_message"))
+            .inject();
+    }
+
+    fn escrow() -> Option<Nat> {
+        STATE.with(|s| s.get_balance(mock_principals::bob(), token()))
+    }
+
+    #[async_std::test]
+    async fn test_retry_send() {
+        mock_calls(
+            Method::new()
+                .response::<Result<OutgoingMessage, String>>(Err(String::from("tera is down"))),
+        );
+
+        // retry is the default policy, the burned amount waits in the escrow
+        assert!(burn(token(), eth_addr(), Nat::from(100_u32)).await.is_err());
+        assert_eq!(escrow(), Some(Nat::from(100_u32)));
+        let compensations = STATE.with(|s| s.get_compensations(0, 10));
+        assert_eq!(compensations.len(), 1);
+        assert_eq!(compensations[0].outcome, CompensationOutcome::Retryable);
+
+        // a withdraw that cannot be sent either keeps it
+        mock_calls(
+            Method::new()
+                .response::<Result<OutgoingMessage, String>>(Err(String::from("tera is down"))),
+        );
+        assert!(withdraw(token(), eth_addr(), Nat::from(40_u32))
+            .await
+            .is_err());
+        assert_eq!(escrow(), Some(Nat::from(100_u32)));
+
+        let erc20_addr_hex = ERC20_ADDRESS_ETH.trim_start_matches("0x");
+        let erc20_addr_pid = Principal::from_slice(&hex::decode(erc20_addr_hex).unwrap());
+        mock_calls(
+            Method::new()
+                .expect_arguments((
+                    erc20_addr_pid,
+                    vec![token().to_nat(), eth_addr().to_nat(), Nat::from(40_u32)],
+                ))
+                .response::<Result<OutgoingMessage, String>>(Ok(outgoing_message())),
+        );
+
+        assert_eq!(
+            withdraw(token(), eth_addr(), Nat::from(40_u32)).await,
+            Ok(outgoing_message())
+        );
+        assert_eq!(escrow(), Some(Nat::from(60_u32)));
+        let claimables = STATE.with(|s| s.get_claimable_messages(eth_addr()));
+        assert_eq!(claimables.len(), 1);
+        assert_eq!(claimables[0].amount, Nat::from(40_u32));
+
+        // more than the escrow is never sent
+        mock_calls(
+            Method::new().response::<Result<OutgoingMessage, String>>(Ok(outgoing_message())),
+        );
+        assert!(withdraw(token(), eth_addr(), Nat::from(61_u32))
+            .await
+            .is_err());
+    }
+
+    fn mock_burn_failed(transfer: Method) {
+        MockContext::new()
+            .with_caller(mock_principals::bob())
+            .with_handler(Method::new().name("name").response("Token".to_string()))
+            .with_handler(
+                Method::new()
+                    .name("transferFrom")
+                    .response::<TxReceipt>(Ok(Nat::from(1_u32))),
+            )
+            .with_handler(
+                Method::new()
+                    .name("burn")
+                    .response::<TxReceipt>(Err(TxError::InsufficientBalance)),
+            )
+            .with_handler(Method::new().name("getMetadata").response(TokenMetadata {
+                fee: Nat::from(2_u32),
+            }))
+            .with_handler(transfer.name("transfer"))
+            .inject();
+        STATE.with(|s| {
+            s.set_fee(
+                token(),
+                FeeConfig {
+                    flat: Nat::from(5_u32),
+                    bps: 0,
+                    on_mint: false,
+                },
+            )
+        });
+    }
+
+    #[async_std::test]
+    async fn test_burn_failed() {
+        // the amount and its bridge fee come back, less the token fee
+        mock_burn_failed(
+            Method::new()
+                .expect_arguments((mock_principals::bob(), Nat::from(98_u32)))
+                .response::<TxReceipt>(Ok(Nat::from(3_u32))),
+        );
+
+        // nothing was burned, so nothing is escrowed, counted or kept as fee
+        assert_eq!(
+            burn(token(), eth_addr(), Nat::from(100_u32)).await,
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(escrow(), None);
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(Some(token()), Flow::Burn)),
+            Nat::from(0_u32)
+        );
+        assert_eq!(STATE.with(|s| s.collected_fee(token())), Nat::from(0_u32));
+    }
+
+    #[async_std::test]
+    async fn test_burn_failed_refund_failed() {
+        mock_burn_failed(Method::new().response::<TxReceipt>(Err(TxError::InsufficientBalance)));
+
+        // the fee stays collected with the tokens it belongs to
+        assert!(matches!(
+            burn(token(), eth_addr(), Nat::from(100_u32)).await,
+            Err(TxError::Other(_))
+        ));
+        assert_eq!(STATE.with(|s| s.collected_fee(token())), Nat::from(5_u32));
+    }
+}
@@ -1,10 +1,11 @@
-use candid::{candid_method, Nat};
+use candid::{candid_method, Principal};
 use ic_cdk_macros::{query, update};
+use ic_kit::ic;
 
 use crate::api::admin::is_authorized;
-use crate::common::types::TokendId;
 use crate::{
-    common::types::{ClaimableMessage, EthereumAddr},
+    common::types::{AuditOutcome, ClaimableMessage, EthereumAddr, MsgHashKey},
+    common::utils::args_digest,
     proxy::STATE,
 };
 
@@ -14,12 +15,140 @@ fn claimable_get_all(eth_address: EthereumAddr) -> Vec<ClaimableMessage> {
     STATE.with(|s| s.get_claimable_messages(eth_address))
 }
 
-#[update(name = "remove_claimable", guard = "is_authorized")]
-#[candid_method(update, rename = "remove_claimable")]
-fn remove_claimable(
+/// Messages to an ETH address in any status, oldest first
+#[query(name = "get_claimables_by_eth_address")]
+#[candid_method(query, rename = "get_claimables_by_eth_address")]
+fn get_claimables_by_eth_address(
     eth_address: EthereumAddr,
-    token_id: TokendId,
-    amount: Nat,
-) -> Result<bool, String> {
-    STATE.with(|s| s.remove_claimable_message(eth_address, token_id.clone(), amount.clone()))
+    start: u64,
+    limit: u64,
+) -> Vec<ClaimableMessage> {
+    STATE.with(|s| s.get_claimables_by_eth_address(eth_address, start, limit as usize))
+}
+
+/// Messages sent by burns and withdrawals of an IC principal, oldest first
+#[query(name = "get_claimables_by_principal")]
+#[candid_method(query, rename = "get_claimables_by_principal")]
+fn get_claimables_by_principal(
+    principal: Principal,
+    start: u64,
+    limit: u64,
+) -> Vec<ClaimableMessage> {
+    STATE.with(|s| s.get_claimables_by_sender(principal, start, limit as usize))
+}
+
+/// Record the L1 transaction that claimed a message
+#[update(name = "mark_claimed", guard = "is_authorized")]
+#[candid_method(update, rename = "mark_claimed")]
+fn mark_claimed(msg_key: MsgHashKey, tx_hash: String) -> Result<(), String> {
+    let result = STATE.with(|s| s.mark_claimed(msg_key, tx_hash.clone(), ic::time()));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "mark_claimed",
+            args_digest((msg_key, tx_hash)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+#[update(name = "expire_claimable", guard = "is_authorized")]
+#[candid_method(update, rename = "expire_claimable")]
+fn expire_claimable(msg_key: MsgHashKey) -> Result<(), String> {
+    let result = STATE.with(|s| s.expire_claimable(msg_key, ic::time()));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "expire_claimable",
+            args_digest((msg_key,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Nat;
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::common::types::{ClaimStatus, OutgoingMessage};
+
+    fn add_claimable(eth_address: EthereumAddr, msg_key: MsgHashKey, created_at: u64) {
+        STATE.with(|s| {
+            s.add_claimable_message(ClaimableMessage::pending(
+                eth_address,
+                mock_principals::bob(),
+                &OutgoingMessage {
+                    msg_key,
+                    msg_hash: String::from("a"),
+                },
+                mock_principals::john(),
+                Nat::from(1_u32),
+                created_at,
+            ))
+        });
+    }
+
+    #[test]
+    fn test_mark_claimed_and_expire() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let eth_address = Principal::from_slice(&[1; 20]);
+        add_claimable(eth_address, [0; 32], 0);
+        add_claimable(eth_address, [1; 32], 1);
+
+        assert_eq!(mark_claimed([0; 32], String::from("0xabc")), Ok(()));
+        assert!(mark_claimed([0; 32], String::from("0xabc")).is_err());
+        assert_eq!(expire_claimable([1; 32]), Ok(()));
+        assert!(expire_claimable([2; 32]).is_err());
+
+        // neither is pending anymore, both are still listed with their status
+        assert!(claimable_get_all(eth_address).is_empty());
+        let statuses: Vec<ClaimStatus> = get_claimables_by_eth_address(eth_address, 0, 10)
+            .into_iter()
+            .map(|message| message.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ClaimStatus::Claimed(String::from("0xabc")),
+                ClaimStatus::Expired
+            ]
+        );
+        assert_eq!(STATE.with(|s| s.audit_log.borrow().len()), 4);
+    }
+
+    #[test]
+    fn test_claimables_paged() {
+        let eth_address = Principal::from_slice(&[1; 20]);
+
+        // created in the reverse order of their keys
+        for i in 0..5_u8 {
+            add_claimable(eth_address, [i; 32], 10 - i as u64);
+        }
+
+        let keys = |page: Vec<ClaimableMessage>| -> Vec<MsgHashKey> {
+            page.into_iter().map(|message| message.msg_key).collect()
+        };
+        assert_eq!(
+            keys(get_claimables_by_principal(mock_principals::bob(), 0, 2)),
+            vec![[4; 32], [3; 32]]
+        );
+        assert_eq!(
+            keys(get_claimables_by_principal(mock_principals::bob(), 2, 2)),
+            vec![[2; 32], [1; 32]]
+        );
+        assert_eq!(
+            keys(get_claimables_by_eth_address(eth_address, 4, 2)),
+            vec![[0; 32]]
+        );
+        assert!(get_claimables_by_principal(mock_principals::alice(), 0, 2).is_empty());
+        assert!(get_claimables_by_eth_address(Principal::from_slice(&[2; 20]), 0, 2).is_empty());
+    }
 }
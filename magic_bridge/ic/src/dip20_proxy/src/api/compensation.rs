@@ -0,0 +1,55 @@
+use ic_kit::{
+    candid::candid_method,
+    macros::{query, update},
+};
+
+use crate::api::admin::is_authorized;
+use crate::common::types::{AuditOutcome, CompensationEntry, CompensationPolicy};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+
+#[query(name = "get_compensation_policy")]
+#[candid_method(query, rename = "get_compensation_policy")]
+fn get_compensation_policy() -> CompensationPolicy {
+    STATE.with(|s| *s.compensation_policy.borrow())
+}
+
+/// Change how burns whose message could not be sent are compensated
+#[update(name = "set_compensation_policy", guard = "is_authorized")]
+#[candid_method(update, rename = "set_compensation_policy")]
+fn set_compensation_policy(policy: CompensationPolicy) {
+    STATE.with(|s| {
+        s.compensation_policy.replace(policy);
+        s.record_audit(
+            "set_compensation_policy",
+            args_digest((policy,)),
+            AuditOutcome::Ok,
+        )
+    });
+}
+
+#[query(name = "get_compensations", guard = "is_authorized")]
+#[candid_method(query, rename = "get_compensations")]
+fn get_compensations(start: u64, limit: u64) -> Vec<CompensationEntry> {
+    STATE.with(|s| s.get_compensations(start, limit as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    #[test]
+    fn test_set_compensation_policy() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        assert_eq!(get_compensation_policy(), CompensationPolicy::Retry);
+        set_compensation_policy(CompensationPolicy::Refund);
+
+        assert_eq!(get_compensation_policy(), CompensationPolicy::Refund);
+        assert_eq!(STATE.with(|s| s.audit_log.borrow().len()), 1);
+    }
+}
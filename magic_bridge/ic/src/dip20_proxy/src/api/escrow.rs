@@ -0,0 +1,43 @@
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{query, update},
+};
+
+use crate::api::admin::is_authorized;
+use crate::common::dip20::Dip20;
+use crate::common::types::{EscrowEntry, EscrowReport, TokendId};
+use crate::proxy::STATE;
+
+#[query(name = "get_escrows", guard = "is_authorized")]
+#[candid_method(query, rename = "get_escrows")]
+fn get_escrows(start: u64, limit: u64) -> Vec<EscrowEntry> {
+    STATE.with(|s| s.get_escrows(start, limit as usize))
+}
+
+/// Compare the collected fees and held burns of token_id against the balance
+/// the proxy holds, escrows are burned and not part of that balance
+#[update(name = "check_escrow_invariant", guard = "is_authorized")]
+#[candid_method(update, rename = "check_escrow_invariant")]
+async fn check_escrow_invariant(token_id: TokendId) -> Result<EscrowReport, String> {
+    let held = token_id
+        .balance_of(ic::id())
+        .await
+        .map_err(|error| format!("{:?}", error))?;
+    let (escrowed, fees, unburned) = STATE.with(|s| {
+        (
+            s.escrow_total(token_id),
+            s.collected_fee(token_id),
+            s.unburned_total(token_id),
+        )
+    });
+
+    Ok(EscrowReport {
+        token: token_id,
+        balanced: held >= fees.clone() + unburned.clone(),
+        escrowed,
+        fees,
+        unburned,
+        held,
+    })
+}
@@ -0,0 +1,194 @@
+use ic_kit::{
+    candid::{candid_method, Nat},
+    macros::{query, update},
+    Principal,
+};
+
+use crate::api::admin::is_authorized;
+use crate::api::governance::open_proposal;
+use crate::common::dip20::Dip20;
+use crate::common::types::{AuditOutcome, FeeConfig, ProposalAction, TokendId, TxError, TxReceipt};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+
+#[query(name = "get_fees")]
+#[candid_method(query, rename = "get_fees")]
+fn get_fees() -> Vec<(TokendId, FeeConfig)> {
+    STATE.with(|s| s.get_fees())
+}
+
+#[query(name = "get_treasury")]
+#[candid_method(query, rename = "get_treasury")]
+fn get_treasury() -> Option<Principal> {
+    STATE.with(|s| *s.treasury.borrow())
+}
+
+#[query(name = "get_collected_fees", guard = "is_authorized")]
+#[candid_method(query, rename = "get_collected_fees")]
+fn get_collected_fees() -> Vec<(TokendId, Nat)> {
+    STATE.with(|s| s.get_collected_fees())
+}
+
+/// Opens a proposal to change the fee of a token, returns the proposal id
+#[update(name = "set_fee", guard = "is_authorized")]
+#[candid_method(update, rename = "set_fee")]
+fn set_fee(token_id: TokendId, fee: FeeConfig) -> Result<u64, String> {
+    open_proposal(
+        "set_fee",
+        args_digest((token_id, &fee)),
+        ProposalAction::SetFee(token_id, fee),
+    )
+}
+
+/// Opens a proposal to change the treasury, returns the proposal id
+#[update(name = "set_treasury", guard = "is_authorized")]
+#[candid_method(update, rename = "set_treasury")]
+fn set_treasury(treasury: Principal) -> Result<u64, String> {
+    open_proposal(
+        "set_treasury",
+        args_digest((treasury,)),
+        ProposalAction::SetTreasury(treasury),
+    )
+}
+
+/// Transfer the collected fees of a token to the treasury, less the
+/// transfer fee the token charges
+#[update(name = "withdraw_fees", guard = "is_authorized")]
+#[candid_method(update, rename = "withdraw_fees")]
+async fn withdraw_fees(token_id: TokendId) -> TxReceipt {
+    let started = STATE.with(|s| match *s.treasury.borrow() {
+        Some(treasury) => s.start_fee_withdrawal(token_id).map(|_| treasury),
+        None => Err(String::from("Treasury is not set")),
+    });
+
+    STATE.with(|s| {
+        s.record_audit(
+            "withdraw_fees",
+            args_digest((token_id,)),
+            AuditOutcome::from(&started),
+        )
+    });
+
+    let treasury = started.map_err(TxError::Other)?;
+    let result = transfer_collected_fee(token_id, treasury).await;
+
+    // the fees are only deducted once they reached the treasury
+    STATE.with(|s| s.finish_fee_withdrawal(token_id, result.as_ref().ok().map(|(_, fee)| fee)));
+
+    result.map(|(txn_id, _)| txn_id)
+}
+
+/// Transfer the collected fees less the token fee,
+/// returns the transaction id and the collected fees it paid out
+async fn transfer_collected_fee(
+    token_id: TokendId,
+    treasury: Principal,
+) -> Result<(Nat, Nat), TxError> {
+    let token_fee = token_id.fee().await?;
+    let collected = STATE.with(|s| s.collected_fee(token_id));
+
+    // the token takes its fee from the proxy balance, which also holds the escrows
+    if collected <= token_fee {
+        return Err(TxError::AmountTooSmall);
+    }
+
+    let txn_id = token_id
+        .transfer(treasury, collected.clone() - token_fee)
+        .await?;
+
+    Ok((txn_id, collected))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext};
+
+    use super::*;
+    use crate::common::dip20::TokenMetadata;
+
+    fn mock_calls(transfer: Method) {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .with_handler(Method::new().name("getMetadata").response(TokenMetadata {
+                fee: Nat::from(2_u32),
+            }))
+            .with_handler(transfer.name("transfer"))
+            .inject();
+
+        STATE.with(|s| {
+            s.treasury.replace(Some(mock_principals::john()));
+            s.collect_fee(mock_principals::bob(), Nat::from(65_u32));
+        });
+    }
+
+    fn collected() -> Nat {
+        STATE.with(|s| s.collected_fee(mock_principals::bob()))
+    }
+
+    #[test]
+    fn test_set_fee() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        let fee = FeeConfig {
+            flat: Nat::from(10_u32),
+            bps: 10_001,
+            on_mint: true,
+        };
+        assert!(set_fee(mock_principals::bob(), fee.clone()).is_err());
+        assert!(get_fees().is_empty());
+
+        let fee = FeeConfig { bps: 30, ..fee };
+        assert!(set_fee(mock_principals::bob(), fee.clone()).is_ok());
+        assert!(set_treasury(mock_principals::john()).is_ok());
+
+        assert_eq!(get_fees(), vec![(mock_principals::bob(), fee)]);
+        assert_eq!(get_treasury(), Some(mock_principals::john()));
+        // the rejected proposal, then each proposal and its execution
+        assert_eq!(STATE.with(|s| s.audit_log.borrow().len()), 5);
+    }
+
+    #[async_std::test]
+    async fn test_withdraw_fees() {
+        // the token fee is paid out of the collected fees
+        mock_calls(
+            Method::new()
+                .expect_arguments((mock_principals::john(), Nat::from(63_u32)))
+                .response::<TxReceipt>(Ok(Nat::from(1_u32))),
+        );
+
+        assert_eq!(
+            withdraw_fees(mock_principals::bob()).await,
+            Ok(Nat::from(1_u32))
+        );
+        assert_eq!(collected(), Nat::from(0_u32));
+    }
+
+    #[async_std::test]
+    async fn test_withdraw_fees_below_token_fee() {
+        mock_calls(Method::new().response::<TxReceipt>(Ok(Nat::from(1_u32))));
+        STATE.with(|s| s.return_collected_fee(mock_principals::bob(), &Nat::from(63_u32)));
+
+        assert_eq!(
+            withdraw_fees(mock_principals::bob()).await,
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(collected(), Nat::from(2_u32));
+    }
+
+    #[async_std::test]
+    async fn test_withdraw_fees_transfer_failed() {
+        mock_calls(Method::new().response::<TxReceipt>(Err(TxError::InsufficientBalance)));
+
+        assert_eq!(
+            withdraw_fees(mock_principals::bob()).await,
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(collected(), Nat::from(65_u32));
+        assert!(STATE
+            .with(|s| s.start_fee_withdrawal(mock_principals::bob()))
+            .is_ok());
+    }
+}
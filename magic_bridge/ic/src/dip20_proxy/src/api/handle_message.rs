@@ -4,12 +4,12 @@ use ic_kit::{ic, macros::update};
 
 use ic_cdk::export::candid::{Nat, Principal};
 
-use crate::common::types::{EthereumAddr, MagicResponse, Nonce, TokenType, TxError, TxReceipt};
+use crate::common::types::{BridgeResult, EthereumAddr, MagicResponse, Nonce, TokenType, TxError};
 use crate::proxy::{ERC20_ADDRESS_ETH, MAGIC_ADDRESS_IC};
 
 #[update(name = "handle_message")]
 #[candid_method(update, rename = "handle_message")]
-async fn handler(eth_addr: EthereumAddr, nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
+async fn handler(eth_addr: EthereumAddr, nonce: Nonce, payload: Vec<Nat>) -> BridgeResult {
     let erc20_addr_hex = hex::encode(eth_addr);
 
     if !(erc20_addr_hex
@@ -8,12 +8,13 @@ use crate::proxy::{FromNat, ToNat, ERC20_ADDRESS_ETH, STATE, TERA_ADDRESS};
 use ic_cdk::export::candid::{Nat, Principal};
 
 use crate::common::types::{
-    IncomingMessageHashParams, Message, MessageStatus, Nonce, TokendId, TxError, TxReceipt,
+    BridgeReceipt, BridgeResult, Flow, HeldAction, IncomingMessageHashParams, Message, MessageHash,
+    MessageStatus, Nonce, TokendId, TxError, VolumeEntry,
 };
 
 #[update(name = "mint")]
 #[candid_method(update, rename = "mint")]
-pub async fn mint(token_id: TokendId, nonce: Nonce, payload: Vec<Nat>) -> TxReceipt {
+pub async fn mint(token_id: TokendId, nonce: Nonce, payload: Vec<Nat>) -> BridgeResult {
     if (token_id.name().await).is_err() {
         return Err(TxError::Other(format!(
             "Token {} canister is not responding!",
@@ -64,13 +65,56 @@ pub async fn mint(token_id: TokendId, nonce: Nonce, payload: Vec<Nat>) -> TxRece
     let amount = Nat::from(payload[2].0.clone());
     let to = Principal::from_nat(payload[1].clone());
 
-    match token_id.mint(to, amount).await {
+    let reserved =
+        STATE.with(|s| s.reserve_volume(token_id, Flow::Mint, amount.clone(), ic::time()));
+
+    match reserved {
+        Ok(volume) => mint_reserved(token_id, msg_hash, to, amount, volume).await,
+        Err(reason) => {
+            let id = STATE.with(|s| {
+                s.update_incoming_message_status(msg_hash.clone(), MessageStatus::Held);
+                s.hold_operation(
+                    to,
+                    token_id,
+                    amount,
+                    Nat::from(0_u32),
+                    HeldAction::Mint(msg_hash.clone()),
+                    reason.clone(),
+                    ic::time(),
+                )
+            });
+
+            Err(TxError::Other(format!(
+                "Mint of message {} is held for approval as operation {}: {}",
+                msg_hash, id, reason
+            )))
+        }
+    }
+}
+
+/// Mint a consumed message whose volume is recorded
+pub(crate) async fn mint_reserved(
+    token_id: TokendId,
+    msg_hash: MessageHash,
+    to: Principal,
+    amount: Nat,
+    volume: VolumeEntry,
+) -> BridgeResult {
+    let fee = STATE.with(|s| s.fee_for(token_id, Flow::Mint, &amount));
+    let amount = amount - fee.clone();
+
+    match token_id.mint(to, amount.clone()).await {
         Ok(txn_id) => {
             if STATE
                 .with(|s| s.remove_incoming_message(msg_hash.clone()))
                 .is_some()
             {
-                return Ok(txn_id);
+                let fee = collect_mint_fee(token_id, fee).await;
+                return Ok(BridgeReceipt {
+                    txn_id,
+                    amount,
+                    fee,
+                });
             }
             Err(TxError::Other(format!(
                 "Message {:?} does not exist!",
@@ -79,9 +123,21 @@ pub async fn mint(token_id: TokendId, nonce: Nonce, payload: Vec<Nat>) -> TxRece
         }
         Err(error) => {
             STATE.with(|s| {
+                s.release_volume(&volume);
                 s.update_incoming_message_status(msg_hash.clone(), MessageStatus::ConsumedNotMinted)
             });
             Err(error)
         }
     }
 }
+
+/// Mint the fee of a mint to the proxy, a fee that cannot be minted is waived
+async fn collect_mint_fee(token_id: TokendId, fee: Nat) -> Nat {
+    if fee == Nat::from(0_u32) || token_id.mint(ic::id(), fee.clone()).await.is_err() {
+        return Nat::from(0_u32);
+    }
+
+    STATE.with(|s| s.collect_fee(token_id, fee.clone()));
+
+    fee
+}
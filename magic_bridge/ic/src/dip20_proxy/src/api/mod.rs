@@ -1,9 +1,15 @@
 mod admin;
 mod burn;
 mod claimable_assets;
+mod compensation;
+mod escrow;
+mod fees;
 mod get_balance;
+mod governance;
 mod handle_message;
 mod init;
 mod mint;
+mod reconciliation;
 mod upgrade;
+mod volume;
 mod withdraw;
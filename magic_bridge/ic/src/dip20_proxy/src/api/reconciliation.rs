@@ -0,0 +1,102 @@
+use ic_kit::{
+    candid::{candid_method, Nat},
+    ic,
+    macros::{query, update},
+    Principal,
+};
+
+use crate::api::admin::is_authorized;
+use crate::common::dip20::Dip20;
+use crate::common::types::{AuditOutcome, ReserveReport, TokendId};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+
+pub fn is_reporter() -> Result<(), String> {
+    STATE.with(|s| s.is_reporter())
+}
+
+#[query(name = "get_reporter")]
+#[candid_method(query, rename = "get_reporter")]
+fn get_reporter() -> Option<Principal> {
+    STATE.with(|s| *s.reporter.borrow())
+}
+
+#[update(name = "set_reporter", guard = "is_authorized")]
+#[candid_method(update, rename = "set_reporter")]
+fn set_reporter(reporter: Principal) {
+    STATE.with(|s| {
+        s.record_audit("set_reporter", args_digest((reporter,)), AuditOutcome::Ok);
+        s.reporter.replace(Some(reporter));
+    });
+}
+
+/// Compare the balance of token_id locked in ERC20Bridge, as reported by the reporter,
+/// against the token supply and what is in flight between the two
+#[update(name = "reconcile", guard = "is_reporter")]
+#[candid_method(update, rename = "reconcile")]
+async fn reconcile(token_id: TokendId, l1_balance: Nat) -> Result<ReserveReport, String> {
+    let reporter = ic::caller();
+    let report = match token_id.total_supply().await {
+        Ok(ic_supply) => Ok(STATE.with(|s| {
+            s.record_reserve_report(
+                token_id,
+                reporter,
+                l1_balance.clone(),
+                ic_supply,
+                ic::time(),
+            )
+        })),
+        Err(error) => Err(format!("{:?}", error)),
+    };
+
+    STATE.with(|s| {
+        s.record_audit_of(
+            reporter,
+            "reconcile",
+            args_digest((token_id, l1_balance)),
+            AuditOutcome::from(&report),
+        )
+    });
+
+    report
+}
+
+#[query(name = "get_reserve_reports", guard = "is_authorized")]
+#[candid_method(query, rename = "get_reserve_reports")]
+fn get_reserve_reports(start: u64, limit: u64) -> Vec<ReserveReport> {
+    STATE.with(|s| s.get_reserve_reports(start, limit as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, Method, MockContext};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn test_reconcile() {
+        MockContext::new()
+            .with_caller(mock_principals::john())
+            .with_handler(
+                Method::new()
+                    .name("totalSupply")
+                    .response(Nat::from(100_u32)),
+            )
+            .inject();
+
+        // an admin is not the reporter until it is set as one
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::john()));
+        assert!(is_reporter().is_err());
+
+        set_reporter(mock_principals::john());
+        assert_eq!(get_reporter(), Some(mock_principals::john()));
+        assert!(is_reporter().is_ok());
+
+        let report = reconcile(mock_principals::alice(), Nat::from(100_u32))
+            .await
+            .unwrap();
+        assert_eq!(report.reporter, mock_principals::john());
+        assert!(report.balanced);
+        assert_eq!(get_reserve_reports(0, 10).len(), 1);
+    }
+}
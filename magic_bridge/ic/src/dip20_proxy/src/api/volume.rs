@@ -0,0 +1,215 @@
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{query, update},
+};
+
+use crate::api::admin::is_authorized;
+use crate::api::burn::burn_received;
+use crate::api::governance::open_proposal;
+use crate::api::mint::mint_reserved;
+use crate::common::dip20::Dip20;
+use crate::common::types::{
+    AuditOutcome, Flow, HeldAction, HeldOperation, MessageStatus, ProposalAction, TokendId,
+    TxError, TxReceipt, VolumeLimit, VolumeLimits,
+};
+use crate::common::utils::args_digest;
+use crate::proxy::STATE;
+
+#[query(name = "get_volume_limits")]
+#[candid_method(query, rename = "get_volume_limits")]
+fn get_volume_limits() -> VolumeLimits {
+    STATE.with(|s| s.get_volume_limits())
+}
+
+/// Opens a proposal to change the limits of a token, or the global limits
+/// without a token, returns the proposal id
+#[update(name = "set_volume_limit", guard = "is_authorized")]
+#[candid_method(update, rename = "set_volume_limit")]
+fn set_volume_limit(token: Option<TokendId>, limit: VolumeLimit) -> Result<u64, String> {
+    open_proposal(
+        "set_volume_limit",
+        args_digest((token, &limit)),
+        ProposalAction::SetVolumeLimit(token, limit),
+    )
+}
+
+#[query(name = "get_held_operations", guard = "is_authorized")]
+#[candid_method(query, rename = "get_held_operations")]
+fn get_held_operations() -> Vec<HeldOperation> {
+    STATE.with(|s| s.get_held_operations())
+}
+
+/// Run a held operation regardless of the limits, its volume still counts
+#[update(name = "approve_held_operation", guard = "is_authorized")]
+#[candid_method(update, rename = "approve_held_operation")]
+async fn approve_held_operation(id: u64) -> TxReceipt {
+    let operation = take_held_operation("approve_held_operation", id)?;
+    let flow = match operation.action {
+        HeldAction::Mint(_) => Flow::Mint,
+        HeldAction::Burn(_) => Flow::Burn,
+    };
+    let volume = STATE
+        .with(|s| s.record_volume(operation.token, flow, operation.amount.clone(), ic::time()));
+
+    match operation.action {
+        HeldAction::Mint(msg_hash) => {
+            STATE.with(|s| {
+                s.update_incoming_message_status(msg_hash.clone(), MessageStatus::Consuming)
+            });
+            mint_reserved(
+                operation.token,
+                msg_hash,
+                operation.owner,
+                operation.amount,
+                volume,
+            )
+            .await
+            .map(|receipt| receipt.txn_id)
+        }
+        HeldAction::Burn(eth_addr) => {
+            burn_received(
+                operation.owner,
+                operation.token,
+                eth_addr,
+                operation.amount,
+                operation.fee,
+                volume,
+            )
+            .await
+        }
+    }
+}
+
+/// Drop a held operation, a mint can be called again and the tokens of a burn are returned
+/// with their bridge fee, less the token fee of the transfer back
+#[update(name = "reject_held_operation", guard = "is_authorized")]
+#[candid_method(update, rename = "reject_held_operation")]
+async fn reject_held_operation(id: u64) -> Result<(), TxError> {
+    let operation = take_held_operation("reject_held_operation", id)?;
+
+    match operation.action.clone() {
+        HeldAction::Mint(msg_hash) => STATE
+            .with(|s| s.update_incoming_message_status(msg_hash, MessageStatus::ConsumedNotMinted)),
+        HeldAction::Burn(_) => {
+            // taken before the await so the fee cannot be withdrawn meanwhile
+            let fee = STATE.with(|s| s.return_collected_fee(operation.token, &operation.fee));
+            let amount = operation.amount.clone() + fee.clone();
+
+            let returned = match operation.token.fee().await {
+                Ok(token_fee) if token_fee < amount => operation
+                    .token
+                    .transfer(operation.owner, amount - token_fee)
+                    .await
+                    .map(|_| ()),
+                Ok(_) => Err(TxError::AmountTooSmall),
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = returned {
+                STATE.with(|s| {
+                    s.collect_fee(operation.token, fee);
+                    s.restore_held_operation(operation)
+                });
+                return Err(error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn take_held_operation(method: &str, id: u64) -> Result<HeldOperation, TxError> {
+    let operation = STATE.with(|s| s.take_held_operation(id));
+
+    STATE.with(|s| s.record_audit(method, args_digest((id,)), AuditOutcome::from(&operation)));
+
+    operation.map_err(TxError::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{candid::Nat, mock_principals, Method, MockContext, Principal};
+
+    use super::*;
+    use crate::common::dip20::TokenMetadata;
+
+    fn hold_burn() -> u64 {
+        let token = mock_principals::john();
+        STATE.with(|s| {
+            s.collect_fee(token, Nat::from(5_u32));
+            s.hold_operation(
+                mock_principals::bob(),
+                token,
+                Nat::from(100_u32),
+                Nat::from(5_u32),
+                HeldAction::Burn(Principal::from_slice(&[1; 20])),
+                String::from("limit"),
+                0,
+            )
+        })
+    }
+
+    fn mock_calls(transfer: Method) {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .with_handler(Method::new().name("getMetadata").response(TokenMetadata {
+                fee: Nat::from(2_u32),
+            }))
+            .with_handler(transfer.name("transfer"))
+            .inject();
+    }
+
+    #[test]
+    fn test_set_volume_limit() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        let limit = VolumeLimit {
+            per_transaction: None,
+            daily_mint: Some(100_u32.into()),
+            daily_burn: Some(100_u32.into()),
+        };
+
+        assert!(set_volume_limit(Some(mock_principals::bob()), limit.clone()).is_ok());
+
+        let limits = get_volume_limits();
+        assert_eq!(limits.global, VolumeLimit::default());
+        assert_eq!(limits.tokens, vec![(mock_principals::bob(), limit)]);
+        // the proposal and its execution
+        assert_eq!(STATE.with(|s| s.audit_log.borrow().len()), 2);
+    }
+
+    #[async_std::test]
+    async fn test_reject_held_burn() {
+        mock_calls(
+            Method::new()
+                .expect_arguments((mock_principals::bob(), Nat::from(103_u32)))
+                .response::<TxReceipt>(Ok(Nat::from(1_u32))),
+        );
+        let id = hold_burn();
+
+        assert_eq!(reject_held_operation(id).await, Ok(()));
+        assert_eq!(
+            STATE.with(|s| s.collected_fee(mock_principals::john())),
+            Nat::from(0_u32)
+        );
+        assert!(STATE.with(|s| s.get_held_operations()).is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_reject_held_burn_failed() {
+        mock_calls(Method::new().response::<TxReceipt>(Err(TxError::InsufficientBalance)));
+        let id = hold_burn();
+
+        // the operation and its fee stay for another try
+        assert!(reject_held_operation(id).await.is_err());
+        assert_eq!(
+            STATE.with(|s| s.collected_fee(mock_principals::john())),
+            Nat::from(5_u32)
+        );
+        assert_eq!(STATE.with(|s| s.get_held_operations()).len(), 1);
+    }
+}
@@ -2,51 +2,21 @@ use ic_kit::{
     candid::{candid_method, Nat},
     ic,
     macros::update,
-    Principal,
 };
 
 use crate::{
-    common::{
-        dip20::Dip20,
-        tera::Tera,
-        types::{EthereumAddr, TokendId, TxError, TxReceipt},
-    },
-    proxy::{ToNat, ERC20_ADDRESS_ETH, STATE, TERA_ADDRESS},
+    api::burn::send_escrow,
+    common::types::{EthereumAddr, OutgoingMessage, TokendId, TxError},
 };
 
-/// withdraw left over balance if burn/mint fails
-/// this will attempt to bridge the leftover balance
-/// todo withdraw specific balance
+/// withdraw balance left over if sending a burn to L1 failed,
+/// the amount was burned already and is sent to eth_addr again
 #[update(name = "withdraw")]
 #[candid_method(update, rename = "withdraw")]
-pub async fn withdraw(token_id: TokendId, eth_addr: EthereumAddr, _amount: Nat) -> TxReceipt {
-    let caller = ic::caller();
-
-    if (token_id.name().await).is_err() {
-        return Err(TxError::Other(format!(
-            "Token {} canister is not responding!",
-            token_id.to_string(),
-        )));
-    }
-
-    let erc20_addr_hex = ERC20_ADDRESS_ETH.trim_start_matches("0x");
-    let erc20_addr_pid = Principal::from_slice(&hex::decode(erc20_addr_hex).unwrap());
-
-    let get_balance = STATE.with(|s| s.get_balance(caller, token_id));
-    if let Some(balance) = get_balance {
-        let payload = [eth_addr.clone().to_nat(), balance.clone()].to_vec();
-        let tera_id = Principal::from_text(TERA_ADDRESS).unwrap();
-        if tera_id.send_message(erc20_addr_pid, payload).await.is_err() {
-            return Err(TxError::Other(format!("Sending message to L1 failed!")));
-        }
-
-        let zero = Nat::from(0_u32);
-        STATE.with(|s| s.update_balance(caller, token_id, zero));
-    }
-
-    Err(TxError::Other(format!(
-        "No balance for caller {:?} in canister {:?}!",
-        caller.to_string(),
-        token_id.to_string(),
-    )))
+pub async fn withdraw(
+    token_id: TokendId,
+    eth_addr: EthereumAddr,
+    amount: Nat,
+) -> Result<OutgoingMessage, TxError> {
+    send_escrow(ic::caller(), token_id, eth_addr, amount).await
 }
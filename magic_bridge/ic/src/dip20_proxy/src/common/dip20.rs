@@ -1,18 +1,28 @@
 use async_trait::async_trait;
-use ic_cdk::call;
-use ic_cdk::export::candid::{Nat, Principal};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_kit::ic::call;
 
 use crate::common::types::{TxError, TxReceipt};
 
-#[async_trait]
+#[async_trait(?Send)]
 pub trait Dip20 {
     async fn burn(&self, amount: Nat) -> TxReceipt;
     async fn name(&self) -> Result<String, TxError>;
     async fn mint(&self, to: Principal, amount: Nat) -> TxReceipt;
+    async fn transfer(&self, to: Principal, amount: Nat) -> TxReceipt;
     async fn transfer_from(&self, from: Principal, to: Principal, amount: Nat) -> TxReceipt;
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, TxError>;
+    async fn total_supply(&self) -> Result<Nat, TxError>;
+    async fn fee(&self) -> Result<Nat, TxError>;
 }
 
-#[async_trait]
+/// The part of the token metadata the proxy reads
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenMetadata {
+    pub fee: Nat,
+}
+
+#[async_trait(?Send)]
 impl Dip20 for Principal {
     async fn name(&self) -> Result<String, TxError> {
         let name: (String,) = match call(*self, "name", ()).await {
@@ -62,6 +72,23 @@ impl Dip20 for Principal {
         }
     }
 
+    async fn transfer(&self, to: Principal, amount: Nat) -> TxReceipt {
+        let transfer: (TxReceipt,) = match call(*self, "transfer", (to, amount)).await {
+            Ok(res) => res,
+            Err((code, err)) => {
+                return Err(TxError::Other(format!(
+                    "RejectionCode: {:?}\n{}",
+                    code, err
+                )))
+            }
+        };
+
+        match transfer {
+            (Ok(tx_id),) => Ok(tx_id),
+            (Err(error),) => Err(error),
+        }
+    }
+
     async fn transfer_from(&self, from: Principal, to: Principal, amount: Nat) -> TxReceipt {
         let transfer_from: (TxReceipt,) =
             match call(*self, "transferFrom", (from, to, amount)).await {
@@ -79,4 +106,46 @@ impl Dip20 for Principal {
             (Err(error),) => Err(error),
         }
     }
+
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, TxError> {
+        let balance: (Nat,) = match call(*self, "balanceOf", (owner,)).await {
+            Ok(res) => res,
+            Err((code, err)) => {
+                return Err(TxError::Other(format!(
+                    "RejectionCode: {:?}\n{}",
+                    code, err
+                )))
+            }
+        };
+
+        Ok(balance.0)
+    }
+
+    async fn total_supply(&self) -> Result<Nat, TxError> {
+        let total_supply: (Nat,) = match call(*self, "totalSupply", ()).await {
+            Ok(res) => res,
+            Err((code, err)) => {
+                return Err(TxError::Other(format!(
+                    "RejectionCode: {:?}\n{}",
+                    code, err
+                )))
+            }
+        };
+
+        Ok(total_supply.0)
+    }
+
+    async fn fee(&self) -> Result<Nat, TxError> {
+        let metadata: (TokenMetadata,) = match call(*self, "getMetadata", ()).await {
+            Ok(res) => res,
+            Err((code, err)) => {
+                return Err(TxError::Other(format!(
+                    "RejectionCode: {:?}\n{}",
+                    code, err
+                )))
+            }
+        };
+
+        Ok(metadata.0.fee)
+    }
 }
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+
+use ic_cdk::api::stable;
+use ic_kit::candid::de::IDLDeserialize;
+use ic_kit::candid::{encode_args, CandidType, Deserialize, Nat, Principal};
+
+use super::types::{
+    AuditEntry, ClaimStatus, ClaimableMessage, CompensationEntry, CompensationPolicy, EthereumAddr,
+    FeeConfig, GovernanceConfig, HeldOperation, MessageHash, MessageStatus, MsgHashKey, Proposal,
+    ReserveReport, StableProxyState, TokendId, VolumeEntry, VolumeLimit,
+};
+
+/// Written ahead of the versioned envelope, the bare record of
+/// canisters deployed before versioning starts with the candid magic
+const ENVELOPE_MAGIC: &[u8; 4] = b"VSTA";
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Message status as deployed, frozen so later variants do not change it
+#[derive(Clone, CandidType, Deserialize)]
+pub enum MessageStatusV0 {
+    Consuming,
+    ConsumedNotMinted,
+}
+
+/// Claimable message before it was keyed by msg_key and had a status
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ClaimableMessageV0 {
+    pub owner: EthereumAddr,
+    pub msg_key: MsgHashKey,
+    pub msg_hash: MessageHash,
+    pub token: TokendId,
+    pub amount: Nat,
+}
+
+/// Stable state layout before versioning was introduced,
+/// stored as a bare record
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableProxyStateV0 {
+    pub incoming_messages: HashMap<MessageHash, MessageStatusV0>,
+    pub balances: HashMap<Principal, HashMap<Principal, Nat>>,
+    pub controllers: Vec<Principal>,
+    pub messages_unclaimed: HashMap<EthereumAddr, Vec<ClaimableMessageV0>>,
+}
+
+/// Stable state layout of version 1, frozen so a change to the live
+/// state needs a new version and a migration
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableProxyStateV1 {
+    pub incoming_messages: HashMap<MessageHash, MessageStatus>,
+    pub balances: HashMap<Principal, HashMap<Principal, Nat>>,
+    pub controllers: Vec<Principal>,
+    pub claimables: HashMap<MsgHashKey, ClaimableMessage>,
+    pub audit_log: Vec<AuditEntry>,
+    pub audit_index: u64,
+    pub compensation_policy: CompensationPolicy,
+    pub compensations: Vec<CompensationEntry>,
+    pub global_limit: VolumeLimit,
+    pub token_limits: HashMap<TokendId, VolumeLimit>,
+    pub volume: Vec<VolumeEntry>,
+    pub held_operations: HashMap<u64, HeldOperation>,
+    pub held_operation_index: u64,
+    pub fees: HashMap<TokendId, FeeConfig>,
+    pub treasury: Option<Principal>,
+    pub collected_fees: HashMap<TokendId, Nat>,
+    pub reserve_reports: Vec<ReserveReport>,
+    pub reporter: Option<Principal>,
+}
+
+/// Stable state layout of version 2, adds the governance proposals
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableProxyStateV2 {
+    pub incoming_messages: HashMap<MessageHash, MessageStatus>,
+    pub balances: HashMap<Principal, HashMap<Principal, Nat>>,
+    pub controllers: Vec<Principal>,
+    pub claimables: HashMap<MsgHashKey, ClaimableMessage>,
+    pub audit_log: Vec<AuditEntry>,
+    pub audit_index: u64,
+    pub compensation_policy: CompensationPolicy,
+    pub compensations: Vec<CompensationEntry>,
+    pub global_limit: VolumeLimit,
+    pub token_limits: HashMap<TokendId, VolumeLimit>,
+    pub volume: Vec<VolumeEntry>,
+    pub held_operations: HashMap<u64, HeldOperation>,
+    pub held_operation_index: u64,
+    pub fees: HashMap<TokendId, FeeConfig>,
+    pub treasury: Option<Principal>,
+    pub collected_fees: HashMap<TokendId, Nat>,
+    pub reserve_reports: Vec<ReserveReport>,
+    pub reporter: Option<Principal>,
+    pub governance: GovernanceConfig,
+    pub proposals: HashMap<u64, Proposal>,
+    pub proposal_index: u64,
+}
+
+/// Versioned envelope written to stable memory on every upgrade
+#[derive(CandidType, Deserialize)]
+pub enum VersionedProxyState {
+    V0(StableProxyStateV0),
+    V1(StableProxyStateV1),
+    V2(StableProxyStateV2),
+}
+
+impl VersionedProxyState {
+    /// Wrap state in the current version
+    pub fn current(state: StableProxyState) -> Self {
+        VersionedProxyState::V2(state.into())
+    }
+
+    /// Apply every migration from the stored version up to the current one
+    pub fn migrate(self) -> Result<StableProxyState, String> {
+        match self {
+            VersionedProxyState::V0(state) => {
+                VersionedProxyState::V1(migrate_v0_to_v1(state)?).migrate()
+            }
+            VersionedProxyState::V1(state) => {
+                VersionedProxyState::V2(migrate_v1_to_v2(state)).migrate()
+            }
+            VersionedProxyState::V2(state) => Ok(state.into()),
+        }
+    }
+}
+
+fn migrate_v0_to_v1(state: StableProxyStateV0) -> Result<StableProxyStateV1, String> {
+    // an upgrade without controllers would lock every admin endpoint
+    if state.controllers.is_empty() {
+        return Err("Stable state has no controllers".to_string());
+    }
+
+    Ok(StableProxyStateV1 {
+        incoming_messages: state
+            .incoming_messages
+            .into_iter()
+            .map(|(msg_hash, status)| (msg_hash, migrate_message_status(status)))
+            .collect(),
+        balances: state.balances,
+        controllers: state.controllers,
+        claimables: migrate_claimables(state.messages_unclaimed),
+        // the rest starts empty, retried compensations keep the previous behaviour
+        ..StableProxyStateV1::default()
+    })
+}
+
+/// Governance starts with the single approval and no delay of the direct calls it replaces
+fn migrate_v1_to_v2(state: StableProxyStateV1) -> StableProxyStateV2 {
+    StableProxyStateV2 {
+        incoming_messages: state.incoming_messages,
+        balances: state.balances,
+        controllers: state.controllers,
+        claimables: state.claimables,
+        audit_log: state.audit_log,
+        audit_index: state.audit_index,
+        compensation_policy: state.compensation_policy,
+        compensations: state.compensations,
+        global_limit: state.global_limit,
+        token_limits: state.token_limits,
+        volume: state.volume,
+        held_operations: state.held_operations,
+        held_operation_index: state.held_operation_index,
+        fees: state.fees,
+        treasury: state.treasury,
+        collected_fees: state.collected_fees,
+        reserve_reports: state.reserve_reports,
+        reporter: state.reporter,
+        ..StableProxyStateV2::default()
+    }
+}
+
+impl From<StableProxyState> for StableProxyStateV2 {
+    fn from(state: StableProxyState) -> Self {
+        StableProxyStateV2 {
+            incoming_messages: state.incoming_messages,
+            balances: state.balances,
+            controllers: state.controllers,
+            claimables: state.claimables,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            compensation_policy: state.compensation_policy,
+            compensations: state.compensations,
+            global_limit: state.global_limit,
+            token_limits: state.token_limits,
+            volume: state.volume,
+            held_operations: state.held_operations,
+            held_operation_index: state.held_operation_index,
+            fees: state.fees,
+            treasury: state.treasury,
+            collected_fees: state.collected_fees,
+            reserve_reports: state.reserve_reports,
+            reporter: state.reporter,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+        }
+    }
+}
+
+impl From<StableProxyStateV2> for StableProxyState {
+    fn from(state: StableProxyStateV2) -> Self {
+        StableProxyState {
+            incoming_messages: state.incoming_messages,
+            balances: state.balances,
+            controllers: state.controllers,
+            claimables: state.claimables,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            compensation_policy: state.compensation_policy,
+            compensations: state.compensations,
+            global_limit: state.global_limit,
+            token_limits: state.token_limits,
+            volume: state.volume,
+            held_operations: state.held_operations,
+            held_operation_index: state.held_operation_index,
+            fees: state.fees,
+            treasury: state.treasury,
+            collected_fees: state.collected_fees,
+            reserve_reports: state.reserve_reports,
+            reporter: state.reporter,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+        }
+    }
+}
+
+fn migrate_message_status(status: MessageStatusV0) -> MessageStatus {
+    match status {
+        MessageStatusV0::Consuming => MessageStatus::Consuming,
+        MessageStatusV0::ConsumedNotMinted => MessageStatus::ConsumedNotMinted,
+    }
+}
+
+/// Key claimable messages by msg_key, the sender of older messages was not recorded
+fn migrate_claimables(
+    messages_unclaimed: HashMap<EthereumAddr, Vec<ClaimableMessageV0>>,
+) -> HashMap<MsgHashKey, ClaimableMessage> {
+    messages_unclaimed
+        .into_values()
+        .flatten()
+        .map(|message| {
+            (
+                message.msg_key,
+                ClaimableMessage {
+                    owner: message.owner,
+                    msg_key: message.msg_key,
+                    msg_hash: message.msg_hash,
+                    token: message.token,
+                    amount: message.amount,
+                    sender: None,
+                    status: ClaimStatus::Pending,
+                    created_at: 0,
+                    updated_at: 0,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Encode state in the current version behind the envelope magic
+pub fn encode(state: StableProxyState) -> Result<Vec<u8>, String> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.extend(encode_args((VersionedProxyState::current(state),)).map_err(|e| e.to_string())?);
+
+    Ok(bytes)
+}
+
+/// Decode stable memory by its version tag, never by trying layouts in turn
+pub fn decode(bytes: &[u8]) -> Result<VersionedProxyState, String> {
+    if bytes.starts_with(ENVELOPE_MAGIC) {
+        let mut de =
+            IDLDeserialize::new(&bytes[ENVELOPE_MAGIC.len()..]).map_err(|e| e.to_string())?;
+        de.get_value::<VersionedProxyState>()
+            .map_err(|e| e.to_string())
+    } else if bytes.starts_with(CANDID_MAGIC) {
+        // canisters deployed before versioning hold a bare record
+        let mut de = IDLDeserialize::new(bytes).map_err(|e| e.to_string())?;
+        de.get_value::<StableProxyStateV0>()
+            .map(VersionedProxyState::V0)
+            .map_err(|e| e.to_string())
+    } else {
+        Err("Stable memory holds no known state layout".to_string())
+    }
+}
+
+/// Save state in the current version over whatever stable memory held
+pub fn store(state: StableProxyState) -> Result<(), String> {
+    let bytes = encode(state)?;
+    let pages = (bytes.len() as u64 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    let size = stable::stable_size() as u64;
+    if pages > size {
+        stable::stable_grow((pages - size) as u32).map_err(|e| format!("{:?}", e))?;
+    }
+    stable::stable_write(0, &bytes);
+
+    Ok(())
+}
+
+/// Restore state from stable memory and migrate it to the current version
+pub fn restore() -> Result<StableProxyState, String> {
+    decode(&stable::stable_bytes())?.migrate()
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::mock_principals;
+
+    use super::*;
+    use crate::proxy::STATE;
+
+    fn round_trip(state: StableProxyState) -> Result<StableProxyState, String> {
+        decode(&encode(state)?)?.migrate()
+    }
+
+    fn legacy_state() -> StableProxyStateV0 {
+        let msg_hash =
+            String::from("c9e23418a985892acc0fa031331080bfce112bdf841a3ae04a5181c6da1610b1");
+
+        StableProxyStateV0 {
+            incoming_messages: HashMap::from([(msg_hash, MessageStatusV0::ConsumedNotMinted)]),
+            balances: HashMap::from([(
+                mock_principals::bob(),
+                HashMap::from([(mock_principals::alice(), Nat::from(100_u32))]),
+            )]),
+            controllers: vec![mock_principals::alice()],
+            messages_unclaimed: HashMap::from([(
+                mock_principals::john(),
+                vec![ClaimableMessageV0 {
+                    owner: mock_principals::john(),
+                    msg_key: [1; 32],
+                    msg_hash: String::from("00"),
+                    token: mock_principals::alice(),
+                    amount: Nat::from(10_u32),
+                }],
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_round_trip() {
+        let msg_hash =
+            String::from("c9e23418a985892acc0fa031331080bfce112bdf841a3ae04a5181c6da1610b1");
+        let amount = Nat::from(100_u32);
+        let user = mock_principals::bob();
+        let token_id = mock_principals::alice();
+
+        STATE.with(|s| {
+            s.controllers.borrow_mut().push(mock_principals::alice());
+            s.store_incoming_message(msg_hash.clone());
+            s.credit_escrow(user, token_id, amount.clone()).unwrap();
+        });
+
+        let stable_state = STATE.with(|s| s.take_all());
+        let restored = round_trip(stable_state).unwrap();
+
+        STATE.with(|s| s.clear_all());
+        STATE.with(|s| s.replace_all(restored));
+
+        assert_eq!(
+            STATE.with(|s| s.get_message(&msg_hash)),
+            Some(MessageStatus::Consuming)
+        );
+        assert_eq!(STATE.with(|s| s.get_balance(user, token_id)), Some(amount));
+        assert_eq!(
+            STATE.with(|s| s.controllers.borrow().clone()),
+            vec![mock_principals::alice()]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy_state() {
+        let bytes = encode_args((legacy_state(),)).unwrap();
+        let migrated = decode(&bytes).unwrap().migrate().unwrap();
+
+        assert_eq!(migrated.incoming_messages.len(), 1);
+        assert_eq!(migrated.balances.len(), 1);
+        assert_eq!(migrated.controllers, vec![mock_principals::alice()]);
+        assert!(migrated.audit_log.is_empty());
+        assert_eq!(migrated.compensation_policy, CompensationPolicy::Retry);
+        assert!(migrated.compensations.is_empty());
+        assert_eq!(migrated.global_limit, VolumeLimit::default());
+        assert!(migrated.held_operations.is_empty());
+        assert!(migrated.fees.is_empty());
+        assert!(migrated.reserve_reports.is_empty());
+        assert_eq!(migrated.reporter, None);
+        assert_eq!(migrated.treasury, None);
+        assert_eq!(migrated.governance, GovernanceConfig::default());
+        assert!(migrated.proposals.is_empty());
+        assert_eq!(
+            migrated.claimables.get(&[1; 32]).unwrap().status,
+            ClaimStatus::Pending
+        );
+    }
+
+    #[test]
+    fn test_migrate_v1_state() {
+        let state = StableProxyStateV1 {
+            controllers: vec![mock_principals::alice()],
+            treasury: Some(mock_principals::john()),
+            ..StableProxyStateV1::default()
+        };
+        let bytes = [
+            ENVELOPE_MAGIC.to_vec(),
+            encode_args((VersionedProxyState::V1(state),)).unwrap(),
+        ]
+        .concat();
+        let migrated = decode(&bytes).unwrap().migrate().unwrap();
+
+        assert_eq!(migrated.controllers, vec![mock_principals::alice()]);
+        assert_eq!(migrated.treasury, Some(mock_principals::john()));
+        assert_eq!(migrated.governance, GovernanceConfig::default());
+        assert_eq!(migrated.proposal_index, 0);
+    }
+
+    #[test]
+    fn test_decode_by_version_tag() {
+        let mut bytes = encode(StableProxyState::default()).unwrap();
+        assert!(matches!(decode(&bytes), Ok(VersionedProxyState::V2(_))));
+
+        // a broken envelope is an error, not a legacy record
+        bytes.truncate(ENVELOPE_MAGIC.len() + 8);
+        assert!(decode(&bytes).is_err());
+
+        assert!(decode(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let mut legacy = legacy_state();
+        legacy.controllers.clear();
+
+        let bytes = encode_args((legacy,)).unwrap();
+
+        // post_upgrade traps on the error and the upgrade is rolled back
+        assert!(decode(&bytes).unwrap().migrate().is_err());
+    }
+}
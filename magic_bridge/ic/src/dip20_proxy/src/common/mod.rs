@@ -1,4 +1,5 @@
 pub mod dip20;
+pub mod migration;
 pub mod tera;
 pub mod types;
 pub mod utils;
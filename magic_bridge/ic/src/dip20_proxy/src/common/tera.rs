@@ -1,10 +1,10 @@
 use async_trait::async_trait;
-use ic_cdk::call;
 use ic_cdk::export::candid::{Nat, Principal};
+use ic_kit::ic::call;
 
 use crate::common::types::{Nonce, OutgoingMessage, TxError};
 
-#[async_trait]
+#[async_trait(?Send)]
 pub trait Tera {
     async fn consume_message(
         &self,
@@ -19,7 +19,7 @@ pub trait Tera {
     ) -> Result<OutgoingMessage, TxError>;
 }
 
-#[async_trait]
+#[async_trait(?Send)]
 impl Tera for Principal {
     async fn consume_message(
         &self,
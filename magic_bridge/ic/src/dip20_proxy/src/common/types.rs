@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use ic_kit::candid::{CandidType, Deserialize, Nat, Principal};
 use serde::Serialize;
@@ -14,8 +14,13 @@ pub type EthereumAddr = Principal;
 
 pub type MsgHashKey = [u8; 32];
 
+/// Claimable keys of one principal ordered by creation time
+pub type ClaimableIndex = BTreeSet<(Principal, u64, MsgHashKey)>;
+
 pub type TxReceipt = Result<Nat, TxError>;
 
+pub type BridgeResult = Result<BridgeReceipt, TxError>;
+
 pub type MagicResponse = Result<Principal, FactoryError>;
 
 #[derive(Serialize, CandidType, Deserialize)]
@@ -34,6 +39,8 @@ pub enum FactoryError {
 pub enum MessageStatus {
     Consuming,
     ConsumedNotMinted,
+    /// consumed, the mint waits in the approval queue
+    Held,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -60,10 +67,337 @@ pub struct OutgoingMessage {
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct ClaimableMessage {
     pub owner: EthereumAddr,
-    pub msg_hash: String,
     pub msg_key: MsgHashKey,
+    pub msg_hash: MessageHash,
     pub token: TokendId,
     pub amount: Nat,
+    /// IC principal whose burn or withdraw sent the message, unknown for older messages
+    pub sender: Option<Principal>,
+    pub status: ClaimStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl ClaimableMessage {
+    pub fn pending(
+        owner: EthereumAddr,
+        sender: Principal,
+        outgoing_message: &OutgoingMessage,
+        token: TokendId,
+        amount: Nat,
+        now: u64,
+    ) -> Self {
+        ClaimableMessage {
+            owner,
+            msg_key: outgoing_message.msg_key,
+            msg_hash: outgoing_message.msg_hash.clone(),
+            token,
+            amount,
+            sender: Some(sender),
+            status: ClaimStatus::Pending,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum ClaimStatus {
+    /// waiting to be claimed on L1
+    Pending,
+    /// claimed on L1 in the transaction with this hash
+    Claimed(String),
+    /// no longer expected to be claimed
+    Expired,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct GovernanceConfig {
+    /// approvals required before a proposal can execute
+    pub threshold: u32,
+    /// nanoseconds a proposal waits before it can execute
+    pub delay: u64,
+    /// nanoseconds before a pending proposal expires
+    pub ttl: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            threshold: 1,
+            delay: 0,
+            ttl: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+impl GovernanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            return Err("Threshold must be at least 1".to_string());
+        }
+
+        if self.ttl <= self.delay {
+            return Err("Proposal ttl must be longer than the delay".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum ProposalAction {
+    Authorize(Principal),
+    SetGovernance(GovernanceConfig),
+    SetVolumeLimit(Option<TokendId>, VolumeLimit),
+    SetFee(TokendId, FeeConfig),
+    SetTreasury(Principal),
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: ProposalAction,
+    pub proposer: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub index: u64,
+    pub caller: Principal,
+    pub method: String,
+    /// hex encoded keccak256 of the candid encoded arguments
+    pub args_digest: String,
+    pub time: u64,
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum EscrowError {
+    ZeroAmount,
+    InsufficientEscrow { available: Nat, requested: Nat },
+}
+
+impl From<EscrowError> for TxError {
+    fn from(error: EscrowError) -> Self {
+        match error {
+            EscrowError::ZeroAmount => TxError::AmountTooSmall,
+            EscrowError::InsufficientEscrow {
+                available,
+                requested,
+            } => TxError::Other(format!(
+                "Escrow of {} is less than the requested {}!",
+                available, requested
+            )),
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct EscrowEntry {
+    pub owner: Principal,
+    pub token: TokendId,
+    pub amount: Nat,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct EscrowReport {
+    pub token: TokendId,
+    /// sum of all user escrows of token, burned already and owed on L1
+    pub escrowed: Nat,
+    /// collected fees not yet withdrawn
+    pub fees: Nat,
+    /// received for burns held for approval
+    pub unburned: Nat,
+    /// balance the proxy holds on the token canister
+    pub held: Nat,
+    /// whether the proxy holds at least what it owes
+    pub balanced: bool,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum CompensationPolicy {
+    /// mint the burned amount back to the user
+    Refund,
+    /// keep the amount so the message can be sent again
+    Retry,
+}
+
+impl Default for CompensationPolicy {
+    fn default() -> Self {
+        CompensationPolicy::Retry
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum CompensationOutcome {
+    /// the amount was minted back, with the mint transaction id
+    Refunded(Nat),
+    /// minting back failed, the amount stays retryable
+    RefundFailed(String),
+    /// the amount was kept for a retry
+    Retryable,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct CompensationEntry {
+    pub index: u64,
+    pub owner: Principal,
+    pub eth_addr: EthereumAddr,
+    pub token: TokendId,
+    pub amount: Nat,
+    /// error of the send that was compensated
+    pub reason: String,
+    pub outcome: CompensationOutcome,
+    pub time: u64,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub enum Flow {
+    Mint,
+    Burn,
+}
+
+/// Caps on bridged amounts, a missing cap is unlimited
+#[derive(Clone, CandidType, Deserialize, Default, Eq, PartialEq, Debug)]
+pub struct VolumeLimit {
+    /// largest amount of a single mint or burn
+    pub per_transaction: Option<Nat>,
+    /// largest amount minted within any 24h
+    pub daily_mint: Option<Nat>,
+    /// largest amount burned within any 24h
+    pub daily_burn: Option<Nat>,
+}
+
+impl VolumeLimit {
+    /// Check an amount against the caps, volume includes the amount
+    pub fn check(&self, flow: Flow, amount: &Nat, volume: &Nat) -> Result<(), String> {
+        if let Some(max) = &self.per_transaction {
+            if amount > max {
+                return Err(format!(
+                    "amount {} exceeds the per transaction limit {}",
+                    amount, max
+                ));
+            }
+        }
+
+        let (daily, name) = match flow {
+            Flow::Mint => (&self.daily_mint, "mint"),
+            Flow::Burn => (&self.daily_burn, "burn"),
+        };
+        if let Some(max) = daily {
+            if volume > max {
+                return Err(format!(
+                    "24h {} volume {} exceeds the limit {}",
+                    name, volume, max
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct VolumeLimits {
+    /// caps on the sum over all tokens
+    pub global: VolumeLimit,
+    pub tokens: Vec<(TokendId, VolumeLimit)>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct VolumeEntry {
+    pub token: TokendId,
+    pub flow: Flow,
+    pub amount: Nat,
+    pub time: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum HeldAction {
+    /// mint of a consumed message
+    Mint(MessageHash),
+    /// burn of tokens the proxy received, bridged to the address
+    Burn(EthereumAddr),
+}
+
+/// Operation over a volume limit, waiting for an admin to approve or reject it
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct HeldOperation {
+    pub id: u64,
+    /// beneficiary of a mint or sender of a burn
+    pub owner: Principal,
+    pub token: TokendId,
+    pub amount: Nat,
+    /// bridge fee collected with the operation, returned if it is rejected
+    pub fee: Nat,
+    pub action: HeldAction,
+    /// limit the operation hit
+    pub reason: String,
+    pub created_at: u64,
+}
+
+/// Fee charged on a bridged amount, a flat part plus basis points of the amount
+#[derive(Clone, CandidType, Deserialize, Default, Eq, PartialEq, Debug)]
+pub struct FeeConfig {
+    pub flat: Nat,
+    /// basis points of the amount, at most 10_000
+    pub bps: u32,
+    /// charge the fee on mint as well as on burn
+    pub on_mint: bool,
+}
+
+impl FeeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bps > 10_000 {
+            return Err(format!("Fee of {} bps exceeds 10000", self.bps));
+        }
+
+        Ok(())
+    }
+
+    pub fn fee(&self, amount: &Nat) -> Nat {
+        self.flat.clone() + amount.clone() * Nat::from(self.bps) / Nat::from(10_000_u32)
+    }
+}
+
+/// Result of a mint or burn, amount is what reached the other side after the fee
+#[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
+pub struct BridgeReceipt {
+    pub txn_id: Nat,
+    pub amount: Nat,
+    pub fee: Nat,
+}
+
+/// Comparison of what is locked on L1 against what the bridge owes for it
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct ReserveReport {
+    pub index: u64,
+    pub token: TokendId,
+    /// principal that supplied the L1 balance
+    pub reporter: Principal,
+    /// balance of token locked in ERC20Bridge as supplied by the reporter
+    pub l1_balance: Nat,
+    /// total supply of token on the IC
+    pub ic_supply: Nat,
+    /// burned on the IC and not yet withdrawn on L1
+    pub pending_claimables: Nat,
+    /// consumed from L1 and held before minting
+    pub unminted: Nat,
+    /// L1 holds more than the bridge owes
+    pub surplus: Nat,
+    /// L1 holds less than the bridge owes
+    pub deficit: Nat,
+    pub balanced: bool,
+    pub time: u64,
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -74,8 +408,48 @@ pub struct ProxyState {
     pub balances: RefCell<HashMap<Principal, HashMap<TokendId, Nat>>>,
     /// authorized principals
     pub controllers: RefCell<Vec<Principal>>,
-    // store outgoing massages waiting to be claimed
-    pub messages_unclaimed: RefCell<HashMap<EthereumAddr, Vec<ClaimableMessage>>>,
+    /// outgoing messages by msg_key, with their claim status
+    pub claimables: RefCell<HashMap<MsgHashKey, ClaimableMessage>>,
+    /// claimables by ETH address, rebuilt from claimables on upgrade
+    pub claimables_by_owner: RefCell<ClaimableIndex>,
+    /// claimables by IC sender, rebuilt from claimables on upgrade
+    pub claimables_by_sender: RefCell<ClaimableIndex>,
+    /// admin action audit log, oldest first
+    pub audit_log: RefCell<Vec<AuditEntry>>,
+    /// index of the next audit entry
+    pub audit_index: RefCell<u64>,
+    /// how burns whose message could not be sent are compensated
+    pub compensation_policy: RefCell<CompensationPolicy>,
+    /// every compensation, oldest first
+    pub compensations: RefCell<Vec<CompensationEntry>>,
+    /// caps on the sum over all tokens
+    pub global_limit: RefCell<VolumeLimit>,
+    /// caps per token
+    pub token_limits: RefCell<HashMap<TokendId, VolumeLimit>>,
+    /// mints and burns within the last 24h
+    pub volume: RefCell<Vec<VolumeEntry>>,
+    /// operations over a limit, waiting for approval
+    pub held_operations: RefCell<HashMap<u64, HeldOperation>>,
+    /// id of the next held operation
+    pub held_operation_index: RefCell<u64>,
+    /// fee charged per token
+    pub fees: RefCell<HashMap<TokendId, FeeConfig>>,
+    /// principal the collected fees are withdrawn to
+    pub treasury: RefCell<Option<Principal>>,
+    /// fees held by the proxy and not yet withdrawn
+    pub collected_fees: RefCell<HashMap<TokendId, Nat>>,
+    /// tokens whose collected fees are being withdrawn, not kept across upgrades
+    pub fee_withdrawals: RefCell<BTreeSet<TokendId>>,
+    /// history of the reserve reconciliations, the oldest are dropped
+    pub reserve_reports: RefCell<Vec<ReserveReport>>,
+    /// principal that reports the L1 balances for reconciliation
+    pub reporter: RefCell<Option<Principal>>,
+    /// approval threshold, delay and ttl of proposals
+    pub governance: RefCell<GovernanceConfig>,
+    /// pending proposals by id
+    pub proposals: RefCell<HashMap<u64, Proposal>>,
+    /// id of the next proposal
+    pub proposal_index: RefCell<u64>,
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -86,8 +460,41 @@ pub struct StableProxyState {
     pub balances: HashMap<Principal, HashMap<Principal, Nat>>,
     /// authorized principals
     pub controllers: Vec<Principal>,
-    // store outgoing massages waiting to be claimed
-    pub messages_unclaimed: HashMap<EthereumAddr, Vec<ClaimableMessage>>,
+    /// outgoing messages by msg_key, with their claim status
+    pub claimables: HashMap<MsgHashKey, ClaimableMessage>,
+    /// admin action audit log, oldest first
+    pub audit_log: Vec<AuditEntry>,
+    /// index of the next audit entry
+    pub audit_index: u64,
+    /// how burns whose message could not be sent are compensated
+    pub compensation_policy: CompensationPolicy,
+    /// every compensation, oldest first
+    pub compensations: Vec<CompensationEntry>,
+    /// caps on the sum over all tokens
+    pub global_limit: VolumeLimit,
+    /// caps per token
+    pub token_limits: HashMap<TokendId, VolumeLimit>,
+    /// mints and burns within the last 24h
+    pub volume: Vec<VolumeEntry>,
+    /// operations over a limit, waiting for approval
+    pub held_operations: HashMap<u64, HeldOperation>,
+    /// id of the next held operation
+    pub held_operation_index: u64,
+    /// fee charged per token
+    pub fees: HashMap<TokendId, FeeConfig>,
+    /// principal the collected fees are withdrawn to
+    pub treasury: Option<Principal>,
+    /// fees held by the proxy and not yet withdrawn
+    pub collected_fees: HashMap<TokendId, Nat>,
+    pub reserve_reports: Vec<ReserveReport>,
+    /// principal that reports the L1 balances for reconciliation
+    pub reporter: Option<Principal>,
+    /// approval threshold, delay and ttl of proposals
+    pub governance: GovernanceConfig,
+    /// pending proposals by id
+    pub proposals: HashMap<u64, Proposal>,
+    /// id of the next proposal
+    pub proposal_index: u64,
 }
 
 #[derive(CandidType, Deserialize, Clone, Copy)]
@@ -107,4 +514,6 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other(String),
+    AccountFrozen,
+    MintCapExceeded,
 }
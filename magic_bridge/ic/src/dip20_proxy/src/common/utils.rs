@@ -1,10 +1,11 @@
 use std::fmt;
 
-use ic_kit::candid::Nat;
+use ic_kit::candid::{encode_args, utils::ArgumentEncoder, Nat};
 use sha3::{Digest, Keccak256};
 
 use super::types::{
-    FactoryError, IncomingMessageHashParams, Message, MessageHash, OutgoingMessageHashParams,
+    AuditOutcome, FactoryError, IncomingMessageHashParams, Message, MessageHash,
+    OutgoingMessageHashParams,
 };
 
 impl fmt::Display for FactoryError {
@@ -21,6 +22,24 @@ impl fmt::Display for FactoryError {
     }
 }
 
+impl<T> From<&Result<T, String>> for AuditOutcome {
+    fn from(result: &Result<T, String>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Ok,
+            Err(error) => AuditOutcome::Err(error.clone()),
+        }
+    }
+}
+
+/// Digest of candid encoded call arguments for the audit log
+pub fn args_digest<T: ArgumentEncoder>(args: T) -> String {
+    let mut hasher = Keccak256::new();
+
+    hasher.update(encode_args(args).unwrap_or_default());
+
+    hex::encode(hasher.finalize().to_vec())
+}
+
 pub trait Keccak256HashFn<T> {
     fn calculate_hash(&self, params: T) -> MessageHash;
 }
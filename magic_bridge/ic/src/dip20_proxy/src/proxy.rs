@@ -1,17 +1,32 @@
-use std::{collections::HashMap, ops::AddAssign};
+use std::cell::RefCell;
+use std::ops::AddAssign;
 
 use ic_cdk::export::candid::{Nat, Principal};
 use ic_kit::ic;
 
 use crate::common::types::{
-    ClaimableMessage, EthereumAddr, MessageHash, MessageStatus, ProxyState, StableProxyState,
-    TokendId,
+    AuditEntry, AuditOutcome, ClaimStatus, ClaimableIndex, ClaimableMessage, CompensationEntry,
+    CompensationOutcome, CompensationPolicy, EscrowEntry, EscrowError, EthereumAddr, FeeConfig,
+    Flow, GovernanceConfig, HeldAction, HeldOperation, MessageHash, MessageStatus, MsgHashKey,
+    Proposal, ProposalAction, ProxyState, ReserveReport, StableProxyState, TokendId, VolumeEntry,
+    VolumeLimit, VolumeLimits,
 };
 
 pub const TERA_ADDRESS: &str = "timop-6qaaa-aaaab-qaeea-cai";
 pub const MAGIC_ADDRESS_IC: &str = "7z6fu-giaaa-aaaab-qafkq-cai";
 pub const ERC20_ADDRESS_ETH: &str = "0x15b661f6d3fd9a7ed8ed4c88bccfd1546644443f";
 
+pub const MAX_AUDIT_ENTRIES: usize = 1_000;
+pub const MAX_AUDIT_PAGE: usize = 100;
+pub const MAX_ESCROW_PAGE: usize = 100;
+pub const MAX_COMPENSATION_PAGE: usize = 100;
+pub const MAX_CLAIMABLE_PAGE: usize = 100;
+pub const MAX_RESERVE_REPORTS: usize = 1_000;
+pub const MAX_RESERVE_REPORT_PAGE: usize = 100;
+
+/// nanoseconds the rolling volume limits look back
+pub const VOLUME_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 thread_local! {
     pub static STATE: ProxyState = ProxyState::default();
 }
@@ -60,68 +75,505 @@ impl ProxyState {
         Err(format!("User {} has no token balances!", &caller))
     }
 
-    pub fn add_balance(&self, caller: Principal, token_id: TokendId, amount: Nat) {
-        self.balances
-            .borrow_mut()
-            .entry(caller)
+    pub fn credit_escrow(
+        &self,
+        owner: Principal,
+        token_id: TokendId,
+        amount: Nat,
+    ) -> Result<Nat, EscrowError> {
+        if amount == Nat::from(0_u32) {
+            return Err(EscrowError::ZeroAmount);
+        }
+
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances
+            .entry(owner)
             .or_default()
             .entry(token_id)
-            .or_default()
-            .add_assign(amount.clone())
+            .or_default();
+        balance.add_assign(amount);
+
+        Ok(balance.clone())
+    }
+
+    pub fn debit_escrow(
+        &self,
+        owner: Principal,
+        token_id: TokendId,
+        amount: Nat,
+    ) -> Result<Nat, EscrowError> {
+        if amount == Nat::from(0_u32) {
+            return Err(EscrowError::ZeroAmount);
+        }
+
+        let mut balances = self.balances.borrow_mut();
+        let available = balances
+            .get(&owner)
+            .and_then(|b| b.get(&token_id))
+            .cloned()
+            .unwrap_or_default();
+
+        if available < amount {
+            return Err(EscrowError::InsufficientEscrow {
+                available,
+                requested: amount,
+            });
+        }
+
+        let remaining = available - amount;
+        let owner_balances = balances.entry(owner).or_default();
+        if remaining == Nat::from(0_u32) {
+            owner_balances.remove(&token_id);
+        } else {
+            owner_balances.insert(token_id, remaining.clone());
+        }
+
+        if owner_balances.is_empty() {
+            balances.remove(&owner);
+        }
+
+        Ok(remaining)
     }
 
-    pub fn update_balance(&self, caller: Principal, token_id: TokendId, amount: Nat) {
+    /// Escrow entries ordered by owner and token
+    pub fn get_escrows(&self, start: u64, limit: usize) -> Vec<EscrowEntry> {
+        let mut escrows: Vec<EscrowEntry> = self
+            .balances
+            .borrow()
+            .iter()
+            .flat_map(|(owner, tokens)| {
+                tokens.iter().map(move |(token, amount)| EscrowEntry {
+                    owner: *owner,
+                    token: *token,
+                    amount: amount.clone(),
+                })
+            })
+            .collect();
+
+        escrows.sort_by(|a, b| (a.owner, a.token).cmp(&(b.owner, b.token)));
+        escrows
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_ESCROW_PAGE))
+            .collect()
+    }
+
+    pub fn escrow_total(&self, token_id: TokendId) -> Nat {
         self.balances
-            .borrow_mut()
-            .insert(caller, HashMap::from([(token_id, amount)]));
+            .borrow()
+            .values()
+            .filter_map(|tokens| tokens.get(&token_id))
+            .fold(Nat::from(0_u32), |total, amount| total + amount.clone())
     }
 
     pub fn add_claimable_message(&self, message: ClaimableMessage) {
-        let mut map = self.messages_unclaimed.borrow_mut();
-        let messages = map.entry(message.owner.clone()).or_insert_with(Vec::new);
+        let previous = self
+            .claimables
+            .borrow_mut()
+            .insert(message.msg_key, message.clone());
+
+        if let Some(previous) = previous {
+            self.unindex_claimable(&previous);
+        }
+        self.index_claimable(&message);
+    }
+
+    fn index_claimable(&self, message: &ClaimableMessage) {
+        self.claimables_by_owner.borrow_mut().insert((
+            message.owner,
+            message.created_at,
+            message.msg_key,
+        ));
+        if let Some(sender) = message.sender {
+            self.claimables_by_sender.borrow_mut().insert((
+                sender,
+                message.created_at,
+                message.msg_key,
+            ));
+        }
+    }
+
+    fn unindex_claimable(&self, message: &ClaimableMessage) {
+        self.claimables_by_owner.borrow_mut().remove(&(
+            message.owner,
+            message.created_at,
+            message.msg_key,
+        ));
+        if let Some(sender) = message.sender {
+            self.claimables_by_sender.borrow_mut().remove(&(
+                sender,
+                message.created_at,
+                message.msg_key,
+            ));
+        }
+    }
+
+    fn reindex_claimables(&self) {
+        self.claimables_by_owner.borrow_mut().clear();
+        self.claimables_by_sender.borrow_mut().clear();
+        for message in self.claimables.borrow().values() {
+            self.index_claimable(message);
+        }
+    }
 
-        messages.push(message.clone());
-        return;
+    /// Messages to an ETH address still waiting to be claimed
+    pub fn get_claimable_messages(&self, eth_address: EthereumAddr) -> Vec<ClaimableMessage> {
+        self.claimables_where(&self.claimables_by_owner, eth_address, |message| {
+            message.status == ClaimStatus::Pending
+        })
     }
 
-    pub fn remove_claimable_message(
+    /// Messages to an ETH address in any status, oldest first
+    pub fn get_claimables_by_eth_address(
         &self,
         eth_address: EthereumAddr,
-        token_id: TokendId,
+        start: u64,
+        limit: usize,
+    ) -> Vec<ClaimableMessage> {
+        self.claimables_where(&self.claimables_by_owner, eth_address, |_| true)
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_CLAIMABLE_PAGE))
+            .collect()
+    }
+
+    /// Messages sent by an IC principal in any status, oldest first
+    pub fn get_claimables_by_sender(
+        &self,
+        sender: Principal,
+        start: u64,
+        limit: usize,
+    ) -> Vec<ClaimableMessage> {
+        self.claimables_where(&self.claimables_by_sender, sender, |_| true)
+            .into_iter()
+            .skip(start as usize)
+            .take(limit.min(MAX_CLAIMABLE_PAGE))
+            .collect()
+    }
+
+    /// Messages of principal in index passing filter, oldest first
+    fn claimables_where<F: Fn(&ClaimableMessage) -> bool>(
+        &self,
+        index: &RefCell<ClaimableIndex>,
+        principal: Principal,
+        filter: F,
+    ) -> Vec<ClaimableMessage> {
+        let claimables = self.claimables.borrow();
+
+        index
+            .borrow()
+            .range((principal, 0, [0; 32])..=(principal, u64::MAX, [u8::MAX; 32]))
+            .filter_map(|(_, _, msg_key)| claimables.get(msg_key))
+            .filter(|message| filter(message))
+            .cloned()
+            .collect()
+    }
+
+    /// Record the L1 transaction that claimed a message, expired messages can still be claimed
+    pub fn mark_claimed(
+        &self,
+        msg_key: MsgHashKey,
+        tx_hash: String,
+        now: u64,
+    ) -> Result<(), String> {
+        let mut claimables = self.claimables.borrow_mut();
+        let message = claimables
+            .get_mut(&msg_key)
+            .ok_or_else(|| String::from("Message not found"))?;
+
+        if let ClaimStatus::Claimed(_) = message.status {
+            return Err(String::from("Message is already claimed"));
+        }
+
+        message.status = ClaimStatus::Claimed(tx_hash);
+        message.updated_at = now;
+
+        Ok(())
+    }
+
+    pub fn expire_claimable(&self, msg_key: MsgHashKey, now: u64) -> Result<(), String> {
+        let mut claimables = self.claimables.borrow_mut();
+        let message = claimables
+            .get_mut(&msg_key)
+            .ok_or_else(|| String::from("Message not found"))?;
+
+        if message.status != ClaimStatus::Pending {
+            return Err(String::from("Message is not pending"));
+        }
+
+        message.status = ClaimStatus::Expired;
+        message.updated_at = now;
+
+        Ok(())
+    }
+
+    pub fn record_compensation(
+        &self,
+        owner: Principal,
+        eth_addr: EthereumAddr,
+        token: TokendId,
         amount: Nat,
-    ) -> Result<bool, String> {
-        let mut map = self.messages_unclaimed.borrow_mut();
-        let messages = map
-            .get_mut(&eth_address)
-            .ok_or_else(|| "Message not found")?;
+        reason: String,
+        outcome: CompensationOutcome,
+    ) -> u64 {
+        let mut compensations = self.compensations.borrow_mut();
+        let index = compensations.len() as u64;
+
+        compensations.push(CompensationEntry {
+            index,
+            owner,
+            eth_addr,
+            token,
+            amount,
+            reason,
+            outcome,
+            time: ic::time(),
+        });
+
+        index
+    }
 
-        let item_index = messages
+    pub fn get_compensations(&self, start: u64, limit: usize) -> Vec<CompensationEntry> {
+        self.compensations
+            .borrow()
             .iter()
-            .position(|m| m.amount == amount && m.token == token_id)
-            .ok_or_else(|| "Message not found")?;
+            .skip(start as usize)
+            .take(limit.min(MAX_COMPENSATION_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    /// Record a mint or burn if it stays within the token and global limits
+    pub fn reserve_volume(
+        &self,
+        token: TokendId,
+        flow: Flow,
+        amount: Nat,
+        now: u64,
+    ) -> Result<VolumeEntry, String> {
+        self.volume
+            .borrow_mut()
+            .retain(|entry| now < entry.time.saturating_add(VOLUME_WINDOW));
 
-        messages.remove(item_index);
-        return Ok(true);
+        let token_limit = self
+            .token_limits
+            .borrow()
+            .get(&token)
+            .cloned()
+            .unwrap_or_default();
+        token_limit
+            .check(
+                flow,
+                &amount,
+                &(self.rolling_volume(Some(token), flow) + amount.clone()),
+            )
+            .map_err(|error| format!("Token {}: {}", token, error))?;
+
+        self.global_limit
+            .borrow()
+            .check(
+                flow,
+                &amount,
+                &(self.rolling_volume(None, flow) + amount.clone()),
+            )
+            .map_err(|error| format!("Global: {}", error))?;
+
+        Ok(self.record_volume(token, flow, amount, now))
     }
 
-    pub fn get_claimable_messages(&self, eth_address: EthereumAddr) -> Vec<ClaimableMessage> {
-        let unclaimed_messages = self
-            .messages_unclaimed
+    /// Record a mint or burn without checking the limits
+    pub fn record_volume(&self, token: TokendId, flow: Flow, amount: Nat, now: u64) -> VolumeEntry {
+        let entry = VolumeEntry {
+            token,
+            flow,
+            amount,
+            time: now,
+        };
+        self.volume.borrow_mut().push(entry.clone());
+
+        entry
+    }
+
+    /// Give back the volume of an operation that failed
+    pub fn release_volume(&self, entry: &VolumeEntry) {
+        let mut volume = self.volume.borrow_mut();
+        if let Some(index) = volume.iter().position(|e| e == entry) {
+            volume.remove(index);
+        }
+    }
+
+    /// Volume of a token, or of all tokens, recorded within the window
+    pub fn rolling_volume(&self, token: Option<TokendId>, flow: Flow) -> Nat {
+        self.volume
+            .borrow()
+            .iter()
+            .filter(|entry| entry.flow == flow && token.map_or(true, |t| t == entry.token))
+            .fold(Nat::from(0_u32), |total, entry| {
+                total + entry.amount.clone()
+            })
+    }
+
+    pub fn set_volume_limit(&self, token: Option<TokendId>, limit: VolumeLimit) {
+        match token {
+            Some(token) => {
+                self.token_limits.borrow_mut().insert(token, limit);
+            }
+            None => {
+                self.global_limit.replace(limit);
+            }
+        }
+    }
+
+    pub fn get_volume_limits(&self) -> VolumeLimits {
+        VolumeLimits {
+            global: self.global_limit.borrow().clone(),
+            tokens: self
+                .token_limits
+                .borrow()
+                .iter()
+                .map(|(token, limit)| (*token, limit.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn hold_operation(
+        &self,
+        owner: Principal,
+        token: TokendId,
+        amount: Nat,
+        fee: Nat,
+        action: HeldAction,
+        reason: String,
+        now: u64,
+    ) -> u64 {
+        let mut held_operation_index = self.held_operation_index.borrow_mut();
+        let id = *held_operation_index;
+        *held_operation_index += 1;
+
+        self.held_operations.borrow_mut().insert(
+            id,
+            HeldOperation {
+                id,
+                owner,
+                token,
+                amount,
+                fee,
+                action,
+                reason,
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    /// Held operations, oldest first
+    pub fn get_held_operations(&self) -> Vec<HeldOperation> {
+        let mut held_operations: Vec<HeldOperation> =
+            self.held_operations.borrow().values().cloned().collect();
+        held_operations.sort_by_key(|operation| operation.id);
+
+        held_operations
+    }
+
+    /// Remove a held operation so it is approved or rejected only once
+    pub fn take_held_operation(&self, id: u64) -> Result<HeldOperation, String> {
+        self.held_operations
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Held operation {} not found", id))
+    }
+
+    /// Put back a held operation whose approval or rejection failed
+    pub fn restore_held_operation(&self, operation: HeldOperation) {
+        self.held_operations
+            .borrow_mut()
+            .insert(operation.id, operation);
+    }
+
+    pub fn set_fee(&self, token: TokendId, fee: FeeConfig) {
+        self.fees.borrow_mut().insert(token, fee);
+    }
+
+    pub fn get_fees(&self) -> Vec<(TokendId, FeeConfig)> {
+        self.fees
             .borrow()
-            .get(&eth_address)
-            .unwrap_or(&vec![])
-            .clone();
-        return unclaimed_messages;
+            .iter()
+            .map(|(token, fee)| (*token, fee.clone()))
+            .collect()
     }
 
-    pub fn authorize(&self, other: Principal) {
-        let caller = ic::caller();
-        let caller_autorized = self.controllers.borrow().iter().any(|p| *p == caller);
-        if caller_autorized {
-            self.controllers.borrow_mut().push(other);
+    /// Fee on amount of token, never more than the amount itself
+    pub fn fee_for(&self, token: TokendId, flow: Flow, amount: &Nat) -> Nat {
+        match self.fees.borrow().get(&token) {
+            Some(fee) if flow == Flow::Burn || fee.on_mint => fee.fee(amount).min(amount.clone()),
+            _ => Nat::from(0_u32),
         }
     }
 
+    pub fn collect_fee(&self, token: TokendId, fee: Nat) {
+        self.collected_fees
+            .borrow_mut()
+            .entry(token)
+            .or_default()
+            .add_assign(fee);
+    }
+
+    pub fn collected_fee(&self, token: TokendId) -> Nat {
+        self.collected_fees
+            .borrow()
+            .get(&token)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn get_collected_fees(&self) -> Vec<(TokendId, Nat)> {
+        self.collected_fees
+            .borrow()
+            .iter()
+            .map(|(token, fee)| (*token, fee.clone()))
+            .collect()
+    }
+
+    /// Take back a fee collected with an operation that is not bridged,
+    /// returns what is left of it when the fees were withdrawn in between
+    pub fn return_collected_fee(&self, token: TokendId, fee: &Nat) -> Nat {
+        let mut collected_fees = self.collected_fees.borrow_mut();
+        let collected = collected_fees.entry(token).or_default();
+        let returned = fee.clone().min(collected.clone());
+        *collected -= returned.clone();
+
+        returned
+    }
+
+    /// Mark the collected fees of a token as being withdrawn,
+    /// so they are not withdrawn twice while the transfer is in flight
+    pub fn start_fee_withdrawal(&self, token: TokendId) -> Result<(), String> {
+        if !self.fee_withdrawals.borrow_mut().insert(token) {
+            return Err(format!("Fees of {} are already being withdrawn", token));
+        }
+
+        Ok(())
+    }
+
+    /// Deduct fees that reached the treasury, fees collected while
+    /// they were in flight stay collected
+    pub fn finish_fee_withdrawal(&self, token: TokendId, withdrawn: Option<&Nat>) {
+        self.fee_withdrawals.borrow_mut().remove(&token);
+
+        if let Some(withdrawn) = withdrawn {
+            let mut collected_fees = self.collected_fees.borrow_mut();
+            let collected = collected_fees.entry(token).or_default();
+            *collected -= withdrawn.clone().min(collected.clone());
+        }
+    }
+
+    pub fn is_reporter(&self) -> Result<(), String> {
+        (*self.reporter.borrow() == Some(ic::caller()))
+            .then(|| ())
+            .ok_or_else(|| String::from("Caller is not the reporter"))
+    }
+
     pub fn is_authorized(&self) -> Result<(), String> {
         self.controllers
             .borrow()
@@ -130,12 +582,275 @@ impl ProxyState {
             .ok_or("Caller is not authorized".to_string())
     }
 
+    pub fn propose(&self, proposer: Principal, action: ProposalAction, now: u64) -> u64 {
+        let id = self.proposal_index.replace_with(|index| *index + 1);
+
+        self.proposals.borrow_mut().insert(
+            id,
+            Proposal {
+                id,
+                action,
+                proposer,
+                approvals: vec![proposer],
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn approve_proposal(&self, id: u64, approver: Principal, now: u64) -> Result<(), String> {
+        self.expire_proposals(now);
+
+        let mut proposals = self.proposals.borrow_mut();
+        let proposal = proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if proposal.approvals.contains(&approver) {
+            return Err(format!("Proposal {} is already approved by caller", id));
+        }
+
+        proposal.approvals.push(approver);
+
+        Ok(())
+    }
+
+    pub fn proposal_ready(&self, id: u64, now: u64) -> Result<(), String> {
+        let config = self.governance.borrow().clone();
+        let proposals = self.proposals.borrow();
+        let proposal = proposals
+            .get(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if now > proposal.created_at.saturating_add(config.ttl) {
+            return Err(format!("Proposal {} has expired", id));
+        }
+
+        // approvals of principals that lost their role do not count
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|p| self.controllers.borrow().contains(p))
+            .count() as u32;
+        if approvals < config.threshold {
+            return Err(format!(
+                "Proposal {} has {} of {} approvals",
+                id, approvals, config.threshold
+            ));
+        }
+
+        let executable_at = proposal.created_at.saturating_add(config.delay);
+        if now < executable_at {
+            return Err(format!(
+                "Proposal {} is timelocked until {}",
+                id, executable_at
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn take_ready_proposal(&self, id: u64, now: u64) -> Result<Proposal, String> {
+        self.expire_proposals(now);
+        self.proposal_ready(id, now)?;
+
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn cancel_proposal(&self, id: u64) -> Result<Proposal, String> {
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn expire_proposals(&self, now: u64) {
+        let ttl = self.governance.borrow().ttl;
+
+        self.proposals
+            .borrow_mut()
+            .retain(|_, p| now <= p.created_at.saturating_add(ttl));
+    }
+
+    pub fn get_pending_proposals(&self, now: u64) -> Vec<Proposal> {
+        let ttl = self.governance.borrow().ttl;
+        let mut proposals: Vec<Proposal> = self
+            .proposals
+            .borrow()
+            .values()
+            .filter(|p| now <= p.created_at.saturating_add(ttl))
+            .cloned()
+            .collect();
+
+        proposals.sort_by_key(|p| p.id);
+        proposals
+    }
+
+    pub fn record_audit(&self, method: &str, args_digest: String, outcome: AuditOutcome) {
+        self.record_audit_of(ic::caller(), method, args_digest, outcome);
+    }
+
+    /// Record a call whose caller was read before an await, the caller
+    /// cannot be read in the callback of an inter-canister call
+    pub fn record_audit_of(
+        &self,
+        caller: Principal,
+        method: &str,
+        args_digest: String,
+        outcome: AuditOutcome,
+    ) {
+        let mut audit_log = self.audit_log.borrow_mut();
+        let mut audit_index = self.audit_index.borrow_mut();
+
+        audit_log.push(AuditEntry {
+            index: *audit_index,
+            caller,
+            method: method.to_string(),
+            args_digest,
+            time: ic::time(),
+            outcome,
+        });
+        *audit_index += 1;
+
+        // the log is bounded, drop the oldest entries
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            let overflow = audit_log.len() - MAX_AUDIT_ENTRIES;
+            audit_log.drain(..overflow);
+        }
+    }
+
+    pub fn get_audit_log(&self, start: u64, limit: usize) -> Vec<AuditEntry> {
+        self.audit_log
+            .borrow()
+            .iter()
+            .filter(|e| e.index >= start)
+            .take(limit.min(MAX_AUDIT_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    /// Amount of token burned on the IC and not yet claimed on L1,
+    /// expired messages can still be claimed so they are owed as well
+    pub fn pending_claimable_total(&self, token: TokendId) -> Nat {
+        self.claimables
+            .borrow()
+            .values()
+            .filter(|message| {
+                message.token == token && !matches!(message.status, ClaimStatus::Claimed(_))
+            })
+            .fold(Nat::from(0_u32), |total, message| {
+                total + message.amount.clone()
+            })
+    }
+
+    /// Amount of token the proxy received for burns held for approval
+    pub fn unburned_total(&self, token: TokendId) -> Nat {
+        self.held_operations
+            .borrow()
+            .values()
+            .filter(|operation| {
+                operation.token == token && matches!(operation.action, HeldAction::Burn(_))
+            })
+            .fold(Nat::from(0_u32), |total, operation| {
+                total + operation.amount.clone()
+            })
+    }
+
+    /// Amount of token consumed from L1 whose mint is held for approval
+    pub fn unminted_total(&self, token: TokendId) -> Nat {
+        self.held_operations
+            .borrow()
+            .values()
+            .filter(|operation| {
+                operation.token == token && matches!(operation.action, HeldAction::Mint(_))
+            })
+            .fold(Nat::from(0_u32), |total, operation| {
+                total + operation.amount.clone()
+            })
+    }
+
+    /// Compare the L1 balance of token against its IC supply and what is in flight,
+    /// the report is kept in the history
+    pub fn record_reserve_report(
+        &self,
+        token: TokendId,
+        reporter: Principal,
+        l1_balance: Nat,
+        ic_supply: Nat,
+        now: u64,
+    ) -> ReserveReport {
+        let pending_claimables = self.pending_claimable_total(token);
+        let unminted = self.unminted_total(token);
+
+        let owed = ic_supply.clone() + pending_claimables.clone() + unminted.clone();
+        let (surplus, deficit) = if l1_balance >= owed {
+            (l1_balance.clone() - owed, Nat::from(0_u32))
+        } else {
+            (Nat::from(0_u32), owed - l1_balance.clone())
+        };
+
+        let mut reserve_reports = self.reserve_reports.borrow_mut();
+        let report = ReserveReport {
+            index: reserve_reports.last().map_or(0, |report| report.index + 1),
+            token,
+            reporter,
+            l1_balance,
+            ic_supply,
+            pending_claimables,
+            unminted,
+            balanced: surplus == Nat::from(0_u32) && deficit == Nat::from(0_u32),
+            surplus,
+            deficit,
+            time: now,
+        };
+        reserve_reports.push(report.clone());
+
+        // the history is bounded, drop the oldest reports
+        if reserve_reports.len() > MAX_RESERVE_REPORTS {
+            let overflow = reserve_reports.len() - MAX_RESERVE_REPORTS;
+            reserve_reports.drain(..overflow);
+        }
+
+        report
+    }
+
+    pub fn get_reserve_reports(&self, start: u64, limit: usize) -> Vec<ReserveReport> {
+        self.reserve_reports
+            .borrow()
+            .iter()
+            .filter(|report| report.index >= start)
+            .take(limit.min(MAX_RESERVE_REPORT_PAGE))
+            .cloned()
+            .collect()
+    }
+
     pub fn take_all(&self) -> StableProxyState {
         StableProxyState {
             balances: self.balances.take(),
             controllers: self.controllers.take(),
             incoming_messages: self.incoming_messages.take(),
-            messages_unclaimed: self.messages_unclaimed.take(),
+            claimables: self.claimables.take(),
+            audit_log: self.audit_log.take(),
+            audit_index: self.audit_index.take(),
+            compensation_policy: self.compensation_policy.take(),
+            compensations: self.compensations.take(),
+            global_limit: self.global_limit.take(),
+            token_limits: self.token_limits.take(),
+            volume: self.volume.take(),
+            held_operations: self.held_operations.take(),
+            held_operation_index: self.held_operation_index.take(),
+            fees: self.fees.take(),
+            treasury: self.treasury.take(),
+            collected_fees: self.collected_fees.take(),
+            reserve_reports: self.reserve_reports.take(),
+            reporter: self.reporter.take(),
+            governance: self.governance.take(),
+            proposals: self.proposals.take(),
+            proposal_index: self.proposal_index.take(),
         }
     }
 
@@ -143,7 +858,28 @@ impl ProxyState {
         self.balances.borrow_mut().clear();
         self.controllers.borrow_mut().clear();
         self.incoming_messages.borrow_mut().clear();
-        self.messages_unclaimed.borrow_mut().clear();
+        self.claimables.borrow_mut().clear();
+        self.claimables_by_owner.borrow_mut().clear();
+        self.claimables_by_sender.borrow_mut().clear();
+        self.audit_log.borrow_mut().clear();
+        self.audit_index.replace(0);
+        self.compensation_policy
+            .replace(CompensationPolicy::default());
+        self.compensations.borrow_mut().clear();
+        self.global_limit.replace(VolumeLimit::default());
+        self.token_limits.borrow_mut().clear();
+        self.volume.borrow_mut().clear();
+        self.held_operations.borrow_mut().clear();
+        self.held_operation_index.replace(0);
+        self.fees.borrow_mut().clear();
+        self.treasury.replace(None);
+        self.collected_fees.borrow_mut().clear();
+        self.fee_withdrawals.borrow_mut().clear();
+        self.reserve_reports.borrow_mut().clear();
+        self.reporter.replace(None);
+        self.governance.replace(GovernanceConfig::default());
+        self.proposals.borrow_mut().clear();
+        self.proposal_index.replace(0);
     }
 
     pub fn replace_all(&self, stable_message_state: StableProxyState) {
@@ -151,8 +887,32 @@ impl ProxyState {
         self.controllers.replace(stable_message_state.controllers);
         self.incoming_messages
             .replace(stable_message_state.incoming_messages);
-        self.messages_unclaimed
-            .replace(stable_message_state.messages_unclaimed);
+        self.claimables.replace(stable_message_state.claimables);
+        self.reindex_claimables();
+        self.audit_log.replace(stable_message_state.audit_log);
+        self.audit_index.replace(stable_message_state.audit_index);
+        self.compensation_policy
+            .replace(stable_message_state.compensation_policy);
+        self.compensations
+            .replace(stable_message_state.compensations);
+        self.global_limit.replace(stable_message_state.global_limit);
+        self.token_limits.replace(stable_message_state.token_limits);
+        self.volume.replace(stable_message_state.volume);
+        self.held_operations
+            .replace(stable_message_state.held_operations);
+        self.held_operation_index
+            .replace(stable_message_state.held_operation_index);
+        self.fees.replace(stable_message_state.fees);
+        self.treasury.replace(stable_message_state.treasury);
+        self.collected_fees
+            .replace(stable_message_state.collected_fees);
+        self.reserve_reports
+            .replace(stable_message_state.reserve_reports);
+        self.reporter.replace(stable_message_state.reporter);
+        self.governance.replace(stable_message_state.governance);
+        self.proposals.replace(stable_message_state.proposals);
+        self.proposal_index
+            .replace(stable_message_state.proposal_index);
     }
 }
 
@@ -193,12 +953,12 @@ mod tests {
     use std::str::FromStr;
 
     use crate::common::{
-        types::{IncomingMessageHashParams, Message},
+        types::{IncomingMessageHashParams, Message, OutgoingMessage},
         utils::Keccak256HashFn,
     };
 
     use super::*;
-    use ic_kit::mock_principals;
+    use ic_kit::{mock_principals, MockContext};
 
     #[test]
     fn test_message_status_new_message() {
@@ -268,7 +1028,7 @@ mod tests {
         let pid = mock_principals::bob();
         let token_id = mock_principals::alice();
 
-        STATE.with(|s| s.add_balance(pid, token_id, amount.clone()));
+        STATE.with(|s| s.credit_escrow(pid, token_id, amount.clone()).unwrap());
 
         let balance_of = STATE.with(|s| s.get_balance(pid, token_id));
         let balance = balance_of.unwrap();
@@ -283,8 +1043,8 @@ mod tests {
         let token_id_1 = mock_principals::alice();
         let token_id_2 = mock_principals::john();
 
-        STATE.with(|s| s.add_balance(caller, token_id_1, amount.clone()));
-        STATE.with(|s| s.add_balance(caller, token_id_2, amount.clone()));
+        STATE.with(|s| s.credit_escrow(caller, token_id_1, amount.clone()).unwrap());
+        STATE.with(|s| s.credit_escrow(caller, token_id_2, amount.clone()).unwrap());
 
         let balances = STATE.with(|s| s.get_all_balances(caller));
 
@@ -296,24 +1056,60 @@ mod tests {
     }
 
     #[test]
-    fn test_update_balance() {
+    fn test_debit_escrow() {
         let amount = Nat::from(100_u32);
         let caller = mock_principals::bob();
-        let token_id = mock_principals::alice();
+        let token_id_1 = mock_principals::alice();
+        let token_id_2 = mock_principals::john();
 
-        STATE.with(|s| s.add_balance(caller, token_id, amount.clone()));
+        STATE.with(|s| s.credit_escrow(caller, token_id_1, amount.clone()).unwrap());
+        STATE.with(|s| s.credit_escrow(caller, token_id_2, amount.clone()).unwrap());
 
-        let balance_of = STATE.with(|s| s.get_balance(caller, token_id));
-        let balance = balance_of.unwrap();
+        let remaining = STATE.with(|s| s.debit_escrow(caller, token_id_1, Nat::from(40_u32)));
+        assert_eq!(remaining, Ok(Nat::from(60_u32)));
 
-        assert_eq!(balance, amount.clone());
+        // other tokens of the same owner are untouched
+        let balance = STATE.with(|s| s.get_balance(caller, token_id_2));
+        assert_eq!(balance, Some(amount.clone()));
+
+        let overdraft = STATE.with(|s| s.debit_escrow(caller, token_id_1, amount.clone()));
+        assert_eq!(
+            overdraft,
+            Err(EscrowError::InsufficientEscrow {
+                available: Nat::from(60_u32),
+                requested: amount.clone(),
+            })
+        );
 
-        let new_balance = Nat::from(134_u32);
-        STATE.with(|s| s.update_balance(caller, token_id, new_balance.clone()));
+        let zero = STATE.with(|s| s.debit_escrow(caller, token_id_1, Nat::from(0_u32)));
+        assert_eq!(zero, Err(EscrowError::ZeroAmount));
+    }
+
+    #[test]
+    fn test_get_escrows() {
+        let amount = Nat::from(100_u32);
+        let token_id = mock_principals::alice();
+
+        STATE.with(|s| {
+            s.credit_escrow(mock_principals::bob(), token_id, amount.clone())
+                .unwrap()
+        });
+        STATE.with(|s| {
+            s.credit_escrow(mock_principals::john(), token_id, amount.clone())
+                .unwrap()
+        });
+        STATE.with(|s| {
+            s.debit_escrow(mock_principals::john(), token_id, amount.clone())
+                .unwrap()
+        });
 
-        let balance_after_update = STATE.with(|s| s.get_balance(caller, token_id));
+        let escrows = STATE.with(|s| s.get_escrows(0, 10));
 
-        assert_eq!(balance_after_update.unwrap(), new_balance);
+        // emptied escrows are dropped
+        assert_eq!(escrows.len(), 1);
+        assert_eq!(escrows[0].owner, mock_principals::bob());
+        assert_eq!(STATE.with(|s| s.escrow_total(token_id)), amount);
+        assert!(STATE.with(|s| s.get_escrows(1, 10)).is_empty());
     }
 
     #[test]
@@ -412,121 +1208,517 @@ mod tests {
         );
     }
 
+    fn claimable(owner: EthereumAddr, msg_key: MsgHashKey, created_at: u64) -> ClaimableMessage {
+        ClaimableMessage::pending(
+            owner,
+            mock_principals::bob(),
+            &OutgoingMessage {
+                msg_key,
+                msg_hash: String::from("123123123"),
+            },
+            mock_principals::alice(),
+            Nat::from(1_u64),
+            created_at,
+        )
+    }
+
     #[test]
     fn test_claimable_messages() {
         let eth_addr_1 = Principal::from_slice(
             &hex::decode("15B661f6D3FD9A7ED8Ed4c88bCcfD1546644443f").unwrap(),
         );
         let msg_key_1: [u8; 32] = [0; 32];
-
-        let msg_hash_1 = String::from("123123123");
         let msg_key_2: [u8; 32] = [1; 32];
-        let token_id_1 = Principal::from_slice(
-            &hex::decode("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap(),
-        );
-        let amount_1 = Nat::from(1_u64);
-
-        // first msg
-        let message_1 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_1.clone(),
-            token: token_id_1.clone(),
-            amount: amount_1.clone(),
-        };
 
-        // second msg -> identical to first msg but with different msg_key
-        let message_2 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_2.clone(),
-            token: token_id_1.clone(),
-            amount: amount_1.clone(),
-        };
-
-        // add first msg
-        STATE.with(|s| s.add_claimable_message(message_1));
-        // add second msg
-        STATE.with(|s| s.add_claimable_message(message_2));
+        // two identical withdrawals only differ in msg_key
+        STATE.with(|s| {
+            s.add_claimable_message(claimable(eth_addr_1, msg_key_1, 1));
+            s.add_claimable_message(claimable(eth_addr_1, msg_key_2, 2));
+        });
 
-        // check if both messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
+        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1));
         assert_eq!(claimable_messages.len(), 2);
 
-        // remove one msg (both have same amount and token)
-        let result_remove_1 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), token_id_1.clone(), amount_1.clone());
-        });
-        assert_eq!(result_remove_1, ());
+        // the second message is claimed, the first one stays pending
+        assert!(STATE
+            .with(|s| s.mark_claimed(msg_key_2, String::from("0xabc"), 3))
+            .is_ok());
+        assert!(STATE
+            .with(|s| s.mark_claimed(msg_key_2, String::from("0xabc"), 3))
+            .is_err());
 
-        // check if only one message is in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
+        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1));
         assert_eq!(claimable_messages.len(), 1);
+        assert_eq!(claimable_messages[0].msg_key, msg_key_1);
+
+        assert!(STATE.with(|s| s.expire_claimable(msg_key_1, 4)).is_ok());
+        assert!(STATE.with(|s| s.expire_claimable(msg_key_2, 4)).is_err());
+        assert!(STATE
+            .with(|s| s.get_claimable_messages(eth_addr_1))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_claimables_paged() {
+        let eth_addr_1 = Principal::from_slice(&[1; 20]);
+        let eth_addr_2 = Principal::from_slice(&[2; 20]);
 
-        // remove second msg (both have same amount and token)
-        let result_remove_2 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), token_id_1.clone(), amount_1.clone());
+        STATE.with(|s| {
+            for i in 0..MAX_CLAIMABLE_PAGE as u8 + 1 {
+                s.add_claimable_message(claimable(eth_addr_1, [i; 32], i as u64));
+            }
+            s.add_claimable_message(claimable(eth_addr_2, [255; 32], 0));
         });
-        assert_eq!(result_remove_2, ());
 
-        // check if no messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 0);
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_1, 0, usize::MAX));
+        assert_eq!(page.len(), MAX_CLAIMABLE_PAGE);
+        assert_eq!(page[1].msg_key, [1; 32]);
+
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_1, 100, 10));
+        assert_eq!(page.len(), 1);
+
+        let page = STATE.with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10));
+        assert_eq!(page.len(), 10);
+        assert_eq!(page[0].msg_key, [0; 32]);
+        assert!(STATE
+            .with(|s| s.get_claimables_by_sender(mock_principals::alice(), 0, 10))
+            .is_empty());
     }
 
     #[test]
-    fn test_claimable_messages_with_different_amounts() {
-        let eth_addr_1 = Principal::from_slice(
-            &hex::decode("15B661f6D3FD9A7ED8Ed4c88bCcfD1546644443f").unwrap(),
+    fn test_claimables_index() {
+        let eth_addr_1 = Principal::from_slice(&[1; 20]);
+        let eth_addr_2 = Principal::from_slice(&[2; 20]);
+
+        // a message stored again under its msg_key moves to its new owner
+        STATE.with(|s| {
+            s.add_claimable_message(claimable(eth_addr_1, [0; 32], 0));
+            s.add_claimable_message(claimable(eth_addr_2, [0; 32], 1));
+        });
+        assert!(STATE
+            .with(|s| s.get_claimables_by_eth_address(eth_addr_1, 0, 10))
+            .is_empty());
+        assert_eq!(
+            STATE
+                .with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10))
+                .len(),
+            1
         );
-        let msg_key_1: [u8; 32] = [0; 32];
-        let msg_hash_1 = String::from("123123123");
-        let token_id_1 = Principal::from_slice(
-            &hex::decode("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap(),
+
+        // the indexes are rebuilt on upgrade
+        STATE.with(|s| {
+            let stable_state = s.take_all();
+            s.clear_all();
+            s.replace_all(stable_state);
+        });
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr_2, 0, 10));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].created_at, 1);
+        assert_eq!(
+            STATE
+                .with(|s| s.get_claimables_by_sender(mock_principals::bob(), 0, 10))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_claim_expired() {
+        let eth_addr = Principal::from_slice(&[1; 20]);
+        STATE.with(|s| s.add_claimable_message(claimable(eth_addr, [0; 32], 0)));
+
+        assert_eq!(
+            STATE.with(|s| s.mark_claimed([1; 32], String::from("0xabc"), 1)),
+            Err(String::from("Message not found"))
         );
-        let amount_1 = Nat::from(1_u64);
 
-        let msg_key_2: [u8; 32] = [1; 32];
-        let amount_2 = Nat::from(2_u64);
-
-        // first msg
-        let message_1 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_1.clone(),
-            token: token_id_1.clone(),
-            amount: amount_1.clone(),
+        // an expired message can still be claimed, a claimed one cannot expire
+        assert!(STATE.with(|s| s.expire_claimable([0; 32], 1)).is_ok());
+        assert!(STATE
+            .with(|s| s.mark_claimed([0; 32], String::from("0xabc"), 2))
+            .is_ok());
+        assert!(STATE.with(|s| s.expire_claimable([0; 32], 3)).is_err());
+
+        let page = STATE.with(|s| s.get_claimables_by_eth_address(eth_addr, 0, 10));
+        assert_eq!(page[0].status, ClaimStatus::Claimed(String::from("0xabc")));
+        assert_eq!(page[0].updated_at, 2);
+    }
+
+    #[test]
+    fn test_record_audit() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        STATE.with(|s| {
+            s.record_audit(
+                "remove_claimable",
+                String::from("01"),
+                AuditOutcome::Err(String::from("Message not found")),
+            )
+        });
+
+        let audit_log = STATE.with(|s| s.get_audit_log(0, 10));
+
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].caller, mock_principals::alice());
+        assert_eq!(audit_log[0].method, "authorize");
+        assert_eq!(audit_log[1].index, 1);
+        assert_eq!(
+            audit_log[1].outcome,
+            AuditOutcome::Err(String::from("Message not found"))
+        );
+
+        let page = STATE.with(|s| s.get_audit_log(1, 10));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].method, "remove_claimable");
+    }
+
+    #[test]
+    fn test_audit_log_bounded() {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        for _ in 0..MAX_AUDIT_ENTRIES + 5 {
+            STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        }
+
+        let audit_log = STATE.with(|s| s.audit_log.borrow().clone());
+
+        assert_eq!(audit_log.len(), MAX_AUDIT_ENTRIES);
+        // oldest entries were dropped
+        assert_eq!(audit_log[0].index, 5);
+        assert_eq!(
+            STATE.with(|s| s.get_audit_log(0, usize::MAX)).len(),
+            MAX_AUDIT_PAGE
+        );
+    }
+
+    #[test]
+    fn test_record_compensation() {
+        MockContext::new().inject();
+
+        let owner = mock_principals::bob();
+        let token_id = mock_principals::alice();
+        let amount = Nat::from(100_u32);
+
+        for _ in 0..MAX_COMPENSATION_PAGE + 1 {
+            STATE.with(|s| {
+                s.record_compensation(
+                    owner,
+                    owner,
+                    token_id,
+                    amount.clone(),
+                    String::from("error"),
+                    CompensationOutcome::Retryable,
+                )
+            });
+        }
+
+        let compensations = STATE.with(|s| s.get_compensations(0, usize::MAX));
+
+        assert_eq!(compensations.len(), MAX_COMPENSATION_PAGE);
+        assert_eq!(compensations[1].index, 1);
+        assert_eq!(compensations[1].outcome, CompensationOutcome::Retryable);
+
+        let last = STATE.with(|s| s.get_compensations(MAX_COMPENSATION_PAGE as u64, 10));
+        assert_eq!(last.len(), 1);
+    }
+
+    #[test]
+    fn test_reserve_volume() {
+        MockContext::new().inject();
+
+        let token_id = mock_principals::alice();
+        let other_token_id = mock_principals::bob();
+
+        STATE.with(|s| {
+            s.set_volume_limit(
+                Some(token_id),
+                VolumeLimit {
+                    per_transaction: Some(Nat::from(50_u32)),
+                    daily_mint: Some(Nat::from(100_u32)),
+                    daily_burn: None,
+                },
+            );
+            s.set_volume_limit(
+                None,
+                VolumeLimit {
+                    per_transaction: None,
+                    daily_mint: Some(Nat::from(120_u32)),
+                    daily_burn: None,
+                },
+            );
+        });
+
+        let reserve = |token: TokendId, amount: u32, now: u64| {
+            STATE.with(|s| s.reserve_volume(token, Flow::Mint, Nat::from(amount), now))
         };
 
-        // second msg -> same token, different amount
-        let message_2 = ClaimableMessage {
-            owner: eth_addr_1.clone(),
-            msg_hash: msg_hash_1.clone(),
-            msg_key: msg_key_2.clone(),
-            token: token_id_1.clone(),
-            amount: amount_2.clone(),
+        assert!(reserve(token_id, 60, 0).is_err());
+        assert!(reserve(token_id, 50, 0).is_ok());
+        assert!(reserve(token_id, 50, 10).is_ok());
+        // token cap reached
+        assert!(reserve(token_id, 10, 20).is_err());
+        // global cap reached
+        assert!(reserve(other_token_id, 30, 20).is_err());
+        assert!(reserve(other_token_id, 20, 20).is_ok());
+        // burns are not capped
+        assert!(STATE
+            .with(|s| s.reserve_volume(token_id, Flow::Burn, Nat::from(50_u32), 20))
+            .is_ok());
+
+        // the first mint left the window
+        assert!(reserve(token_id, 50, VOLUME_WINDOW).is_ok());
+
+        let entry = reserve(other_token_id, 0, VOLUME_WINDOW).unwrap();
+        STATE.with(|s| s.release_volume(&entry));
+        assert_eq!(
+            STATE.with(|s| s.rolling_volume(None, Flow::Mint)),
+            Nat::from(120_u32)
+        );
+    }
+
+    #[test]
+    fn test_held_operations() {
+        MockContext::new().inject();
+
+        let id = STATE.with(|s| {
+            s.hold_operation(
+                mock_principals::bob(),
+                mock_principals::alice(),
+                Nat::from(100_u32),
+                Nat::from(0_u32),
+                HeldAction::Burn(Principal::from_slice(&[1; 20])),
+                String::from("limit"),
+                0,
+            )
+        });
+
+        assert_eq!(STATE.with(|s| s.get_held_operations()).len(), 1);
+        assert_eq!(
+            STATE.with(|s| s.unburned_total(mock_principals::alice())),
+            Nat::from(100_u32)
+        );
+        assert!(STATE.with(|s| s.take_held_operation(id)).is_ok());
+        assert_eq!(
+            STATE.with(|s| s.unburned_total(mock_principals::alice())),
+            Nat::from(0_u32)
+        );
+        assert!(STATE.with(|s| s.take_held_operation(id)).is_err());
+    }
+
+    #[test]
+    fn test_fees() {
+        MockContext::new().inject();
+
+        let token_id = mock_principals::alice();
+        STATE.with(|s| {
+            s.set_fee(
+                token_id,
+                FeeConfig {
+                    flat: Nat::from(10_u32),
+                    bps: 50,
+                    on_mint: false,
+                },
+            )
+        });
+
+        let fee_for = |token: TokendId, flow: Flow, amount: u32| {
+            STATE.with(|s| s.fee_for(token, flow, &Nat::from(amount)))
         };
 
-        // add first msg
-        STATE.with(|s| s.add_claimable_message(message_1));
-        // add second msg
-        STATE.with(|s| s.add_claimable_message(message_2));
+        assert_eq!(fee_for(token_id, Flow::Burn, 10_000), Nat::from(60_u32));
+        assert_eq!(fee_for(token_id, Flow::Burn, 5), Nat::from(5_u32));
+        assert_eq!(fee_for(token_id, Flow::Mint, 10_000), Nat::from(0_u32));
+        assert_eq!(
+            fee_for(mock_principals::bob(), Flow::Burn, 10_000),
+            Nat::from(0_u32)
+        );
 
-        // check if both messages are in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 2);
+        STATE.with(|s| {
+            s.collect_fee(token_id, Nat::from(60_u32));
+            s.collect_fee(token_id, Nat::from(5_u32));
+        });
+        assert_eq!(STATE.with(|s| s.collected_fee(token_id)), Nat::from(65_u32));
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_ok());
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_err());
 
-        // remove one msg -> the one with amount_2 (both are the same token, but different amount)
-        let result_remove_1 = STATE.with(|s| {
-            s.remove_claimable_message(eth_addr_1.clone(), token_id_1.clone(), amount_2.clone());
+        // a fee collected while the withdrawal is in flight stays collected
+        STATE.with(|s| {
+            s.collect_fee(token_id, Nat::from(10_u32));
+            s.finish_fee_withdrawal(token_id, Some(&Nat::from(65_u32)));
         });
-        assert_eq!(result_remove_1, ());
+        assert_eq!(STATE.with(|s| s.collected_fee(token_id)), Nat::from(10_u32));
+        assert!(STATE.with(|s| s.start_fee_withdrawal(token_id)).is_ok());
+    }
 
-        // check if only one message is in the claimable messages list for eth_addr_1
-        let claimable_messages = STATE.with(|s| s.get_claimable_messages(eth_addr_1.clone()));
-        assert_eq!(claimable_messages.len(), 1);
+    #[test]
+    fn test_reserve_report() {
+        MockContext::new().inject();
+
+        let token_id = mock_principals::alice();
+        let eth_addr = Principal::from_slice(&[1; 20]);
+
+        STATE.with(|s| {
+            // 1 burned and pending on L1, 1 expired and still claimable,
+            // 10 consumed from L1 and held
+            s.add_claimable_message(claimable(eth_addr, [0; 32], 0));
+            s.add_claimable_message(claimable(eth_addr, [1; 32], 0));
+            s.expire_claimable([1; 32], 0).unwrap();
+            s.hold_operation(
+                mock_principals::bob(),
+                token_id,
+                Nat::from(10_u32),
+                Nat::from(0_u32),
+                HeldAction::Mint(String::from("a")),
+                String::from("limit"),
+                0,
+            );
+            s.hold_operation(
+                mock_principals::bob(),
+                token_id,
+                Nat::from(100_u32),
+                Nat::from(0_u32),
+                HeldAction::Burn(eth_addr),
+                String::from("limit"),
+                0,
+            );
+        });
+
+        let report = STATE.with(|s| {
+            s.record_reserve_report(
+                token_id,
+                mock_principals::john(),
+                Nat::from(1_012_u32),
+                Nat::from(1_000_u32),
+                5,
+            )
+        });
+        assert_eq!(report.pending_claimables, Nat::from(2_u32));
+        assert_eq!(report.unminted, Nat::from(10_u32));
+        assert!(report.balanced);
+
+        let report = STATE.with(|s| {
+            s.record_reserve_report(
+                token_id,
+                mock_principals::john(),
+                Nat::from(1_100_u32),
+                Nat::from(1_000_u32),
+                6,
+            )
+        });
+        assert_eq!(report.surplus, Nat::from(88_u32));
+        assert_eq!(report.deficit, Nat::from(0_u32));
+        assert!(!report.balanced);
+
+        let reports = STATE.with(|s| s.get_reserve_reports(1, 10));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].index, 1);
+    }
+
+    #[test]
+    fn test_reserve_reports_bounded() {
+        MockContext::new().inject();
+
+        for time in 0..MAX_RESERVE_REPORTS as u64 + 5 {
+            STATE.with(|s| {
+                s.record_reserve_report(
+                    mock_principals::alice(),
+                    mock_principals::john(),
+                    Nat::from(0_u32),
+                    Nat::from(0_u32),
+                    time,
+                )
+            });
+        }
+
+        let reserve_reports = STATE.with(|s| s.reserve_reports.borrow().clone());
+        assert_eq!(reserve_reports.len(), MAX_RESERVE_REPORTS);
+        // oldest reports were dropped, indexes keep counting
+        assert_eq!(reserve_reports[0].index, 5);
+        let page = STATE.with(|s| s.get_reserve_reports(MAX_RESERVE_REPORTS as u64, 10));
+        assert_eq!(page.len(), 5);
+        assert_eq!(page[0].index, MAX_RESERVE_REPORTS as u64);
+    }
+
+    fn governance_state(threshold: u32, delay: u64) {
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.controllers
+                .borrow_mut()
+                .extend([mock_principals::alice(), mock_principals::bob()]);
+            s.governance.replace(GovernanceConfig {
+                threshold,
+                delay,
+                ..GovernanceConfig::default()
+            });
+        });
+    }
+
+    #[test]
+    fn test_proposal_threshold() {
+        governance_state(2, 0);
+        let action = ProposalAction::Authorize(mock_principals::john());
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action.clone(), 0));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+        // approving twice does not count twice
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::alice(), 0))
+            .is_err());
+
+        STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), 0))
+            .unwrap();
+        let proposal = STATE.with(|s| s.take_ready_proposal(id, 0)).unwrap();
+
+        assert_eq!(proposal.action, action);
+        assert!(STATE.with(|s| s.get_pending_proposals(0)).is_empty());
+    }
+
+    #[test]
+    fn test_proposal_delay() {
+        governance_state(1, 100);
+        let action = ProposalAction::SetTreasury(mock_principals::john());
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action, 10));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 109)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 110)).is_ok());
+    }
+
+    #[test]
+    fn test_proposal_expiry() {
+        governance_state(2, 0);
+        let ttl = GovernanceConfig::default().ttl;
+        let action = ProposalAction::SetTreasury(mock_principals::john());
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action, 0));
+
+        assert_eq!(STATE.with(|s| s.get_pending_proposals(ttl)).len(), 1);
+        assert!(STATE.with(|s| s.get_pending_proposals(ttl + 1)).is_empty());
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), ttl + 1))
+            .is_err());
+        assert!(STATE.with(|s| s.proposals.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        governance_state(2, 0);
+        let action = ProposalAction::SetTreasury(mock_principals::john());
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action, 0));
 
-        // the message that is left is the one with amount_1
-        assert_eq!(claimable_messages[0].amount, amount_1);
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_ok());
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
     }
 }
@@ -1,13 +1,93 @@
-use ic_kit::{candid::candid_method, macros::update, Principal};
+use ic_kit::{
+    candid::candid_method,
+    macros::{query, update},
+    Principal,
+};
 
-use crate::magic::STATE;
+use crate::{
+    api::governance::open_proposal,
+    magic::STATE,
+    types::{AuditEntry, ProposalAction},
+    utils::args_digest,
+};
 
 pub fn is_authorized() -> Result<(), String> {
     STATE.with(|s| s.is_authorized())
 }
 
-#[update(name = "authorize")]
+/// Authorizing goes through a proposal, returns its id
+#[update(name = "authorize", guard = "is_authorized")]
 #[candid_method(update)]
-fn authorize(other: Principal) {
-    STATE.with(|s| s.authorize(other))
+async fn authorize(other: Principal) -> Result<u64, String> {
+    open_proposal(
+        "authorize",
+        args_digest((other,)),
+        ProposalAction::Authorize(other),
+    )
+    .await
+}
+
+#[query(name = "get_audit_log", guard = "is_authorized")]
+#[candid_method(query, rename = "get_audit_log")]
+fn get_audit_log(start: u64, limit: u64) -> Vec<AuditEntry> {
+    STATE.with(|s| s.get_audit_log(start, limit as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::{
+        magic::{MAX_AUDIT_ENTRIES, MAX_AUDIT_PAGE},
+        types::AuditOutcome,
+    };
+
+    fn before_each() -> &'static mut MockContext {
+        MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_caller(mock_principals::alice())
+            .inject()
+    }
+
+    #[test]
+    fn test_get_audit_log() {
+        before_each();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        STATE.with(|s| {
+            s.record_audit(
+                "authorize",
+                args_digest((mock_principals::bob(),)),
+                AuditOutcome::Ok,
+            )
+        });
+        let audit_log = get_audit_log(0, 10);
+
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].caller, mock_principals::alice());
+        assert_eq!(audit_log[0].method, "authorize");
+        assert_eq!(
+            audit_log[0].args_digest,
+            args_digest((mock_principals::bob(),))
+        );
+        assert_eq!(audit_log[0].outcome, AuditOutcome::Ok);
+    }
+
+    #[test]
+    fn test_audit_log_bounded() {
+        before_each();
+
+        for _ in 0..MAX_AUDIT_ENTRIES + 5 {
+            STATE.with(|s| s.record_audit("authorize", String::from("00"), AuditOutcome::Ok));
+        }
+
+        let audit_log = STATE.with(|s| s.audit_log.borrow().clone());
+
+        assert_eq!(audit_log.len(), MAX_AUDIT_ENTRIES);
+        // oldest entries were dropped
+        assert_eq!(audit_log[0].index, 5);
+        assert_eq!(get_audit_log(0, u64::MAX).len(), MAX_AUDIT_PAGE);
+        assert_eq!(get_audit_log(MAX_AUDIT_ENTRIES as u64, 10).len(), 5);
+    }
 }
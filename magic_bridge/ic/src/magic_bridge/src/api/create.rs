@@ -1,4 +1,4 @@
-use crate::api::admin::is_authorized;
+use crate::api::governance::is_authorized_and_not_paused;
 use crate::factory::{FromNat, CAP_ADDRESS};
 use crate::{
     factory::{CreateCanisterParam, Factory},
@@ -14,7 +14,7 @@ use ic_kit::{
 
 use std::str;
 
-#[update(name = "create", guard = "is_authorized")]
+#[update(name = "create", guard = "is_authorized_and_not_paused")]
 #[candid_method(update, rename = "create")]
 async fn create(token_type: TokenType, payload: Vec<Nat>) -> MagicResponse {
     let self_id = ic::id();
@@ -6,7 +6,11 @@ use ic_kit::{
 };
 
 use crate::{
-    api::admin::is_authorized, dab::retry_failed_canisters, magic::STATE, types::RetryCount,
+    api::admin::is_authorized,
+    dab::retry_failed_canisters,
+    magic::STATE,
+    types::{AuditOutcome, RetryCount},
+    utils::args_digest,
 };
 
 #[update(name = "flush_failed_registrations", guard = "is_authorized")]
@@ -15,6 +19,13 @@ async fn flush_failed_registrations() -> () {
     let failed_canisters = STATE.with(|s| s.get_failed_canisters());
     let retry_failed = retry_failed_canisters(failed_canisters).await;
     STATE.with(|s| s.replace_failed_canisters(retry_failed));
+    STATE.with(|s| {
+        s.record_audit(
+            "flush_failed_registrations",
+            args_digest(()),
+            AuditOutcome::Ok,
+        )
+    });
 }
 
 #[query(name = "get_failed_registrations", guard = "is_authorized")]
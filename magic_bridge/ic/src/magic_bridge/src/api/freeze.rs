@@ -0,0 +1,23 @@
+use ic_kit::{candid::candid_method, macros::update, Principal};
+
+use crate::{
+    api::{admin::is_authorized, governance::open_proposal},
+    types::ProposalAction,
+    utils::args_digest,
+};
+
+/// Freezing goes through a proposal, returns its id
+#[update(name = "set_frozen", guard = "is_authorized")]
+#[candid_method(update, rename = "set_frozen")]
+async fn set_frozen(
+    canister_id: Principal,
+    account: Principal,
+    frozen: bool,
+) -> Result<u64, String> {
+    open_proposal(
+        "set_frozen",
+        args_digest((canister_id, account, frozen)),
+        ProposalAction::SetFrozen(canister_id, account, frozen),
+    )
+    .await
+}
@@ -0,0 +1,243 @@
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{query, update},
+};
+
+use crate::{
+    api::{admin::is_authorized, install::install_upgrade},
+    factory::Factory,
+    magic::STATE,
+    types::{AuditOutcome, GovernanceConfig, Proposal, ProposalAction},
+    utils::args_digest,
+};
+
+pub fn is_not_paused() -> Result<(), String> {
+    STATE.with(|s| s.is_not_paused())
+}
+
+pub fn is_authorized_and_not_paused() -> Result<(), String> {
+    is_authorized()?;
+    is_not_paused()
+}
+
+fn validate_action(action: &ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::UpgradeCode(canister_id, _) => {
+            STATE.with(|s| s.canister_exists(*canister_id)).map(|_| ())
+        }
+        ProposalAction::SetGovernance(config) => config.validate(),
+        ProposalAction::SetFrozen(canister_id, _, _) => {
+            STATE.with(|s| s.canister_exists(*canister_id)).map(|_| ())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn apply_action(action: ProposalAction) -> Result<(), String> {
+    match action {
+        ProposalAction::Authorize(other) => STATE.with(|s| {
+            let mut controllers = s.controllers.borrow_mut();
+            if !controllers.contains(&other) {
+                controllers.push(other);
+            }
+        }),
+        ProposalAction::UpgradeCode(canister_id, token_type) => {
+            install_upgrade(canister_id, token_type)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+        }
+        ProposalAction::SetGovernance(config) => {
+            config.validate()?;
+            STATE.with(|s| s.governance.replace(config));
+        }
+        ProposalAction::Unpause => {
+            STATE.with(|s| s.paused.replace(false));
+        }
+        ProposalAction::SetFrozen(canister_id, account, frozen) => {
+            Factory::set_frozen(canister_id, account, frozen).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute a ready proposal and record the outcome,
+/// the proposal is taken before any await so it runs at most once
+async fn execute(id: u64) -> Result<(), String> {
+    let result = match STATE.with(|s| s.take_ready_proposal(id, ic::time())) {
+        Ok(proposal) => apply_action(proposal.action).await,
+        Err(error) => Err(error),
+    };
+
+    STATE.with(|s| {
+        s.record_audit(
+            "execute_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+/// Execute a proposal if it needs no further approvals or delay,
+/// returns whether it was executed
+async fn execute_if_ready(id: u64) -> Result<bool, String> {
+    if STATE.with(|s| s.proposal_ready(id, ic::time())).is_err() {
+        return Ok(false);
+    }
+
+    execute(id).await.map(|_| true)
+}
+
+/// Open a proposal approved by the caller
+pub(crate) async fn open_proposal(
+    method: &str,
+    digest: String,
+    action: ProposalAction,
+) -> Result<u64, String> {
+    let result = validate_action(&action)
+        .map(|_| STATE.with(|s| s.propose(ic::caller(), action, ic::time())));
+
+    STATE.with(|s| s.record_audit(method, digest, AuditOutcome::from(&result)));
+
+    let id = result?;
+    execute_if_ready(id).await?;
+
+    Ok(id)
+}
+
+#[update(name = "propose", guard = "is_authorized")]
+#[candid_method(update, rename = "propose")]
+async fn propose(action: ProposalAction) -> Result<u64, String> {
+    let digest = args_digest((&action,));
+
+    open_proposal("propose", digest, action).await
+}
+
+#[update(name = "approve_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "approve_proposal")]
+async fn approve_proposal(id: u64) -> Result<bool, String> {
+    let result = STATE.with(|s| s.approve_proposal(id, ic::caller(), ic::time()));
+
+    STATE.with(|s| {
+        s.record_audit(
+            "approve_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result?;
+    execute_if_ready(id).await
+}
+
+#[update(name = "execute_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "execute_proposal")]
+async fn execute_proposal(id: u64) -> Result<(), String> {
+    execute(id).await
+}
+
+#[update(name = "cancel_proposal", guard = "is_authorized")]
+#[candid_method(update, rename = "cancel_proposal")]
+fn cancel_proposal(id: u64) -> Result<(), String> {
+    let result = STATE.with(|s| s.cancel_proposal(id)).map(|_| ());
+
+    STATE.with(|s| {
+        s.record_audit(
+            "cancel_proposal",
+            args_digest((id,)),
+            AuditOutcome::from(&result),
+        )
+    });
+
+    result
+}
+
+#[query(name = "get_pending_proposals", guard = "is_authorized")]
+#[candid_method(query, rename = "get_pending_proposals")]
+fn get_pending_proposals() -> Vec<Proposal> {
+    STATE.with(|s| s.get_pending_proposals(ic::time()))
+}
+
+#[query(name = "get_governance_config", guard = "is_authorized")]
+#[candid_method(query, rename = "get_governance_config")]
+fn get_governance_config() -> GovernanceConfig {
+    STATE.with(|s| s.governance.borrow().clone())
+}
+
+/// Pausing takes effect immediately, unpausing is a proposal
+#[update(name = "pause", guard = "is_authorized")]
+#[candid_method(update, rename = "pause")]
+fn pause() {
+    STATE.with(|s| s.paused.replace(true));
+    STATE.with(|s| s.record_audit("pause", args_digest(()), AuditOutcome::Ok));
+}
+
+#[query(name = "paused")]
+#[candid_method(query, rename = "paused")]
+fn paused() -> bool {
+    STATE.with(|s| *s.paused.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::types::TokenType;
+
+    fn before_each() -> &'static mut MockContext {
+        let mock_ctx = MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        mock_ctx
+    }
+
+    #[test]
+    fn test_pause() {
+        before_each();
+
+        assert!(is_authorized_and_not_paused().is_ok());
+
+        pause();
+
+        assert!(paused());
+        assert!(is_authorized_and_not_paused().is_err());
+    }
+
+    #[test]
+    fn test_validate_action() {
+        before_each();
+
+        let upgrade = ProposalAction::UpgradeCode(mock_principals::xtc(), TokenType::DIP20);
+        let freeze =
+            ProposalAction::SetFrozen(mock_principals::xtc(), mock_principals::bob(), true);
+        let config = GovernanceConfig {
+            ttl: 0,
+            ..GovernanceConfig::default()
+        };
+
+        // unknown canisters cannot be proposed for an upgrade
+        assert!(validate_action(&upgrade).is_err());
+        assert!(validate_action(&freeze).is_err());
+        assert!(validate_action(&ProposalAction::SetGovernance(config)).is_err());
+        assert!(validate_action(&ProposalAction::Unpause).is_ok());
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        before_each();
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 0));
+
+        assert!(cancel_proposal(id).is_ok());
+        assert!(cancel_proposal(id).is_err());
+        assert_eq!(STATE.with(|s| s.audit_log.borrow().len()), 2);
+    }
+}
@@ -1,11 +1,234 @@
-use ic_cdk::api::call::accept_message;
-use ic_kit::macros::inspect_message;
+use ic_cdk::api::call::{accept_message, arg_data_raw, method_name};
+use ic_kit::{
+    candid::candid_method,
+    ic,
+    macros::{inspect_message, query, update},
+    Principal,
+};
 
-use crate::api::admin::is_authorized;
+use crate::{
+    api::admin::is_authorized,
+    magic::STATE,
+    types::{AuditOutcome, IngressPolicy},
+    utils::args_digest,
+};
+
+/// Largest ingress argument accepted, in bytes
+const MAX_ARG_SIZE: usize = 256 * 1024;
+
+/// Ingress policy of every method unless overridden,
+/// methods not listed here are disabled
+const DEFAULT_INGRESS_POLICIES: &[(&str, IngressPolicy)] = &[
+    ("get_canister", IngressPolicy::Public),
+    ("get_all_canisters", IngressPolicy::Public),
+    ("authorize", IngressPolicy::Authorized),
+    ("create", IngressPolicy::Authorized),
+    ("upgrade_code", IngressPolicy::Authorized),
+    ("set_frozen", IngressPolicy::Authorized),
+    ("flush_failed_registrations", IngressPolicy::Authorized),
+    ("get_failed_registrations", IngressPolicy::Authorized),
+    ("set_ingress_policy", IngressPolicy::Authorized),
+    ("get_ingress_policies", IngressPolicy::Authorized),
+    ("get_audit_log", IngressPolicy::Authorized),
+    ("propose", IngressPolicy::Authorized),
+    ("approve_proposal", IngressPolicy::Authorized),
+    ("execute_proposal", IngressPolicy::Authorized),
+    ("cancel_proposal", IngressPolicy::Authorized),
+    ("get_pending_proposals", IngressPolicy::Authorized),
+    ("get_governance_config", IngressPolicy::Authorized),
+    ("pause", IngressPolicy::Authorized),
+    ("paused", IngressPolicy::Public),
+];
+
+fn default_ingress_policy(method: &str) -> IngressPolicy {
+    DEFAULT_INGRESS_POLICIES
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, policy)| *policy)
+        .unwrap_or(IngressPolicy::Disabled)
+}
+
+fn ingress_policy(method: &str) -> IngressPolicy {
+    STATE
+        .with(|s| s.get_ingress_policy(method))
+        .unwrap_or_else(|| default_ingress_policy(method))
+}
+
+fn check_ingress(method: &str, arg_size: usize) -> Result<(), String> {
+    if ic::caller() == Principal::anonymous() {
+        return Err("Anonymous caller is not allowed".to_string());
+    }
+
+    if arg_size > MAX_ARG_SIZE {
+        return Err(format!(
+            "Argument size {} exceeds limit of {} bytes",
+            arg_size, MAX_ARG_SIZE
+        ));
+    }
+
+    match ingress_policy(method) {
+        IngressPolicy::Public => Ok(()),
+        IngressPolicy::Authorized => is_authorized(),
+        IngressPolicy::Disabled => Err(format!("Method {} is disabled", method)),
+    }
+}
 
 #[inspect_message]
 fn inspect_message() {
-    if is_authorized().is_ok() {
+    let method = method_name();
+    let arg_size = arg_data_raw().len();
+
+    if check_ingress(&method, arg_size).is_ok() {
         accept_message()
     }
 }
+
+#[update(name = "set_ingress_policy", guard = "is_authorized")]
+#[candid_method(update, rename = "set_ingress_policy")]
+fn set_ingress_policy(method: String, policy: IngressPolicy) -> Result<(), String> {
+    let digest = args_digest((&method, policy));
+
+    // keep controllers from locking themselves out of the policy table
+    let result = if method == "set_ingress_policy" {
+        Err("Ingress policy of set_ingress_policy cannot be changed".to_string())
+    } else {
+        STATE.with(|s| s.set_ingress_policy(method, policy));
+        Ok(())
+    };
+
+    STATE.with(|s| s.record_audit("set_ingress_policy", digest, AuditOutcome::from(&result)));
+
+    result
+}
+
+#[query(name = "get_ingress_policies", guard = "is_authorized")]
+#[candid_method(query, rename = "get_ingress_policies")]
+fn get_ingress_policies() -> Vec<(String, IngressPolicy)> {
+    let mut policies: Vec<(String, IngressPolicy)> = DEFAULT_INGRESS_POLICIES
+        .iter()
+        .map(|(method, _)| (method.to_string(), ingress_policy(method)))
+        .collect();
+
+    let overrides = STATE.with(|s| s.get_ingress_policies());
+    for (method, policy) in overrides {
+        if !policies.iter().any(|(name, _)| *name == method) {
+            policies.push((method, policy));
+        }
+    }
+
+    policies
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    // update methods of the service in the candid interface
+    fn exported_update_methods() -> Vec<&'static str> {
+        let did = include_str!("../../magic_bridge.did");
+        let service = &did[did.find("service").expect("no service in candid interface")..];
+        let methods = &service[service.find('{').unwrap() + 1..service.rfind('}').unwrap()];
+
+        methods
+            .split(";\n")
+            .map(str::trim)
+            .filter(|method| !method.is_empty() && !method.ends_with("query"))
+            .map(|method| method.split(':').next().unwrap().trim())
+            .collect()
+    }
+
+    fn before_each() -> &'static mut MockContext {
+        MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_caller(mock_principals::alice())
+            .inject()
+    }
+
+    #[test]
+    fn test_public_method() {
+        before_each();
+
+        assert!(check_ingress("get_canister", 128).is_ok());
+        assert!(check_ingress("get_all_canisters", 128).is_ok());
+    }
+
+    #[test]
+    fn test_authorized_method() {
+        let mock_ctx = before_each();
+
+        assert!(check_ingress("create", 128).is_err());
+
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+        assert!(check_ingress("create", 128).is_ok());
+
+        mock_ctx.update_caller(mock_principals::bob());
+        assert!(check_ingress("create", 128).is_err());
+    }
+
+    #[test]
+    fn test_unknown_method_disabled() {
+        before_each();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        assert!(check_ingress("unknown_method", 128).is_err());
+    }
+
+    #[test]
+    fn test_anonymous_rejected() {
+        let mock_ctx = before_each();
+        mock_ctx.update_caller(Principal::anonymous());
+
+        assert!(check_ingress("get_canister", 128).is_err());
+    }
+
+    #[test]
+    fn test_arg_size_limit() {
+        before_each();
+
+        assert!(check_ingress("get_canister", MAX_ARG_SIZE).is_ok());
+        assert!(check_ingress("get_canister", MAX_ARG_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn test_set_ingress_policy() {
+        before_each();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        let result = set_ingress_policy("get_canister".to_string(), IngressPolicy::Disabled);
+
+        assert!(result.is_ok());
+        assert!(check_ingress("get_canister", 128).is_err());
+        assert!(
+            get_ingress_policies().contains(&("get_canister".to_string(), IngressPolicy::Disabled))
+        );
+    }
+
+    #[test]
+    fn test_set_ingress_policy_self() {
+        before_each();
+        STATE.with(|s| s.controllers.borrow_mut().push(mock_principals::alice()));
+
+        let result = set_ingress_policy("set_ingress_policy".to_string(), IngressPolicy::Disabled);
+
+        assert!(result.is_err());
+        assert!(check_ingress("set_ingress_policy", 128).is_ok());
+    }
+
+    #[test]
+    fn test_every_update_method_has_policy() {
+        let methods = exported_update_methods();
+        assert!(!methods.is_empty());
+
+        for method in methods {
+            assert!(
+                DEFAULT_INGRESS_POLICIES
+                    .iter()
+                    .any(|(name, _)| *name == method),
+                "{} has no ingress policy",
+                method
+            );
+        }
+    }
+}
@@ -3,15 +3,26 @@ use ic_kit::{
 };
 
 use crate::{
-    api::admin::is_authorized,
+    api::{admin::is_authorized, governance::open_proposal},
     factory::DIP20_WASM,
     magic::{MagicState, STATE},
-    types::{InstallCodeArgumentBorrowed, InstallCodeError, TokenType},
+    types::{InstallCodeArgumentBorrowed, InstallCodeError, ProposalAction, TokenType},
+    utils::args_digest,
 };
 
+/// Upgrades go through a proposal, returns its id
 #[update(name = "upgrade_code", guard = "is_authorized")]
 #[candid_method(update, rename = "upgrade_code")]
-async fn upgrade_code(
+async fn upgrade_code(canister_id: Principal, token_type: TokenType) -> Result<u64, String> {
+    open_proposal(
+        "upgrade_code",
+        args_digest((canister_id, token_type)),
+        ProposalAction::UpgradeCode(canister_id, token_type),
+    )
+    .await
+}
+
+pub(crate) async fn install_upgrade(
     canister_id: Principal,
     token_type: TokenType,
 ) -> Result<Principal, InstallCodeError> {
@@ -1,7 +1,9 @@
 mod admin;
 mod create;
 mod dab;
+mod freeze;
 mod get_canister;
+mod governance;
 mod init;
 mod inspect_message;
 mod install;
@@ -41,6 +41,8 @@ impl FromNat for Principal {
 // fee: Nat,
 // fee_to: Principal,
 // cap: Principal,
+// history_sink: Option<HistorySinkKind>,
+// factory: Option<Principal>,
 // DIP20 init args
 
 // struct InitArgs {
@@ -168,6 +170,9 @@ impl Factory {
             param.fee,
             param.fee_to,
             param.cap,
+            None::<HistorySinkKind>,
+            // lets the bridge freeze accounts on the tokens it creates
+            Some(ic::id()),
         )) {
             Err(_) => return Err(FactoryError::EncodeError),
             Ok(res) => res,
@@ -201,4 +206,18 @@ impl Factory {
 
         Ok(canister_id)
     }
+
+    /// Freeze or unfreeze an account on a token, the token accepts
+    /// this from the canister that installed it
+    pub async fn set_frozen(
+        canister_id: Principal,
+        account: Principal,
+        frozen: bool,
+    ) -> Result<(), String> {
+        let method = if frozen { "freeze" } else { "unfreeze" };
+
+        (ic::call(canister_id, method, (account,)).await
+            as Result<(), (RejectionCode, std::string::String)>)
+            .map_err(|(code, details)| format!("{:?}: {}", code, details))
+    }
 }
@@ -12,6 +12,9 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::str;
 
+pub const MAX_AUDIT_ENTRIES: usize = 1_000;
+pub const MAX_AUDIT_PAGE: usize = 100;
+
 thread_local! {
     pub static STATE: MagicState = MagicState::default();
 }
@@ -22,13 +25,27 @@ pub struct MagicState {
     pub controllers: RefCell<Vec<Principal>>,
     pub failed_registration_canisters:
         RefCell<HashMap<Principal, (CreateCanisterParam, RetryCount)>>,
+    pub ingress_policies: RefCell<HashMap<String, IngressPolicy>>,
+    pub audit_log: RefCell<Vec<AuditEntry>>,
+    pub audit_index: RefCell<u64>,
+    pub governance: RefCell<GovernanceConfig>,
+    pub proposals: RefCell<HashMap<u64, Proposal>>,
+    pub proposal_index: RefCell<u64>,
+    pub paused: RefCell<bool>,
 }
 
 #[derive(CandidType, Deserialize, Default)]
 pub struct StableMagicState {
-    canisters: HashMap<EthereumAddr, CanisterId>,
-    controllers: Vec<Principal>,
-    failed_registration_canisters: HashMap<Principal, (CreateCanisterParam, RetryCount)>,
+    pub canisters: HashMap<EthereumAddr, CanisterId>,
+    pub controllers: Vec<Principal>,
+    pub failed_registration_canisters: HashMap<Principal, (CreateCanisterParam, RetryCount)>,
+    pub ingress_policies: HashMap<String, IngressPolicy>,
+    pub audit_log: Vec<AuditEntry>,
+    pub audit_index: u64,
+    pub governance: GovernanceConfig,
+    pub proposals: HashMap<u64, Proposal>,
+    pub proposal_index: u64,
+    pub paused: bool,
 }
 
 impl MagicState {
@@ -170,11 +187,179 @@ impl MagicState {
             .ok_or("Caller is not authorized".to_string())
     }
 
+    pub fn get_ingress_policy(&self, method: &str) -> Option<IngressPolicy> {
+        self.ingress_policies.borrow().get(method).copied()
+    }
+
+    pub fn set_ingress_policy(&self, method: String, policy: IngressPolicy) {
+        self.ingress_policies.borrow_mut().insert(method, policy);
+    }
+
+    pub fn get_ingress_policies(&self) -> Vec<(String, IngressPolicy)> {
+        self.ingress_policies
+            .borrow()
+            .iter()
+            .map(|(method, policy)| (method.clone(), *policy))
+            .collect()
+    }
+
+    pub fn propose(&self, proposer: Principal, action: ProposalAction, now: u64) -> u64 {
+        let id = self.proposal_index.replace_with(|index| *index + 1);
+
+        self.proposals.borrow_mut().insert(
+            id,
+            Proposal {
+                id,
+                action,
+                proposer,
+                approvals: vec![proposer],
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn approve_proposal(&self, id: u64, approver: Principal, now: u64) -> Result<(), String> {
+        self.expire_proposals(now);
+
+        let mut proposals = self.proposals.borrow_mut();
+        let proposal = proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if proposal.approvals.contains(&approver) {
+            return Err(format!("Proposal {} is already approved by caller", id));
+        }
+
+        proposal.approvals.push(approver);
+
+        Ok(())
+    }
+
+    pub fn proposal_ready(&self, id: u64, now: u64) -> Result<(), String> {
+        let config = self.governance.borrow().clone();
+        let proposals = self.proposals.borrow();
+        let proposal = proposals
+            .get(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))?;
+
+        if now > proposal.created_at.saturating_add(config.ttl) {
+            return Err(format!("Proposal {} has expired", id));
+        }
+
+        // approvals of principals that lost their role do not count
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|p| self.controllers.borrow().contains(p))
+            .count() as u32;
+        if approvals < config.threshold {
+            return Err(format!(
+                "Proposal {} has {} of {} approvals",
+                id, approvals, config.threshold
+            ));
+        }
+
+        let executable_at = proposal.created_at.saturating_add(config.delay);
+        if now < executable_at {
+            return Err(format!(
+                "Proposal {} is timelocked until {}",
+                id, executable_at
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn take_ready_proposal(&self, id: u64, now: u64) -> Result<Proposal, String> {
+        self.expire_proposals(now);
+        self.proposal_ready(id, now)?;
+
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn cancel_proposal(&self, id: u64) -> Result<Proposal, String> {
+        self.proposals
+            .borrow_mut()
+            .remove(&id)
+            .ok_or_else(|| format!("Proposal {} not found", id))
+    }
+
+    pub fn expire_proposals(&self, now: u64) {
+        let ttl = self.governance.borrow().ttl;
+
+        self.proposals
+            .borrow_mut()
+            .retain(|_, p| now <= p.created_at.saturating_add(ttl));
+    }
+
+    pub fn get_pending_proposals(&self, now: u64) -> Vec<Proposal> {
+        let ttl = self.governance.borrow().ttl;
+        let mut proposals: Vec<Proposal> = self
+            .proposals
+            .borrow()
+            .values()
+            .filter(|p| now <= p.created_at.saturating_add(ttl))
+            .cloned()
+            .collect();
+
+        proposals.sort_by_key(|p| p.id);
+        proposals
+    }
+
+    pub fn is_not_paused(&self) -> Result<(), String> {
+        (!*self.paused.borrow())
+            .then(|| ())
+            .ok_or("Magic bridge is paused".to_string())
+    }
+
+    pub fn record_audit(&self, method: &str, args_digest: String, outcome: AuditOutcome) {
+        let mut audit_log = self.audit_log.borrow_mut();
+        let mut audit_index = self.audit_index.borrow_mut();
+
+        audit_log.push(AuditEntry {
+            index: *audit_index,
+            caller: ic::caller(),
+            method: method.to_string(),
+            args_digest,
+            time: ic::time(),
+            outcome,
+        });
+        *audit_index += 1;
+
+        // the log is bounded, drop the oldest entries
+        if audit_log.len() > MAX_AUDIT_ENTRIES {
+            let overflow = audit_log.len() - MAX_AUDIT_ENTRIES;
+            audit_log.drain(..overflow);
+        }
+    }
+
+    pub fn get_audit_log(&self, start: u64, limit: usize) -> Vec<AuditEntry> {
+        self.audit_log
+            .borrow()
+            .iter()
+            .filter(|e| e.index >= start)
+            .take(limit.min(MAX_AUDIT_PAGE))
+            .cloned()
+            .collect()
+    }
+
     pub fn take_all(&self) -> StableMagicState {
         StableMagicState {
             canisters: self.canisters.take(),
             controllers: self.controllers.take(),
             failed_registration_canisters: self.failed_registration_canisters.take(),
+            ingress_policies: self.ingress_policies.take(),
+            audit_log: self.audit_log.take(),
+            audit_index: self.audit_index.take(),
+            governance: self.governance.take(),
+            proposals: self.proposals.take(),
+            proposal_index: self.proposal_index.take(),
+            paused: self.paused.take(),
         }
     }
 
@@ -182,6 +367,13 @@ impl MagicState {
         self.canisters.borrow_mut().clear();
         self.controllers.borrow_mut().clear();
         self.failed_registration_canisters.borrow_mut().clear();
+        self.ingress_policies.borrow_mut().clear();
+        self.audit_log.borrow_mut().clear();
+        self.audit_index.replace(0);
+        self.governance.replace(GovernanceConfig::default());
+        self.proposals.borrow_mut().clear();
+        self.proposal_index.replace(0);
+        self.paused.replace(false);
     }
 
     pub fn replace_all(&self, stable_magic_state: StableMagicState) {
@@ -189,5 +381,97 @@ impl MagicState {
         self.controllers.replace(stable_magic_state.controllers);
         self.failed_registration_canisters
             .replace(stable_magic_state.failed_registration_canisters);
+        self.ingress_policies
+            .replace(stable_magic_state.ingress_policies);
+        self.audit_log.replace(stable_magic_state.audit_log);
+        self.audit_index.replace(stable_magic_state.audit_index);
+        self.governance.replace(stable_magic_state.governance);
+        self.proposals.replace(stable_magic_state.proposals);
+        self.proposal_index
+            .replace(stable_magic_state.proposal_index);
+        self.paused.replace(stable_magic_state.paused);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+
+    fn governance_state(threshold: u32, delay: u64) {
+        MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        STATE.with(|s| {
+            s.controllers
+                .borrow_mut()
+                .extend([mock_principals::alice(), mock_principals::bob()]);
+            s.governance.replace(GovernanceConfig {
+                threshold,
+                delay,
+                ..GovernanceConfig::default()
+            });
+        });
+    }
+
+    #[test]
+    fn test_proposal_threshold() {
+        governance_state(2, 0);
+        let action = ProposalAction::UpgradeCode(mock_principals::xtc(), TokenType::DIP20);
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), action.clone(), 0));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
+        // approving twice does not count twice
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::alice(), 0))
+            .is_err());
+
+        STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), 0))
+            .unwrap();
+        let proposal = STATE.with(|s| s.take_ready_proposal(id, 0)).unwrap();
+
+        assert_eq!(proposal.action, action);
+        assert!(STATE.with(|s| s.get_pending_proposals(0)).is_empty());
+    }
+
+    #[test]
+    fn test_proposal_delay() {
+        governance_state(1, 100);
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 10));
+
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 109)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 110)).is_ok());
+    }
+
+    #[test]
+    fn test_proposal_expiry() {
+        governance_state(2, 0);
+        let ttl = GovernanceConfig::default().ttl;
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 0));
+
+        assert_eq!(STATE.with(|s| s.get_pending_proposals(ttl)).len(), 1);
+        assert!(STATE.with(|s| s.get_pending_proposals(ttl + 1)).is_empty());
+        assert!(STATE
+            .with(|s| s.approve_proposal(id, mock_principals::bob(), ttl + 1))
+            .is_err());
+        assert!(STATE.with(|s| s.proposals.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_cancel_proposal() {
+        governance_state(2, 0);
+
+        let id = STATE.with(|s| s.propose(mock_principals::alice(), ProposalAction::Unpause, 0));
+
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_ok());
+        assert!(STATE.with(|s| s.cancel_proposal(id)).is_err());
+        assert!(STATE.with(|s| s.take_ready_proposal(id, 0)).is_err());
     }
 }
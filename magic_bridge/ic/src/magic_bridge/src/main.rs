@@ -2,7 +2,9 @@ mod api;
 mod dab;
 mod factory;
 mod magic;
+mod migration;
 mod types;
+mod utils;
 
 #[cfg(any(target_arch = "wasm32", test))]
 fn main() {}
@@ -0,0 +1,291 @@
+use ic_cdk::api::stable;
+use ic_kit::candid::de::IDLDeserialize;
+use ic_kit::candid::{encode_args, CandidType, Deserialize, Nat};
+use ic_kit::Principal;
+use std::collections::HashMap;
+
+use crate::factory::CreateCanisterParam;
+use crate::magic::StableMagicState;
+use crate::types::*;
+
+/// Written ahead of the versioned envelope, the bare record of
+/// canisters deployed before versioning starts with the candid magic
+const ENVELOPE_MAGIC: &[u8; 4] = b"VSTA";
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Token type as deployed, frozen so later variants do not change it
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum TokenTypeV0 {
+    DIP20,
+    DIP721,
+}
+
+/// Canister creation arguments as deployed
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CreateCanisterParamV0 {
+    pub logo: String,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub total_supply: Nat,
+    pub owner: Principal,
+    pub controllers: Vec<Principal>,
+    pub cycles: u64,
+    pub fee: Nat,
+    pub fee_to: Principal,
+    pub cap: Principal,
+    pub token_type: TokenTypeV0,
+}
+
+/// Stable state layout before versioning was introduced,
+/// stored as a bare record
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableMagicStateV0 {
+    pub canisters: HashMap<EthereumAddr, CanisterId>,
+    pub controllers: Vec<Principal>,
+    pub failed_registration_canisters: HashMap<Principal, (CreateCanisterParamV0, RetryCount)>,
+}
+
+/// Stable state layout of version 1, frozen so a change to the live
+/// state needs a new version and a migration
+#[derive(CandidType, Deserialize, Default)]
+pub struct StableMagicStateV1 {
+    pub canisters: HashMap<EthereumAddr, CanisterId>,
+    pub controllers: Vec<Principal>,
+    pub failed_registration_canisters: HashMap<Principal, (CreateCanisterParam, RetryCount)>,
+    pub ingress_policies: HashMap<String, IngressPolicy>,
+    pub audit_log: Vec<AuditEntry>,
+    pub audit_index: u64,
+    pub governance: GovernanceConfig,
+    pub proposals: HashMap<u64, Proposal>,
+    pub proposal_index: u64,
+    pub paused: bool,
+}
+
+/// Versioned envelope written to stable memory on every upgrade
+#[derive(CandidType, Deserialize)]
+pub enum VersionedMagicState {
+    V0(StableMagicStateV0),
+    V1(StableMagicStateV1),
+}
+
+impl VersionedMagicState {
+    /// Wrap state in the current version
+    pub fn current(state: StableMagicState) -> Self {
+        VersionedMagicState::V1(state.into())
+    }
+
+    /// Apply every migration from the stored version up to the current one
+    pub fn migrate(self) -> Result<StableMagicState, String> {
+        match self {
+            VersionedMagicState::V0(state) => {
+                VersionedMagicState::V1(migrate_v0_to_v1(state)?).migrate()
+            }
+            VersionedMagicState::V1(state) => Ok(state.into()),
+        }
+    }
+}
+
+fn migrate_v0_to_v1(state: StableMagicStateV0) -> Result<StableMagicStateV1, String> {
+    // an upgrade without controllers would lock every admin endpoint
+    if state.controllers.is_empty() {
+        return Err("Stable state has no controllers".to_string());
+    }
+
+    Ok(StableMagicStateV1 {
+        canisters: state.canisters,
+        controllers: state.controllers,
+        failed_registration_canisters: state
+            .failed_registration_canisters
+            .into_iter()
+            .map(|(canister_id, (param, retries))| {
+                (canister_id, (migrate_create_canister_param(param), retries))
+            })
+            .collect(),
+        // the rest starts empty, every method on its default ingress policy
+        // and a single approval without delay keep the previous behaviour
+        ..StableMagicStateV1::default()
+    })
+}
+
+impl From<StableMagicState> for StableMagicStateV1 {
+    fn from(state: StableMagicState) -> Self {
+        StableMagicStateV1 {
+            canisters: state.canisters,
+            controllers: state.controllers,
+            failed_registration_canisters: state.failed_registration_canisters,
+            ingress_policies: state.ingress_policies,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+            paused: state.paused,
+        }
+    }
+}
+
+impl From<StableMagicStateV1> for StableMagicState {
+    fn from(state: StableMagicStateV1) -> Self {
+        StableMagicState {
+            canisters: state.canisters,
+            controllers: state.controllers,
+            failed_registration_canisters: state.failed_registration_canisters,
+            ingress_policies: state.ingress_policies,
+            audit_log: state.audit_log,
+            audit_index: state.audit_index,
+            governance: state.governance,
+            proposals: state.proposals,
+            proposal_index: state.proposal_index,
+            paused: state.paused,
+        }
+    }
+}
+
+fn migrate_create_canister_param(param: CreateCanisterParamV0) -> CreateCanisterParam {
+    CreateCanisterParam {
+        logo: param.logo,
+        name: param.name,
+        symbol: param.symbol,
+        decimals: param.decimals,
+        total_supply: param.total_supply,
+        owner: param.owner,
+        controllers: param.controllers,
+        cycles: param.cycles,
+        fee: param.fee,
+        fee_to: param.fee_to,
+        cap: param.cap,
+        token_type: match param.token_type {
+            TokenTypeV0::DIP20 => TokenType::DIP20,
+            TokenTypeV0::DIP721 => TokenType::DIP721,
+        },
+    }
+}
+
+/// Encode state in the current version behind the envelope magic
+pub fn encode(state: StableMagicState) -> Result<Vec<u8>, String> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.extend(encode_args((VersionedMagicState::current(state),)).map_err(|e| e.to_string())?);
+
+    Ok(bytes)
+}
+
+/// Decode stable memory by its version tag, never by trying layouts in turn
+pub fn decode(bytes: &[u8]) -> Result<VersionedMagicState, String> {
+    if bytes.starts_with(ENVELOPE_MAGIC) {
+        let mut de =
+            IDLDeserialize::new(&bytes[ENVELOPE_MAGIC.len()..]).map_err(|e| e.to_string())?;
+        de.get_value::<VersionedMagicState>()
+            .map_err(|e| e.to_string())
+    } else if bytes.starts_with(CANDID_MAGIC) {
+        // canisters deployed before versioning hold a bare record
+        let mut de = IDLDeserialize::new(bytes).map_err(|e| e.to_string())?;
+        de.get_value::<StableMagicStateV0>()
+            .map(VersionedMagicState::V0)
+            .map_err(|e| e.to_string())
+    } else {
+        Err("Stable memory holds no known state layout".to_string())
+    }
+}
+
+/// Save state in the current version over whatever stable memory held
+pub fn store(state: StableMagicState) -> Result<(), String> {
+    let bytes = encode(state)?;
+    let pages = (bytes.len() as u64 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    let size = stable::stable_size() as u64;
+    if pages > size {
+        stable::stable_grow((pages - size) as u32).map_err(|e| format!("{:?}", e))?;
+    }
+    stable::stable_write(0, &bytes);
+
+    Ok(())
+}
+
+/// Restore state from stable memory and migrate it to the current version
+pub fn restore() -> Result<StableMagicState, String> {
+    decode(&stable::stable_bytes())?.migrate()
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::magic::STATE;
+
+    fn round_trip(state: StableMagicState) -> Result<StableMagicState, String> {
+        decode(&encode(state)?)?.migrate()
+    }
+
+    fn legacy_state() -> StableMagicStateV0 {
+        StableMagicStateV0 {
+            canisters: HashMap::from([(mock_principals::john(), mock_principals::xtc())]),
+            controllers: vec![mock_principals::alice()],
+            failed_registration_canisters: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_round_trip() {
+        MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_caller(mock_principals::alice())
+            .inject();
+
+        let eth_addr = mock_principals::john();
+        let canister_id = mock_principals::xtc();
+
+        STATE.with(|s| {
+            s.controllers.borrow_mut().push(mock_principals::alice());
+            s.insert_canister(eth_addr, canister_id);
+            s.add_failed_canister(canister_id, &CreateCanisterParam::default(), 3);
+        });
+
+        let stable_state = STATE.with(|s| s.take_all());
+        let restored = round_trip(stable_state).unwrap();
+
+        STATE.with(|s| s.clear_all());
+        STATE.with(|s| s.replace_all(restored));
+
+        assert_eq!(STATE.with(|s| s.get_canister(eth_addr)), Some(canister_id));
+        assert_eq!(STATE.with(|s| s.get_failed_canisters()).len(), 1);
+        assert!(STATE.with(|s| s.is_authorized()).is_ok());
+    }
+
+    #[test]
+    fn test_migrate_legacy_state() {
+        let bytes = encode_args((legacy_state(),)).unwrap();
+        let migrated = decode(&bytes).unwrap().migrate().unwrap();
+
+        assert_eq!(migrated.canisters.len(), 1);
+        assert_eq!(migrated.controllers, vec![mock_principals::alice()]);
+        assert!(migrated.ingress_policies.is_empty());
+        assert!(migrated.audit_log.is_empty());
+        assert_eq!(migrated.governance, GovernanceConfig::default());
+        assert!(!migrated.paused);
+    }
+
+    #[test]
+    fn test_decode_by_version_tag() {
+        let mut bytes = encode(StableMagicState::default()).unwrap();
+        assert!(matches!(decode(&bytes), Ok(VersionedMagicState::V1(_))));
+
+        // a broken envelope is an error, not a legacy record
+        bytes.truncate(ENVELOPE_MAGIC.len() + 8);
+        assert!(decode(&bytes).is_err());
+
+        assert!(decode(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let mut legacy = legacy_state();
+        legacy.controllers.clear();
+
+        let bytes = encode_args((legacy,)).unwrap();
+
+        // post_upgrade traps on the error and the upgrade is rolled back
+        assert!(decode(&bytes).unwrap().migrate().is_err());
+    }
+}
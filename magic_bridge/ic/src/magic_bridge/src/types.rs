@@ -9,12 +9,99 @@ pub type RetryCount = u8;
 
 pub type MagicResponse = Result<Principal, FactoryError>;
 
-#[derive(CandidType, Deserialize, Clone, Copy)]
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenType {
     DIP20,
     DIP721,
 }
 
+/// Where a DIP20 token keeps its transaction history, cap when none is given
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistorySinkKind {
+    Cap,
+    Memory,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IngressPolicy {
+    /// Any non-anonymous principal
+    Public,
+    /// Controllers only
+    Authorized,
+    /// Rejected for every ingress caller
+    Disabled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GovernanceConfig {
+    /// approvals required before a proposal can execute
+    pub threshold: u32,
+    /// nanoseconds a proposal waits before it can execute
+    pub delay: u64,
+    /// nanoseconds before a pending proposal expires
+    pub ttl: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            threshold: 1,
+            delay: 0,
+            ttl: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+impl GovernanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            return Err("Threshold must be at least 1".to_string());
+        }
+
+        if self.ttl <= self.delay {
+            return Err("Proposal ttl must be longer than the delay".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ProposalAction {
+    Authorize(Principal),
+    UpgradeCode(CanisterId, TokenType),
+    SetGovernance(GovernanceConfig),
+    Unpause,
+    /// Freeze or unfreeze an account on a token created by the bridge
+    SetFrozen(CanisterId, Principal, bool),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: ProposalAction,
+    pub proposer: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Ok,
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub index: u64,
+    pub caller: Principal,
+    pub method: String,
+    /// hex encoded keccak256 of the candid encoded arguments
+    pub args_digest: String,
+    pub time: u64,
+    pub outcome: AuditOutcome,
+}
+
 #[derive(CandidType, Deserialize, Debug)]
 pub enum FactoryError {
     CreateCanisterError,
@@ -43,6 +130,8 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other(String),
+    AccountFrozen,
+    MintCapExceeded,
 }
 #[derive(CandidType, Deserialize)]
 
@@ -0,0 +1,22 @@
+use ic_kit::candid::{encode_args, utils::ArgumentEncoder};
+use sha3::{Digest, Keccak256};
+
+use crate::types::AuditOutcome;
+
+impl<T> From<&Result<T, String>> for AuditOutcome {
+    fn from(result: &Result<T, String>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Ok,
+            Err(error) => AuditOutcome::Err(error.clone()),
+        }
+    }
+}
+
+/// Digest of candid encoded call arguments for the audit log
+pub fn args_digest<T: ArgumentEncoder>(args: T) -> String {
+    let mut hasher = Keccak256::new();
+
+    hasher.update(encode_args(args).unwrap_or_default());
+
+    hex::encode(hasher.finalize().to_vec())
+}